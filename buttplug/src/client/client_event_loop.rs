@@ -10,28 +10,39 @@
 use super::{
   client_message_sorter::ClientMessageSorter,
   device::{ButtplugClientDevice, ButtplugClientDeviceEvent},
+  ButtplugClientError,
   ButtplugClientEvent,
   ButtplugClientMessageFuturePair,
+  ButtplugClientReconnectPolicy,
+  ButtplugServerMessageFuture,
 };
 use crate::{
-  connector::{ButtplugConnector, ButtplugConnectorStateShared},
+  connector::{ButtplugConnector, ButtplugConnectorError, ButtplugConnectorStateShared},
   core::{
-    errors::{ButtplugDeviceError, ButtplugError},
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugHandshakeError},
     messages::{
       ButtplugCurrentSpecClientMessage,
       ButtplugCurrentSpecServerMessage,
       ButtplugDeviceMessage,
+      ButtplugMessage,
       ButtplugMessageValidator,
       DeviceList,
       DeviceMessageInfo,
+      RequestDeviceList,
+      RequestServerInfo,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
 };
 use dashmap::DashMap;
 use futures::FutureExt;
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use futures_timer::Delay;
+use std::{
+  collections::HashSet,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
 };
 use tokio::sync::{broadcast, mpsc};
 
@@ -50,6 +61,30 @@ pub(super) enum ButtplugClientRequest {
   Message(ButtplugClientMessageFuturePair),
 }
 
+/// Everything the event loop needs to reestablish a dropped connection.
+///
+/// Connectors are single use, so we hold on to a factory for building new ones,
+/// as well as the information needed to rerun the handshake.
+pub(super) struct ButtplugClientReconnector<ConnectorType> {
+  client_name: String,
+  policy: ButtplugClientReconnectPolicy,
+  connector_factory: Box<dyn Fn() -> ConnectorType + Send + Sync>,
+}
+
+impl<ConnectorType> ButtplugClientReconnector<ConnectorType> {
+  pub fn new(
+    client_name: &str,
+    policy: ButtplugClientReconnectPolicy,
+    connector_factory: Box<dyn Fn() -> ConnectorType + Send + Sync>,
+  ) -> Self {
+    Self {
+      client_name: client_name.to_owned(),
+      policy,
+      connector_factory,
+    }
+  }
+}
+
 /// Event loop for running [ButtplugClient] connections.
 ///
 /// Acts as a hub for communication between the connector and [ButtplugClient]
@@ -67,7 +102,10 @@ pub(super) enum ButtplugClientRequest {
 ///
 /// - On disconnect, it will tear down, and cannot be used again. All clients
 ///   and devices associated with the loop will be invalidated, and connect must
-///   be called on the client again (or a new client should be created).
+///   be called on the client again (or a new client should be created). The
+///   exception is if the loop was given a [ButtplugClientReconnector] and the
+///   connector dropped, in which case the loop will try to build a new
+///   connector and carry on with the same devices.
 ///
 /// # Why an event loop?
 ///
@@ -99,6 +137,8 @@ where
   /// Receives incoming messages from client instances.
   from_client_receiver: broadcast::Receiver<ButtplugClientRequest>,
   sorter: ClientMessageSorter,
+  /// If set, used to reestablish the connection when the connector drops.
+  reconnector: Option<ButtplugClientReconnector<ConnectorType>>,
 }
 
 impl<ConnectorType> ButtplugClientEventLoop<ConnectorType>
@@ -118,6 +158,7 @@ where
     to_client_sender: broadcast::Sender<ButtplugClientEvent>,
     from_client_sender: broadcast::Sender<ButtplugClientRequest>,
     device_map: Arc<DashMap<u32, Arc<ButtplugClientDevice>>>,
+    reconnector: Option<ButtplugClientReconnector<ConnectorType>>,
   ) -> Self {
     trace!("Creating ButtplugClientEventLoop instance.");
     Self {
@@ -129,6 +170,7 @@ where
       from_connector_receiver,
       connector,
       sorter: ClientMessageSorter::default(),
+      reconnector,
     }
   }
 
@@ -211,6 +253,15 @@ where
       self.send_client_event(ButtplugClientEvent::Error(ButtplugError::from(e)));
       return;
     }
+    // Forwarded server logs are passed along as-is. Logging them here would
+    // just echo them back to the server if it's running in-process.
+    if let ButtplugCurrentSpecServerMessage::Log(log) = msg {
+      self.send_client_event(ButtplugClientEvent::Log(
+        log.log_level().clone(),
+        log.log_message().clone(),
+      ));
+      return;
+    }
     trace!("Message future not found, assuming server event.");
     info!("{:?}", msg);
     match msg {
@@ -254,6 +305,16 @@ where
             ));
         }
       }
+      ButtplugCurrentSpecServerMessage::SensorReading(msg) => {
+        let device_idx = msg.device_index();
+        if let Some(device) = self.device_map.get(&device_idx) {
+          device
+            .value()
+            .queue_event(ButtplugClientDeviceEvent::Message(
+              ButtplugCurrentSpecServerMessage::from(msg),
+            ));
+        }
+      }
       ButtplugCurrentSpecServerMessage::Error(e) => {
         self.send_client_event(ButtplugClientEvent::Error(e.into()));
       }
@@ -312,6 +373,197 @@ where
     }
   }
 
+  /// Marks all devices as no longer having a connected client, and lets
+  /// anything listening on device event streams (e.g. for raw subscription
+  /// readings) know that no more events will arrive.
+  fn disconnect_client_devices(&self) {
+    self.device_map.iter().for_each(|val| {
+      let device = val.value();
+      device.set_client_connected(false);
+      device.queue_event(ButtplugClientDeviceEvent::ClientDisconnect);
+    });
+  }
+
+  /// Sends a message through the connector during reconnection, waiting for
+  /// its reply.
+  ///
+  /// Since the loop isn't running while we reconnect, we pump the connector
+  /// receiver ourselves. Anything that isn't the reply is dropped, as the
+  /// device list we request afterward will cover whatever it may have told us.
+  async fn send_reconnect_message(
+    &mut self,
+    msg: ButtplugCurrentSpecClientMessage,
+  ) -> Result<ButtplugCurrentSpecServerMessage, ButtplugClientError> {
+    let fut = ButtplugServerMessageFuture::default();
+    let mut msg_fut = ButtplugClientMessageFuturePair::new(msg, fut.get_state_clone());
+    self.sorter.register_future(&mut msg_fut);
+    let id = msg_fut.msg.id();
+    self.connector.send(msg_fut.msg).await?;
+    loop {
+      match self.from_connector_receiver.recv().await {
+        None => return Err(ButtplugConnectorError::ConnectorNotConnected.into()),
+        Some(msg) => {
+          if msg.id() == id && self.sorter.maybe_resolve_result(&msg) {
+            return fut.await;
+          }
+          debug!("Dropping message received during reconnection: {:?}", msg);
+        }
+      }
+    }
+  }
+
+  /// Runs the handshake over a freshly connected connector, returning the
+  /// server's device list.
+  async fn run_reconnect_handshake(
+    &mut self,
+    client_name: &str,
+  ) -> Result<DeviceList, ButtplugClientError> {
+    let mut request_server_info =
+      RequestServerInfo::new(client_name, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    request_server_info.set_auth_token(self.connector.auth_token());
+    let msg = self
+      .send_reconnect_message(request_server_info.into())
+      .await?;
+    if !matches!(msg, ButtplugCurrentSpecServerMessage::ServerInfo(_)) {
+      return Err(ButtplugClientError::ButtplugError(
+        ButtplugHandshakeError::UnexpectedHandshakeMessageReceived(format!("{:?}", msg)).into(),
+      ));
+    }
+    match self
+      .send_reconnect_message(RequestDeviceList::default().into())
+      .await?
+    {
+      ButtplugCurrentSpecServerMessage::DeviceList(list) => Ok(list),
+      msg => Err(ButtplugClientError::ButtplugError(
+        ButtplugHandshakeError::UnexpectedHandshakeMessageReceived(format!("{:?}", msg)).into(),
+      )),
+    }
+  }
+
+  /// Matches the device list from a new connection against the devices we
+  /// already have.
+  ///
+  /// Devices with the same index and name keep their handles. Anything we had
+  /// that didn't come back is removed, and anything new is added.
+  fn rebind_devices(&mut self, device_list: &DeviceList) {
+    let mut kept = HashSet::new();
+    for info in device_list.devices() {
+      if let Some(device) = self.device_map.get(&info.device_index) {
+        if device.name == info.device_name {
+          kept.insert(info.device_index);
+        }
+      }
+    }
+    let stale: Vec<u32> = self
+      .device_map
+      .iter()
+      .map(|pair| *pair.key())
+      .filter(|index| !kept.contains(index))
+      .collect();
+    stale
+      .iter()
+      .for_each(|index| self.disconnect_device(*index));
+    for index in &kept {
+      if let Some(device) = self.device_map.get(index) {
+        device.set_client_connected(true);
+      }
+    }
+    for info in device_list.devices() {
+      if kept.contains(&info.device_index) {
+        continue;
+      }
+      let device = self.create_client_device(info);
+      self.send_client_event(ButtplugClientEvent::DeviceAdded(device));
+    }
+  }
+
+  /// Waits out the delay between reconnection attempts.
+  ///
+  /// Client requests can't be serviced while we're disconnected, so messages
+  /// are failed immediately. Returns false if the client asked to disconnect,
+  /// meaning we should stop trying.
+  async fn wait_for_reconnect_attempt(&mut self, delay: std::time::Duration) -> bool {
+    let mut sleep = Delay::new(delay).fuse();
+    loop {
+      select! {
+        _ = sleep => return true,
+        client = self.from_client_receiver.recv().fuse() => match client {
+          Err(_) => return false,
+          Ok(ButtplugClientRequest::Message(msg_fut)) => {
+            msg_fut
+              .waker
+              .set_reply(Err(ButtplugConnectorError::ConnectorNotConnected.into()));
+          }
+          Ok(ButtplugClientRequest::Disconnect(state)) => {
+            state.set_reply(Ok(()));
+            return false;
+          }
+          Ok(ButtplugClientRequest::HandleDeviceList(_)) => {}
+        },
+      }
+    }
+  }
+
+  /// Tries to reestablish the connection after the connector drops.
+  ///
+  /// Returns true if we reconnected and the loop should keep running, false if
+  /// reconnection isn't configured or has been given up on.
+  async fn reconnect(&mut self) -> bool {
+    let reconnector = if let Some(reconnector) = self.reconnector.take() {
+      reconnector
+    } else {
+      return false;
+    };
+    self.connected_status.store(false, Ordering::SeqCst);
+    self.sorter.reject_all_futures();
+    self
+      .device_map
+      .iter()
+      .for_each(|device| device.value().set_client_connected(false));
+    self.send_client_event(ButtplugClientEvent::Reconnecting);
+
+    let policy = reconnector.policy.clone();
+    let mut delay = policy.initial_delay;
+    let mut attempt = 0u32;
+    loop {
+      if let Some(max_attempts) = policy.max_attempts {
+        if attempt >= max_attempts {
+          info!("Giving up on reconnection after {} attempts.", attempt);
+          return false;
+        }
+      }
+      attempt += 1;
+      if !self.wait_for_reconnect_attempt(delay).await {
+        info!("Client disconnected during reconnection.");
+        return false;
+      }
+      delay = policy.next_delay(delay);
+      info!("Reconnection attempt {}.", attempt);
+      let mut connector = (reconnector.connector_factory)();
+      let (connector_sender, connector_receiver) = mpsc::channel(256);
+      if let Err(e) = connector.connect(connector_sender).await {
+        info!("Reconnection attempt {} failed: {:?}", attempt, e);
+        continue;
+      }
+      self.connector = connector;
+      self.from_connector_receiver = connector_receiver;
+      match self.run_reconnect_handshake(&reconnector.client_name).await {
+        Ok(device_list) => {
+          info!("Reconnected to server.");
+          self.connected_status.store(true, Ordering::SeqCst);
+          self.rebind_devices(&device_list);
+          self.reconnector = Some(reconnector);
+          self.send_client_event(ButtplugClientEvent::Reconnected);
+          return true;
+        }
+        Err(e) => {
+          info!("Reconnection handshake failed: {:?}", e);
+          self.sorter.reject_all_futures();
+        }
+      }
+    }
+  }
+
   /// Runs the event loop, returning once either the client or connector drops.
   pub async fn run(&mut self) {
     debug!("Running client event loop.");
@@ -319,9 +571,12 @@ where
       select! {
         event = self.from_connector_receiver.recv().fuse() => match event {
           None => {
-            info!("Connector disconnected, exiting loop.");
-            self.send_client_event(ButtplugClientEvent::ServerDisconnect);
-            return;
+            if !self.reconnect().await {
+              info!("Connector disconnected, exiting loop.");
+              self.disconnect_client_devices();
+              self.send_client_event(ButtplugClientEvent::ServerDisconnect);
+              return;
+            }
           }
           Some(msg) => {
             self.parse_connector_message(msg).await;
@@ -331,7 +586,7 @@ where
           Err(_) => {
             info!("Client disconnected, exiting loop.");
             self.connected_status.store(false, Ordering::SeqCst);
-            self.device_map.iter().for_each(|val| val.value().set_client_connected(false));
+            self.disconnect_client_devices();
             self.send_client_event(ButtplugClientEvent::ServerDisconnect);
             return;
           }
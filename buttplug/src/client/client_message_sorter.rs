@@ -13,6 +13,7 @@ use crate::{
     ButtplugClientMessageFuturePair,
     ButtplugServerMessageStateShared,
   },
+  connector::ButtplugConnectorError,
   core::messages::{ButtplugCurrentSpecServerMessage, ButtplugMessage, ButtplugMessageValidator},
 };
 use dashmap::DashMap;
@@ -117,6 +118,20 @@ impl ClientMessageSorter {
       }
     }
   }
+
+  /// Resolves all outstanding futures with a connector error.
+  ///
+  /// Used when the connector drops out from under us, as replies to anything
+  /// still waiting in the map will never arrive.
+  pub fn reject_all_futures(&self) {
+    let ids: Vec<u32> = self.future_map.iter().map(|pair| *pair.key()).collect();
+    for id in ids {
+      if let Some((_, state)) = self.future_map.remove(&id) {
+        trace!("Rejecting message future for id {}.", id);
+        state.set_reply(Err(ButtplugConnectorError::ConnectorNotConnected.into()));
+      }
+    }
+  }
 }
 
 impl Default for ClientMessageSorter {
@@ -14,6 +14,7 @@ use crate::{
   core::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
     messages::{
+      ActuatorType,
       BatteryLevelCmd,
       ButtplugCurrentSpecClientMessage,
       ButtplugCurrentSpecDeviceMessageType,
@@ -30,6 +31,12 @@ use crate::{
       RawWriteCmd,
       RotateCmd,
       RotationSubcommand,
+      ScalarCmd,
+      ScalarSubcommand,
+      SensorReadCmd,
+      SensorSubscribeCmd,
+      SensorType,
+      SensorUnsubscribeCmd,
       StopDeviceCmd,
       VectorSubcommand,
       VibrateCmd,
@@ -81,6 +88,25 @@ pub enum VibrateCommand {
   SpeedMap(HashMap<u32, f64>),
 }
 
+/// Convenience enum for forming [ScalarCmd] commands.
+///
+/// Allows users to easily specify values across different scalar features in a
+/// device. Units are in absolute values (0.0-1.0), with the actuator type the
+/// value is meant for.
+pub enum ScalarCommand {
+  /// Sets all features of a device with the given actuator type to the same
+  /// value.
+  Scalar((f64, ActuatorType)),
+  /// Sets features to values based on the index of the value in the vec (i.e.
+  /// feature 0 is set to `ScalarVec[0]`, feature 1 is set to `ScalarVec[1]`,
+  /// etc...)
+  ScalarVec(Vec<(f64, ActuatorType)>),
+  /// Sets features indicated by index to requested value. For instance, if the
+  /// map has an entry of (1, (0.5, ActuatorType::Inflate)), it will set
+  /// feature 1 to a value of 0.5.
+  ScalarMap(HashMap<u32, (f64, ActuatorType)>),
+}
+
 /// Convenience enum for forming [RotateCmd] commands.
 ///
 /// Allows users to easily specify speeds/directions across different rotation
@@ -369,6 +395,73 @@ impl ButtplugClientDevice {
     self.send_message_expect_ok(msg)
   }
 
+  /// Commands device to set scalar values on its features, assuming it has the
+  /// features to do so.
+  pub fn scalar(&self, scalar_cmd: ScalarCommand) -> ButtplugClientResultFuture {
+    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::ScalarCmd);
+    let mut actuator_types: Vec<ActuatorType> = vec![];
+    if let Some(features) = self
+      .allowed_messages
+      .get(&ButtplugCurrentSpecDeviceMessageType::ScalarCmd)
+    {
+      if let Some(types) = &features.actuator_type {
+        actuator_types = types.clone();
+      }
+    }
+    let feature_count = actuator_types.len() as u32;
+    let mut scalar_vec: Vec<ScalarSubcommand>;
+    match scalar_cmd {
+      ScalarCommand::Scalar((scalar, actuator_type)) => {
+        scalar_vec = actuator_types
+          .iter()
+          .enumerate()
+          .filter(|(_, x)| **x == actuator_type)
+          .map(|(i, _)| ScalarSubcommand::new(i as u32, scalar, actuator_type))
+          .collect();
+        if scalar_vec.is_empty() {
+          return self.create_boxed_future_client_error(
+            ButtplugDeviceError::ProtocolRequirementError(format!(
+              "Device has no features with actuator type {}.",
+              actuator_type
+            ))
+            .into(),
+          );
+        }
+      }
+      ScalarCommand::ScalarMap(map) => {
+        if map.len() as u32 > feature_count {
+          return self.create_boxed_future_client_error(
+            ButtplugDeviceError::DeviceFeatureCountMismatch(feature_count, map.len() as u32)
+              .into(),
+          );
+        }
+        scalar_vec = Vec::with_capacity(map.len());
+        for (idx, (scalar, actuator_type)) in map {
+          if idx >= feature_count {
+            return self.create_boxed_future_client_error(
+              ButtplugDeviceError::DeviceFeatureIndexError(feature_count, idx).into(),
+            );
+          }
+          scalar_vec.push(ScalarSubcommand::new(idx, scalar, actuator_type));
+        }
+      }
+      ScalarCommand::ScalarVec(vec) => {
+        if vec.len() as u32 > feature_count {
+          return self.create_boxed_future_client_error(
+            ButtplugDeviceError::DeviceFeatureCountMismatch(feature_count, vec.len() as u32)
+              .into(),
+          );
+        }
+        scalar_vec = Vec::with_capacity(vec.len());
+        for (i, (scalar, actuator_type)) in vec.iter().enumerate() {
+          scalar_vec.push(ScalarSubcommand::new(i as u32, *scalar, *actuator_type));
+        }
+      }
+    }
+    let msg = ScalarCmd::new(self.index, scalar_vec).into();
+    self.send_message_expect_ok(msg)
+  }
+
   /// Commands device to move linearly, assuming it has the features to do so.
   pub fn linear(&self, linear_cmd: LinearCommand) -> ButtplugClientResultFuture {
     check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::LinearCmd);
@@ -513,6 +606,69 @@ impl ButtplugClientDevice {
     })
   }
 
+  pub fn sensor_read(
+    &self,
+    sensor_index: u32,
+    sensor_type: SensorType,
+  ) -> ButtplugClientResultFuture<Vec<i32>> {
+    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::SensorReadCmd);
+    let msg = ButtplugCurrentSpecClientMessage::SensorReadCmd(SensorReadCmd::new(
+      self.index,
+      sensor_index,
+      sensor_type,
+    ));
+    let send_fut = self.send_message(msg);
+    Box::pin(async move {
+      match send_fut.await? {
+        ButtplugCurrentSpecServerMessage::SensorReading(reading) => Ok(reading.data().clone()),
+        ButtplugCurrentSpecServerMessage::Error(err) => Err(ButtplugError::from(err).into()),
+        msg => Err(
+          ButtplugError::from(ButtplugMessageError::UnexpectedMessageType(format!(
+            "{:?}",
+            msg
+          )))
+          .into(),
+        ),
+      }
+    })
+  }
+
+  /// Subscribes to a sensor. Readings will show up as
+  /// [ButtplugClientDeviceEvent::Message] events on the device event stream.
+  pub fn sensor_subscribe(
+    &self,
+    sensor_index: u32,
+    sensor_type: SensorType,
+  ) -> ButtplugClientResultFuture {
+    check_message_support!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd
+    );
+    let msg = ButtplugCurrentSpecClientMessage::SensorSubscribeCmd(SensorSubscribeCmd::new(
+      self.index,
+      sensor_index,
+      sensor_type,
+    ));
+    self.send_message_expect_ok(msg)
+  }
+
+  pub fn sensor_unsubscribe(
+    &self,
+    sensor_index: u32,
+    sensor_type: SensorType,
+  ) -> ButtplugClientResultFuture {
+    check_message_support!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd
+    );
+    let msg = ButtplugCurrentSpecClientMessage::SensorUnsubscribeCmd(SensorUnsubscribeCmd::new(
+      self.index,
+      sensor_index,
+      sensor_type,
+    ));
+    self.send_message_expect_ok(msg)
+  }
+
   pub fn raw_write(
     &self,
     endpoint: Endpoint,
@@ -0,0 +1,522 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Loading and playback of funscript files.
+//!
+//! Funscripts are JSON files containing a list of timed positions, usually
+//! synchronized to a video or audio file. [FunscriptPlayer] plays those
+//! positions on a device, following a media clock that the application keeps
+//! it updated with.
+//!
+//! As with [pattern playback][super::pattern], player time is advanced by
+//! ticks rather than read from a clock, so applications should call
+//! [FunscriptPlayer::sync] regularly with the actual media time to correct any
+//! drift.
+
+use super::{
+  device::{ButtplugClientDevice, LinearCommand, VibrateCommand},
+  ButtplugClientResult,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
+    messages::{serializer::ButtplugSerializerError, ButtplugCurrentSpecDeviceMessageType},
+  },
+  device::protocol::fleshlight_launch_helper::get_speed,
+  util::async_manager,
+};
+use futures::{future, FutureExt};
+use futures_timer::Delay;
+use serde::{Deserialize, Serialize};
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::sync::{oneshot, Notify};
+use tokio_util::sync::CancellationToken;
+
+/// A single point in a funscript.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunscriptAction {
+  /// Time of the action, in milliseconds from the start of the media.
+  pub at: u32,
+  /// Position, from 0 to 100.
+  pub pos: u32,
+}
+
+impl FunscriptAction {
+  pub fn new(at: u32, pos: u32) -> Self {
+    Self { at, pos }
+  }
+}
+
+/// Contents of a funscript file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Funscript {
+  #[serde(default)]
+  pub version: String,
+  /// If true, positions in the script are flipped (i.e. 0 is 100, 100 is 0).
+  #[serde(default)]
+  pub inverted: bool,
+  pub actions: Vec<FunscriptAction>,
+}
+
+impl Funscript {
+  /// Parses a funscript from JSON. Actions are sorted by time, and positions
+  /// are clamped to 0-100.
+  pub fn from_json(json: &str) -> Result<Self, ButtplugSerializerError> {
+    let mut script: Funscript = serde_json::from_str(json)
+      .map_err(|e| ButtplugSerializerError::JsonSerializerError(e.to_string()))?;
+    script.actions.sort_by_key(|action| action.at);
+    for action in script.actions.iter_mut() {
+      action.pos = action.pos.min(100);
+    }
+    Ok(script)
+  }
+
+  /// Time of the last action in the script.
+  pub fn duration(&self) -> Duration {
+    self
+      .actions
+      .last()
+      .map(|action| Duration::from_millis(action.at as u64))
+      .unwrap_or_default()
+  }
+}
+
+/// Settings for [FunscriptPlayer].
+#[derive(Clone, Debug)]
+pub struct FunscriptPlayerOptions {
+  /// How often the player checks whether it needs to send a new command.
+  /// Defaults to 10ms.
+  pub tick_rate: Duration,
+  /// How far the time passed to [FunscriptPlayer::sync] can be from the
+  /// player's own time before the current move is resent. Defaults to 50ms.
+  pub resync_threshold: Duration,
+}
+
+impl Default for FunscriptPlayerOptions {
+  fn default() -> Self {
+    Self {
+      tick_rate: Duration::from_millis(10),
+      resync_threshold: Duration::from_millis(50),
+    }
+  }
+}
+
+impl FunscriptPlayerOptions {
+  /// Checks that the options can be played back. The tick rate has to be
+  /// nonzero.
+  pub fn validate(&self) -> Result<(), ButtplugMessageError> {
+    if self.tick_rate.is_zero() {
+      return Err(ButtplugMessageError::ValidationError(
+        "Funscript tick rate must be greater than 0.".to_owned(),
+      ));
+    }
+    Ok(())
+  }
+}
+
+#[derive(Default)]
+struct FunscriptPlayerState {
+  media_time: Duration,
+  offset: i64,
+  inverted: bool,
+  paused: bool,
+  resend: bool,
+}
+
+impl FunscriptPlayerState {
+  /// Script time in milliseconds, which is the media time with the offset
+  /// applied. May be negative if the offset is larger than the media time.
+  fn script_time(&self) -> i64 {
+    self.media_time.as_millis() as i64 - self.offset
+  }
+}
+
+/// How the script is turned into device commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FunscriptOutput {
+  Linear,
+  Vibrate,
+}
+
+/// Plays a [Funscript] on a device.
+///
+/// Devices with linear features are sent moves to each action's position,
+/// timed to arrive when the action happens. Devices with only vibration
+/// features have the speed of each movement mapped to vibration strength.
+///
+/// The player keeps running after the last action, so that seeking backward
+/// still works. Call [FunscriptPlayer::stop] to end playback.
+pub struct FunscriptPlayer {
+  state: Arc<Mutex<FunscriptPlayerState>>,
+  notifier: Arc<Notify>,
+  resync_threshold: Duration,
+  token: CancellationToken,
+  finished: oneshot::Receiver<ButtplugClientResult>,
+}
+
+impl FunscriptPlayer {
+  /// Starts playing a script on a device, from media time 0.
+  ///
+  /// Returns an error if the options fail [FunscriptPlayerOptions::validate],
+  /// or if the device supports neither linear nor vibrate commands.
+  pub fn play(
+    device: Arc<ButtplugClientDevice>,
+    script: Funscript,
+    options: FunscriptPlayerOptions,
+  ) -> Result<Self, ButtplugError> {
+    options.validate()?;
+    let output = if device
+      .allowed_messages
+      .contains_key(&ButtplugCurrentSpecDeviceMessageType::LinearCmd)
+    {
+      FunscriptOutput::Linear
+    } else if device
+      .allowed_messages
+      .contains_key(&ButtplugCurrentSpecDeviceMessageType::VibrateCmd)
+    {
+      FunscriptOutput::Vibrate
+    } else {
+      return Err(
+        ButtplugDeviceError::MessageNotSupported(
+          ButtplugCurrentSpecDeviceMessageType::LinearCmd.into(),
+        )
+        .into(),
+      );
+    };
+    let state = Arc::new(Mutex::new(FunscriptPlayerState {
+      inverted: script.inverted,
+      ..Default::default()
+    }));
+    let notifier = Arc::new(Notify::new());
+    let token = CancellationToken::new();
+    let (finished_sender, finished) = oneshot::channel();
+    let task_state = state.clone();
+    let task_notifier = notifier.clone();
+    let task_token = token.clone();
+    let tick_rate = options.tick_rate;
+    async_manager::spawn(async move {
+      let result = run_funscript(
+        device,
+        script,
+        output,
+        tick_rate,
+        task_state,
+        task_notifier,
+        task_token,
+      )
+      .await;
+      if let Err(e) = &result {
+        error!("Funscript playback ended with error: {:?}", e);
+      }
+      let _ = finished_sender.send(result);
+    });
+    Ok(Self {
+      state,
+      notifier,
+      resync_threshold: options.resync_threshold,
+      token,
+      finished,
+    })
+  }
+
+  fn update<F: FnOnce(&mut FunscriptPlayerState)>(&self, func: F) {
+    func(
+      &mut self
+        .state
+        .lock()
+        .expect("Player state lock should never be poisoned."),
+    );
+    self.notifier.notify_one();
+  }
+
+  /// Pauses playback, stopping the device until [FunscriptPlayer::resume] is
+  /// called.
+  pub fn pause(&self) {
+    self.update(|state| state.paused = true);
+  }
+
+  pub fn resume(&self) {
+    self.update(|state| {
+      state.paused = false;
+      state.resend = true;
+    });
+  }
+
+  pub fn paused(&self) -> bool {
+    self
+      .state
+      .lock()
+      .expect("Player state lock should never be poisoned.")
+      .paused
+  }
+
+  /// Current media time, as tracked by the player.
+  pub fn media_time(&self) -> Duration {
+    self
+      .state
+      .lock()
+      .expect("Player state lock should never be poisoned.")
+      .media_time
+  }
+
+  /// Jumps to a media time, immediately sending the device toward the
+  /// position it should be at.
+  pub fn seek(&self, media_time: Duration) {
+    self.update(|state| {
+      state.media_time = media_time;
+      state.resend = true;
+    });
+  }
+
+  /// Updates the player with the current time of the media it is following.
+  ///
+  /// Meant to be called regularly, i.e. every time a video player reports its
+  /// position. The current move is only resent if the player's time was off by
+  /// more than the resync threshold.
+  pub fn sync(&self, media_time: Duration) {
+    let threshold = self.resync_threshold;
+    self.update(|state| {
+      let drift = media_time.abs_diff(state.media_time);
+      state.media_time = media_time;
+      if drift > threshold {
+        state.resend = true;
+      }
+    });
+  }
+
+  /// Sets how far behind the media the script plays, in milliseconds. Negative
+  /// values play the script ahead of the media.
+  pub fn set_offset(&self, offset: i64) {
+    self.update(|state| {
+      state.offset = offset;
+      state.resend = true;
+    });
+  }
+
+  /// Sets whether positions are flipped. Defaults to the script's `inverted`
+  /// value.
+  pub fn set_inverted(&self, inverted: bool) {
+    self.update(|state| {
+      state.inverted = inverted;
+      state.resend = true;
+    });
+  }
+
+  /// Stops playback and sends a stop command to the device.
+  pub fn stop(&self) {
+    self.token.cancel();
+  }
+
+  /// Waits for playback to end, either by being stopped or failing to send a
+  /// command to the device.
+  pub async fn finished(self) -> ButtplugClientResult {
+    self.finished.await.unwrap_or(Ok(()))
+  }
+}
+
+/// Vibration speed for moving between two actions, based on how fast the
+/// movement is.
+///
+/// Actions with the same timestamp are an instant jump, which is as fast as a
+/// movement gets, unless they're also at the same position.
+fn vibrate_speed(previous: FunscriptAction, next: FunscriptAction) -> f64 {
+  let distance = (next.pos as f64 - previous.pos as f64).abs() / 100f64;
+  let duration = next.at.saturating_sub(previous.at);
+  if duration == 0 {
+    return if distance > 0f64 { 1f64 } else { 0f64 };
+  }
+  get_speed(distance, duration).clamp(0f64, 1f64)
+}
+
+/// Sends the command for moving toward the action at `next_index`.
+async fn send_move(
+  device: &ButtplugClientDevice,
+  actions: &[FunscriptAction],
+  output: FunscriptOutput,
+  next_index: usize,
+  script_time: i64,
+  inverted: bool,
+) -> ButtplugClientResult {
+  let next = actions[next_index];
+  match output {
+    FunscriptOutput::Linear => {
+      let pos = if inverted {
+        100u32.saturating_sub(next.pos)
+      } else {
+        next.pos
+      };
+      let duration = (next.at as i64 - script_time) as u32;
+      device
+        .linear(LinearCommand::Linear(duration, pos as f64 / 100f64))
+        .await
+    }
+    FunscriptOutput::Vibrate => {
+      // Speed is the same whichever way the positions are flipped, so
+      // inversion doesn't matter here.
+      let speed = if next_index == 0 {
+        0f64
+      } else {
+        vibrate_speed(actions[next_index - 1], next)
+      };
+      device.vibrate(VibrateCommand::Speed(speed)).await
+    }
+  }
+}
+
+async fn run_funscript(
+  device: Arc<ButtplugClientDevice>,
+  script: Funscript,
+  output: FunscriptOutput,
+  tick_rate: Duration,
+  state: Arc<Mutex<FunscriptPlayerState>>,
+  notifier: Arc<Notify>,
+  token: CancellationToken,
+) -> ButtplugClientResult {
+  // Index of the action we last sent a move toward, so each move is only sent
+  // once.
+  let mut current_target = None;
+  let mut stopped = false;
+  loop {
+    let (script_time, inverted, paused, resend) = {
+      let mut state = state
+        .lock()
+        .expect("Player state lock should never be poisoned.");
+      let resend = state.resend;
+      state.resend = false;
+      (state.script_time(), state.inverted, state.paused, resend)
+    };
+    if resend {
+      current_target = None;
+    }
+    let next_index = script
+      .actions
+      .iter()
+      .position(|action| action.at as i64 > script_time);
+    if paused {
+      if !stopped {
+        device.stop().await?;
+        stopped = true;
+      }
+      current_target = None;
+    } else if next_index != current_target {
+      match next_index {
+        Some(index) => {
+          send_move(
+            &device,
+            &script.actions,
+            output,
+            index,
+            script_time,
+            inverted,
+          )
+          .await?;
+          stopped = false;
+        }
+        // Past the end of the script, so there's nowhere left to move to.
+        None => {
+          if !stopped {
+            device.stop().await?;
+            stopped = true;
+          }
+        }
+      }
+      current_target = next_index;
+    }
+    // While paused or past the end there's nothing to do until something
+    // changes, so don't bother ticking.
+    let idle = paused || next_index.is_none();
+    let tick = if idle {
+      future::Either::Left(future::pending::<()>())
+    } else {
+      future::Either::Right(Delay::new(tick_rate))
+    };
+    select! {
+      _ = tick.fuse() => {
+        state
+          .lock()
+          .expect("Player state lock should never be poisoned.")
+          .media_time += tick_rate;
+      },
+      _ = notifier.notified().fuse() => {},
+      _ = token.cancelled().fuse() => {
+        return device.stop().await;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_funscript_parsing() {
+    let script = Funscript::from_json(
+      r#"{
+        "version": "1.0",
+        "inverted": true,
+        "range": 90,
+        "actions": [
+          {"at": 500, "pos": 100},
+          {"at": 0, "pos": 0},
+          {"at": 1000, "pos": 150}
+        ]
+      }"#,
+    )
+    .expect("Test, assuming infallible.");
+    assert!(script.inverted);
+    assert_eq!(
+      script.actions,
+      vec![
+        FunscriptAction::new(0, 0),
+        FunscriptAction::new(500, 100),
+        FunscriptAction::new(1000, 100)
+      ]
+    );
+    assert_eq!(script.duration(), Duration::from_millis(1000));
+
+    let script = Funscript::from_json(r#"{"actions": []}"#).expect("Test, assuming infallible.");
+    assert!(!script.inverted);
+    assert_eq!(script.duration(), Duration::ZERO);
+
+    assert!(Funscript::from_json(r#"{"version": "1.0"}"#).is_err());
+  }
+
+  #[test]
+  fn test_funscript_vibrate_speed() {
+    assert_eq!(
+      vibrate_speed(FunscriptAction::new(0, 0), FunscriptAction::new(100, 100)),
+      1.0
+    );
+    assert_eq!(
+      vibrate_speed(FunscriptAction::new(0, 50), FunscriptAction::new(100, 50)),
+      0.0
+    );
+    // Actions at the same timestamp shouldn't divide by zero.
+    assert_eq!(
+      vibrate_speed(FunscriptAction::new(100, 0), FunscriptAction::new(100, 100)),
+      1.0
+    );
+    assert_eq!(
+      vibrate_speed(FunscriptAction::new(100, 50), FunscriptAction::new(100, 50)),
+      0.0
+    );
+  }
+  #[test]
+  fn test_funscript_player_options_validation() {
+    assert!(FunscriptPlayerOptions::default().validate().is_ok());
+    assert!(FunscriptPlayerOptions {
+      tick_rate: Duration::ZERO,
+      ..Default::default()
+    }
+    .validate()
+    .is_err());
+  }
+}
@@ -9,23 +9,30 @@
 pub mod client_event_loop;
 mod client_message_sorter;
 pub mod device;
+pub mod funscript;
+pub mod pattern;
 
 #[cfg(feature = "server")]
 use crate::server::ButtplugServer;
 use crate::{
   connector::{ButtplugConnector, ButtplugConnectorError, ButtplugConnectorFuture},
   core::{
-    errors::{ButtplugError, ButtplugHandshakeError},
+    errors::{ButtplugError, ButtplugHandshakeError, ButtplugMessageError},
     messages::{
       ButtplugCurrentSpecClientMessage,
       ButtplugCurrentSpecServerMessage,
-      ButtplugMessageSpecVersion,
+      KnownDeviceInfo,
+      LogLevel,
       Ping,
       RequestDeviceList,
+      RequestKnownDeviceList,
+      RequestLog,
       RequestServerInfo,
+      SetDeviceUserConfig,
       StartScanning,
       StopAllDevices,
       StopScanning,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
   util::{
@@ -34,7 +41,11 @@ use crate::{
     stream::convert_broadcast_receiver_to_stream,
   },
 };
-use client_event_loop::{ButtplugClientEventLoop, ButtplugClientRequest};
+use client_event_loop::{
+  ButtplugClientEventLoop,
+  ButtplugClientReconnector,
+  ButtplugClientRequest,
+};
 use dashmap::DashMap;
 pub use device::{
   ButtplugClientDevice,
@@ -42,15 +53,19 @@ pub use device::{
   ButtplugClientDeviceMessageType,
   LinearCommand,
   RotateCommand,
+  ScalarCommand,
   VibrateCommand,
 };
 use futures::{
   future::{self, BoxFuture},
   Stream,
 };
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, Mutex};
@@ -147,14 +162,63 @@ pub enum ButtplugClientEvent {
   ServerConnect,
   /// Emitted when a client connector detects that the server has disconnected.
   ServerDisconnect,
+  /// Emitted when the connection to the server has been lost and the client is
+  /// trying to reestablish it, as configured via
+  /// [ButtplugClient::connect_with_reconnect]. Device handles stay in place
+  /// while this happens, but commands sent through them will fail until
+  /// [ButtplugClientEvent::Reconnected] is received.
+  Reconnecting,
+  /// Emitted when the client has reconnected to the server and finished
+  /// rebinding devices.
+  Reconnected,
   /// Emitted when an error that cannot be matched to a request is received from
   /// the server.
   Error(ButtplugError),
+  /// Emitted when the server forwards a log message, after logs have been
+  /// requested via [ButtplugClient::request_log].
+  Log(LogLevel, String),
 }
 
 impl Unpin for ButtplugClientEvent {
 }
 
+/// Settings for how a [ButtplugClient] should retry after losing its server
+/// connection.
+///
+/// Used with [ButtplugClient::connect_with_reconnect]. The delay between
+/// attempts starts at `initial_delay` and is multiplied by `backoff_multiplier`
+/// after every failure, topping out at `max_delay`.
+#[derive(Clone, Debug)]
+pub struct ButtplugClientReconnectPolicy {
+  /// Time to wait before the first reconnection attempt.
+  pub initial_delay: Duration,
+  /// Upper bound for the time between reconnection attempts.
+  pub max_delay: Duration,
+  /// Factor the delay is multiplied by after each failed attempt.
+  pub backoff_multiplier: f64,
+  /// Number of attempts to make before giving up and emitting
+  /// [ButtplugClientEvent::ServerDisconnect]. `None` retries forever.
+  pub max_attempts: Option<u32>,
+}
+
+impl Default for ButtplugClientReconnectPolicy {
+  fn default() -> Self {
+    Self {
+      initial_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(30),
+      backoff_multiplier: 2.0,
+      max_attempts: Some(10),
+    }
+  }
+}
+
+impl ButtplugClientReconnectPolicy {
+  /// Returns the delay to use after a failed attempt that waited `delay`.
+  pub(crate) fn next_delay(&self, delay: Duration) -> Duration {
+    delay.mul_f64(self.backoff_multiplier).min(self.max_delay)
+  }
+}
+
 /// Struct used by applications to communicate with a Buttplug Server.
 ///
 /// Buttplug Clients provide an API layer on top of the Buttplug Protocol that
@@ -201,8 +265,52 @@ impl ButtplugClient {
   }
 
   pub async fn connect<ConnectorType>(
+    &self,
+    connector: ConnectorType,
+  ) -> Result<(), ButtplugClientError>
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+  {
+    self.connect_internal(connector, None).await
+  }
+
+  /// Connects to a server, reconnecting automatically if the connection drops.
+  ///
+  /// Connectors can only be used for a single connection, so this takes a
+  /// factory that will be called to build a fresh connector for the initial
+  /// connection and every reconnection attempt.
+  ///
+  /// If the connector reports that the server went away, the client emits
+  /// [ButtplugClientEvent::Reconnecting] and retries according to `policy`. On
+  /// success, the handshake is rerun, the device list is fetched again, and
+  /// [ButtplugClientEvent::Reconnected] is emitted. Devices that come back with
+  /// the same index and name keep their existing [ButtplugClientDevice]
+  /// handles. Devices that don't come back are removed as usual, and new
+  /// devices are added as usual. If all attempts fail,
+  /// [ButtplugClientEvent::ServerDisconnect] is emitted.
+  ///
+  /// Calling [ButtplugClient::disconnect] will not trigger reconnection.
+  pub async fn connect_with_reconnect<ConnectorType, FactoryType>(
+    &self,
+    connector_factory: FactoryType,
+    policy: ButtplugClientReconnectPolicy,
+  ) -> Result<(), ButtplugClientError>
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+    FactoryType: Fn() -> ConnectorType + Send + Sync + 'static,
+  {
+    let connector = connector_factory();
+    let reconnector =
+      ButtplugClientReconnector::new(&self.client_name, policy, Box::new(connector_factory));
+    self.connect_internal(connector, Some(reconnector)).await
+  }
+
+  async fn connect_internal<ConnectorType>(
     &self,
     mut connector: ConnectorType,
+    reconnector: Option<ButtplugClientReconnector<ConnectorType>>,
   ) -> Result<(), ButtplugClientError>
   where
     ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
@@ -221,6 +329,7 @@ impl ButtplugClient {
       Some(span)
     };
     info!("Connecting to server.");
+    let auth_token = connector.auth_token();
     let (connector_sender, connector_receiver) = mpsc::channel(256);
     connector.connect(connector_sender).await.map_err(|e| {
       error!("Connection to server failed: {:?}", e);
@@ -234,6 +343,7 @@ impl ButtplugClient {
       self.event_stream.clone(),
       self.message_sender.clone(),
       self.device_map.clone(),
+      reconnector,
     );
 
     // Start the event loop before we run the handshake.
@@ -243,7 +353,7 @@ impl ButtplugClient {
       }
       .instrument(tracing::info_span!("Client Loop Span")),
     );
-    self.run_handshake().await
+    self.run_handshake(auth_token).await
   }
 
   /// Convenience function for creating in-process connectors.
@@ -362,13 +472,14 @@ impl ButtplugClient {
   /// the struct, then tries to run connect and execute the Buttplug protocol
   /// handshake. Will return a connected and ready to use ButtplugClient is all
   /// goes well.
-  async fn run_handshake(&self) -> ButtplugClientResult {
+  async fn run_handshake(&self, auth_token: Option<String>) -> ButtplugClientResult {
     // Run our handshake
     info!("Running handshake with server.");
+    let mut request_server_info =
+      RequestServerInfo::new(&self.client_name, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    request_server_info.set_auth_token(auth_token);
     let msg = self
-      .send_message_ignore_connect_status(
-        RequestServerInfo::new(&self.client_name, ButtplugMessageSpecVersion::Version2).into(),
-      )
+      .send_message_ignore_connect_status(request_server_info.into())
       .await?;
 
     debug!("Got ServerInfo return.");
@@ -538,6 +649,47 @@ impl ButtplugClient {
       .collect()
   }
 
+  /// Asks the server to forward its log output at or above the requested
+  /// level. Logs will show up as [ButtplugClientEvent::Log] events.
+  ///
+  /// Sending [LogLevel::Off] will stop log forwarding.
+  pub fn request_log(&self, level: LogLevel) -> ButtplugClientResultFuture {
+    self.send_message_expect_ok(RequestLog::new(level).into())
+  }
+
+  /// Lists every device address the server knows about, connected or not,
+  /// along with its user configuration. Only works if the server allows
+  /// device configuration messages.
+  pub fn known_devices(&self) -> ButtplugClientResultFuture<Vec<KnownDeviceInfo>> {
+    let send_fut = self.send_message(RequestKnownDeviceList::default().into());
+    Box::pin(async move {
+      match send_fut.await? {
+        ButtplugCurrentSpecServerMessage::KnownDeviceList(list) => Ok(list.devices().clone()),
+        ButtplugCurrentSpecServerMessage::Error(err) => Err(ButtplugError::from(err).into()),
+        msg => Err(
+          ButtplugError::from(ButtplugMessageError::UnexpectedMessageType(format!(
+            "{:?}",
+            msg
+          )))
+          .into(),
+        ),
+      }
+    })
+  }
+
+  /// Sets the display name and allow/deny entries for a device address,
+  /// replacing whatever was set before. Denied devices are disconnected right
+  /// away. Only works if the server allows device configuration messages.
+  pub fn set_device_user_config(
+    &self,
+    address: &str,
+    display_name: Option<String>,
+    allow: Option<bool>,
+    deny: Option<bool>,
+  ) -> ButtplugClientResultFuture {
+    self.send_message_expect_ok(SetDeviceUserConfig::new(address, display_name, allow, deny).into())
+  }
+
   pub fn ping(&self) -> ButtplugClientResultFuture {
     let ping_fut = self.send_message_expect_ok(Ping::default().into());
     Box::pin(async move { ping_fut.await })
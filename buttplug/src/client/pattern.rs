@@ -0,0 +1,443 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Client-side playback of keyframed patterns on devices.
+//!
+//! Rather than making every application build its own timers for waves, ramps
+//! and pulses, a [Pattern] describes how each feature of a device should change
+//! over time, and [play_pattern] takes care of turning that into device
+//! commands.
+//!
+//! Pattern time is advanced by a fixed amount every tick instead of being read
+//! from a clock. If the connection to the device is slower than the tick rate,
+//! playback stretches out rather than skipping ahead or queuing up commands.
+
+use super::{
+  device::{ButtplugClientDevice, LinearCommand, RotateCommand, ScalarCommand, VibrateCommand},
+  ButtplugClientResult,
+};
+use crate::{
+  core::{errors::ButtplugMessageError, messages::ActuatorType},
+  util::async_manager,
+};
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+/// How values are calculated between two keyframes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatternInterpolation {
+  /// Moves between keyframe values at a constant rate.
+  Linear,
+  /// Moves between keyframe values slowly at the start and end, and quickly in
+  /// the middle.
+  Ease,
+  /// Holds each keyframe value until the next keyframe is reached.
+  Step,
+}
+
+/// A value (0.0-1.0) that a feature should be at a certain time into a pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PatternKeyframe {
+  pub time: Duration,
+  pub value: f64,
+}
+
+impl PatternKeyframe {
+  pub fn new(time: Duration, value: f64) -> Self {
+    Self { time, value }
+  }
+}
+
+/// A series of keyframes, along with how to interpolate between them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatternCurve {
+  interpolation: PatternInterpolation,
+  keyframes: Vec<PatternKeyframe>,
+}
+
+impl PatternCurve {
+  /// Creates a new curve. Keyframes do not need to be in order.
+  pub fn new(interpolation: PatternInterpolation, mut keyframes: Vec<PatternKeyframe>) -> Self {
+    keyframes.sort_by_key(|keyframe| keyframe.time);
+    Self {
+      interpolation,
+      keyframes,
+    }
+  }
+
+  pub fn interpolation(&self) -> PatternInterpolation {
+    self.interpolation
+  }
+
+  pub fn keyframes(&self) -> &Vec<PatternKeyframe> {
+    &self.keyframes
+  }
+
+  /// Time of the last keyframe in the curve.
+  pub fn duration(&self) -> Duration {
+    self
+      .keyframes
+      .last()
+      .map(|keyframe| keyframe.time)
+      .unwrap_or_default()
+  }
+
+  /// Returns the value of the curve at the given time, clamped to 0.0-1.0.
+  ///
+  /// Times before the first keyframe get the first keyframe's value, and times
+  /// after the last keyframe get the last keyframe's value. Empty curves always
+  /// return 0.0.
+  pub fn value_at(&self, time: Duration) -> f64 {
+    let next_index = self
+      .keyframes
+      .iter()
+      .position(|keyframe| keyframe.time > time);
+    let value = match next_index {
+      None => self.keyframes.last().map(|k| k.value).unwrap_or(0.0),
+      Some(0) => self.keyframes[0].value,
+      Some(index) => {
+        let previous = self.keyframes[index - 1];
+        let next = self.keyframes[index];
+        let progress =
+          (time - previous.time).as_nanos() as f64 / (next.time - previous.time).as_nanos() as f64;
+        let progress = match self.interpolation {
+          PatternInterpolation::Linear => progress,
+          PatternInterpolation::Ease => progress * progress * (3.0 - 2.0 * progress),
+          PatternInterpolation::Step => 0.0,
+        };
+        previous.value + (next.value - previous.value) * progress
+      }
+    };
+    value.clamp(0.0, 1.0)
+  }
+}
+
+/// Device feature a [PatternCurve] drives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PatternFeature {
+  /// Vibration feature index, driven via [ButtplugClientDevice::vibrate].
+  Vibrate(u32),
+  /// Scalar feature index and actuator type, driven via
+  /// [ButtplugClientDevice::scalar].
+  Scalar(u32, ActuatorType),
+  /// Rotation feature index and direction (clockwise if true), driven via
+  /// [ButtplugClientDevice::rotate].
+  Rotate(u32, bool),
+  /// Linear feature index, driven via [ButtplugClientDevice::linear]. Each tick
+  /// moves to the current curve value over the length of the tick.
+  Linear(u32),
+}
+
+/// Set of curves, one per device feature, that are played back together.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pattern {
+  tracks: Vec<(PatternFeature, PatternCurve)>,
+}
+
+impl Pattern {
+  /// Adds a curve for a feature. If the feature already has a curve, it is
+  /// replaced.
+  pub fn track(mut self, feature: PatternFeature, curve: PatternCurve) -> Self {
+    self.tracks.retain(|(existing, _)| *existing != feature);
+    self.tracks.push((feature, curve));
+    self
+  }
+
+  pub fn tracks(&self) -> &Vec<(PatternFeature, PatternCurve)> {
+    &self.tracks
+  }
+
+  /// Length of the longest curve in the pattern.
+  pub fn duration(&self) -> Duration {
+    self
+      .tracks
+      .iter()
+      .map(|(_, curve)| curve.duration())
+      .max()
+      .unwrap_or_default()
+  }
+}
+
+/// Settings for [play_pattern].
+#[derive(Clone, Debug)]
+pub struct PatternPlayerOptions {
+  /// How often device commands are sent. Defaults to 50ms.
+  pub tick_rate: Duration,
+  /// Multiplier for how fast pattern time passes, i.e. 2.0 plays a pattern at
+  /// double speed. Defaults to 1.0.
+  pub speed: f64,
+  /// If true, the pattern starts over when it reaches the end, and will play
+  /// until stopped. Defaults to false.
+  pub looping: bool,
+}
+
+impl Default for PatternPlayerOptions {
+  fn default() -> Self {
+    Self {
+      tick_rate: Duration::from_millis(50),
+      speed: 1.0,
+      looping: false,
+    }
+  }
+}
+
+impl PatternPlayerOptions {
+  /// Checks that the options can be played back. The tick rate has to be
+  /// nonzero, and the speed has to be a finite, non-negative number.
+  pub fn validate(&self) -> Result<(), ButtplugMessageError> {
+    if self.tick_rate.is_zero() {
+      return Err(invalid_options("Pattern tick rate must be greater than 0."));
+    }
+    if !self.speed.is_finite() || self.speed < 0.0 {
+      return Err(invalid_options(&format!(
+        "Pattern speed must be a finite, non-negative number, got {}.",
+        self.speed
+      )));
+    }
+    Ok(())
+  }
+}
+
+fn invalid_options(msg: &str) -> ButtplugMessageError {
+  ButtplugMessageError::ValidationError(msg.to_owned())
+}
+
+/// Handle to a pattern started via [play_pattern].
+///
+/// Dropping the handle does not stop playback.
+pub struct PatternHandle {
+  token: CancellationToken,
+  finished: oneshot::Receiver<ButtplugClientResult>,
+}
+
+impl PatternHandle {
+  /// Stops playback and sends a stop command to the device.
+  pub fn stop(&self) {
+    self.token.cancel();
+  }
+
+  /// Waits for playback to end, either by reaching the end of a non-looping
+  /// pattern, being stopped, or failing to send a command to the device.
+  pub async fn finished(self) -> ButtplugClientResult {
+    // If the playback task went away without replying, there's nothing left
+    // to report.
+    self.finished.await.unwrap_or(Ok(()))
+  }
+}
+
+/// Values most recently sent for each feature, so we only send changes.
+#[derive(Default)]
+struct SentValues {
+  values: HashMap<PatternFeature, f64>,
+}
+
+impl SentValues {
+  fn changed(&mut self, feature: PatternFeature, value: f64) -> bool {
+    if self.values.get(&feature) == Some(&value) {
+      false
+    } else {
+      self.values.insert(feature, value);
+      true
+    }
+  }
+}
+
+/// Sends the values for a single tick, coalescing all features driven by the
+/// same command type into a single message.
+async fn send_tick(
+  device: &ButtplugClientDevice,
+  pattern: &Pattern,
+  time: Duration,
+  tick_rate: Duration,
+  sent: &mut SentValues,
+) -> ButtplugClientResult {
+  let mut vibrations = HashMap::new();
+  let mut scalars = HashMap::new();
+  let mut rotations = HashMap::new();
+  let mut linears = HashMap::new();
+  for (feature, curve) in pattern.tracks() {
+    let value = curve.value_at(time);
+    if !sent.changed(*feature, value) {
+      continue;
+    }
+    match *feature {
+      PatternFeature::Vibrate(index) => {
+        vibrations.insert(index, value);
+      }
+      PatternFeature::Scalar(index, actuator_type) => {
+        scalars.insert(index, (value, actuator_type));
+      }
+      PatternFeature::Rotate(index, clockwise) => {
+        rotations.insert(index, (value, clockwise));
+      }
+      PatternFeature::Linear(index) => {
+        linears.insert(index, (tick_rate.as_millis() as u32, value));
+      }
+    }
+  }
+  if !vibrations.is_empty() {
+    device.vibrate(VibrateCommand::SpeedMap(vibrations)).await?;
+  }
+  if !scalars.is_empty() {
+    device.scalar(ScalarCommand::ScalarMap(scalars)).await?;
+  }
+  if !rotations.is_empty() {
+    device.rotate(RotateCommand::RotateMap(rotations)).await?;
+  }
+  if !linears.is_empty() {
+    device.linear(LinearCommand::LinearMap(linears)).await?;
+  }
+  Ok(())
+}
+
+async fn run_pattern(
+  device: Arc<ButtplugClientDevice>,
+  pattern: Pattern,
+  options: PatternPlayerOptions,
+  token: CancellationToken,
+) -> ButtplugClientResult {
+  let duration = pattern.duration();
+  let mut sent = SentValues::default();
+  let mut elapsed = Duration::ZERO;
+  loop {
+    let time = if options.looping && !duration.is_zero() {
+      Duration::from_nanos((elapsed.as_nanos() % duration.as_nanos()) as u64)
+    } else {
+      elapsed.min(duration)
+    };
+    send_tick(&device, &pattern, time, options.tick_rate, &mut sent).await?;
+    if !options.looping && elapsed >= duration {
+      return Ok(());
+    }
+    select! {
+      _ = Delay::new(options.tick_rate).fuse() => {},
+      _ = token.cancelled().fuse() => {
+        return device.stop().await;
+      }
+    }
+    elapsed += options.tick_rate.mul_f64(options.speed);
+  }
+}
+
+/// Plays a pattern on a device.
+///
+/// Playback happens in its own task, with each feature's curve being sampled
+/// once per tick. All features that use the same command type are sent as a
+/// single message, features whose value hasn't changed since the last tick are
+/// skipped, and each tick waits for the previous tick's commands to finish, so
+/// devices won't be flooded with messages.
+///
+/// Stopping via the returned [PatternHandle] sends a stop command to the
+/// device. Reaching the end of a non-looping pattern leaves the device at the
+/// last keyframe values.
+///
+/// Returns an error without starting playback if the options fail
+/// [PatternPlayerOptions::validate].
+pub fn play_pattern(
+  device: Arc<ButtplugClientDevice>,
+  pattern: Pattern,
+  options: PatternPlayerOptions,
+) -> Result<PatternHandle, ButtplugMessageError> {
+  options.validate()?;
+  let token = CancellationToken::new();
+  let (finished_sender, finished) = oneshot::channel();
+  let task_token = token.clone();
+  async_manager::spawn(async move {
+    let result = run_pattern(device, pattern, options, task_token).await;
+    if let Err(e) = &result {
+      error!("Pattern playback ended with error: {:?}", e);
+    }
+    let _ = finished_sender.send(result);
+  });
+  Ok(PatternHandle { token, finished })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn keyframes() -> Vec<PatternKeyframe> {
+    vec![
+      PatternKeyframe::new(Duration::from_millis(100), 1.0),
+      PatternKeyframe::new(Duration::from_millis(0), 0.0),
+      PatternKeyframe::new(Duration::from_millis(200), 0.5),
+    ]
+  }
+
+  #[test]
+  fn test_pattern_curve_interpolation() {
+    let linear = PatternCurve::new(PatternInterpolation::Linear, keyframes());
+    assert_eq!(linear.duration(), Duration::from_millis(200));
+    assert_eq!(linear.value_at(Duration::from_millis(0)), 0.0);
+    assert_eq!(linear.value_at(Duration::from_millis(25)), 0.25);
+    assert_eq!(linear.value_at(Duration::from_millis(150)), 0.75);
+    assert_eq!(linear.value_at(Duration::from_millis(500)), 0.5);
+
+    let ease = PatternCurve::new(PatternInterpolation::Ease, keyframes());
+    assert_eq!(ease.value_at(Duration::from_millis(50)), 0.5);
+    assert!(ease.value_at(Duration::from_millis(25)) < 0.25);
+    assert!(ease.value_at(Duration::from_millis(75)) > 0.75);
+
+    let step = PatternCurve::new(PatternInterpolation::Step, keyframes());
+    assert_eq!(step.value_at(Duration::from_millis(99)), 0.0);
+    assert_eq!(step.value_at(Duration::from_millis(100)), 1.0);
+    assert_eq!(step.value_at(Duration::from_millis(199)), 1.0);
+
+    let empty = PatternCurve::new(PatternInterpolation::Linear, vec![]);
+    assert_eq!(empty.value_at(Duration::from_millis(100)), 0.0);
+  }
+
+  #[test]
+  fn test_pattern_duration_and_tracks() {
+    let pattern = Pattern::default()
+      .track(
+        PatternFeature::Vibrate(0),
+        PatternCurve::new(PatternInterpolation::Linear, keyframes()),
+      )
+      .track(
+        PatternFeature::Vibrate(1),
+        PatternCurve::new(
+          PatternInterpolation::Step,
+          vec![PatternKeyframe::new(Duration::from_millis(300), 1.0)],
+        ),
+      )
+      .track(
+        PatternFeature::Vibrate(0),
+        PatternCurve::new(PatternInterpolation::Step, keyframes()),
+      );
+    assert_eq!(pattern.tracks().len(), 2);
+    assert_eq!(pattern.duration(), Duration::from_millis(300));
+  }
+
+  #[test]
+  fn test_pattern_player_options_validation() {
+    assert!(PatternPlayerOptions::default().validate().is_ok());
+    assert!(PatternPlayerOptions {
+      speed: 0.0,
+      ..Default::default()
+    }
+    .validate()
+    .is_ok());
+    assert!(PatternPlayerOptions {
+      tick_rate: Duration::ZERO,
+      ..Default::default()
+    }
+    .validate()
+    .is_err());
+    for speed in [-1.0, f64::NAN, f64::INFINITY] {
+      assert!(PatternPlayerOptions {
+        speed,
+        ..Default::default()
+      }
+      .validate()
+      .is_err());
+    }
+  }
+}
@@ -91,9 +91,25 @@ pub use transport::{
   ButtplugPipeClientTransportBuilder,
   ButtplugPipeServerTransport,
   ButtplugPipeServerTransportBuilder,
+  ButtplugStreamFraming,
+  ButtplugTcpClientTransport,
+  ButtplugTcpClientTransportBuilder,
+  ButtplugTcpServerTransport,
+  ButtplugTcpServerTransportBuilder,
+};
+#[cfg(unix)]
+pub use transport::{
+  ButtplugUnixSocketClientTransport,
+  ButtplugUnixSocketClientTransportBuilder,
+  ButtplugUnixSocketServerTransport,
+  ButtplugUnixSocketServerTransportBuilder,
 };
 #[cfg(feature = "websockets")]
-pub use transport::{ButtplugWebsocketServerTransport, ButtplugWebsocketServerTransportBuilder};
+pub use transport::{
+  ButtplugWebsocketServerTlsIdentity,
+  ButtplugWebsocketServerTransport,
+  ButtplugWebsocketServerTransportBuilder,
+};
 
 pub type ButtplugConnectorResult = Result<(), ButtplugConnectorError>;
 pub type ButtplugConnectorStateShared =
@@ -184,4 +200,11 @@ where
   /// If the connector is not currently connected, or an error happens during
   /// the send operation, this will return a [ButtplugConnectorError]
   fn send(&self, msg: OutboundMessageType) -> ButtplugConnectorResultFuture;
+  /// Auth token to send during the handshake, for servers that require one.
+  ///
+  /// Connectors that don't support authentication can leave this as the
+  /// default, which sends no token.
+  fn auth_token(&self) -> Option<String> {
+    None
+  }
 }
@@ -201,6 +201,8 @@ pub struct ButtplugRemoteConnector<
   transport: Option<TransportType>,
   /// Sender for forwarding outgoing messages to the connector event loop.
   event_loop_sender: Option<Sender<ButtplugRemoteConnectorMessage<OutboundMessageType>>>,
+  /// Auth token to send during the handshake, if the server requires one.
+  auth_token: Option<String>,
   dummy_serializer: PhantomData<SerializerType>,
 }
 
@@ -217,9 +219,17 @@ where
     Self {
       transport: Some(transport),
       event_loop_sender: None,
+      auth_token: None,
       dummy_serializer: PhantomData::default(),
     }
   }
+
+  /// Sets the token to send during the handshake, for servers that require
+  /// authentication.
+  pub fn with_auth_token(mut self, token: &str) -> Self {
+    self.auth_token = Some(token.to_owned());
+    self
+  }
 }
 
 impl<TransportType, SerializerType, OutboundMessageType, InboundMessageType>
@@ -310,4 +320,8 @@ where
       ButtplugConnectorError::ConnectorNotConnected.into()
     }
   }
+
+  fn auth_token(&self) -> Option<String> {
+    self.auth_token.clone()
+  }
 }
@@ -1,5 +1,7 @@
 #[cfg(not(target_arch = "wasm32"))]
 mod pipe;
+#[cfg(not(target_arch = "wasm32"))]
+mod stream;
 #[cfg(feature = "websockets")]
 mod websocket;
 use crate::connector::{
@@ -13,11 +15,29 @@ pub use pipe::{
   pipe_client::{ButtplugPipeClientTransport, ButtplugPipeClientTransportBuilder},
   pipe_server::{ButtplugPipeServerTransport, ButtplugPipeServerTransportBuilder},
 };
+#[cfg(unix)]
+pub use stream::unix_socket::{
+  ButtplugUnixSocketClientTransport,
+  ButtplugUnixSocketClientTransportBuilder,
+  ButtplugUnixSocketServerTransport,
+  ButtplugUnixSocketServerTransportBuilder,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use stream::{
+  tcp::{
+    ButtplugTcpClientTransport,
+    ButtplugTcpClientTransportBuilder,
+    ButtplugTcpServerTransport,
+    ButtplugTcpServerTransportBuilder,
+  },
+  ButtplugStreamFraming,
+};
 use thiserror::Error;
 use tokio::sync::mpsc::{Receiver, Sender};
 #[cfg(feature = "websockets")]
 pub use websocket::{
   ButtplugWebsocketClientTransport,
+  ButtplugWebsocketServerTlsIdentity,
   ButtplugWebsocketServerTransport,
   ButtplugWebsocketServerTransportBuilder,
   TungsteniteError,
@@ -53,4 +73,6 @@ pub enum ButtplugConnectorTransportSpecificError {
   TungsteniteError(#[from] TungsteniteError),
   #[error("Network error: {0}")]
   GenericNetworkError(String),
+  #[error("TLS error: {0}")]
+  TlsError(String),
 }
@@ -0,0 +1,479 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Transports over plain byte streams (TCP, Unix domain sockets), where
+//! message boundaries have to be added by the transport itself.
+
+pub mod tcp;
+#[cfg(unix)]
+pub mod unix_socket;
+
+use crate::{
+  connector::{
+    transport::{
+      ButtplugConnectorTransport,
+      ButtplugConnectorTransportSpecificError,
+      ButtplugTransportIncomingMessage,
+    },
+    ButtplugConnectorError,
+    ButtplugConnectorResultFuture,
+  },
+  core::messages::serializer::ButtplugSerializedMessage,
+  util::async_manager,
+};
+use futures::{future::BoxFuture, pin_mut, FutureExt};
+use std::{io, marker::PhantomData};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+  sync::mpsc::{Receiver, Sender},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Largest length-prefixed frame we'll accept, so a bad length can't make us
+/// allocate unbounded memory.
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// How messages are delimited on the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ButtplugStreamFraming {
+  /// Each message is followed by a `\n`. Easy to use from shell tools and
+  /// line-oriented languages, but only works for text serializers.
+  #[default]
+  Newline,
+  /// Each message is preceded by its length in bytes, as a big-endian u32.
+  /// Works for both text and binary serializers.
+  LengthPrefixed,
+}
+
+/// A kind of socket the stream transports can run over.
+pub trait ButtplugStreamSocket: Send + Sync + 'static {
+  type Stream: AsyncRead + AsyncWrite + Send + 'static;
+
+  /// Connects to a listening socket at the address.
+  fn connect(address: String) -> BoxFuture<'static, io::Result<Self::Stream>>;
+
+  /// Listens on the address until a single client connects, then stops
+  /// listening.
+  fn accept(address: String) -> BoxFuture<'static, io::Result<Self::Stream>>;
+}
+
+fn network_error(err: io::Error) -> ButtplugConnectorError {
+  ButtplugConnectorError::TransportSpecificError(
+    ButtplugConnectorTransportSpecificError::GenericNetworkError(format!("{}", err)),
+  )
+}
+
+#[derive(Clone, Debug)]
+pub struct ButtplugStreamClientTransportBuilder<S> {
+  /// Address to connect to
+  address: String,
+  framing: ButtplugStreamFraming,
+  socket_type: PhantomData<S>,
+}
+
+impl<S> ButtplugStreamClientTransportBuilder<S>
+where
+  S: ButtplugStreamSocket,
+{
+  pub fn new(address: &str) -> Self {
+    Self {
+      address: address.to_owned(),
+      framing: ButtplugStreamFraming::default(),
+      socket_type: PhantomData,
+    }
+  }
+
+  /// Sets how messages are delimited on the socket. Defaults to newlines.
+  pub fn framing(&mut self, framing: ButtplugStreamFraming) -> &mut Self {
+    self.framing = framing;
+    self
+  }
+
+  pub fn finish(&self) -> ButtplugStreamClientTransport<S> {
+    ButtplugStreamClientTransport {
+      address: self.address.clone(),
+      framing: self.framing,
+      disconnect_token: CancellationToken::new(),
+      socket_type: PhantomData,
+    }
+  }
+}
+
+/// Stream socket connector for ButtplugClients
+pub struct ButtplugStreamClientTransport<S> {
+  /// Address of the server we'll connect to.
+  address: String,
+  framing: ButtplugStreamFraming,
+  /// Cancelled when disconnect is called.
+  disconnect_token: CancellationToken,
+  socket_type: PhantomData<S>,
+}
+
+impl<S> ButtplugConnectorTransport for ButtplugStreamClientTransport<S>
+where
+  S: ButtplugStreamSocket,
+{
+  fn connect(
+    &self,
+    outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+    incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    let disconnect_token = self.disconnect_token.clone();
+    let address = self.address.clone();
+    let framing = self.framing;
+    Box::pin(async move {
+      let client = S::connect(address).await.map_err(network_error)?;
+      async_manager::spawn(async move {
+        run_stream_connection_loop(
+          client,
+          framing,
+          outgoing_receiver,
+          incoming_sender,
+          disconnect_token,
+        )
+        .await;
+      });
+      Ok(())
+    })
+  }
+
+  fn disconnect(self) -> ButtplugConnectorResultFuture {
+    self.disconnect_token.cancel();
+    Box::pin(async move { Ok(()) })
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ButtplugStreamServerTransportBuilder<S> {
+  /// Address to listen on
+  address: String,
+  framing: ButtplugStreamFraming,
+  socket_type: PhantomData<S>,
+}
+
+impl<S> ButtplugStreamServerTransportBuilder<S>
+where
+  S: ButtplugStreamSocket,
+{
+  pub fn new(address: &str) -> Self {
+    Self {
+      address: address.to_owned(),
+      framing: ButtplugStreamFraming::default(),
+      socket_type: PhantomData,
+    }
+  }
+
+  /// Sets how messages are delimited on the socket. Defaults to newlines.
+  pub fn framing(&mut self, framing: ButtplugStreamFraming) -> &mut Self {
+    self.framing = framing;
+    self
+  }
+
+  pub fn finish(&self) -> ButtplugStreamServerTransport<S> {
+    ButtplugStreamServerTransport {
+      address: self.address.clone(),
+      framing: self.framing,
+      disconnect_token: CancellationToken::new(),
+      socket_type: PhantomData,
+    }
+  }
+}
+
+/// Stream socket connector for ButtplugServers. Accepts a single client
+/// connection, then stops listening.
+pub struct ButtplugStreamServerTransport<S> {
+  address: String,
+  framing: ButtplugStreamFraming,
+  disconnect_token: CancellationToken,
+  socket_type: PhantomData<S>,
+}
+
+impl<S> ButtplugConnectorTransport for ButtplugStreamServerTransport<S>
+where
+  S: ButtplugStreamSocket,
+{
+  fn connect(
+    &self,
+    outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+    incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    let disconnect_token = self.disconnect_token.clone();
+    let address = self.address.clone();
+    let framing = self.framing;
+    Box::pin(async move {
+      let client = S::accept(address).await.map_err(network_error)?;
+      async_manager::spawn(async move {
+        run_stream_connection_loop(
+          client,
+          framing,
+          outgoing_receiver,
+          incoming_sender,
+          disconnect_token,
+        )
+        .await;
+      });
+      Ok(())
+    })
+  }
+
+  fn disconnect(self) -> ButtplugConnectorResultFuture {
+    self.disconnect_token.cancel();
+    Box::pin(async move { Ok(()) })
+  }
+}
+
+async fn write_frame<W>(
+  writer: &mut W,
+  framing: ButtplugStreamFraming,
+  msg: ButtplugSerializedMessage,
+) -> io::Result<()>
+where
+  W: AsyncWrite + Unpin,
+{
+  let data = match msg {
+    ButtplugSerializedMessage::Text(text) => text.into_bytes(),
+    ButtplugSerializedMessage::Binary(binary) => binary,
+  };
+  match framing {
+    ButtplugStreamFraming::Newline => {
+      if data.contains(&b'\n') {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "Message contains a newline, cannot use newline framing",
+        ));
+      }
+      writer.write_all(&data).await?;
+      writer.write_all(b"\n").await?;
+    }
+    ButtplugStreamFraming::LengthPrefixed => {
+      if data.len() > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "Message too long for length-prefixed framing",
+        ));
+      }
+      writer.write_u32(data.len() as u32).await?;
+      writer.write_all(&data).await?;
+    }
+  }
+  writer.flush().await
+}
+
+/// Reads the next frame off the stream, returning None once the other side
+/// has closed it.
+async fn read_frame<R>(
+  reader: &mut BufReader<R>,
+  framing: ButtplugStreamFraming,
+) -> io::Result<Option<Vec<u8>>>
+where
+  R: AsyncRead + Unpin,
+{
+  match framing {
+    ButtplugStreamFraming::Newline => loop {
+      let mut data = vec![];
+      if reader.read_until(b'\n', &mut data).await? == 0 {
+        return Ok(None);
+      }
+      while matches!(data.last(), Some(b'\n') | Some(b'\r')) {
+        data.pop();
+      }
+      // Skip blank lines, so people typing into a socket by hand don't get
+      // errors back for hitting enter twice.
+      if !data.is_empty() {
+        return Ok(Some(data));
+      }
+    },
+    ButtplugStreamFraming::LengthPrefixed => {
+      let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+      };
+      if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("Frame length {} is too long", length),
+        ));
+      }
+      let mut data = vec![0; length];
+      reader.read_exact(&mut data).await?;
+      Ok(Some(data))
+    }
+  }
+}
+
+async fn read_frames<R>(
+  reader: R,
+  framing: ButtplugStreamFraming,
+  response_sender: Sender<ButtplugTransportIncomingMessage>,
+) where
+  R: AsyncRead + Unpin,
+{
+  let mut reader = BufReader::new(reader);
+  loop {
+    let data = match read_frame(&mut reader, framing).await {
+      Ok(Some(data)) => data,
+      Ok(None) => {
+        info!("Stream closed by remote.");
+        return;
+      }
+      Err(err) => {
+        error!(
+          "Error reading from stream, assuming disconnection: {:?}",
+          err
+        );
+        return;
+      }
+    };
+    // Text serializers expect text, so hand over anything that looks like it.
+    // MessagePack message arrays always start with a byte that can't start a
+    // UTF-8 sequence, so binary messages never end up here as text.
+    let msg = match String::from_utf8(data) {
+      Ok(text) => ButtplugSerializedMessage::Text(text),
+      Err(err) => ButtplugSerializedMessage::Binary(err.into_bytes()),
+    };
+    if response_sender
+      .send(ButtplugTransportIncomingMessage::Message(msg))
+      .await
+      .is_err()
+    {
+      error!("Connector that owns transport no longer available, exiting.");
+      return;
+    }
+  }
+}
+
+/// Shuttles framed messages between a connected stream and its connector,
+/// until either side goes away or a disconnect is requested.
+async fn run_stream_connection_loop<S>(
+  stream: S,
+  framing: ButtplugStreamFraming,
+  mut request_receiver: Receiver<ButtplugSerializedMessage>,
+  response_sender: Sender<ButtplugTransportIncomingMessage>,
+  disconnect_token: CancellationToken,
+) where
+  S: AsyncRead + AsyncWrite,
+{
+  info!("Starting stream connection event loop.");
+  let (reader, mut writer) = tokio::io::split(stream);
+  // The read future lives across loop iterations, so a partially read frame is
+  // never dropped when one of the other branches fires.
+  let read_fut = read_frames(reader, framing, response_sender.clone()).fuse();
+  pin_mut!(read_fut);
+  loop {
+    select! {
+      _ = disconnect_token.cancelled().fuse() => {
+        info!("Stream connector requested disconnect, exiting loop.");
+        break;
+      },
+      _ = read_fut => break,
+      serialized_msg = request_receiver.recv().fuse() => {
+        if let Some(serialized_msg) = serialized_msg {
+          if let Err(err) = write_frame(&mut writer, framing, serialized_msg).await {
+            error!("Cannot send value to stream, considering connection closed: {:?}", err);
+            break;
+          }
+        } else {
+          info!("Stream connector owner dropped, disconnecting stream connection.");
+          break;
+        }
+      }
+    }
+  }
+  if writer.shutdown().await.is_err() {
+    debug!("Cannot shut down stream, assuming connection already closed");
+  }
+  let _ = response_sender
+    .send(ButtplugTransportIncomingMessage::Close(
+      "Stream closed".to_owned(),
+    ))
+    .await;
+}
+
+#[cfg(test)]
+mod test {
+  use super::{read_frame, write_frame, ButtplugStreamFraming};
+  use crate::{core::messages::serializer::ButtplugSerializedMessage, util::async_manager};
+  use tokio::io::{duplex, AsyncWriteExt, BufReader};
+
+  #[test]
+  fn test_stream_framing_round_trip() {
+    async_manager::block_on(async {
+      for framing in [
+        ButtplugStreamFraming::Newline,
+        ButtplugStreamFraming::LengthPrefixed,
+      ] {
+        let (mut writer, reader) = duplex(1024);
+        let mut reader = BufReader::new(reader);
+        for msg in ["[{\"Ok\":{\"Id\":1}}]", "[{\"Ok\":{\"Id\":2}}]"] {
+          write_frame(
+            &mut writer,
+            framing,
+            ButtplugSerializedMessage::Text(msg.to_owned()),
+          )
+          .await
+          .expect("Test, assuming infallible.");
+          assert_eq!(
+            read_frame(&mut reader, framing)
+              .await
+              .expect("Test, assuming infallible."),
+            Some(msg.as_bytes().to_vec())
+          );
+        }
+        drop(writer);
+        assert_eq!(
+          read_frame(&mut reader, framing)
+            .await
+            .expect("Test, assuming infallible."),
+          None
+        );
+      }
+    });
+  }
+
+  #[test]
+  fn test_stream_newline_framing() {
+    async_manager::block_on(async {
+      let (mut writer, reader) = duplex(1024);
+      let mut reader = BufReader::new(reader);
+      writer
+        .write_all(b"\r\n\n[]\r\n")
+        .await
+        .expect("Test, assuming infallible.");
+      assert_eq!(
+        read_frame(&mut reader, ButtplugStreamFraming::Newline)
+          .await
+          .expect("Test, assuming infallible."),
+        Some(b"[]".to_vec())
+      );
+      assert!(write_frame(
+        &mut writer,
+        ButtplugStreamFraming::Newline,
+        ButtplugSerializedMessage::Binary(vec![1, b'\n', 2]),
+      )
+      .await
+      .is_err());
+    });
+  }
+
+  #[test]
+  fn test_stream_length_prefixed_framing_too_long() {
+    async_manager::block_on(async {
+      let (mut writer, reader) = duplex(1024);
+      let mut reader = BufReader::new(reader);
+      writer
+        .write_u32(u32::MAX)
+        .await
+        .expect("Test, assuming infallible.");
+      assert!(
+        read_frame(&mut reader, ButtplugStreamFraming::LengthPrefixed)
+          .await
+          .is_err()
+      );
+    });
+  }
+}
@@ -0,0 +1,135 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Raw TCP socket transports, via tokio.
+
+use super::{
+  ButtplugStreamClientTransport,
+  ButtplugStreamClientTransportBuilder,
+  ButtplugStreamServerTransport,
+  ButtplugStreamServerTransportBuilder,
+  ButtplugStreamSocket,
+};
+use futures::future::BoxFuture;
+use std::io;
+use tokio::net::{TcpListener, TcpStream};
+
+/// TCP sockets, with addresses given as host:port.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpSocket;
+
+impl ButtplugStreamSocket for TcpSocket {
+  type Stream = TcpStream;
+
+  fn connect(address: String) -> BoxFuture<'static, io::Result<TcpStream>> {
+    Box::pin(async move {
+      let client = TcpStream::connect(address).await?;
+      // Buttplug messages are small and latency sensitive.
+      let _ = client.set_nodelay(true);
+      Ok(client)
+    })
+  }
+
+  fn accept(address: String) -> BoxFuture<'static, io::Result<TcpStream>> {
+    Box::pin(async move {
+      let listener = TcpListener::bind(&address).await?;
+      let (client, addr) = listener.accept().await?;
+      info!("Got TCP connection from {}", addr);
+      let _ = client.set_nodelay(true);
+      Ok(client)
+    })
+  }
+}
+
+pub type ButtplugTcpClientTransportBuilder = ButtplugStreamClientTransportBuilder<TcpSocket>;
+/// TCP socket connector for ButtplugClients
+pub type ButtplugTcpClientTransport = ButtplugStreamClientTransport<TcpSocket>;
+pub type ButtplugTcpServerTransportBuilder = ButtplugStreamServerTransportBuilder<TcpSocket>;
+/// TCP socket connector for ButtplugServers. Accepts a single client
+/// connection, then stops listening.
+pub type ButtplugTcpServerTransport = ButtplugStreamServerTransport<TcpSocket>;
+
+#[cfg(test)]
+mod test {
+  use super::{ButtplugTcpClientTransportBuilder, ButtplugTcpServerTransportBuilder};
+  use crate::{
+    client::ButtplugClient,
+    connector::{
+      transport::{stream::ButtplugStreamFraming, ButtplugConnectorTransport},
+      ButtplugRemoteClientConnector,
+      ButtplugRemoteServerConnector,
+    },
+    core::messages::serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer},
+    server::ButtplugRemoteServer,
+    util::async_manager,
+  };
+  use std::time::Duration;
+  use tokio::sync::mpsc;
+
+  #[test]
+  pub fn test_client_transport_error_no_server() {
+    async_manager::block_on(async move {
+      // Bind then drop a listener to find a port nothing is listening on.
+      let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .expect("Test, assuming infallible.")
+        .local_addr()
+        .expect("Test, assuming infallible.")
+        .to_string();
+      let transport = ButtplugTcpClientTransportBuilder::new(&address).finish();
+      let (_, receiver) = mpsc::channel(1);
+      let (sender, _) = mpsc::channel(1);
+      assert!(transport.connect(receiver, sender).await.is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_client_server_connection() {
+    async_manager::block_on(async move {
+      for (port, framing) in [
+        (12360, ButtplugStreamFraming::Newline),
+        (12361, ButtplugStreamFraming::LengthPrefixed),
+      ] {
+        let address = format!("127.0.0.1:{}", port);
+        let server = ButtplugRemoteServer::default();
+        let server_transport = ButtplugTcpServerTransportBuilder::new(&address)
+          .framing(framing)
+          .finish();
+        let server_task = async_manager::spawn_with_handle(async move {
+          server
+            .start(ButtplugRemoteServerConnector::<
+              _,
+              ButtplugServerJSONSerializer,
+            >::new(server_transport))
+            .await
+            .expect("Test, assuming infallible.");
+        })
+        .expect("Test, assuming infallible.");
+        // Give the server a moment to start listening.
+        futures_timer::Delay::new(Duration::from_millis(100)).await;
+        let client = ButtplugClient::new("Test Client");
+        client
+          .connect(ButtplugRemoteClientConnector::<
+            _,
+            ButtplugClientJSONSerializer,
+          >::new(
+            ButtplugTcpClientTransportBuilder::new(&address)
+              .framing(framing)
+              .finish(),
+          ))
+          .await
+          .expect("Test, assuming infallible.");
+        assert!(client.connected());
+        assert!(client.stop_all_devices().await.is_ok());
+        client
+          .disconnect()
+          .await
+          .expect("Test, assuming infallible.");
+        server_task.await;
+      }
+    });
+  }
+}
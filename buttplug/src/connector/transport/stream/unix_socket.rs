@@ -0,0 +1,226 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Unix domain socket transports, via tokio.
+
+use super::{
+  ButtplugStreamClientTransport,
+  ButtplugStreamClientTransportBuilder,
+  ButtplugStreamServerTransport,
+  ButtplugStreamServerTransportBuilder,
+  ButtplugStreamSocket,
+};
+use futures::future::BoxFuture;
+use std::{fs, io, os::unix::fs::FileTypeExt};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Unix domain sockets, with addresses given as socket file paths.
+#[derive(Clone, Copy, Debug)]
+pub struct UnixSocket;
+
+/// Removes a socket file left behind by a server that didn't get to clean up
+/// after itself, so it doesn't block binding. A socket is only stale if
+/// connecting to it is refused, since otherwise another server is still using
+/// it. Anything that isn't a stale socket is left alone, and binding will fail
+/// on it instead.
+async fn remove_stale_socket(address: &str) {
+  match fs::symlink_metadata(address) {
+    Ok(metadata) if metadata.file_type().is_socket() => {}
+    _ => return,
+  }
+  match UnixStream::connect(address).await {
+    Ok(_) => warn!("Socket file {} is in use by another server.", address),
+    Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+      info!("Removing stale socket file {}", address);
+      if let Err(err) = fs::remove_file(address) {
+        warn!("Cannot remove stale socket file {}: {:?}", address, err);
+      }
+    }
+    Err(err) => warn!("Cannot check socket file {}: {:?}", address, err),
+  }
+}
+
+impl ButtplugStreamSocket for UnixSocket {
+  type Stream = UnixStream;
+
+  fn connect(address: String) -> BoxFuture<'static, io::Result<UnixStream>> {
+    Box::pin(async move { UnixStream::connect(address).await })
+  }
+
+  fn accept(address: String) -> BoxFuture<'static, io::Result<UnixStream>> {
+    Box::pin(async move {
+      remove_stale_socket(&address).await;
+      let listener = UnixListener::bind(&address)?;
+      let accepted = listener.accept().await;
+      // We only ever take one connection, so clean up the socket file now
+      // rather than leaving it around to block the next bind.
+      drop(listener);
+      if let Err(err) = fs::remove_file(&address) {
+        warn!("Cannot remove socket file {}: {:?}", address, err);
+      }
+      let (client, _addr) = accepted?;
+      info!("Got unix socket connection on {}", address);
+      Ok(client)
+    })
+  }
+}
+
+pub type ButtplugUnixSocketClientTransportBuilder =
+  ButtplugStreamClientTransportBuilder<UnixSocket>;
+/// Unix domain socket connector for ButtplugClients
+pub type ButtplugUnixSocketClientTransport = ButtplugStreamClientTransport<UnixSocket>;
+pub type ButtplugUnixSocketServerTransportBuilder =
+  ButtplugStreamServerTransportBuilder<UnixSocket>;
+/// Unix domain socket connector for ButtplugServers. Accepts a single client
+/// connection, then removes the socket file.
+pub type ButtplugUnixSocketServerTransport = ButtplugStreamServerTransport<UnixSocket>;
+
+#[cfg(test)]
+mod test {
+  use super::{ButtplugUnixSocketClientTransportBuilder, ButtplugUnixSocketServerTransportBuilder};
+  use crate::{
+    client::ButtplugClient,
+    connector::{
+      transport::{stream::ButtplugStreamFraming, ButtplugConnectorTransport},
+      ButtplugRemoteClientConnector,
+      ButtplugRemoteServerConnector,
+    },
+    core::messages::serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer},
+    server::ButtplugRemoteServer,
+    util::async_manager,
+  };
+  use std::time::Duration;
+  use tokio::sync::mpsc;
+
+  fn socket_path(name: &str) -> String {
+    std::env::temp_dir()
+      .join(format!("buttplug-{}-{}.sock", name, std::process::id()))
+      .to_string_lossy()
+      .into_owned()
+  }
+
+  #[test]
+  pub fn test_client_transport_error_no_socket() {
+    async_manager::block_on(async move {
+      let transport =
+        ButtplugUnixSocketClientTransportBuilder::new(&socket_path("missing")).finish();
+      let (_, receiver) = mpsc::channel(1);
+      let (sender, _) = mpsc::channel(1);
+      assert!(transport.connect(receiver, sender).await.is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_server_transport_stale_socket_file() {
+    async_manager::block_on(async move {
+      // Dropping a std listener leaves its socket file behind, like a server
+      // that crashed would.
+      let address = socket_path("stale");
+      drop(std::os::unix::net::UnixListener::bind(&address).expect("Test, assuming infallible."));
+      let server_transport = ButtplugUnixSocketServerTransportBuilder::new(&address).finish();
+      let (_, server_receiver) = mpsc::channel(1);
+      let (server_sender, _) = mpsc::channel(1);
+      let server_task = async_manager::spawn_with_handle(async move {
+        server_transport
+          .connect(server_receiver, server_sender)
+          .await
+      })
+      .expect("Test, assuming infallible.");
+      futures_timer::Delay::new(Duration::from_millis(100)).await;
+      let client_transport = ButtplugUnixSocketClientTransportBuilder::new(&address).finish();
+      let (_, client_receiver) = mpsc::channel(1);
+      let (client_sender, _) = mpsc::channel(1);
+      client_transport
+        .connect(client_receiver, client_sender)
+        .await
+        .expect("Test, assuming infallible.");
+      assert!(server_task.await.is_ok());
+    });
+  }
+
+  #[test]
+  pub fn test_server_transport_keeps_socket_in_use() {
+    async_manager::block_on(async move {
+      let address = socket_path("in-use");
+      let listener =
+        std::os::unix::net::UnixListener::bind(&address).expect("Test, assuming infallible.");
+      let server_transport = ButtplugUnixSocketServerTransportBuilder::new(&address).finish();
+      let (_, receiver) = mpsc::channel(1);
+      let (sender, _) = mpsc::channel(1);
+      assert!(server_transport.connect(receiver, sender).await.is_err());
+      assert!(std::path::Path::new(&address).exists());
+      drop(listener);
+      std::fs::remove_file(&address).expect("Test, assuming infallible.");
+    });
+  }
+
+  #[test]
+  pub fn test_server_transport_keeps_non_socket_file() {
+    async_manager::block_on(async move {
+      let address = socket_path("not-a-socket");
+      std::fs::write(&address, "data").expect("Test, assuming infallible.");
+      let server_transport = ButtplugUnixSocketServerTransportBuilder::new(&address).finish();
+      let (_, receiver) = mpsc::channel(1);
+      let (sender, _) = mpsc::channel(1);
+      assert!(server_transport.connect(receiver, sender).await.is_err());
+      assert_eq!(
+        std::fs::read_to_string(&address).expect("Test, assuming infallible."),
+        "data"
+      );
+      std::fs::remove_file(&address).expect("Test, assuming infallible.");
+    });
+  }
+
+  #[test]
+  pub fn test_client_server_connection() {
+    async_manager::block_on(async move {
+      // Both framings use the same path, which also checks the server cleans
+      // up its socket file.
+      let address = socket_path("connection");
+      for framing in [
+        ButtplugStreamFraming::Newline,
+        ButtplugStreamFraming::LengthPrefixed,
+      ] {
+        let server = ButtplugRemoteServer::default();
+        let server_transport = ButtplugUnixSocketServerTransportBuilder::new(&address)
+          .framing(framing)
+          .finish();
+        let server_task = async_manager::spawn_with_handle(async move {
+          server
+            .start(ButtplugRemoteServerConnector::<
+              _,
+              ButtplugServerJSONSerializer,
+            >::new(server_transport))
+            .await
+            .expect("Test, assuming infallible.");
+        })
+        .expect("Test, assuming infallible.");
+        // Give the server a moment to create the socket.
+        futures_timer::Delay::new(Duration::from_millis(100)).await;
+        let client = ButtplugClient::new("Test Client");
+        client
+          .connect(ButtplugRemoteClientConnector::<
+            _,
+            ButtplugClientJSONSerializer,
+          >::new(
+            ButtplugUnixSocketClientTransportBuilder::new(&address)
+              .framing(framing)
+              .finish(),
+          ))
+          .await
+          .expect("Test, assuming infallible.");
+        assert!(client.connected());
+        assert!(client.stop_all_devices().await.is_ok());
+        client
+          .disconnect()
+          .await
+          .expect("Test, assuming infallible.");
+        server_task.await;
+      }
+    });
+  }
+}
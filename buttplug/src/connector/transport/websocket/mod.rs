@@ -5,6 +5,7 @@ pub use async_tungstenite::tungstenite::Error as TungsteniteError;
 pub use websocket_client::ButtplugWebsocketClientTransport;
 
 pub use websocket_server::{
+  ButtplugWebsocketServerTlsIdentity,
   ButtplugWebsocketServerTransport,
   ButtplugWebsocketServerTransportBuilder,
 };
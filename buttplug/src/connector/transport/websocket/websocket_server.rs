@@ -13,26 +13,83 @@ use crate::{
 };
 use futures::{future::BoxFuture, AsyncRead, AsyncWrite, FutureExt, SinkExt, StreamExt};
 use futures_timer::Delay;
-use std::{sync::Arc, time::Duration};
-use tokio::net::TcpListener;
+use std::{fs, future::Future, io, path::Path, sync::Arc, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{
   mpsc::{Receiver, Sender},
   Notify,
 };
+use tokio::time::timeout;
+use tokio_native_tls::TlsAcceptor;
+
+/// How long a client has to finish the TLS and websocket handshakes after
+/// connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate and private key used to serve secure (wss://) websocket
+/// connections.
+#[derive(Clone)]
+pub enum ButtplugWebsocketServerTlsIdentity {
+  /// PEM encoded certificate chain, and PEM encoded PKCS #8 private key.
+  Pem { certificate: Vec<u8>, key: Vec<u8> },
+  /// DER encoded PKCS #12 archive, and the password used to decrypt it.
+  Pkcs12 { archive: Vec<u8>, password: String },
+}
+
+impl ButtplugWebsocketServerTlsIdentity {
+  pub fn from_pem_files(
+    certificate_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+  ) -> io::Result<Self> {
+    Ok(Self::Pem {
+      certificate: fs::read(certificate_path)?,
+      key: fs::read(key_path)?,
+    })
+  }
+
+  pub fn from_pkcs12_file(archive_path: impl AsRef<Path>, password: &str) -> io::Result<Self> {
+    Ok(Self::Pkcs12 {
+      archive: fs::read(archive_path)?,
+      password: password.to_owned(),
+    })
+  }
+
+  fn acceptor(&self) -> Result<TlsAcceptor, native_tls::Error> {
+    let identity = match self {
+      Self::Pem { certificate, key } => native_tls::Identity::from_pkcs8(certificate, key)?,
+      Self::Pkcs12 { archive, password } => native_tls::Identity::from_pkcs12(archive, password)?,
+    };
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+  }
+}
+
+// Don't print key material into logs.
+impl std::fmt::Debug for ButtplugWebsocketServerTlsIdentity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Pem { .. } => f.write_str("Pem"),
+      Self::Pkcs12 { .. } => f.write_str("Pkcs12"),
+    }
+  }
+}
 
 #[derive(Clone, Debug)]
 pub struct ButtplugWebsocketServerTransportBuilder {
   /// If true, listens all on available interfaces. Otherwise, only listens on 127.0.0.1.
   listen_on_all_interfaces: bool,
-  /// Insecure port for listening for websocket connections.
-  port: u16,
+  /// Insecure port for listening for websocket connections, if enabled.
+  port: Option<u16>,
+  /// Secure port for listening for websocket connections, along with the
+  /// identity to serve TLS with.
+  secure_port: Option<(u16, ButtplugWebsocketServerTlsIdentity)>,
 }
 
 impl Default for ButtplugWebsocketServerTransportBuilder {
   fn default() -> Self {
     Self {
       listen_on_all_interfaces: false,
-      port: 12345,
+      port: Some(12345),
+      secure_port: None,
     }
   }
 }
@@ -44,13 +101,33 @@ impl ButtplugWebsocketServerTransportBuilder {
   }
 
   pub fn port(&mut self, port: u16) -> &mut Self {
-    self.port = port;
+    self.port = Some(port);
+    self
+  }
+
+  /// Stops listening on the insecure port, for when only the secure port
+  /// should be served.
+  pub fn disable_insecure_port(&mut self) -> &mut Self {
+    self.port = None;
+    self
+  }
+
+  /// Listens for secure (wss://) connections on the given port. This can be
+  /// used alongside the insecure port, in which case the first connection on
+  /// either port is used.
+  pub fn secure_port(
+    &mut self,
+    port: u16,
+    identity: ButtplugWebsocketServerTlsIdentity,
+  ) -> &mut Self {
+    self.secure_port = Some((port, identity));
     self
   }
 
   pub fn finish(&self) -> ButtplugWebsocketServerTransport {
     ButtplugWebsocketServerTransport {
       port: self.port,
+      secure_port: self.secure_port.clone(),
       listen_on_all_interfaces: self.listen_on_all_interfaces,
       disconnect_notifier: Arc::new(Notify::new()),
     }
@@ -157,8 +234,12 @@ async fn run_connection_loop<S>(
                   pong_count += 1;
                   continue;
                 }
-                async_tungstenite::tungstenite::Message::Binary(_) => {
-                  error!("Don't know how to handle binary message types!");
+                async_tungstenite::tungstenite::Message::Binary(binary_msg) => {
+                  trace!("Got binary: {:?}", binary_msg);
+                  if response_sender.send(ButtplugTransportIncomingMessage::Message(ButtplugSerializedMessage::Binary(binary_msg))).await.is_err() {
+                    warn!("Connector that owns transport no longer available, exiting.");
+                    break;
+                  }
                 }
               }
             },
@@ -178,9 +259,50 @@ async fn run_connection_loop<S>(
   }
 }
 
+async fn bind_listener(addr: &str) -> Result<TcpListener, ButtplugConnectorError> {
+  debug!("Websocket: Trying to listen on {}", addr);
+  let listener = TcpListener::bind(addr).await.map_err(|e| {
+    ButtplugConnectorError::TransportSpecificError(
+      ButtplugConnectorTransportSpecificError::GenericNetworkError(format!("{:?}", e)),
+    )
+  })?;
+  debug!("Websocket: Listening on: {}", addr);
+  Ok(listener)
+}
+
+/// Waits for a connection on the listener, or forever if there isn't one, so
+/// it can be raced against other listeners.
+async fn accept_connection(listener: &Option<TcpListener>) -> io::Result<TcpStream> {
+  match listener {
+    Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+    None => futures::future::pending().await,
+  }
+}
+
+/// Runs a connection handshake, giving up if the client doesn't finish it
+/// within [HANDSHAKE_TIMEOUT]. Failures are only logged, since a bad client
+/// shouldn't stop us from listening for the next one.
+async fn handshake<T, E>(name: &str, fut: impl Future<Output = Result<T, E>>) -> Option<T>
+where
+  E: std::fmt::Debug,
+{
+  match timeout(HANDSHAKE_TIMEOUT, fut).await {
+    Ok(Ok(stream)) => Some(stream),
+    Ok(Err(err)) => {
+      error!("Websocket server {} accept error: {:?}", name, err);
+      None
+    }
+    Err(_) => {
+      error!("Websocket server {} handshake timed out.", name);
+      None
+    }
+  }
+}
+
 /// Websocket connector for ButtplugClients, using [async_tungstenite]
 pub struct ButtplugWebsocketServerTransport {
-  port: u16,
+  port: Option<u16>,
+  secure_port: Option<(u16, ButtplugWebsocketServerTlsIdentity)>,
   listen_on_all_interfaces: bool,
   disconnect_notifier: Arc<Notify>,
 }
@@ -199,44 +321,102 @@ impl ButtplugConnectorTransport for ButtplugWebsocketServerTransport {
       "127.0.0.1"
     };
 
-    let addr = format!("{}:{}", base_addr, self.port);
-    debug!("Websocket: Trying to listen on {}", addr);
+    let addr = self.port.map(|port| format!("{}:{}", base_addr, port));
+    let secure_port = self.secure_port.clone();
     let response_sender_clone = incoming_sender;
     let disconnect_notifier_clone = disconnect_notifier;
     let fut = async move {
-      // Create the event loop and TCP listener we'll accept connections on.
-      let try_socket = TcpListener::bind(&addr).await;
+      if addr.is_none() && secure_port.is_none() {
+        return Err(ButtplugConnectorError::ConnectorGenericError(
+          "Websocket server has no ports to listen on".to_owned(),
+        ));
+      }
+      // Set up TLS before binding anything, so bad certificates fail early.
+      let (secure_addr, tls_acceptor) = match &secure_port {
+        Some((port, identity)) => {
+          let acceptor = identity.acceptor().map_err(|e| {
+            error!("Websocket server cannot load TLS identity: {:?}", e);
+            ButtplugConnectorError::TransportSpecificError(
+              ButtplugConnectorTransportSpecificError::TlsError(format!("{:?}", e)),
+            )
+          })?;
+          (Some(format!("{}:{}", base_addr, port)), Some(acceptor))
+        }
+        None => (None, None),
+      };
+      // Create the event loop and TCP listeners we'll accept connections on.
+      let listener = match &addr {
+        Some(addr) => Some(bind_listener(addr).await?),
+        None => None,
+      };
+      let secure_listener = match &secure_addr {
+        Some(addr) => Some(bind_listener(addr).await?),
+        None => None,
+      };
       debug!("Websocket: Socket bound.");
-      let listener = try_socket.map_err(|e| {
-        ButtplugConnectorError::TransportSpecificError(
-          ButtplugConnectorTransportSpecificError::GenericNetworkError(format!("{:?}", e)),
-        )
-      })?;
-      debug!("Websocket: Listening on: {}", addr);
-      if let Ok((stream, _)) = listener.accept().await {
-        info!("Websocket: Got connection");
-        let ws_fut = async_tungstenite::tokio::accept_async(stream);
-        let ws_stream = ws_fut.await.map_err(|err| {
-          error!("Websocket server accept error: {:?}", err);
-          ButtplugConnectorError::TransportSpecificError(
-            ButtplugConnectorTransportSpecificError::TungsteniteError(err),
-          )
-        })?;
-        async_manager::spawn(async move {
-          run_connection_loop(
-            ws_stream,
-            outgoing_receiver,
-            response_sender_clone,
-            disconnect_notifier_clone,
-          )
-          .await;
-        });
-        Ok(())
-      } else {
-        Err(ButtplugConnectorError::ConnectorGenericError(
-          "Could not run accept for port".to_owned(),
-        ))
+      // Keep accepting until a client makes it through the handshake, so a
+      // client that fails or stalls doesn't take the server down with it.
+      loop {
+        select! {
+          stream = accept_connection(&listener).fuse() => {
+            let stream = match stream {
+              Ok(stream) => stream,
+              Err(e) => {
+                warn!("Websocket: Could not accept connection on port: {:?}", e);
+                continue;
+              }
+            };
+            info!("Websocket: Got connection");
+            if let Some(ws_stream) =
+              handshake("websocket", async_tungstenite::tokio::accept_async(stream)).await
+            {
+              async_manager::spawn(async move {
+                run_connection_loop(
+                  ws_stream,
+                  outgoing_receiver,
+                  response_sender_clone,
+                  disconnect_notifier_clone,
+                )
+                .await;
+              });
+              break;
+            }
+          },
+          stream = accept_connection(&secure_listener).fuse() => {
+            let stream = match stream {
+              Ok(stream) => stream,
+              Err(e) => {
+                warn!("Websocket: Could not accept connection on secure port: {:?}", e);
+                continue;
+              }
+            };
+            info!("Websocket: Got secure connection");
+            let acceptor = tls_acceptor
+              .as_ref()
+              .expect("Secure listener only exists if we have an acceptor");
+            let tls_stream = if let Some(tls_stream) = handshake("TLS", acceptor.accept(stream)).await {
+              tls_stream
+            } else {
+              continue;
+            };
+            if let Some(ws_stream) =
+              handshake("websocket", async_tungstenite::tokio::accept_async(tls_stream)).await
+            {
+              async_manager::spawn(async move {
+                run_connection_loop(
+                  ws_stream,
+                  outgoing_receiver,
+                  response_sender_clone,
+                  disconnect_notifier_clone,
+                )
+                .await;
+              });
+              break;
+            }
+          },
+        };
       }
+      Ok(())
     };
 
     Box::pin(async move { fut.await })
@@ -43,6 +43,10 @@ pub enum ButtplugHandshakeError {
   HandshakeAlreadyHappened,
   /// Server spec version ({0}) must be equal or greater than client version ({1})
   MessageSpecVersionMismatch(ButtplugMessageSpecVersion, ButtplugMessageSpecVersion),
+  /// Server requires authentication, but no auth token was sent with RequestServerInfo.
+  AuthenticationRequired,
+  /// Auth token sent with RequestServerInfo does not match the server's.
+  InvalidCredentials,
   /// Untyped Deserialized Error: {0}
   UntypedDeserializedError(String),
 }
@@ -144,6 +148,8 @@ pub enum ButtplugDeviceError {
   DeviceSpecificError(String),
   /// No device available at index {0}
   DeviceNotAvailable(u32),
+  /// Device {0} is being controlled by another client
+  DeviceInUse(u32),
   /// Device scanning already started.
   DeviceScanningAlreadyStarted,
   /// Device scanning already stopped.
@@ -65,6 +65,59 @@ impl From<DeviceAdded> for DeviceMessageInfo {
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct DeviceMessageInfoV2 {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  pub device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
+  pub device_name: String,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "DeviceMessages", serialize_with = "ordered_map")
+  )]
+  pub device_messages: DeviceMessageAttributesMap,
+}
+
+impl From<DeviceAdded> for DeviceMessageInfoV2 {
+  fn from(device_added: DeviceAdded) -> Self {
+    let dmi = DeviceMessageInfo::from(device_added);
+    DeviceMessageInfoV2::from(dmi)
+  }
+}
+
+impl From<DeviceMessageInfo> for DeviceMessageInfoV2 {
+  fn from(device_message_info: DeviceMessageInfo) -> Self {
+    // No structural difference, it's all content changes
+    let mut dmi_v2 = Self {
+      device_index: device_message_info.device_index,
+      device_name: device_message_info.device_name,
+      device_messages: device_message_info.original_device_messages,
+    };
+    // Remove entries that weren't in V2.
+    let v3_message_types = [
+      ButtplugDeviceMessageType::ScalarCmd,
+      ButtplugDeviceMessageType::SensorReadCmd,
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
+    ];
+    for t in &v3_message_types {
+      dmi_v2.device_messages.remove(t);
+    }
+
+    // ActuatorType, FeatureDescriptor, SensorType, SensorRange and
+    // OutputRange were added in V3.
+    for attributes in &mut dmi_v2.device_messages.values_mut() {
+      attributes.actuator_type = None;
+      attributes.feature_descriptor = None;
+      attributes.sensor_type = None;
+      attributes.sensor_range = None;
+      attributes.output_range = None;
+    }
+
+    dmi_v2
+  }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
 pub struct DeviceMessageInfoV1 {
@@ -102,6 +155,9 @@ impl From<DeviceMessageInfo> for DeviceMessageInfoV1 {
       ButtplugDeviceMessageType::RawUnsubscribeCmd,
       ButtplugDeviceMessageType::BatteryLevelCmd,
       ButtplugDeviceMessageType::RSSILevelCmd,
+      ButtplugDeviceMessageType::ScalarCmd,
+      ButtplugDeviceMessageType::SensorReadCmd,
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
     ];
     for t in &v2_message_types {
       dmi_v1.device_messages.remove(t);
@@ -0,0 +1,135 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// A device the server has seen, or has user configuration for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct KnownDeviceInfo {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Address"))]
+  address: String,
+  /// Index the device gets when it connects. Devices that have only been
+  /// configured, but never seen, don't have one yet.
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DeviceIndex",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  device_index: Option<u32>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DeviceName",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  device_name: Option<String>,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Connected"))]
+  connected: bool,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DisplayName",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  display_name: Option<String>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "Allow", skip_serializing_if = "Option::is_none", default)
+  )]
+  allow: Option<bool>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "Deny", skip_serializing_if = "Option::is_none", default)
+  )]
+  deny: Option<bool>,
+}
+
+impl KnownDeviceInfo {
+  pub fn new(
+    address: &str,
+    device_index: Option<u32>,
+    device_name: Option<String>,
+    connected: bool,
+    display_name: Option<String>,
+    allow: Option<bool>,
+    deny: Option<bool>,
+  ) -> Self {
+    Self {
+      address: address.to_owned(),
+      device_index,
+      device_name,
+      connected,
+      display_name,
+      allow,
+      deny,
+    }
+  }
+
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  pub fn device_index(&self) -> Option<u32> {
+    self.device_index
+  }
+
+  pub fn device_name(&self) -> &Option<String> {
+    &self.device_name
+  }
+
+  pub fn connected(&self) -> bool {
+    self.connected
+  }
+
+  pub fn display_name(&self) -> &Option<String> {
+    &self.display_name
+  }
+
+  pub fn allow(&self) -> Option<bool> {
+    self.allow
+  }
+
+  pub fn deny(&self) -> Option<bool> {
+    self.deny
+  }
+}
+
+/// Reply to [RequestKnownDeviceList].
+#[derive(Default, Clone, Debug, PartialEq, ButtplugMessage)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct KnownDeviceList {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Devices"))]
+  devices: Vec<KnownDeviceInfo>,
+}
+
+impl KnownDeviceList {
+  pub fn new(devices: Vec<KnownDeviceInfo>) -> Self {
+    Self { id: 1, devices }
+  }
+
+  pub fn devices(&self) -> &Vec<KnownDeviceInfo> {
+    &self.devices
+  }
+}
+
+impl ButtplugMessageValidator for KnownDeviceList {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
@@ -28,6 +28,14 @@ impl Log {
       log_message: log_message.to_owned(),
     }
   }
+
+  pub fn log_level(&self) -> &LogLevel {
+    &self.log_level
+  }
+
+  pub fn log_message(&self) -> &String {
+    &self.log_message
+  }
 }
 
 impl ButtplugMessageValidator for Log {
@@ -8,6 +8,30 @@
 use crate::device::Endpoint;
 use serde::{Deserialize, Serialize};
 
+/// Type of actuator a feature drives, used by [ScalarCmd][crate::core::messages::ScalarCmd] to
+/// describe what a scalar value will actually do on a device.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+pub enum ActuatorType {
+  Vibrate,
+  Rotate,
+  Oscillate,
+  Constrict,
+  Inflate,
+  Position,
+}
+
+/// Type of sensor a feature reads from, used by
+/// [SensorReadCmd][crate::core::messages::SensorReadCmd] and
+/// [SensorSubscribeCmd][crate::core::messages::SensorSubscribeCmd] to address sensors.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+pub enum SensorType {
+  Battery,
+  RSSI,
+  Pressure,
+  Button,
+  Accelerometer,
+}
+
 // Unlike other message components, MessageAttributes is always turned on for
 // serialization, because it's used by device configuration files also.
 //
@@ -30,14 +54,30 @@ pub struct DeviceMessageAttributes {
   #[serde(rename = "MaxDuration")]
   #[serde(skip_serializing_if = "Option::is_none")]
   pub max_duration: Option<Vec<u32>>,
+  #[serde(rename = "ActuatorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub actuator_type: Option<Vec<ActuatorType>>,
+  #[serde(rename = "FeatureDescriptor")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub feature_descriptor: Option<Vec<String>>,
+  #[serde(rename = "SensorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sensor_type: Option<Vec<SensorType>>,
+  // Each sensor can return multiple values (i.e. one per accelerometer axis),
+  // so this holds a list of [min, max] ranges per sensor.
+  #[serde(rename = "SensorRange")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sensor_range: Option<Vec<Vec<(i32, i32)>>>,
+  // Set from user output limits, this is the [min, max] range of actual
+  // device output that command values of 0.0-1.0 are scaled into, per feature.
+  #[serde(rename = "OutputRange")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub output_range: Option<Vec<(f64, f64)>>,
   /*
   // Unimplemented attributes
   #[serde(rename = "Patterns")]
   #[serde(skip_serializing_if = "Option::is_none")]
   patterns: Option<Vec<Vec<String>>>,
-  #[serde(rename = "ActuatorType")]
-  #[serde(skip_serializing_if = "Option::is_none")]
-  actuator_type: Option<Vec<String>>,
   */
   // Never serialize this, its for internal use only
   #[serde(rename = "FeatureOrder")]
@@ -23,6 +23,7 @@ mod device_removed;
 mod error;
 mod fleshlight_launch_fw12_cmd;
 mod kiiroo_cmd;
+mod known_device_list;
 mod linear_cmd;
 mod log;
 mod log_level;
@@ -36,14 +37,21 @@ mod raw_subscribe_cmd;
 mod raw_unsubscribe_cmd;
 mod raw_write_cmd;
 mod request_device_list;
+mod request_known_device_list;
 mod request_log;
 mod request_server_info;
 mod rotate_cmd;
 mod rssi_level_cmd;
 mod rssi_level_reading;
+mod scalar_cmd;
 mod scanning_finished;
+mod sensor_read_cmd;
+mod sensor_reading;
+mod sensor_subscribe_cmd;
+mod sensor_unsubscribe_cmd;
 pub mod serializer;
 mod server_info;
+mod set_device_user_config;
 mod single_motor_vibrate_cmd;
 mod start_scanning;
 mod stop_all_devices;
@@ -56,17 +64,18 @@ mod vorze_a10_cyclone_cmd;
 pub use self::log::Log;
 pub use battery_level_cmd::BatteryLevelCmd;
 pub use battery_level_reading::BatteryLevelReading;
-pub use device_added::{DeviceAdded, DeviceAddedV0, DeviceAddedV1};
-pub use device_list::{DeviceList, DeviceListV0, DeviceListV1};
+pub use device_added::{DeviceAdded, DeviceAddedV0, DeviceAddedV1, DeviceAddedV2};
+pub use device_list::{DeviceList, DeviceListV0, DeviceListV1, DeviceListV2};
 pub use device_message_info::{DeviceMessageAttributesMap, DeviceMessageInfo};
 pub use device_removed::DeviceRemoved;
 pub use error::{Error, ErrorCode, ErrorV0};
 pub use fleshlight_launch_fw12_cmd::FleshlightLaunchFW12Cmd;
 pub use kiiroo_cmd::KiirooCmd;
+pub use known_device_list::{KnownDeviceInfo, KnownDeviceList};
 pub use linear_cmd::{LinearCmd, VectorSubcommand};
 pub use log_level::LogLevel;
 pub use lovense_cmd::LovenseCmd;
-pub use message_attributes::DeviceMessageAttributes;
+pub use message_attributes::{ActuatorType, DeviceMessageAttributes, SensorType};
 pub use ok::Ok;
 pub use ping::Ping;
 pub use raw_read_cmd::RawReadCmd;
@@ -75,13 +84,20 @@ pub use raw_subscribe_cmd::RawSubscribeCmd;
 pub use raw_unsubscribe_cmd::RawUnsubscribeCmd;
 pub use raw_write_cmd::RawWriteCmd;
 pub use request_device_list::RequestDeviceList;
+pub use request_known_device_list::RequestKnownDeviceList;
 pub use request_log::RequestLog;
 pub use request_server_info::RequestServerInfo;
 pub use rotate_cmd::{RotateCmd, RotationSubcommand};
 pub use rssi_level_cmd::RSSILevelCmd;
 pub use rssi_level_reading::RSSILevelReading;
+pub use scalar_cmd::{ScalarCmd, ScalarSubcommand};
 pub use scanning_finished::ScanningFinished;
+pub use sensor_read_cmd::SensorReadCmd;
+pub use sensor_reading::SensorReading;
+pub use sensor_subscribe_cmd::SensorSubscribeCmd;
+pub use sensor_unsubscribe_cmd::SensorUnsubscribeCmd;
 pub use server_info::{ServerInfo, ServerInfoV0};
+pub use set_device_user_config::SetDeviceUserConfig;
 pub use single_motor_vibrate_cmd::SingleMotorVibrateCmd;
 pub use start_scanning::StartScanning;
 pub use stop_all_devices::StopAllDevices;
@@ -107,6 +123,7 @@ pub enum ButtplugMessageSpecVersion {
   Version0 = 0,
   Version1 = 1,
   Version2 = 2,
+  Version3 = 3,
 }
 
 /// Message Id for events sent from the server, which are not in response to a
@@ -115,7 +132,7 @@ pub const BUTTPLUG_SERVER_EVENT_ID: u32 = 0;
 
 /// The current latest version of the spec implemented by the library.
 pub const BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION: ButtplugMessageSpecVersion =
-  ButtplugMessageSpecVersion::Version2;
+  ButtplugMessageSpecVersion::Version3;
 
 /// Base trait for all Buttplug Protocol Message Structs. Handles management of
 /// message ids, as well as implementing conveinence functions for converting
@@ -187,6 +204,7 @@ pub enum ButtplugDeviceMessageType {
   VibrateCmd,
   LinearCmd,
   RotateCmd,
+  ScalarCmd,
   StopDeviceCmd,
   RawWriteCmd,
   RawReadCmd,
@@ -194,6 +212,8 @@ pub enum ButtplugDeviceMessageType {
   RawUnsubscribeCmd,
   BatteryLevelCmd,
   RSSILevelCmd,
+  SensorReadCmd,
+  SensorSubscribeCmd,
   // Deprecated generic commands
   SingleMotorVibrateCmd,
   // Deprecated device specific commands
@@ -229,6 +249,7 @@ pub enum ButtplugCurrentSpecDeviceMessageType {
   VibrateCmd,
   LinearCmd,
   RotateCmd,
+  ScalarCmd,
   StopDeviceCmd,
   RawWriteCmd,
   RawReadCmd,
@@ -236,6 +257,8 @@ pub enum ButtplugCurrentSpecDeviceMessageType {
   RawUnsubscribeCmd,
   BatteryLevelCmd,
   RSSILevelCmd,
+  SensorReadCmd,
+  SensorSubscribeCmd,
 }
 
 // Ordering for ButtplugCurrentDeviceMessageType should be lexicographic, for
@@ -259,6 +282,7 @@ impl TryFrom<ButtplugDeviceMessageType> for ButtplugCurrentSpecDeviceMessageType
       ButtplugDeviceMessageType::VibrateCmd => Ok(ButtplugCurrentSpecDeviceMessageType::VibrateCmd),
       ButtplugDeviceMessageType::LinearCmd => Ok(ButtplugCurrentSpecDeviceMessageType::LinearCmd),
       ButtplugDeviceMessageType::RotateCmd => Ok(ButtplugCurrentSpecDeviceMessageType::RotateCmd),
+      ButtplugDeviceMessageType::ScalarCmd => Ok(ButtplugCurrentSpecDeviceMessageType::ScalarCmd),
       ButtplugDeviceMessageType::StopDeviceCmd => {
         Ok(ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd)
       }
@@ -278,6 +302,12 @@ impl TryFrom<ButtplugDeviceMessageType> for ButtplugCurrentSpecDeviceMessageType
       ButtplugDeviceMessageType::RSSILevelCmd => {
         Ok(ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd)
       }
+      ButtplugDeviceMessageType::SensorReadCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::SensorReadCmd)
+      }
+      ButtplugDeviceMessageType::SensorSubscribeCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd)
+      }
       _ => Err(ButtplugMessageError::MessageConversionError(
         "Device message deprecated, does not exist in current version of protocol.".to_owned(),
       )),
@@ -291,6 +321,7 @@ impl From<ButtplugCurrentSpecDeviceMessageType> for ButtplugDeviceMessageType {
       ButtplugCurrentSpecDeviceMessageType::VibrateCmd => ButtplugDeviceMessageType::VibrateCmd,
       ButtplugCurrentSpecDeviceMessageType::LinearCmd => ButtplugDeviceMessageType::LinearCmd,
       ButtplugCurrentSpecDeviceMessageType::RotateCmd => ButtplugDeviceMessageType::RotateCmd,
+      ButtplugCurrentSpecDeviceMessageType::ScalarCmd => ButtplugDeviceMessageType::ScalarCmd,
       ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd => {
         ButtplugDeviceMessageType::StopDeviceCmd
       }
@@ -306,6 +337,12 @@ impl From<ButtplugCurrentSpecDeviceMessageType> for ButtplugDeviceMessageType {
         ButtplugDeviceMessageType::BatteryLevelCmd
       }
       ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd => ButtplugDeviceMessageType::RSSILevelCmd,
+      ButtplugCurrentSpecDeviceMessageType::SensorReadCmd => {
+        ButtplugDeviceMessageType::SensorReadCmd
+      }
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd => {
+        ButtplugDeviceMessageType::SensorSubscribeCmd
+      }
     }
   }
 }
@@ -336,6 +373,7 @@ pub enum ButtplugClientMessage {
   VibrateCmd(VibrateCmd),
   LinearCmd(LinearCmd),
   RotateCmd(RotateCmd),
+  ScalarCmd(ScalarCmd),
   RawWriteCmd(RawWriteCmd),
   RawReadCmd(RawReadCmd),
   StopDeviceCmd(StopDeviceCmd),
@@ -344,6 +382,12 @@ pub enum ButtplugClientMessage {
   // Sensor commands
   BatteryLevelCmd(BatteryLevelCmd),
   RSSILevelCmd(RSSILevelCmd),
+  SensorReadCmd(SensorReadCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+  // Device configuration messages
+  RequestKnownDeviceList(RequestKnownDeviceList),
+  SetDeviceUserConfig(SetDeviceUserConfig),
   // Deprecated generic commands
   SingleMotorVibrateCmd(SingleMotorVibrateCmd),
   // Deprecated device specific commands
@@ -387,12 +431,92 @@ pub enum ButtplugServerMessage {
   // Sensor Reading Messages
   BatteryLevelReading(BatteryLevelReading),
   RSSILevelReading(RSSILevelReading),
+  SensorReading(SensorReading),
+  // Device configuration messages
+  KnownDeviceList(KnownDeviceList),
 }
 
 /// Type alias for the latest version of client-to-server messages.
-pub type ButtplugCurrentSpecClientMessage = ButtplugSpecV2ClientMessage;
+pub type ButtplugCurrentSpecClientMessage = ButtplugSpecV3ClientMessage;
 /// Type alias for the latest version of server-to-client messages.
-pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV2ServerMessage;
+pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV3ServerMessage;
+
+/// Represents all client-to-server messages in v3 of the Buttplug Spec
+#[derive(
+  Debug,
+  Clone,
+  PartialEq,
+  ButtplugMessage,
+  ButtplugMessageValidator,
+  ButtplugClientMessageType,
+  FromSpecificButtplugMessage,
+  TryFromButtplugClientMessage,
+)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ButtplugSpecV3ClientMessage {
+  // Handshake messages
+  RequestServerInfo(RequestServerInfo),
+  Ping(Ping),
+  RequestLog(RequestLog),
+  // Device enumeration messages
+  StartScanning(StartScanning),
+  StopScanning(StopScanning),
+  RequestDeviceList(RequestDeviceList),
+  // Generic commands
+  StopAllDevices(StopAllDevices),
+  VibrateCmd(VibrateCmd),
+  LinearCmd(LinearCmd),
+  RotateCmd(RotateCmd),
+  ScalarCmd(ScalarCmd),
+  RawWriteCmd(RawWriteCmd),
+  RawReadCmd(RawReadCmd),
+  StopDeviceCmd(StopDeviceCmd),
+  RawSubscribeCmd(RawSubscribeCmd),
+  RawUnsubscribeCmd(RawUnsubscribeCmd),
+  // Sensor commands
+  BatteryLevelCmd(BatteryLevelCmd),
+  RSSILevelCmd(RSSILevelCmd),
+  SensorReadCmd(SensorReadCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+  // Device configuration messages
+  RequestKnownDeviceList(RequestKnownDeviceList),
+  SetDeviceUserConfig(SetDeviceUserConfig),
+}
+
+/// Represents all server-to-client messages in v3 of the Buttplug Spec
+#[derive(
+  Debug,
+  Clone,
+  PartialEq,
+  ButtplugMessage,
+  ButtplugMessageValidator,
+  ButtplugServerMessageType,
+  FromSpecificButtplugMessage,
+  TryFromButtplugServerMessage,
+)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ButtplugSpecV3ServerMessage {
+  // Status messages
+  Ok(Ok),
+  Error(Error),
+  Log(Log),
+  // Handshake messages
+  ServerInfo(ServerInfo),
+  // Device enumeration messages
+  DeviceList(DeviceList),
+  DeviceAdded(DeviceAdded),
+  DeviceRemoved(DeviceRemoved),
+  ScanningFinished(ScanningFinished),
+  // Generic commands
+  RawReading(RawReading),
+  // Sensor commands
+  BatteryLevelReading(BatteryLevelReading),
+  RSSILevelReading(RSSILevelReading),
+  SensorReading(SensorReading),
+  // Device configuration messages
+  KnownDeviceList(KnownDeviceList),
+}
 
 /// Represents all client-to-server messages in v2 of the Buttplug Spec
 #[derive(
@@ -410,6 +534,7 @@ pub enum ButtplugSpecV2ClientMessage {
   // Handshake messages
   RequestServerInfo(RequestServerInfo),
   Ping(Ping),
+  RequestLog(RequestLog),
   // Device enumeration messages
   StartScanning(StartScanning),
   StopScanning(StopScanning),
@@ -437,7 +562,6 @@ pub enum ButtplugSpecV2ClientMessage {
   ButtplugMessage,
   ButtplugMessageValidator,
   ButtplugServerMessageType,
-  FromSpecificButtplugMessage,
   TryFromButtplugServerMessage,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
@@ -445,11 +569,12 @@ pub enum ButtplugSpecV2ServerMessage {
   // Status messages
   Ok(Ok),
   Error(Error),
+  Log(Log),
   // Handshake messages
   ServerInfo(ServerInfo),
   // Device enumeration messages
-  DeviceList(DeviceList),
-  DeviceAdded(DeviceAdded),
+  DeviceList(DeviceListV2),
+  DeviceAdded(DeviceAddedV2),
   DeviceRemoved(DeviceRemoved),
   ScanningFinished(ScanningFinished),
   // Generic commands
@@ -474,6 +599,7 @@ pub(crate) enum ButtplugSpecV1ClientMessage {
   // Handshake messages
   RequestServerInfo(RequestServerInfo),
   Ping(Ping),
+  RequestLog(RequestLog),
   // Device enumeration messages
   StartScanning(StartScanning),
   StopScanning(StopScanning),
@@ -671,6 +797,7 @@ pub enum ButtplugDeviceCommandMessageUnion {
   VibrateCmd(VibrateCmd),
   LinearCmd(LinearCmd),
   RotateCmd(RotateCmd),
+  ScalarCmd(ScalarCmd),
   RawWriteCmd(RawWriteCmd),
   RawReadCmd(RawReadCmd),
   StopDeviceCmd(StopDeviceCmd),
@@ -678,4 +805,7 @@ pub enum ButtplugDeviceCommandMessageUnion {
   RawUnsubscribeCmd(RawUnsubscribeCmd),
   BatteryLevelCmd(BatteryLevelCmd),
   RSSILevelCmd(RSSILevelCmd),
+  SensorReadCmd(SensorReadCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
 }
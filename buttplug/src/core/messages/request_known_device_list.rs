@@ -0,0 +1,32 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Asks for every device the server knows about, connected or not, along with
+/// its user configuration. Only available on servers that allow device
+/// configuration messages.
+#[derive(Debug, ButtplugMessage, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct RequestKnownDeviceList {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+}
+
+impl Default for RequestKnownDeviceList {
+  fn default() -> Self {
+    Self { id: 1 }
+  }
+}
+
+impl ButtplugMessageValidator for RequestKnownDeviceList {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
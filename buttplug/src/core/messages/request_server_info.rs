@@ -27,6 +27,14 @@ pub struct RequestServerInfo {
     serde(default = "return_version0")
   )]
   message_version: ButtplugMessageSpecVersion,
+  // Only sent to servers that require authentication.
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "AuthToken"),
+    serde(default),
+    serde(skip_serializing_if = "Option::is_none")
+  )]
+  auth_token: Option<String>,
 }
 
 impl RequestServerInfo {
@@ -35,6 +43,7 @@ impl RequestServerInfo {
       id: 1,
       client_name: client_name.to_string(),
       message_version,
+      auth_token: None,
     }
   }
 
@@ -45,6 +54,14 @@ impl RequestServerInfo {
   pub fn message_version(&self) -> ButtplugMessageSpecVersion {
     self.message_version
   }
+
+  pub fn auth_token(&self) -> &Option<String> {
+    &self.auth_token
+  }
+
+  pub fn set_auth_token(&mut self, auth_token: Option<String>) {
+    self.auth_token = auth_token;
+  }
 }
 
 impl ButtplugMessageValidator for RequestServerInfo {
@@ -71,6 +88,7 @@ mod test {
       id: 1,
       client_name: "Test Client".to_owned(),
       message_version: ButtplugMessageSpecVersion::Version2,
+      auth_token: None,
     };
     assert_eq!(
       serde_json::from_str::<RequestServerInfo>(new_json).expect("Test unwrap"),
@@ -91,10 +109,30 @@ mod test {
       id: 1,
       client_name: "Test Client".to_owned(),
       message_version: ButtplugMessageSpecVersion::Version0,
+      auth_token: None,
     };
     assert_eq!(
       serde_json::from_str::<RequestServerInfo>(old_json).expect("Test unwrap"),
       old_msg
     );
   }
+
+  #[cfg(feature = "serialize-json")]
+  #[test]
+  fn test_request_server_info_auth_token_json_conversion() {
+    let json = r#"
+{
+        "Id": 1,
+        "ClientName": "Test Client",
+        "MessageVersion": 3,
+        "AuthToken": "secret"
+}
+        "#;
+    let mut msg = RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version3);
+    msg.set_auth_token(Some("secret".to_owned()));
+    assert_eq!(
+      serde_json::from_str::<RequestServerInfo>(json).expect("Test unwrap"),
+      msg
+    );
+  }
 }
@@ -0,0 +1,56 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+// Sensor readings can be replies to SensorReadCmd, or events from a
+// SensorSubscribeCmd subscription, so the id can be a system id.
+#[derive(Debug, ButtplugDeviceMessage, ButtplugMessageValidator, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct SensorReading {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorIndex"))]
+  sensor_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorType"))]
+  sensor_type: SensorType,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Data"))]
+  data: Vec<i32>,
+}
+
+impl SensorReading {
+  pub fn new(
+    device_index: u32,
+    sensor_index: u32,
+    sensor_type: SensorType,
+    data: Vec<i32>,
+  ) -> Self {
+    Self {
+      id: 1,
+      device_index,
+      sensor_index,
+      sensor_type,
+      data,
+    }
+  }
+
+  pub fn sensor_index(&self) -> u32 {
+    self.sensor_index
+  }
+
+  pub fn sensor_type(&self) -> SensorType {
+    self.sensor_type
+  }
+
+  pub fn data(&self) -> &Vec<i32> {
+    &self.data
+  }
+}
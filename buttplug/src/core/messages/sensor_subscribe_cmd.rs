@@ -0,0 +1,48 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct SensorSubscribeCmd {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorIndex"))]
+  sensor_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorType"))]
+  sensor_type: SensorType,
+}
+
+impl SensorSubscribeCmd {
+  pub fn new(device_index: u32, sensor_index: u32, sensor_type: SensorType) -> Self {
+    Self {
+      id: 1,
+      device_index,
+      sensor_index,
+      sensor_type,
+    }
+  }
+
+  pub fn sensor_index(&self) -> u32 {
+    self.sensor_index
+  }
+
+  pub fn sensor_type(&self) -> SensorType {
+    self.sensor_type
+  }
+}
+
+impl ButtplugMessageValidator for SensorSubscribeCmd {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
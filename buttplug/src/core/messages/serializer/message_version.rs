@@ -0,0 +1,183 @@
+//! Spec version handling shared by all serializer formats.
+//!
+//! Servers have to figure out which message spec version a client speaks from
+//! its RequestServerInfo message, then downgrade everything they send to that
+//! version. None of that depends on the wire format, so it's written once
+//! here against [ButtplugMessageEncoding].
+
+use super::{ButtplugSerializedMessage, ButtplugSerializerError, ButtplugSerializerResult};
+use crate::core::{
+  errors::{ButtplugError, ButtplugHandshakeError},
+  messages::{
+    self,
+    ButtplugClientMessage,
+    ButtplugCurrentSpecClientMessage,
+    ButtplugCurrentSpecServerMessage,
+    ButtplugMessageSpecVersion,
+    ButtplugServerMessage,
+    ButtplugSpecV0ClientMessage,
+    ButtplugSpecV0ServerMessage,
+    ButtplugSpecV1ClientMessage,
+    ButtplugSpecV1ServerMessage,
+    ButtplugSpecV2ClientMessage,
+    ButtplugSpecV2ServerMessage,
+    ButtplugSpecV3ClientMessage,
+    ButtplugSpecV3ServerMessage,
+  },
+};
+use once_cell::sync::OnceCell;
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::TryFrom;
+
+/// Wire format for arrays of messages.
+pub(super) trait ButtplugMessageEncoding {
+  fn encode<T: Serialize>(&self, msgs: &[T]) -> ButtplugSerializedMessage;
+  fn decode<T: DeserializeOwned>(
+    &self,
+    msg: ButtplugSerializedMessage,
+  ) -> ButtplugSerializerResult<Vec<T>>;
+}
+
+fn decode_as<T, E>(
+  encoding: &E,
+  msg: ButtplugSerializedMessage,
+) -> ButtplugSerializerResult<Vec<ButtplugClientMessage>>
+where
+  T: DeserializeOwned + Into<ButtplugClientMessage>,
+  E: ButtplugMessageEncoding,
+{
+  Ok(
+    encoding
+      .decode::<T>(msg)?
+      .into_iter()
+      .map(|m| m.into())
+      .collect(),
+  )
+}
+
+fn serialize_to_version<E>(
+  encoding: &E,
+  version: ButtplugMessageSpecVersion,
+  msgs: Vec<ButtplugServerMessage>,
+) -> ButtplugSerializedMessage
+where
+  E: ButtplugMessageEncoding,
+{
+  match version {
+    ButtplugMessageSpecVersion::Version0 => {
+      let msg_vec: Vec<ButtplugSpecV0ServerMessage> = msgs
+        .into_iter()
+        .map(|msg| match ButtplugSpecV0ServerMessage::try_from(msg) {
+          Ok(msgv0) => msgv0,
+          Err(err) => ButtplugSpecV0ServerMessage::Error(
+            messages::Error::from(ButtplugError::from(err)).into(),
+          ),
+        })
+        .collect();
+      encoding.encode(&msg_vec)
+    }
+    ButtplugMessageSpecVersion::Version1 => {
+      let msg_vec: Vec<ButtplugSpecV1ServerMessage> = msgs
+        .into_iter()
+        .map(|msg| match ButtplugSpecV1ServerMessage::try_from(msg) {
+          Ok(msgv1) => msgv1,
+          Err(err) => ButtplugSpecV1ServerMessage::Error(
+            messages::Error::from(ButtplugError::from(err)).into(),
+          ),
+        })
+        .collect();
+      encoding.encode(&msg_vec)
+    }
+    ButtplugMessageSpecVersion::Version2 => {
+      let msg_vec: Vec<ButtplugSpecV2ServerMessage> = msgs
+        .into_iter()
+        .map(|msg| match ButtplugSpecV2ServerMessage::try_from(msg) {
+          Ok(msgv2) => msgv2,
+          Err(err) => ButtplugSpecV2ServerMessage::Error(ButtplugError::from(err).into()),
+        })
+        .collect();
+      encoding.encode(&msg_vec)
+    }
+    ButtplugMessageSpecVersion::Version3 => {
+      let msg_vec: Vec<ButtplugSpecV3ServerMessage> = msgs
+        .into_iter()
+        .map(|msg| match ButtplugSpecV3ServerMessage::try_from(msg) {
+          Ok(msgv3) => msgv3,
+          Err(err) => ButtplugSpecV3ServerMessage::Error(ButtplugError::from(err).into()),
+        })
+        .collect();
+      encoding.encode(&msg_vec)
+    }
+  }
+}
+
+/// Deserializes messages from a client, picking up the client's spec version
+/// from its first RequestServerInfo message.
+pub(super) fn deserialize_client_messages<E>(
+  encoding: &E,
+  message_version: &OnceCell<ButtplugMessageSpecVersion>,
+  msg: ButtplugSerializedMessage,
+) -> ButtplugSerializerResult<Vec<ButtplugClientMessage>>
+where
+  E: ButtplugMessageEncoding,
+{
+  if let Some(version) = message_version.get() {
+    return match version {
+      ButtplugMessageSpecVersion::Version0 => {
+        decode_as::<ButtplugSpecV0ClientMessage, _>(encoding, msg)
+      }
+      ButtplugMessageSpecVersion::Version1 => {
+        decode_as::<ButtplugSpecV1ClientMessage, _>(encoding, msg)
+      }
+      ButtplugMessageSpecVersion::Version2 => {
+        decode_as::<ButtplugSpecV2ClientMessage, _>(encoding, msg)
+      }
+      ButtplugMessageSpecVersion::Version3 => {
+        decode_as::<ButtplugSpecV3ClientMessage, _>(encoding, msg)
+      }
+    };
+  }
+  // If we don't have a message version yet, we need to parse this as a
+  // RequestServerInfo message to get the version. RequestServerInfo can
+  // always be parsed as the latest message version, as we keep it
+  // compatible across versions via serde options.
+  let msg_union = encoding.decode::<ButtplugCurrentSpecClientMessage>(msg)?;
+  // If the message is malformed, just return an spec version not received error.
+  if let Some(ButtplugCurrentSpecClientMessage::RequestServerInfo(rsi)) = msg_union.first() {
+    info!(
+      "Setting serializer message version to {}",
+      rsi.message_version()
+    );
+    message_version
+      .set(rsi.message_version())
+      .expect("This should only ever be called once.");
+  } else {
+    return Err(ButtplugSerializerError::MessageSpecVersionNotReceived);
+  }
+  Ok(msg_union.into_iter().map(|m| m.into()).collect())
+}
+
+/// Serializes messages to a client, at whatever spec version it asked for.
+pub(super) fn serialize_server_messages<E>(
+  encoding: &E,
+  message_version: &OnceCell<ButtplugMessageSpecVersion>,
+  msgs: Vec<ButtplugServerMessage>,
+) -> ButtplugSerializedMessage
+where
+  E: ButtplugMessageEncoding,
+{
+  if let Some(version) = message_version.get() {
+    serialize_to_version(encoding, *version, msgs)
+  } else if let Some(ButtplugServerMessage::Error(_)) = msgs.first() {
+    // In the rare event that there is a problem with the RequestServerInfo
+    // message (so we can't set up our known spec version), just encode to the
+    // latest and return.
+    serialize_to_version(encoding, ButtplugMessageSpecVersion::Version3, msgs)
+  } else {
+    // If we don't even have enough info to know which message version to
+    // convert to, consider this a handshake error.
+    encoding.encode(&[ButtplugCurrentSpecServerMessage::Error(
+      ButtplugError::from(ButtplugHandshakeError::RequestServerInfoExpected).into(),
+    )])
+  }
+}
@@ -1,7 +1,17 @@
 #[cfg(feature = "serialize-json")]
 mod json_serializer;
 #[cfg(feature = "serialize-json")]
+mod message_version;
+#[cfg(feature = "serialize-msgpack")]
+mod msgpack_serializer;
+#[cfg(feature = "serialize-json")]
 pub use json_serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer};
+#[cfg(feature = "serialize-msgpack")]
+pub use msgpack_serializer::{
+  ButtplugClientMessagePackSerializer,
+  ButtplugServerMessagePackSerializer,
+  ButtplugServerNegotiatedSerializer,
+};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -16,6 +26,9 @@ pub enum ButtplugSerializerError {
   /// Serialization error.
   #[error("Cannot serialize to JSON: {0}")]
   JsonSerializerError(String),
+  /// MessagePack de/serialization error.
+  #[error("Cannot de/serialize MessagePack: {0}")]
+  MessagePackSerializerError(String),
   #[error("Cannot deserialize binary in a text handler")]
   BinaryDeserializationError,
   #[error("Cannot deserialize text in a binary handler.")]
@@ -0,0 +1,266 @@
+//! MessagePack serializers, for clients that want a smaller, faster encoding
+//! than JSON.
+//!
+//! Messages have the same structure as in the JSON protocol (arrays of
+//! externally tagged messages, with fields keyed by the same names), just
+//! encoded as MessagePack and sent as binary. There's no schema validation
+//! on the way in, so malformed messages are only caught by deserialization.
+
+use super::{
+  json_serializer::JSONEncoding,
+  message_version::{
+    deserialize_client_messages,
+    serialize_server_messages,
+    ButtplugMessageEncoding,
+  },
+  ButtplugMessageSerializer,
+  ButtplugSerializedMessage,
+  ButtplugSerializerError,
+  ButtplugSerializerResult,
+};
+use crate::core::messages::{
+  ButtplugClientMessage,
+  ButtplugCurrentSpecClientMessage,
+  ButtplugCurrentSpecServerMessage,
+  ButtplugMessageSpecVersion,
+  ButtplugServerMessage,
+};
+use once_cell::sync::OnceCell;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Default)]
+struct MessagePackEncoding {}
+
+impl ButtplugMessageEncoding for MessagePackEncoding {
+  fn encode<T: Serialize>(&self, msgs: &[T]) -> ButtplugSerializedMessage {
+    // Structs are encoded as maps rather than arrays, as optional fields are
+    // skipped when empty and field position can't be relied on.
+    ButtplugSerializedMessage::Binary(
+      rmp_serde::to_vec_named(msgs).expect("Infallible serialization"),
+    )
+  }
+
+  fn decode<T: DeserializeOwned>(
+    &self,
+    msg: ButtplugSerializedMessage,
+  ) -> ButtplugSerializerResult<Vec<T>> {
+    if let ButtplugSerializedMessage::Binary(binary_msg) = msg {
+      rmp_serde::from_slice(&binary_msg)
+        .map_err(|e| ButtplugSerializerError::MessagePackSerializerError(format!("{:?}", e)))
+    } else {
+      Err(ButtplugSerializerError::TextDeserializationError)
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct ButtplugServerMessagePackSerializer {
+  message_version: OnceCell<ButtplugMessageSpecVersion>,
+  encoding: MessagePackEncoding,
+}
+
+impl ButtplugMessageSerializer for ButtplugServerMessagePackSerializer {
+  type Inbound = ButtplugClientMessage;
+  type Outbound = ButtplugServerMessage;
+
+  fn deserialize(
+    &self,
+    msg: ButtplugSerializedMessage,
+  ) -> ButtplugSerializerResult<Vec<ButtplugClientMessage>> {
+    deserialize_client_messages(&self.encoding, &self.message_version, msg)
+  }
+
+  fn serialize(&self, msgs: Vec<ButtplugServerMessage>) -> ButtplugSerializedMessage {
+    serialize_server_messages(&self.encoding, &self.message_version, msgs)
+  }
+}
+
+#[derive(Default)]
+pub struct ButtplugClientMessagePackSerializer {
+  encoding: MessagePackEncoding,
+}
+
+impl ButtplugMessageSerializer for ButtplugClientMessagePackSerializer {
+  type Inbound = ButtplugCurrentSpecServerMessage;
+  type Outbound = ButtplugCurrentSpecClientMessage;
+
+  fn deserialize(
+    &self,
+    msg: ButtplugSerializedMessage,
+  ) -> ButtplugSerializerResult<Vec<ButtplugCurrentSpecServerMessage>> {
+    self.encoding.decode(msg)
+  }
+
+  fn serialize(&self, msgs: Vec<ButtplugCurrentSpecClientMessage>) -> ButtplugSerializedMessage {
+    self.encoding.encode(&msgs)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NegotiatedFormat {
+  Json,
+  MessagePack,
+}
+
+/// Server serializer that speaks whichever format the client opens with.
+///
+/// A client that sends its RequestServerInfo as text gets JSON for the rest of
+/// the connection, one that sends it as binary gets MessagePack. Use this
+/// with transports that can carry both, like websockets, so JSON and binary
+/// clients can share a server port.
+#[derive(Default)]
+pub struct ButtplugServerNegotiatedSerializer {
+  message_version: OnceCell<ButtplugMessageSpecVersion>,
+  format: OnceCell<NegotiatedFormat>,
+  json: JSONEncoding,
+  msgpack: MessagePackEncoding,
+}
+
+impl ButtplugMessageSerializer for ButtplugServerNegotiatedSerializer {
+  type Inbound = ButtplugClientMessage;
+  type Outbound = ButtplugServerMessage;
+
+  fn deserialize(
+    &self,
+    msg: ButtplugSerializedMessage,
+  ) -> ButtplugSerializerResult<Vec<ButtplugClientMessage>> {
+    let format = *self.format.get_or_init(|| match msg {
+      ButtplugSerializedMessage::Text(_) => NegotiatedFormat::Json,
+      ButtplugSerializedMessage::Binary(_) => NegotiatedFormat::MessagePack,
+    });
+    match format {
+      NegotiatedFormat::Json => deserialize_client_messages(&self.json, &self.message_version, msg),
+      NegotiatedFormat::MessagePack => {
+        deserialize_client_messages(&self.msgpack, &self.message_version, msg)
+      }
+    }
+  }
+
+  fn serialize(&self, msgs: Vec<ButtplugServerMessage>) -> ButtplugSerializedMessage {
+    // If the client hasn't sent anything yet, JSON is the safest guess.
+    match self.format.get() {
+      Some(NegotiatedFormat::MessagePack) => {
+        serialize_server_messages(&self.msgpack, &self.message_version, msgs)
+      }
+      _ => serialize_server_messages(&self.json, &self.message_version, msgs),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::messages::{
+    self,
+    ButtplugMessage,
+    ButtplugSpecV0ServerMessage,
+    RequestServerInfo,
+    BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+  };
+
+  fn request_server_info(version: ButtplugMessageSpecVersion) -> ButtplugSerializedMessage {
+    ButtplugClientMessagePackSerializer::default().serialize(vec![RequestServerInfo::new(
+      "Test Client",
+      version,
+    )
+    .into()])
+  }
+
+  #[test]
+  fn test_client_server_round_trip() {
+    let server = ButtplugServerMessagePackSerializer::default();
+    let client = ButtplugClientMessagePackSerializer::default();
+    let msgs = server
+      .deserialize(request_server_info(BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION))
+      .expect("Test, assuming infallible.");
+    assert!(matches!(
+      msgs[0],
+      ButtplugClientMessage::RequestServerInfo(..)
+    ));
+    let mut server_info =
+      messages::ServerInfo::new("Test Server", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION, 0);
+    server_info.set_id(1);
+    let reply = server.serialize(vec![server_info.clone().into()]);
+    assert!(matches!(reply, ButtplugSerializedMessage::Binary(_)));
+    let reply = client
+      .deserialize(reply)
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      reply,
+      vec![ButtplugCurrentSpecServerMessage::ServerInfo(server_info)]
+    );
+  }
+
+  #[test]
+  fn test_server_downgrades_to_client_version() {
+    let server = ButtplugServerMessagePackSerializer::default();
+    server
+      .deserialize(request_server_info(ButtplugMessageSpecVersion::Version0))
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      server.message_version.get(),
+      Some(&ButtplugMessageSpecVersion::Version0)
+    );
+    // ServerInfo changed shape after spec v0, so make sure the old one is what
+    // goes out.
+    let reply = server.serialize(vec![messages::ServerInfo::new(
+      "Test Server",
+      ButtplugMessageSpecVersion::Version0,
+      0,
+    )
+    .into()]);
+    let reply: Vec<ButtplugSpecV0ServerMessage> = match reply {
+      ButtplugSerializedMessage::Binary(binary_msg) => {
+        rmp_serde::from_slice(&binary_msg).expect("Test, assuming infallible.")
+      }
+      _ => panic!("Should get binary back"),
+    };
+    assert!(matches!(
+      reply[0],
+      ButtplugSpecV0ServerMessage::ServerInfo(..)
+    ));
+  }
+
+  #[test]
+  fn test_wrong_format() {
+    let server = ButtplugServerMessagePackSerializer::default();
+    assert!(matches!(
+      server.deserialize(ButtplugSerializedMessage::Text("[]".to_owned())),
+      Err(ButtplugSerializerError::TextDeserializationError)
+    ));
+    assert!(matches!(
+      server.deserialize(ButtplugSerializedMessage::Binary(vec![0xc1])),
+      Err(ButtplugSerializerError::MessagePackSerializerError(_))
+    ));
+  }
+
+  #[test]
+  fn test_negotiated_serializer() {
+    let server = ButtplugServerNegotiatedSerializer::default();
+    server
+      .deserialize(request_server_info(BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION))
+      .expect("Test, assuming infallible.");
+    assert!(matches!(
+      server.serialize(vec![messages::Ok::new(1).into()]),
+      ButtplugSerializedMessage::Binary(_)
+    ));
+    // Once binary is picked, the client can't switch to text.
+    assert!(server
+      .deserialize(ButtplugSerializedMessage::Text(
+        "[{\"Ping\":{\"Id\":2}}]".to_owned()
+      ))
+      .is_err());
+
+    let server = ButtplugServerNegotiatedSerializer::default();
+    server
+      .deserialize(ButtplugSerializedMessage::Text(
+        "[{\"RequestServerInfo\":{\"Id\":1,\"ClientName\":\"Test Client\",\"MessageVersion\":3}}]"
+          .to_owned(),
+      ))
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      server.serialize(vec![messages::Ok::new(1).into()]),
+      ButtplugSerializedMessage::Text("[{\"Ok\":{\"Id\":1}}]".to_owned())
+    );
+  }
+}
@@ -0,0 +1,81 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Sets the display name, allow and deny entries for a device address,
+/// replacing whatever was set before. Leaving all of them unset removes them.
+/// Other user configuration for the device, like output limits, is kept. Only
+/// available on servers that allow device configuration messages.
+#[derive(Debug, ButtplugMessage, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct SetDeviceUserConfig {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Address"))]
+  address: String,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DisplayName",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  display_name: Option<String>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "Allow", skip_serializing_if = "Option::is_none", default)
+  )]
+  allow: Option<bool>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "Deny", skip_serializing_if = "Option::is_none", default)
+  )]
+  deny: Option<bool>,
+}
+
+impl SetDeviceUserConfig {
+  pub fn new(
+    address: &str,
+    display_name: Option<String>,
+    allow: Option<bool>,
+    deny: Option<bool>,
+  ) -> Self {
+    Self {
+      id: 1,
+      address: address.to_owned(),
+      display_name,
+      allow,
+      deny,
+    }
+  }
+
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  pub fn display_name(&self) -> &Option<String> {
+    &self.display_name
+  }
+
+  pub fn allow(&self) -> Option<bool> {
+    self.allow
+  }
+
+  pub fn deny(&self) -> Option<bool> {
+    self.deny
+  }
+}
+
+impl ButtplugMessageValidator for SetDeviceUserConfig {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
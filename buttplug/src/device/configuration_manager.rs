@@ -7,16 +7,25 @@
 
 //! Device specific identification and protocol implementations.
 
+#[cfg(feature = "scripted-protocols")]
+use super::protocol::scripted::{ProtocolScript, Scripted};
 use super::protocol::{
   add_to_protocol_map,
   get_default_protocol_map,
+  template::{ProtocolTemplate, Template},
   ButtplugProtocol,
   TryCreateProtocolFunc,
 };
 use crate::{
   core::{
     errors::{ButtplugDeviceError, ButtplugError},
-    messages::{ButtplugDeviceMessageType, DeviceMessageAttributes, DeviceMessageAttributesMap},
+    messages::{
+      ActuatorType,
+      ButtplugDeviceMessageType,
+      DeviceMessageAttributes,
+      DeviceMessageAttributesMap,
+      SensorType,
+    },
   },
   device::Endpoint,
 };
@@ -24,7 +33,7 @@ use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use std::{
   collections::{HashMap, HashSet},
-  sync::Arc,
+  sync::{Arc, RwLock},
 };
 use getset::{Getters, Setters, MutGetters};
 use uuid::Uuid;
@@ -263,8 +272,17 @@ pub struct ProtocolDefinition {
   lovense_connect_service: Option<LovenseConnectServiceSpecifier>,
   #[serde(skip_serializing_if = "Option::is_none")]
   defaults: Option<ProtocolAttributes>,
-  #[serde(default)]
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
   configurations: Vec<ProtocolAttributes>,
+  // Minimum time, in milliseconds, between commands sent to devices using this
+  // protocol. Commands that arrive faster than this are coalesced by the server.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(rename = "min-command-interval")]
+  min_command_interval: Option<u32>,
+  // Packet layout for simple devices, used if there's no compiled in protocol
+  // with this name. See the template protocol.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  template: Option<ProtocolTemplate>,
 }
 
 fn option_some_eq<T>(a: &Option<T>, b: &T) -> bool
@@ -358,6 +376,14 @@ impl ProtocolDefinition {
       self.defaults = other.defaults;
     }
 
+    if other.min_command_interval.is_some() {
+      self.min_command_interval = other.min_command_interval;
+    }
+
+    if other.template.is_some() {
+      self.template = other.template;
+    }
+
     // Treat configurations like paths; Extend using the new ones first, so we'll find them first,
     // but leave everything in. Post warning messages if anything repeats after this.
     if !other.configurations.is_empty() {
@@ -376,6 +402,9 @@ pub struct DeviceProtocolConfiguration {
   allow_raw_messages: bool,
   defaults: Option<ProtocolAttributes>,
   configurations: Vec<ProtocolAttributes>,
+  template: Option<ProtocolTemplate>,
+  #[cfg(feature = "scripted-protocols")]
+  script: Option<Arc<ProtocolScript>>,
 }
 
 impl DeviceProtocolConfiguration {
@@ -388,9 +417,30 @@ impl DeviceProtocolConfiguration {
       allow_raw_messages,
       defaults,
       configurations,
+      template: None,
+      #[cfg(feature = "scripted-protocols")]
+      script: None,
     }
   }
 
+  pub fn set_template(&mut self, template: Option<ProtocolTemplate>) {
+    self.template = template;
+  }
+
+  pub fn template(&self) -> &Option<ProtocolTemplate> {
+    &self.template
+  }
+
+  #[cfg(feature = "scripted-protocols")]
+  pub fn set_script(&mut self, script: Option<Arc<ProtocolScript>>) {
+    self.script = script;
+  }
+
+  #[cfg(feature = "scripted-protocols")]
+  pub fn script(&self) -> &Option<Arc<ProtocolScript>> {
+    &self.script
+  }
+
   pub fn get_attributes(
     &self,
     identifier: &str,
@@ -460,6 +510,23 @@ impl DeviceProtocolConfiguration {
       .entry(ButtplugDeviceMessageType::StopDeviceCmd)
       .or_insert_with(DeviceMessageAttributes::default);
 
+    // Vibrators and rotators can always be driven by ScalarCmd, so configs
+    // only need to list ScalarCmd attributes for other actuator types.
+    if let Some(scalar_attributes) = scalar_attributes_from_generic(&attributes) {
+      let scalar_attributes = match attributes.remove(&ButtplugDeviceMessageType::ScalarCmd) {
+        Some(configured) => merge_scalar_attributes(scalar_attributes, configured),
+        None => scalar_attributes,
+      };
+      attributes.insert(ButtplugDeviceMessageType::ScalarCmd, scalar_attributes);
+    }
+
+    // Same goes for battery and RSSI readings with SensorReadCmd.
+    if !attributes.contains_key(&ButtplugDeviceMessageType::SensorReadCmd) {
+      if let Some(sensor_attributes) = sensor_attributes_from_generic(&attributes) {
+        attributes.insert(ButtplugDeviceMessageType::SensorReadCmd, sensor_attributes);
+      }
+    }
+
     Ok((
       device_attrs
         .name
@@ -471,10 +538,118 @@ impl DeviceProtocolConfiguration {
   }
 }
 
+/// Builds ScalarCmd attributes out of VibrateCmd and RotateCmd attributes.
+/// Vibrators are listed first, followed by rotators, which is the index order
+/// ScalarCmd subcommands are translated with.
+pub(crate) fn scalar_attributes_from_generic(
+  attributes: &DeviceMessageAttributesMap,
+) -> Option<DeviceMessageAttributes> {
+  let mut feature_count = 0;
+  let mut step_count = vec![];
+  let mut actuator_type = vec![];
+  for (message_type, actuator) in [
+    (ButtplugDeviceMessageType::VibrateCmd, ActuatorType::Vibrate),
+    (ButtplugDeviceMessageType::RotateCmd, ActuatorType::Rotate),
+  ] {
+    if let Some(attrs) = attributes.get(&message_type) {
+      let count = attrs.feature_count.unwrap_or(0);
+      feature_count += count;
+      actuator_type.extend(vec![actuator; count as usize]);
+      if let Some(steps) = &attrs.step_count {
+        step_count.extend(steps.iter().cloned());
+      }
+    }
+  }
+  if feature_count == 0 {
+    return None;
+  }
+  Some(DeviceMessageAttributes {
+    feature_count: Some(feature_count),
+    step_count: if step_count.len() == feature_count as usize {
+      Some(step_count)
+    } else {
+      None
+    },
+    actuator_type: Some(actuator_type),
+    ..Default::default()
+  })
+}
+
+/// Adds configured ScalarCmd attributes after the ones built from VibrateCmd
+/// and RotateCmd. If the configured attributes already list vibrators or
+/// rotators, they're taken to describe every feature and are used as is.
+fn merge_scalar_attributes(
+  generic: DeviceMessageAttributes,
+  configured: DeviceMessageAttributes,
+) -> DeviceMessageAttributes {
+  let configured_types = configured.actuator_type.clone().unwrap_or_default();
+  if configured_types
+    .iter()
+    .any(|x| *x == ActuatorType::Vibrate || *x == ActuatorType::Rotate)
+  {
+    return configured;
+  }
+  let generic_count = generic.feature_count.unwrap_or(0);
+  let configured_count = configured
+    .feature_count
+    .unwrap_or(configured_types.len() as u32);
+  let mut actuator_type = generic.actuator_type.unwrap_or_default();
+  actuator_type.extend(configured_types);
+  let step_count = match (generic.step_count, configured.step_count) {
+    (Some(mut generic), Some(configured)) => {
+      generic.extend(configured);
+      Some(generic)
+    }
+    _ => None,
+  };
+  let feature_descriptor = configured.feature_descriptor.map(|descriptors| {
+    let mut merged = vec![String::new(); generic_count as usize];
+    merged.extend(descriptors);
+    merged
+  });
+  DeviceMessageAttributes {
+    feature_count: Some(generic_count + configured_count),
+    step_count,
+    actuator_type: Some(actuator_type),
+    feature_descriptor,
+    ..configured
+  }
+}
+
+/// Builds SensorReadCmd attributes out of BatteryLevelCmd and RSSILevelCmd
+/// attributes. Battery is listed first, followed by RSSI.
+pub(crate) fn sensor_attributes_from_generic(
+  attributes: &DeviceMessageAttributesMap,
+) -> Option<DeviceMessageAttributes> {
+  let mut sensor_type = vec![];
+  let mut sensor_range = vec![];
+  if attributes.contains_key(&ButtplugDeviceMessageType::BatteryLevelCmd) {
+    sensor_type.push(SensorType::Battery);
+    sensor_range.push(vec![(0, 100)]);
+  }
+  if attributes.contains_key(&ButtplugDeviceMessageType::RSSILevelCmd) {
+    sensor_type.push(SensorType::RSSI);
+    sensor_range.push(vec![(-128, 0)]);
+  }
+  if sensor_type.is_empty() {
+    return None;
+  }
+  Some(DeviceMessageAttributes {
+    feature_count: Some(sensor_type.len() as u32),
+    sensor_type: Some(sensor_type),
+    sensor_range: Some(sensor_range),
+    ..Default::default()
+  })
+}
+
 pub struct DeviceConfigurationManager {
   allow_raw_messages: bool,
-  protocol_definitions: Arc<DashMap<String, ProtocolDefinition>>,
+  /// Swapped out as a whole when the configuration is replaced, so lookups
+  /// never see a half updated set of definitions.
+  protocol_definitions: RwLock<Arc<DashMap<String, ProtocolDefinition>>>,
   protocol_map: Arc<DashMap<String, TryCreateProtocolFunc>>,
+  #[cfg(feature = "scripted-protocols")]
+  protocol_scripts: Arc<DashMap<String, Arc<ProtocolScript>>>,
 }
 
 impl Default for DeviceConfigurationManager {
@@ -489,8 +664,10 @@ impl DeviceConfigurationManager {
   pub fn new(allow_raw_messages: bool) -> Self {
     Self {
       allow_raw_messages,
-      protocol_definitions: Arc::new(DashMap::new()),
+      protocol_definitions: RwLock::new(Arc::new(DashMap::new())),
       protocol_map: Arc::new(get_default_protocol_map()),
+      #[cfg(feature = "scripted-protocols")]
+      protocol_scripts: Arc::new(DashMap::new()),
     }
   }
 
@@ -500,12 +677,25 @@ impl DeviceConfigurationManager {
     protocol_definition: ProtocolDefinition,
   ) {
     self
-      .protocol_definitions
+      .protocol_definitions()
       .insert(protocol_name.to_owned(), protocol_definition);
   }
 
   pub fn remove_protocol_definition(&self, protocol_name: &str) {
-    self.protocol_definitions.remove(protocol_name);
+    self.protocol_definitions().remove(protocol_name);
+  }
+
+  /// Replaces all protocol definitions at once, so devices being identified
+  /// while this happens see either the old set or the new one.
+  pub fn replace_protocol_definitions(
+    &self,
+    protocol_definitions: HashMap<String, ProtocolDefinition>,
+  ) {
+    let protocol_definitions = Arc::new(protocol_definitions.into_iter().collect());
+    *self
+      .protocol_definitions
+      .write()
+      .expect("Lock should never be poisoned") = protocol_definitions;
   }
 
   pub fn add_protocol<T>(&self, protocol_name: &str)
@@ -527,18 +717,65 @@ impl DeviceConfigurationManager {
     self.protocol_map.contains_key(protocol_name)
   }
 
-  pub fn get_protocol_creator(&self, protocol_name: &str) -> Option<TryCreateProtocolFunc> {
+  /// Compiles and registers a protocol script, see the scripted protocol.
+  /// Replaces any script already registered under this name.
+  #[cfg(feature = "scripted-protocols")]
+  pub fn add_protocol_script(
+    &self,
+    protocol_name: &str,
+    source: &str,
+  ) -> Result<(), ButtplugError> {
+    let script = ProtocolScript::new(protocol_name, source)?;
     self
-      .protocol_map
+      .protocol_scripts
+      .insert(protocol_name.to_owned(), Arc::new(script));
+    Ok(())
+  }
+
+  #[cfg(feature = "scripted-protocols")]
+  pub fn remove_protocol_script(&self, protocol_name: &str) {
+    self.protocol_scripts.remove(protocol_name);
+  }
+
+  #[cfg(feature = "scripted-protocols")]
+  pub fn get_protocol_script(&self, protocol_name: &str) -> Option<Arc<ProtocolScript>> {
+    self
+      .protocol_scripts
       .get(protocol_name)
-      .map(|pair| *pair.value())
+      .map(|pair| pair.value().clone())
+  }
+
+  /// Creator for a protocol. Compiled in protocols come first, then protocol
+  /// scripts, otherwise protocol definitions with a template use the template
+  /// protocol.
+  pub fn get_protocol_creator(&self, protocol_name: &str) -> Option<TryCreateProtocolFunc> {
+    let creator = self.protocol_map.get(protocol_name).map(|pair| *pair.value());
+    #[cfg(feature = "scripted-protocols")]
+    let creator = creator.or_else(|| {
+      self
+        .protocol_scripts
+        .get(protocol_name)
+        .map(|_| Scripted::try_create as TryCreateProtocolFunc)
+    });
+    creator.or_else(|| {
+      self
+        .protocol_definitions()
+        .get(protocol_name)
+        .filter(|definition| definition.template.is_some())
+        .map(|_| Template::try_create as TryCreateProtocolFunc)
+    })
   }
 
   /// Provides read-only access to the internal protocol/identifier map. Mainly
   /// used for WebBluetooth filter construction, but could also be handy for
-  /// listing capabilities in UI, etc.
+  /// listing capabilities in UI, etc. If the definitions are replaced, the
+  /// returned map keeps the old ones.
   pub fn protocol_definitions(&self) -> Arc<DashMap<String, ProtocolDefinition>> {
-    self.protocol_definitions.clone()
+    self
+      .protocol_definitions
+      .read()
+      .expect("Lock should never be poisoned")
+      .clone()
   }
 
   pub fn find_protocol_definitions(
@@ -549,7 +786,7 @@ impl DeviceConfigurationManager {
       "Looking for protocol that matches specifier: {:?}",
       specifier
     );
-    for config in self.protocol_definitions.iter() {
+    for config in self.protocol_definitions().iter() {
       if config.value() == specifier {
         info!(
           "Found protocol {:?} for specifier {:?}.",
@@ -571,13 +808,15 @@ impl DeviceConfigurationManager {
     debug!("Looking for protocol {}", name);
     // TODO It feels like maybe there should be a cleaner way to do this,
     // but I'm not really sure what it is?
-    if let Some(proto) = self.protocol_definitions.get(name) {
+    if let Some(proto) = self.protocol_definitions().get(name) {
       info!("Found a protocol definition for {}", name);
-      Some(DeviceProtocolConfiguration::new(
+      let mut config = DeviceProtocolConfiguration::new(
         self.allow_raw_messages,
         proto.defaults.clone(),
         proto.configurations.clone(),
-      ))
+      );
+      config.set_template(proto.template.clone());
+      Some(config)
     } else {
       debug!("No matching protocol definition found.");
       None
@@ -594,7 +833,7 @@ mod test {
     SerialSpecifier,
   };
   use crate::{
-    core::messages::ButtplugDeviceMessageType,
+    core::messages::{ButtplugDeviceMessageType, SensorType},
     device::configuration_manager::ProtocolDefinition,
     util::device_configuration::create_test_dcm,
   };
@@ -649,6 +888,28 @@ mod test {
     );
   }
 
+  #[test]
+  fn test_sensor_attributes_from_battery_level() {
+    let config = create_test_dcm(false);
+    let lovense =
+      DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("LVS-Whatever", &[]));
+    let proto = config
+      .find_protocol_definitions(&lovense)
+      .expect("Test, assuming infallible");
+    let proto_config =
+      DeviceProtocolConfiguration::new(false, proto.2.defaults.clone(), proto.2.configurations);
+    let (_, message_map) = proto_config
+      .get_attributes("P", &[])
+      .expect("Test, assuming infallible");
+    let sensor_attrs = message_map
+      .get(&ButtplugDeviceMessageType::SensorReadCmd)
+      .expect("Test, assuming infallible");
+    assert_eq!(sensor_attrs.feature_count, Some(1));
+    assert_eq!(sensor_attrs.sensor_type, Some(vec![SensorType::Battery]));
+    assert_eq!(sensor_attrs.sensor_range, Some(vec![vec![(0, 100)]]));
+    assert!(!message_map.contains_key(&ButtplugDeviceMessageType::SensorSubscribeCmd));
+  }
+
   #[test]
   fn test_raw_device_config_creation() {
     let config = create_test_dcm(true);
@@ -1,5 +1,7 @@
 pub mod configuration_manager;
+pub mod output_limits;
 pub mod protocol;
+pub mod recording;
 use serde::{
   de::{self, Visitor},
   Deserialize,
@@ -11,7 +13,8 @@ use std::{
   fmt::{self, Debug},
   str::FromStr,
   string::ToString,
-  sync::Arc,
+  sync::{Arc, RwLock},
+  time::Duration,
 };
 
 use crate::{
@@ -20,6 +23,7 @@ use crate::{
     messages::{
       self,
       ButtplugDeviceCommandMessageUnion,
+      ButtplugMessage,
       ButtplugServerMessage,
       DeviceMessageAttributesMap,
       RawReadCmd,
@@ -27,18 +31,22 @@ use crate::{
       RawSubscribeCmd,
       RawUnsubscribeCmd,
       RawWriteCmd,
+      SensorReading,
+      SensorType,
     },
     ButtplugResultFuture,
   },
   device::{
     configuration_manager::{DeviceConfigurationManager, DeviceSpecifier, ProtocolDefinition},
+    output_limits::DeviceOutputLimits,
     protocol::ButtplugProtocol,
   },
 };
 use async_trait::async_trait;
 use configuration_manager::DeviceProtocolConfiguration;
 use core::hash::{Hash, Hasher};
-use futures::future::BoxFuture;
+use dashmap::{DashMap, DashSet};
+use futures::future::{self, BoxFuture, FutureExt};
 use tokio::sync::broadcast;
 
 // We need this array to be exposed in our WASM FFI, but the only way to do that
@@ -313,6 +321,8 @@ pub struct DeviceImpl {
   address: String,
   endpoints: Vec<Endpoint>,
   internal_impl: Box<dyn DeviceImplInternal>,
+  /// Endpoints currently subscribed to, by anyone.
+  subscribed_endpoints: Arc<DashSet<Endpoint>>,
 }
 
 impl DeviceImpl {
@@ -327,6 +337,7 @@ impl DeviceImpl {
       address: address.to_owned(),
       endpoints: endpoints.into(),
       internal_impl,
+      subscribed_endpoints: Arc::new(DashSet::new()),
     }
   }
 
@@ -366,11 +377,29 @@ impl DeviceImpl {
   }
 
   pub fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
-    self.internal_impl.subscribe(msg)
+    let endpoint = msg.endpoint;
+    let subscribed_endpoints = self.subscribed_endpoints.clone();
+    Box::pin(self.internal_impl.subscribe(msg).map(move |result| {
+      if result.is_ok() {
+        subscribed_endpoints.insert(endpoint);
+      }
+      result
+    }))
   }
 
   pub fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
-    self.internal_impl.unsubscribe(msg)
+    let endpoint = msg.endpoint;
+    let subscribed_endpoints = self.subscribed_endpoints.clone();
+    Box::pin(self.internal_impl.unsubscribe(msg).map(move |result| {
+      if result.is_ok() {
+        subscribed_endpoints.remove(&endpoint);
+      }
+      result
+    }))
+  }
+
+  pub fn is_subscribed(&self, endpoint: &Endpoint) -> bool {
+    self.subscribed_endpoints.contains(endpoint)
   }
 }
 
@@ -398,8 +427,28 @@ pub trait ButtplugDeviceImplCreator: Sync + Send + Debug {
 
 pub struct ButtplugDevice {
   protocol: Box<dyn ButtplugProtocol>,
+  /// Name of the protocol in the device configuration, e.g. "lovense".
+  protocol_name: String,
   device: Arc<DeviceImpl>,
-  display_name: Option<String>,
+  /// Endpoints that a client has subscribed to via RawSubscribeCmd. Only
+  /// notifications from these endpoints are forwarded as RawReading events.
+  raw_subscribed_endpoints: Arc<DashSet<Endpoint>>,
+  /// Sensors that a client has subscribed to via SensorSubscribeCmd, keyed by
+  /// sensor index and type, along with the endpoint their notifications arrive
+  /// on. Several sensors can share an endpoint.
+  sensor_subscriptions: Arc<DashMap<(u32, SensorType), Endpoint>>,
+  /// Endpoints that weren't subscribed to until a client raw or sensor
+  /// subscription came along. Anything else was subscribed to by the protocol,
+  /// and has to stay that way when clients unsubscribe.
+  client_subscribed_endpoints: Arc<DashSet<Endpoint>>,
+  /// User set caps on device output, applied to commands before the protocol
+  /// sees them. Can be changed while the device is connected, when the user
+  /// configuration is reloaded.
+  output_limits: RwLock<Option<DeviceOutputLimits>>,
+  /// Last position sent to each linear feature, used for limiting stroke speed.
+  linear_positions: Arc<DashMap<u32, f64>>,
+  /// Minimum time between commands, from the protocol's device configuration.
+  min_command_interval: Option<Duration>,
 }
 
 impl Debug for ButtplugDevice {
@@ -430,8 +479,14 @@ impl ButtplugDevice {
   pub fn new(protocol: Box<dyn ButtplugProtocol>, device: Arc<DeviceImpl>) -> Self {
     Self {
       protocol,
+      protocol_name: String::new(),
       device,
-      display_name: None,
+      raw_subscribed_endpoints: Arc::new(DashSet::new()),
+      sensor_subscriptions: Arc::new(DashMap::new()),
+      client_subscribed_endpoints: Arc::new(DashSet::new()),
+      output_limits: RwLock::new(None),
+      linear_positions: Arc::new(DashMap::new()),
+      min_command_interval: None,
     }
   }
 
@@ -455,14 +510,20 @@ impl ButtplugDevice {
         // configuration for that device, try to initialize the implementation.
         // This usually means trying to connect to whatever the device is,
         // finding endpoints, etc.
-        let device_protocol_config = DeviceProtocolConfiguration::new(
+        let mut device_protocol_config = DeviceProtocolConfiguration::new(
           allow_raw_messages,
           config.defaults().clone(),
           config.configurations().clone(),
         );
+        device_protocol_config.set_template(config.template().clone());
+        #[cfg(feature = "scripted-protocols")]
+        device_protocol_config.set_script(device_config_mgr.get_protocol_script(&config_name));
         // TODO Should we even return a config from the device_config_mgr if the
         // protocol isn't there?
-        if device_config_mgr.has_protocol(&*config_name) {
+        if let Some(protocol_creator_func) = device_config_mgr.get_protocol_creator(&config_name) {
+          let min_command_interval = config
+            .min_command_interval()
+            .map(|interval| Duration::from_millis(interval.into()));
           let device_impl = device_creator.try_create_device_impl(config).await?;
           info!(
             address = tracing::field::display(device_impl.address()),
@@ -477,15 +538,12 @@ impl ButtplugDevice {
           // devices like Lovense, some Kiiroo, etc, this can get fairly
           // complicated.
           let sharable_device_impl = Arc::new(device_impl);
-          let protocol_creator_func = device_config_mgr
-            .get_protocol_creator(&*config_name)
-            .expect("Already checked for protocol existence");
           let protocol_impl =
             protocol_creator_func(sharable_device_impl.clone(), device_protocol_config).await?;
-          Ok(Some(ButtplugDevice::new(
-            protocol_impl,
-            sharable_device_impl,
-          )))
+          let mut device = ButtplugDevice::new(protocol_impl, sharable_device_impl);
+          device.min_command_interval = min_command_interval;
+          device.protocol_name = config_name;
+          Ok(Some(device))
         } else {
           info!("Protocol {} not available", config_name);
           Ok(None)
@@ -494,22 +552,47 @@ impl ButtplugDevice {
       None => Ok(None),
     }
   }
-  // Added device ID append to create unique names for similar devices
-  let deviceIDmod = 1
 
-  pub fn set_display_name(&mut self, name: &str) {
+  pub fn set_output_limits(&self, limits: DeviceOutputLimits) {
+    info!(
+      "Adding output limits {:?} to device {} ({})",
+      limits,
+      self.name(),
+      self.address()
+    );
+    *self
+      .output_limits
+      .write()
+      .expect("Lock should never be poisoned") = Some(limits);
+  }
+
+  pub fn clear_output_limits(&self) {
     info!(
-      "Adding display name {} to device {} ({})",
-      name,
+      "Removing output limits from device {} ({})",
       self.name(),
       self.address()
     );
-    self.display_name = Some(name.to_owned()&deviceIDmod);
-    deviceIDmod += 1;
+    *self
+      .output_limits
+      .write()
+      .expect("Lock should never be poisoned") = None;
   }
 
-  pub fn display_name(&self) -> Option<String> {
-    self.display_name.clone()
+  pub fn output_limits(&self) -> Option<DeviceOutputLimits> {
+    *self
+      .output_limits
+      .read()
+      .expect("Lock should never be poisoned")
+  }
+
+  /// Name of the protocol in the device configuration, e.g. "lovense".
+  pub fn protocol_name(&self) -> &str {
+    &self.protocol_name
+  }
+
+  /// Minimum time the server should leave between commands to this device.
+  pub fn min_command_interval(&self) -> Option<Duration> {
+    self.min_command_interval
   }
 
   pub fn name(&self) -> String {
@@ -537,19 +620,189 @@ impl ButtplugDevice {
   }
 
   pub fn message_attributes(&self) -> DeviceMessageAttributesMap {
-    self.protocol.message_attributes()
+    let mut attributes = self.protocol.message_attributes();
+    if let Some(limits) = self.output_limits() {
+      limits.apply_to_attributes(&mut attributes);
+    }
+    attributes
   }
 
   pub fn parse_message(
     &self,
     message: ButtplugDeviceCommandMessageUnion,
   ) -> ButtplugDeviceResultFuture {
-    self.protocol.handle_command(self.device.clone(), message)
+    let limits = if let Some(limits) = self.output_limits() {
+      limits
+    } else {
+      return self.handle_message(message);
+    };
+    let limited = match limits.apply(message, &self.linear_positions) {
+      Ok(limited) => limited,
+      Err(err) => return Box::pin(future::ready(Err(err.into()))),
+    };
+    let fut = self.handle_message(limited.message);
+    if limited.linear_positions.is_empty() {
+      return fut;
+    }
+    // Only remember where linear features were moved to once the move has
+    // actually been sent.
+    let linear_positions = self.linear_positions.clone();
+    Box::pin(async move {
+      let result = fut.await;
+      if result.is_ok() {
+        for (index, position) in limited.linear_positions {
+          linear_positions.insert(index, position);
+        }
+      }
+      result
+    })
+  }
+
+  fn handle_message(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceResultFuture {
+    // Keep track of raw and sensor subscriptions ourselves, so we know which
+    // notifications to forward to the client, and what to clean up when the
+    // client goes away.
+    let raw_update = match &message {
+      ButtplugDeviceCommandMessageUnion::RawSubscribeCmd(msg) => Some((msg.endpoint(), true)),
+      ButtplugDeviceCommandMessageUnion::RawUnsubscribeCmd(msg) => Some((msg.endpoint(), false)),
+      _ => None,
+    };
+    let sensor_update = match &message {
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => self
+        .protocol
+        .sensor_endpoint(msg.sensor_type())
+        .map(|endpoint| (endpoint, (msg.sensor_index(), msg.sensor_type()), true)),
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => self
+        .protocol
+        .sensor_endpoint(msg.sensor_type())
+        .map(|endpoint| (endpoint, (msg.sensor_index(), msg.sensor_type()), false)),
+      _ => None,
+    };
+    let (endpoint, subscribe) = match (raw_update, sensor_update) {
+      (Some(update), _) => update,
+      (None, Some((endpoint, _, subscribe))) => (endpoint, subscribe),
+      (None, None) => return self.protocol.handle_command(self.device.clone(), message),
+    };
+    let sensor = sensor_update.map(|(_, sensor, _)| sensor);
+    // Don't unsubscribe from endpoints that something else still needs, just
+    // stop forwarding their notifications.
+    let subscription_exists = match sensor {
+      Some(sensor) => self.sensor_subscriptions.contains_key(&sensor),
+      None => self.raw_subscribed_endpoints.contains(&endpoint),
+    };
+    if !subscribe && subscription_exists && self.endpoint_shared(&endpoint, sensor) {
+      if let Some(sensor) = sensor {
+        self.sensor_subscriptions.remove(&sensor);
+      } else {
+        self.raw_subscribed_endpoints.remove(&endpoint);
+      }
+      return Box::pin(future::ready(Ok(messages::Ok::new(message.id()).into())));
+    }
+    let newly_subscribed = subscribe && !self.device.is_subscribed(&endpoint);
+    let fut = self.protocol.handle_command(self.device.clone(), message);
+    let raw_subscribed_endpoints = self.raw_subscribed_endpoints.clone();
+    let sensor_subscriptions = self.sensor_subscriptions.clone();
+    let client_subscribed_endpoints = self.client_subscribed_endpoints.clone();
+    Box::pin(async move {
+      let result = fut.await;
+      if result.is_ok() {
+        match (sensor, subscribe) {
+          (Some(sensor), true) => {
+            sensor_subscriptions.insert(sensor, endpoint);
+          }
+          (Some(sensor), false) => {
+            sensor_subscriptions.remove(&sensor);
+          }
+          (None, true) => {
+            raw_subscribed_endpoints.insert(endpoint);
+          }
+          (None, false) => {
+            raw_subscribed_endpoints.remove(&endpoint);
+          }
+        }
+        if newly_subscribed {
+          client_subscribed_endpoints.insert(endpoint);
+        } else if !subscribe {
+          client_subscribed_endpoints.remove(&endpoint);
+        }
+      }
+      result
+    })
+  }
+
+  /// True if something other than the subscription being removed uses the
+  /// endpoint, either the protocol itself or other raw or sensor
+  /// subscriptions. `sensor` is the sensor subscription being removed, or None
+  /// for a raw subscription.
+  fn endpoint_shared(&self, endpoint: &Endpoint, sensor: Option<(u32, SensorType)>) -> bool {
+    !self.client_subscribed_endpoints.contains(endpoint)
+      || (sensor.is_some() && self.raw_subscribed_endpoints.contains(endpoint))
+      || self.sensor_subscriptions.iter().any(|subscription| {
+        subscription.value() == endpoint && Some(*subscription.key()) != sensor
+      })
+  }
+
+  /// Returns true if a client has an active RawSubscribeCmd on the endpoint.
+  pub fn is_raw_subscribed(&self, endpoint: &Endpoint) -> bool {
+    self.raw_subscribed_endpoints.contains(endpoint)
+  }
+
+  /// Turns a notification from an endpoint into SensorReadings, one for each
+  /// sensor on that endpoint that a client has an active SensorSubscribeCmd
+  /// for, in sensor index order.
+  pub fn sensor_readings(
+    &self,
+    device_index: u32,
+    endpoint: &Endpoint,
+    data: &[u8],
+  ) -> Vec<SensorReading> {
+    let parser = self.protocol.sensor_data_parser();
+    let mut sensors: Vec<(u32, SensorType)> = self
+      .sensor_subscriptions
+      .iter()
+      .filter(|subscription| subscription.value() == endpoint)
+      .map(|subscription| *subscription.key())
+      .collect();
+    sensors.sort_by_key(|(sensor_index, _)| *sensor_index);
+    sensors
+      .into_iter()
+      .map(|(sensor_index, sensor_type)| {
+        SensorReading::new(
+          device_index,
+          sensor_index,
+          sensor_type,
+          parser(sensor_type, data),
+        )
+      })
+      .collect()
+  }
+
+  /// Drops all RawSubscribeCmd and SensorSubscribeCmd subscriptions, and
+  /// unsubscribes from the endpoints they subscribed to. Endpoints the protocol
+  /// subscribed to itself are left alone. Used when the client that made the
+  /// subscriptions goes away.
+  pub fn unsubscribe_all(&self) -> ButtplugResultFuture {
+    let endpoints: Vec<Endpoint> = self
+      .client_subscribed_endpoints
+      .iter()
+      .map(|endpoint| *endpoint)
+      .collect();
+    self.raw_subscribed_endpoints.clear();
+    self.sensor_subscriptions.clear();
+    self.client_subscribed_endpoints.clear();
+    let unsubscribe_futures: Vec<ButtplugResultFuture> = endpoints
+      .into_iter()
+      .map(|endpoint| self.device.unsubscribe(DeviceUnsubscribeCmd::new(endpoint)))
+      .collect();
+    Box::pin(
+      future::join_all(unsubscribe_futures).map(|results| results.into_iter().collect()),
+    )
   }
 
   pub fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
     self.device.event_stream()
   }
-
-  // TODO Handle raw messages here.
 }
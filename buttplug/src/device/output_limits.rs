@@ -0,0 +1,554 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! User set caps on how hard a device can be driven.
+//!
+//! Limits work by scaling rather than clamping, so clients can still use the
+//! full 0.0-1.0 range for commands, and that range is mapped into the range
+//! the user has allowed. For instance, with a `vibrate-max` of 0.6, a
+//! VibrateCmd speed of 1.0 will be sent to the device as 0.6, and 0.5 as 0.3.
+//!
+//! Commands that can't be scaled are rejected instead of being let through.
+//! RawWriteCmd could do anything, so it is rejected whenever limits are set.
+//! KiirooCmd positions are too coarse to scale, so it is rejected whenever
+//! linear limits are set.
+
+use super::protocol::fleshlight_launch_helper::{get_duration, get_speed};
+use crate::core::{
+  errors::ButtplugDeviceError,
+  messages::{
+    ActuatorType,
+    ButtplugDeviceCommandMessageUnion,
+    ButtplugDeviceMessage,
+    ButtplugDeviceMessageType,
+    ButtplugMessage,
+    DeviceMessageAttributesMap,
+    FleshlightLaunchFW12Cmd,
+    LinearCmd,
+    RotateCmd,
+    RotationSubcommand,
+    ScalarCmd,
+    ScalarSubcommand,
+    SingleMotorVibrateCmd,
+    VectorSubcommand,
+    VibrateCmd,
+    VibrateSubcommand,
+    VorzeA10CycloneCmd,
+  },
+};
+use dashmap::DashMap;
+use getset::{CopyGetters, Setters};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, CopyGetters, Setters, Default, Clone, Copy, PartialEq)]
+#[getset(get_copy = "pub", set = "pub")]
+pub struct DeviceOutputLimits {
+  /// Maximum vibration speed, from 0.0 to 1.0.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "vibrate-max")]
+  vibrate_max: Option<f64>,
+  /// Maximum rotation speed, from 0.0 to 1.0.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "rotate-max")]
+  rotate_max: Option<f64>,
+  /// Minimum and maximum linear position, from 0.0 to 1.0.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "linear-range")]
+  linear_range: Option<(f64, f64)>,
+  /// Maximum linear movement speed, in full strokes per second (i.e. 2.0 means
+  /// moving from position 0.0 to 1.0 must take at least 500ms). Applied after
+  /// `linear-range`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "linear-max-speed")]
+  linear_max_speed: Option<f64>,
+}
+
+/// A device command with limits applied, along with the linear positions it
+/// moves features to.
+///
+/// Positions should only be recorded once the command has actually been sent,
+/// otherwise a failed command would throw off speed limiting for the next one.
+pub(crate) struct LimitedCommand {
+  pub message: ButtplugDeviceCommandMessageUnion,
+  pub linear_positions: Vec<(u32, f64)>,
+}
+
+impl From<ButtplugDeviceCommandMessageUnion> for LimitedCommand {
+  fn from(message: ButtplugDeviceCommandMessageUnion) -> Self {
+    Self {
+      message,
+      linear_positions: vec![],
+    }
+  }
+}
+
+fn scale_to_range(value: f64, (min, max): (f64, f64)) -> f64 {
+  min + value.clamp(0f64, 1f64) * (max - min)
+}
+
+impl DeviceOutputLimits {
+  /// Checks that the limits can be applied. The schema already keeps values
+  /// between 0.0 and 1.0, but can't check that `linear-range` is in order.
+  pub fn validate(&self) -> Result<(), ButtplugDeviceError> {
+    if let Some((min, max)) = self.linear_range {
+      if min > max {
+        return Err(ButtplugDeviceError::DeviceConfigurationFileError(format!(
+          "linear-range minimum {} is greater than its maximum {}.",
+          min, max
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  fn vibrate_range(&self) -> Option<(f64, f64)> {
+    self.vibrate_max.map(|max| (0f64, max.clamp(0f64, 1f64)))
+  }
+
+  fn rotate_range(&self) -> Option<(f64, f64)> {
+    self.rotate_max.map(|max| (0f64, max.clamp(0f64, 1f64)))
+  }
+
+  fn position_range(&self) -> Option<(f64, f64)> {
+    self
+      .linear_range
+      .map(|(min, max)| (min.clamp(0f64, 1f64), max.clamp(0f64, 1f64)))
+  }
+
+  fn has_linear_limits(&self) -> bool {
+    self.linear_range.is_some() || self.linear_max_speed.is_some()
+  }
+
+  fn scalar_range(&self, actuator_type: ActuatorType) -> Option<(f64, f64)> {
+    match actuator_type {
+      ActuatorType::Vibrate => self.vibrate_range(),
+      ActuatorType::Rotate => self.rotate_range(),
+      ActuatorType::Position => self.position_range(),
+      _ => None,
+    }
+  }
+
+  /// Shortest duration a linear move between two positions may take, in
+  /// milliseconds.
+  ///
+  /// If we don't know where the feature currently is, we assume the move
+  /// covers the whole allowed range, to be safe.
+  fn min_linear_duration(&self, previous: Option<f64>, position: f64) -> u32 {
+    match self.linear_max_speed {
+      Some(max_speed) if max_speed > 0f64 => {
+        let distance = match previous {
+          Some(previous) => (position - previous).abs(),
+          None => {
+            let (min, max) = self.position_range().unwrap_or((0f64, 1f64));
+            max - min
+          }
+        };
+        (distance / max_speed * 1000f64).round() as u32
+      }
+      _ => 0,
+    }
+  }
+
+  /// Scales the values in a device command to fit within the limits, or
+  /// rejects the command if it can't be limited.
+  ///
+  /// `linear_positions` holds the last position sent to each linear feature,
+  /// for enforcing `linear-max-speed`. It isn't updated here, the new positions
+  /// are returned in the [LimitedCommand] instead.
+  pub(crate) fn apply(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+    linear_positions: &DashMap<u32, f64>,
+  ) -> Result<LimitedCommand, ButtplugDeviceError> {
+    let previous_position = |index: u32| {
+      linear_positions
+        .get(&index)
+        .map(|position| *position.value())
+    };
+    let message: ButtplugDeviceCommandMessageUnion = match message {
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => match self.vibrate_range() {
+        Some(range) => {
+          let mut limited = VibrateCmd::new(
+            msg.device_index(),
+            msg
+              .speeds()
+              .iter()
+              .map(|cmd| VibrateSubcommand::new(cmd.index(), scale_to_range(cmd.speed(), range)))
+              .collect(),
+          );
+          limited.set_id(msg.id());
+          limited.into()
+        }
+        None => msg.into(),
+      },
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => match self.vibrate_range() {
+        Some(range) => {
+          let mut limited =
+            SingleMotorVibrateCmd::new(msg.device_index(), scale_to_range(msg.speed(), range));
+          limited.set_id(msg.id());
+          limited.into()
+        }
+        None => msg.into(),
+      },
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => match self.rotate_range() {
+        Some(range) => {
+          let mut limited = RotateCmd::new(
+            msg.device_index(),
+            msg
+              .rotations
+              .iter()
+              .map(|cmd| {
+                RotationSubcommand::new(
+                  cmd.index(),
+                  scale_to_range(cmd.speed(), range),
+                  cmd.clockwise(),
+                )
+              })
+              .collect(),
+          );
+          limited.set_id(msg.id());
+          limited.into()
+        }
+        None => msg.into(),
+      },
+      ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(msg) => match self.rotate_range() {
+        Some(range) => {
+          let speed = scale_to_range(msg.speed() as f64 / 99f64, range) * 99f64;
+          let mut limited =
+            VorzeA10CycloneCmd::new(msg.device_index(), speed as u32, msg.clockwise());
+          limited.set_id(msg.id());
+          limited.into()
+        }
+        None => msg.into(),
+      },
+      ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) => {
+        let mut limited = ScalarCmd::new(
+          msg.device_index(),
+          msg
+            .scalars()
+            .iter()
+            .map(|cmd| match self.scalar_range(cmd.actuator_type()) {
+              Some(range) => ScalarSubcommand::new(
+                cmd.index(),
+                scale_to_range(cmd.scalar(), range),
+                cmd.actuator_type(),
+              ),
+              None => cmd.clone(),
+            })
+            .collect(),
+        );
+        limited.set_id(msg.id());
+        limited.into()
+      }
+      ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => {
+        let range = self.position_range().unwrap_or((0f64, 1f64));
+        let vectors: Vec<VectorSubcommand> = msg
+          .vectors()
+          .iter()
+          .map(|cmd| {
+            let position = scale_to_range(*cmd.position(), range);
+            let duration = cmd
+              .duration()
+              .max(self.min_linear_duration(previous_position(cmd.index()), position));
+            VectorSubcommand::new(cmd.index(), duration, position)
+          })
+          .collect();
+        let positions = vectors
+          .iter()
+          .map(|cmd| (cmd.index(), *cmd.position()))
+          .collect();
+        let mut limited = LinearCmd::new(msg.device_index(), vectors);
+        limited.set_id(msg.id());
+        return Ok(LimitedCommand {
+          message: limited.into(),
+          linear_positions: positions,
+        });
+      }
+      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(msg) => {
+        let range = self.position_range().unwrap_or((0f64, 1f64));
+        let position = scale_to_range(msg.position() as f64 / 99f64, range);
+        let previous = previous_position(0);
+        let mut speed = msg.speed() as f64 / 99f64;
+        let min_duration = self.min_linear_duration(previous, position);
+        if let Some(previous) = previous {
+          // FW12 commands are in terms of speed, so go through duration to
+          // limit them the same way as LinearCmd.
+          let distance = (position - previous).abs();
+          if get_duration(distance, speed) < min_duration {
+            speed = get_speed(distance, min_duration);
+          }
+        } else if min_duration > 0 {
+          // Without knowing the distance we can't work out a speed, so move
+          // slowly.
+          speed = 0f64;
+        }
+        let mut limited = FleshlightLaunchFW12Cmd::new(
+          msg.device_index(),
+          (position * 99f64) as u8,
+          (speed.clamp(0f64, 1f64) * 99f64) as u8,
+        );
+        limited.set_id(msg.id());
+        return Ok(LimitedCommand {
+          message: limited.into(),
+          linear_positions: vec![(0, position)],
+        });
+      }
+      ButtplugDeviceCommandMessageUnion::RawWriteCmd(_) => {
+        return Err(ButtplugDeviceError::DevicePermissionError(
+          "Raw writes are not allowed to devices with output limits.".to_owned(),
+        ));
+      }
+      ButtplugDeviceCommandMessageUnion::KiirooCmd(_) if self.has_linear_limits() => {
+        return Err(ButtplugDeviceError::DevicePermissionError(
+          "KiirooCmd is not allowed to devices with linear output limits.".to_owned(),
+        ));
+      }
+      msg => msg,
+    };
+    Ok(message.into())
+  }
+
+  /// Adds the OutputRange attribute to the messages that the limits affect.
+  pub(crate) fn apply_to_attributes(&self, attributes: &mut DeviceMessageAttributesMap) {
+    let ranges = [
+      (ButtplugDeviceMessageType::VibrateCmd, self.vibrate_range()),
+      (ButtplugDeviceMessageType::RotateCmd, self.rotate_range()),
+      (ButtplugDeviceMessageType::LinearCmd, self.position_range()),
+    ];
+    for (message_type, range) in ranges {
+      if let (Some(range), Some(attrs)) = (range, attributes.get_mut(&message_type)) {
+        attrs.output_range = Some(vec![range; attrs.feature_count.unwrap_or(1) as usize]);
+      }
+    }
+    if let Some(attrs) = attributes.get_mut(&ButtplugDeviceMessageType::ScalarCmd) {
+      if let Some(actuator_types) = &attrs.actuator_type {
+        let ranges: Vec<Option<(f64, f64)>> = actuator_types
+          .iter()
+          .map(|actuator_type| self.scalar_range(*actuator_type))
+          .collect();
+        if ranges.iter().any(|range| range.is_some()) {
+          attrs.output_range = Some(
+            ranges
+              .into_iter()
+              .map(|range| range.unwrap_or((0f64, 1f64)))
+              .collect(),
+          );
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    core::messages::{DeviceMessageAttributes, KiirooCmd, RawWriteCmd},
+    device::Endpoint,
+  };
+
+  /// Applies limits, recording positions as if the command was sent.
+  fn apply(
+    limits: DeviceOutputLimits,
+    message: ButtplugDeviceCommandMessageUnion,
+    positions: &DashMap<u32, f64>,
+  ) -> ButtplugDeviceCommandMessageUnion {
+    let limited = limits
+      .apply(message, positions)
+      .expect("Test, assuming infallible.");
+    for (index, position) in limited.linear_positions {
+      positions.insert(index, position);
+    }
+    limited.message
+  }
+
+  fn limits() -> DeviceOutputLimits {
+    let mut limits = DeviceOutputLimits::default();
+    limits
+      .set_vibrate_max(Some(0.6))
+      .set_linear_range(Some((0.1, 0.9)))
+      .set_linear_max_speed(Some(2.0));
+    limits
+  }
+
+  #[test]
+  fn test_output_limits_scale_commands() {
+    let positions = DashMap::new();
+    let limited = apply(
+      limits(),
+      VibrateCmd::new(
+        0,
+        vec![
+          VibrateSubcommand::new(0, 1.0),
+          VibrateSubcommand::new(1, 0.5),
+        ],
+      )
+      .into(),
+      &positions,
+    );
+    assert_eq!(
+      limited,
+      VibrateCmd::new(
+        0,
+        vec![
+          VibrateSubcommand::new(0, 0.6),
+          VibrateSubcommand::new(1, 0.3),
+        ]
+      )
+      .into()
+    );
+
+    // No rotate limit set, so rotation passes through untouched.
+    let rotate: ButtplugDeviceCommandMessageUnion =
+      RotateCmd::new(0, vec![RotationSubcommand::new(0, 1.0, true)]).into();
+    assert_eq!(apply(limits(), rotate.clone(), &positions), rotate);
+
+    // First move has no known starting point, so assumes it's crossing the
+    // whole allowed range.
+    let limited = apply(
+      limits(),
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 1.0)]).into(),
+      &positions,
+    );
+    assert_eq!(
+      limited,
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 400, 0.9)]).into()
+    );
+    let limited = apply(
+      limits(),
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 0.5)]).into(),
+      &positions,
+    );
+    assert_eq!(
+      limited,
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 200, 0.5)]).into()
+    );
+    let limited = apply(
+      limits(),
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.0)]).into(),
+      &positions,
+    );
+    assert_eq!(
+      limited,
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.1)]).into()
+    );
+  }
+
+  #[test]
+  fn test_output_limits_clamp_out_of_range_values() {
+    let positions = DashMap::new();
+    let limited = apply(
+      limits(),
+      VibrateCmd::new(
+        0,
+        vec![
+          VibrateSubcommand::new(0, 1.5),
+          VibrateSubcommand::new(1, -0.5),
+        ],
+      )
+      .into(),
+      &positions,
+    );
+    assert_eq!(
+      limited,
+      VibrateCmd::new(
+        0,
+        vec![
+          VibrateSubcommand::new(0, 0.6),
+          VibrateSubcommand::new(1, 0.0),
+        ]
+      )
+      .into()
+    );
+    let limited = apply(
+      limits(),
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 1000, 2.0)]).into(),
+      &positions,
+    );
+    assert_eq!(
+      limited,
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 1000, 0.9)]).into()
+    );
+  }
+
+  #[test]
+  fn test_output_limits_validation() {
+    assert!(limits().validate().is_ok());
+    let mut reversed = DeviceOutputLimits::default();
+    reversed.set_linear_range(Some((0.8, 0.2)));
+    assert!(reversed.validate().is_err());
+  }
+
+  #[test]
+  fn test_output_limits_reject_unlimitable_commands() {
+    let positions = DashMap::new();
+    // Positions are left for the caller to record after sending.
+    let limited = limits()
+      .apply(
+        LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 1.0)]).into(),
+        &positions,
+      )
+      .expect("Test, assuming infallible.");
+    assert_eq!(limited.linear_positions, vec![(0, 0.9)]);
+    assert!(positions.is_empty());
+
+    let raw_write: ButtplugDeviceCommandMessageUnion =
+      RawWriteCmd::new(0, Endpoint::Tx, vec![0], false).into();
+    assert!(limits().apply(raw_write.clone(), &positions).is_err());
+    let kiiroo: ButtplugDeviceCommandMessageUnion = KiirooCmd::new(0, "4").into();
+    assert!(limits().apply(kiiroo.clone(), &positions).is_err());
+    // KiirooCmd is only blocked by linear limits.
+    let mut vibrate_limits = DeviceOutputLimits::default();
+    vibrate_limits.set_vibrate_max(Some(0.5));
+    assert!(vibrate_limits.apply(kiiroo, &positions).is_ok());
+    assert!(vibrate_limits.apply(raw_write, &positions).is_err());
+  }
+
+  #[test]
+  fn test_output_limits_attributes() {
+    let mut attributes = DeviceMessageAttributesMap::new();
+    attributes.insert(
+      ButtplugDeviceMessageType::VibrateCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(2),
+        ..Default::default()
+      },
+    );
+    attributes.insert(
+      ButtplugDeviceMessageType::RotateCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(1),
+        ..Default::default()
+      },
+    );
+    attributes.insert(
+      ButtplugDeviceMessageType::ScalarCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(2),
+        actuator_type: Some(vec![ActuatorType::Vibrate, ActuatorType::Inflate]),
+        ..Default::default()
+      },
+    );
+    limits().apply_to_attributes(&mut attributes);
+    assert_eq!(
+      attributes[&ButtplugDeviceMessageType::VibrateCmd].output_range,
+      Some(vec![(0.0, 0.6), (0.0, 0.6)])
+    );
+    assert_eq!(
+      attributes[&ButtplugDeviceMessageType::RotateCmd].output_range,
+      None
+    );
+    assert_eq!(
+      attributes[&ButtplugDeviceMessageType::ScalarCmd].output_range,
+      Some(vec![(0.0, 0.6), (0.0, 1.0)])
+    );
+  }
+}
@@ -1,27 +1,40 @@
 use crate::core::{
   errors::{ButtplugDeviceError, ButtplugError},
   messages::{
+    ActuatorType,
     ButtplugDeviceCommandMessageUnion,
     ButtplugDeviceMessageType,
     DeviceMessageAttributesMap,
     LinearCmd,
     RotateCmd,
     RotationSubcommand,
+    ScalarCmd,
+    ScalarSubcommand,
     VibrateCmd,
     VibrateSubcommand,
   },
 };
 
+/// Actuator type and value of every ScalarCmd feature, or None for features
+/// that don't need to be sent.
+pub type ScalarCommands = Vec<Option<(ActuatorType, u32)>>;
+
+/// (position, duration) pair for every linear actuator, or None for actuators
+/// that don't need to be sent.
+pub type LinearCommands = Vec<Option<(u32, u32)>>;
+
 pub struct GenericCommandManager {
   sent_vibration: bool,
   sent_rotation: bool,
-  _sent_linear: bool,
+  sent_scalar: bool,
   vibrations: Vec<u32>,
   vibration_step_counts: Vec<u32>,
   rotations: Vec<(u32, bool)>,
   rotation_step_counts: Vec<u32>,
-  _linears: Vec<(u32, u32)>,
-  _linear_step_counts: Vec<u32>,
+  scalars: Vec<(ActuatorType, u32)>,
+  scalar_step_counts: Vec<u32>,
+  linears: Vec<Option<(u32, u32)>>,
+  linear_step_counts: Vec<u32>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
 }
 
@@ -31,7 +44,9 @@ impl GenericCommandManager {
     let mut vibration_step_counts: Vec<u32> = vec![];
     let mut rotations: Vec<(u32, bool)> = vec![];
     let mut rotation_step_counts: Vec<u32> = vec![];
-    let mut linears: Vec<(u32, u32)> = vec![];
+    let mut scalars: Vec<(ActuatorType, u32)> = vec![];
+    let mut scalar_step_counts: Vec<u32> = vec![];
+    let mut linears: Vec<Option<(u32, u32)>> = vec![];
     let mut linear_step_counts: Vec<u32> = vec![];
 
     let mut stop_commands = vec![];
@@ -69,9 +84,33 @@ impl GenericCommandManager {
       }
       stop_commands.push(RotateCmd::new(0, subcommands).into());
     }
+    if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::ScalarCmd) {
+      if let Some(actuator_types) = &attr.actuator_type {
+        scalars = actuator_types.iter().map(|x| (*x, 0)).collect();
+      }
+      if let Some(step_counts) = &attr.step_count {
+        scalar_step_counts = step_counts.clone();
+      }
+
+      // Vibrators and rotators are already stopped via VibrateCmd and
+      // RotateCmd above, so only stop the other actuator types here.
+      let subcommands: Vec<ScalarSubcommand> = scalars
+        .iter()
+        .enumerate()
+        .filter(|(_, (actuator, _))| {
+          *actuator != ActuatorType::Vibrate && *actuator != ActuatorType::Rotate
+        })
+        .map(|(index, (actuator, _))| ScalarSubcommand::new(index as u32, 0.0, *actuator))
+        .collect();
+      if !subcommands.is_empty() {
+        stop_commands.push(ScalarCmd::new(0, subcommands).into());
+      }
+    }
     if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::LinearCmd) {
       if let Some(count) = attr.feature_count {
-        linears = vec![(0, 0); count as usize];
+        // We don't know where linear actuators are until we've moved them, so
+        // start out with no position.
+        linears = vec![None; count as usize];
       }
       if let Some(step_counts) = &attr.step_count {
         linear_step_counts = step_counts.clone();
@@ -81,13 +120,15 @@ impl GenericCommandManager {
     Self {
       sent_vibration: false,
       sent_rotation: false,
-      _sent_linear: false,
+      sent_scalar: false,
       vibrations,
       rotations,
-      _linears: linears,
+      scalars,
+      scalar_step_counts,
+      linears,
       vibration_step_counts,
       rotation_step_counts,
-      _linear_step_counts: linear_step_counts,
+      linear_step_counts,
       stop_commands,
     }
   }
@@ -236,26 +277,161 @@ impl GenericCommandManager {
     Ok(result)
   }
 
-  pub fn _update_linear(
+  pub fn update_scalar(
     &mut self,
-    _msg: &LinearCmd,
-  ) -> Result<Option<Vec<(u32, u32)>>, ButtplugError> {
-    // First, make sure this is a valid command, that doesn't contain an
-    // index we can't reach.
+    msg: &ScalarCmd,
+  ) -> Result<Option<ScalarCommands>, ButtplugError> {
+    // First, make sure this is a valid command, that contains at least one
+    // subcommand.
+    if msg.scalars().is_empty() {
+      return Err(
+        ButtplugDeviceError::ProtocolRequirementError(
+          "ScalarCmd has 0 commands, will not do anything.".to_owned(),
+        )
+        .into(),
+      );
+    }
 
-    // If we've already sent commands before, we should check against our
-    // old values. Otherwise, we should always send whatever command we're
-    // going to send.
+    let mut changed_value = false;
+    let mut result: ScalarCommands = vec![None; self.scalars.len()];
+    for scalar_command in msg.scalars() {
+      let index = scalar_command.index() as usize;
+      if index >= self.scalars.len() {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "ScalarCmd has {} commands, device has {} features.",
+            msg.scalars().len(),
+            self.scalars.len()
+          ))
+          .into(),
+        );
+      }
+      let (actuator_type, current_value) = self.scalars[index];
+      if actuator_type != scalar_command.actuator_type() {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "ScalarCmd index {} is actuator type {}, but command was for {}.",
+            index,
+            actuator_type,
+            scalar_command.actuator_type()
+          ))
+          .into(),
+        );
+      }
 
-    // Now we convert from the generic 0.0-1.0 range to the StepCount
-    // attribute given by the device config.
+      // Same rounding as vibration, see update_vibration.
+      let value = (scalar_command.scalar() * self.scalar_step_counts[index] as f64).ceil() as u32;
+
+      // Skip values we've already sent, see update_vibration.
+      if !self.sent_scalar || value != current_value {
+        changed_value = true;
+        self.scalars[index] = (actuator_type, value);
+        result[index] = Some((actuator_type, value));
+      }
+    }
 
-    // If we've already sent commands, we don't want to send them again,
-    // because some of our communication busses are REALLY slow. Make sure
-    // these values get None in our return vector.
+    self.sent_scalar = true;
 
     // Return the command vector for the protocol to turn into proprietary commands
-    Ok(None)
+    if !changed_value {
+      Ok(None)
+    } else {
+      Ok(Some(result))
+    }
+  }
+
+  pub fn get_scalar(&self) -> Vec<(ActuatorType, u32)> {
+    self.scalars.clone()
+  }
+
+  pub fn update_linear(
+    &mut self,
+    msg: &LinearCmd,
+  ) -> Result<Option<LinearCommands>, ButtplugError> {
+    // First, make sure this is a valid command, that contains at least one
+    // subcommand.
+    if msg.vectors().is_empty() {
+      return Err(
+        ButtplugDeviceError::ProtocolRequirementError(
+          "LinearCmd has 0 commands, will not do anything.".to_owned(),
+        )
+        .into(),
+      );
+    }
+
+    let mut changed_value = false;
+    let mut result: LinearCommands = vec![None; self.linears.len()];
+    for vector in msg.vectors() {
+      let index = vector.index() as usize;
+      if index >= self.linears.len() {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "LinearCmd has {} commands, device has {} linear actuators.",
+            msg.vectors().len(),
+            self.linears.len()
+          ))
+          .into(),
+        );
+      }
+
+      // Unlike speeds, positions are truncated rather than rounded up, which
+      // is what all of our linear protocols did before moving them here.
+      let position = (*vector.position() * self.linear_step_counts[index] as f64) as u32;
+      let linear = (position, vector.duration());
+
+      // Moving to the same place over the same time is a no-op, so don't
+      // bother the bus with it.
+      if self.linears[index] != Some(linear) {
+        changed_value = true;
+        self.linears[index] = Some(linear);
+        result[index] = Some(linear);
+      }
+    }
+
+    // Return the command vector for the protocol to turn into proprietary commands
+    if !changed_value {
+      Ok(None)
+    } else {
+      Ok(Some(result))
+    }
+  }
+
+  /// Returns the last (position, duration) pair sent to each linear actuator,
+  /// or None for actuators that haven't been moved yet.
+  pub fn get_linear(&self) -> Vec<Option<(u32, u32)>> {
+    self.linears.clone()
+  }
+
+  /// Records a position reached via something other than
+  /// [GenericCommandManager::update_linear], like a raw Fleshlight Launch
+  /// command, so later moves are calculated from the right place.
+  pub fn set_linear_position(&mut self, index: usize, position: u32) {
+    if let Some(linear) = self.linears.get_mut(index) {
+      *linear = Some((position, 0));
+    }
+  }
+
+  /// Builds commands to hold all linear actuators at their current position.
+  ///
+  /// We have no way of knowing where a device is partway through a move, so
+  /// "current" means the last position we sent. Each actuator that has been
+  /// moved is sent to that position with a duration of 0, which finishes any
+  /// move in progress and leaves it there. Actuators that have never been
+  /// moved are left alone, since sending them anywhere would be a move rather
+  /// than a stop.
+  ///
+  /// Returns None if there is nothing to hold.
+  pub fn stop_linear(&mut self) -> Option<LinearCommands> {
+    let result: LinearCommands = self
+      .linears
+      .iter()
+      .map(|linear| linear.map(|(position, _)| (position, 0)))
+      .collect();
+    if result.iter().all(|linear| linear.is_none()) {
+      return None;
+    }
+    self.linears = result.clone();
+    Some(result)
   }
 
   pub fn get_stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
@@ -268,11 +444,16 @@ mod test {
 
   use super::GenericCommandManager;
   use crate::core::messages::{
+    ActuatorType,
     ButtplugDeviceMessageType,
     DeviceMessageAttributes,
     DeviceMessageAttributesMap,
+    LinearCmd,
     RotateCmd,
     RotationSubcommand,
+    ScalarCmd,
+    ScalarSubcommand,
+    VectorSubcommand,
     VibrateCmd,
     VibrateSubcommand,
   };
@@ -372,5 +553,115 @@ mod test {
     assert!(mgr.update_rotation(&rotate_msg_invalid).is_err());
   }
 
+  #[test]
+  pub fn test_command_generator_scalar() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let scalar_attributes = DeviceMessageAttributes {
+      feature_count: Some(2),
+      step_count: Some(vec![20, 10]),
+      actuator_type: Some(vec![ActuatorType::Oscillate, ActuatorType::Inflate]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::ScalarCmd, scalar_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    let scalar_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Oscillate),
+        ScalarSubcommand::new(1, 0.5, ActuatorType::Inflate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&scalar_msg)
+        .expect("Test, assuming infallible"),
+      Some(vec![
+        Some((ActuatorType::Oscillate, 10)),
+        Some((ActuatorType::Inflate, 5))
+      ])
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&scalar_msg)
+        .expect("Test, assuming infallible"),
+      None
+    );
+    let scalar_msg_wrong_type = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+    );
+    assert!(mgr.update_scalar(&scalar_msg_wrong_type).is_err());
+    let scalar_msg_invalid = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Oscillate)],
+    );
+    assert!(mgr.update_scalar(&scalar_msg_invalid).is_err());
+    // Neither actuator is covered by VibrateCmd/RotateCmd, so stopping should
+    // go through ScalarCmd.
+    assert_eq!(
+      mgr.get_stop_commands(),
+      vec![ScalarCmd::new(
+        0,
+        vec![
+          ScalarSubcommand::new(0, 0.0, ActuatorType::Oscillate),
+          ScalarSubcommand::new(1, 0.0, ActuatorType::Inflate),
+        ],
+      )
+      .into()]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_linear() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let linear_attributes = DeviceMessageAttributes {
+      feature_count: Some(2),
+      step_count: Some(vec![99, 99]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::LinearCmd, linear_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    // Nothing has moved yet, so there's nothing to hold.
+    assert_eq!(mgr.stop_linear(), None);
+    let linear_msg = LinearCmd::new(
+      0,
+      vec![
+        VectorSubcommand::new(0, 500, 0.5),
+        VectorSubcommand::new(1, 500, 0.5),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg)
+        .expect("Test, assuming infallible"),
+      Some(vec![Some((49, 500)), Some((49, 500))])
+    );
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg)
+        .expect("Test, assuming infallible"),
+      None
+    );
+    let linear_msg_2 = LinearCmd::new(
+      0,
+      vec![
+        VectorSubcommand::new(0, 500, 0.5),
+        VectorSubcommand::new(1, 250, 0.75),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg_2)
+        .expect("Test, assuming infallible"),
+      Some(vec![None, Some((74, 250))])
+    );
+    let linear_msg_invalid = LinearCmd::new(0, vec![VectorSubcommand::new(2, 500, 0.5)]);
+    assert!(mgr.update_linear(&linear_msg_invalid).is_err());
+    assert_eq!(mgr.get_linear(), vec![Some((49, 500)), Some((74, 250))]);
+    assert_eq!(mgr.stop_linear(), Some(vec![Some((49, 0)), Some((74, 0))]));
+  }
+
   // TODO Write test for vibration stop generator
 }
@@ -5,12 +5,9 @@ use super::{
   ButtplugProtocolCommandHandler,
 };
 use crate::{
-  core::messages::{
-    self,
-    ButtplugDeviceCommandMessageUnion,
-    ButtplugDeviceMessage,
-    DeviceMessageAttributesMap,
-    FleshlightLaunchFW12Cmd,
+  core::{
+    errors::ButtplugError,
+    messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
   },
   device::{
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
@@ -19,10 +16,7 @@ use crate::{
     Endpoint,
   },
 };
-use std::sync::{
-  atomic::{AtomicU8, Ordering::SeqCst},
-  Arc,
-};
+use std::sync::Arc;
 use tokio::sync::Mutex;
 
 #[derive(ButtplugProtocolProperties)]
@@ -31,7 +25,6 @@ pub struct KiirooV21 {
   message_attributes: DeviceMessageAttributesMap,
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  previous_position: Arc<AtomicU8>,
 }
 
 impl KiirooV21 {
@@ -43,13 +36,38 @@ impl KiirooV21 {
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
-      previous_position: Arc::new(AtomicU8::new(0)),
     }
   }
 }
 
 super::default_protocol_trait_declaration!(KiirooV21);
 
+async fn write_linear(
+  device: &DeviceImpl,
+  previous: Vec<Option<(u32, u32)>>,
+  cmds: Vec<Option<(u32, u32)>>,
+) -> Result<(), ButtplugError> {
+  if let Some(Some((position, duration))) = cmds.first() {
+    // In the protocol, we know max speed is 99, so convert here.
+    let previous_position = previous
+      .first()
+      .cloned()
+      .flatten()
+      .map(|(position, _)| position)
+      .unwrap_or(0);
+    let distance = (previous_position as f64 - *position as f64).abs() / 99f64;
+    let speed = (get_speed(distance, *duration) * 99f64) as u8;
+    device
+      .write_value(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x03, 0x00, speed, *position as u8],
+        false,
+      ))
+      .await?;
+  }
+  Ok(())
+}
+
 impl ButtplugProtocolCommandHandler for KiirooV21 {
   fn handle_vibrate_cmd(
     &self,
@@ -78,17 +96,31 @@ impl ButtplugProtocolCommandHandler for KiirooV21 {
     device: Arc<DeviceImpl>,
     message: messages::LinearCmd,
   ) -> ButtplugDeviceResultFuture {
-    let v = message.vectors()[0].clone();
-    // In the protocol, we know max speed is 99, so convert here. We have to
-    // use AtomicU8 because there's no AtomicF64 yet.
-    let previous_position = self.previous_position.load(SeqCst);
-    let distance = (previous_position as f64 - (v.position * 99f64)).abs() / 99f64;
-    let fl_cmd = FleshlightLaunchFW12Cmd::new(
-      message.device_index(),
-      (v.position * 99f64) as u8,
-      (get_speed(distance, v.duration) * 99f64) as u8,
-    );
-    self.handle_fleshlight_launch_fw12_cmd(device, fl_cmd)
+    let manager = self.manager.clone();
+    Box::pin(async move {
+      let (previous, result) = {
+        let mut manager = manager.lock().await;
+        (manager.get_linear(), manager.update_linear(&message)?)
+      };
+      if let Some(cmds) = result {
+        write_linear(&device, previous, cmds).await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_linear_stop(&self, device: Arc<DeviceImpl>) -> Option<ButtplugDeviceResultFuture> {
+    let manager = self.manager.clone();
+    Some(Box::pin(async move {
+      let (previous, result) = {
+        let mut manager = manager.lock().await;
+        (manager.get_linear(), manager.stop_linear())
+      };
+      if let Some(cmds) = result {
+        write_linear(&device, previous, cmds).await?;
+      }
+      Ok(messages::Ok::default().into())
+    }))
   }
 
   fn handle_fleshlight_launch_fw12_cmd(
@@ -96,7 +128,7 @@ impl ButtplugProtocolCommandHandler for KiirooV21 {
     device: Arc<DeviceImpl>,
     message: messages::FleshlightLaunchFW12Cmd,
   ) -> ButtplugDeviceResultFuture {
-    let previous_position = self.previous_position.clone();
+    let manager = self.manager.clone();
     let position = message.position();
     let msg = DeviceWriteCmd::new(
       Endpoint::Tx,
@@ -105,7 +137,9 @@ impl ButtplugProtocolCommandHandler for KiirooV21 {
     );
     let fut = device.write_value(msg);
     Box::pin(async move {
-      previous_position.store(position, SeqCst);
+      // Keep the command manager up to date, so LinearCmd speed calculations
+      // start from the right place.
+      manager.lock().await.set_linear_position(0, position as u32);
       fut.await?;
       Ok(messages::Ok::default().into())
     })
@@ -40,11 +40,14 @@ pub mod prettylove;
 pub mod raw_protocol;
 pub mod realov;
 pub mod satisfyer;
+#[cfg(feature = "scripted-protocols")]
+pub mod scripted;
 pub mod svakom;
 pub mod svakom_alex;
 pub mod svakom_iker;
 pub mod svakom_sam;
 pub mod tcode_v03;
+pub mod template;
 pub mod thehandy;
 pub mod vibratissimo;
 pub mod vorze_sa;
@@ -61,12 +64,20 @@ use crate::{
     errors::{ButtplugDeviceError, ButtplugError},
     messages::{
       self,
+      ActuatorType,
+      BatteryLevelCmd,
       ButtplugDeviceCommandMessageUnion,
       ButtplugDeviceMessage,
       ButtplugDeviceMessageType,
       ButtplugMessage,
+      ButtplugServerMessage,
       DeviceMessageAttributesMap,
+      RSSILevelCmd,
       RawReading,
+      RotateCmd,
+      RotationSubcommand,
+      SensorReading,
+      SensorType,
       VibrateCmd,
       VibrateSubcommand,
     },
@@ -75,6 +86,8 @@ use crate::{
     configuration_manager::DeviceProtocolConfiguration,
     ButtplugDeviceResultFuture,
     DeviceReadCmd,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
     Endpoint,
   },
 };
@@ -89,6 +102,15 @@ pub type TryCreateProtocolFunc =
     DeviceProtocolConfiguration,
   ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>;
 
+/// Converts raw data from a sensor endpoint into sensor values. This is a
+/// function pointer so it can be moved into reading futures.
+pub type SensorDataParserFunc = fn(SensorType, &[u8]) -> Vec<i32>;
+
+/// Default sensor data parser, which treats each byte as a separate value.
+pub fn parse_sensor_data_as_bytes(_sensor_type: SensorType, data: &[u8]) -> Vec<i32> {
+  data.iter().map(|b| *b as i32).collect()
+}
+
 pub fn add_to_protocol_map<T>(map: &DashMap<String, TryCreateProtocolFunc>, protocol_name: &str)
 where
   T: ButtplugProtocol,
@@ -198,6 +220,31 @@ fn check_message_support(
   }
 }
 
+fn check_sensor_support(
+  message_type: &ButtplugDeviceMessageType,
+  sensor_index: u32,
+  sensor_type: SensorType,
+  message_attributes: &DeviceMessageAttributesMap,
+) -> Result<(), ButtplugError> {
+  let sensor_types = message_attributes
+    .get(message_type)
+    .and_then(|attrs| attrs.sensor_type.clone())
+    .unwrap_or_default();
+  match sensor_types.get(sensor_index as usize) {
+    Some(device_sensor_type) if *device_sensor_type == sensor_type => Ok(()),
+    Some(device_sensor_type) => Err(
+      ButtplugDeviceError::ProtocolRequirementError(format!(
+        "Sensor index {} is sensor type {}, but command was for {}.",
+        sensor_index, device_sensor_type, sensor_type
+      ))
+      .into(),
+    ),
+    None => Err(
+      ButtplugDeviceError::DeviceFeatureIndexError(sensor_types.len() as u32, sensor_index).into(),
+    ),
+  }
+}
+
 pub trait ButtplugProtocolProperties {
   fn name(&self) -> &str;
   fn message_attributes(&self) -> DeviceMessageAttributesMap;
@@ -249,6 +296,24 @@ pub trait ButtplugProtocolProperties {
         &ButtplugDeviceMessageType::RSSILevelCmd,
         &self.message_attributes(),
       ),
+      ButtplugDeviceCommandMessageUnion::ScalarCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::ScalarCmd,
+        &self.message_attributes(),
+      ),
+      ButtplugDeviceCommandMessageUnion::SensorReadCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::SensorReadCmd,
+        &self.message_attributes(),
+      ),
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::SensorSubscribeCmd,
+        &self.message_attributes(),
+      ),
+      // Anything that can be subscribed to can be unsubscribed from, so there
+      // are no separate attributes for SensorUnsubscribeCmd.
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::SensorSubscribeCmd,
+        &self.message_attributes(),
+      ),
       // We translate SingleMotorVibrateCmd into Vibrate, so this one is special.
       ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_) => check_message_support(
         &ButtplugDeviceMessageType::VibrateCmd,
@@ -295,6 +360,7 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
       ButtplugDeviceCommandMessageUnion::RawReadCmd(msg) => self.handle_raw_read_cmd(device, msg),
       ButtplugDeviceCommandMessageUnion::RawWriteCmd(msg) => self.handle_raw_write_cmd(device, msg),
       ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => self.handle_rotate_cmd(device, msg),
+      ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) => self.handle_scalar_cmd(device, msg),
       ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => {
         self.handle_single_motor_vibrate_cmd(device, msg)
       }
@@ -317,6 +383,15 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
       ButtplugDeviceCommandMessageUnion::RSSILevelCmd(msg) => {
         self.handle_rssi_level_cmd(device, msg)
       }
+      ButtplugDeviceCommandMessageUnion::SensorReadCmd(msg) => {
+        self.handle_sensor_read_cmd(device, msg)
+      }
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => {
+        self.handle_sensor_subscribe_cmd(device, msg)
+      }
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => {
+        self.handle_sensor_unsubscribe_cmd(device, msg)
+      }
     }
   }
 
@@ -326,11 +401,14 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     message: messages::StopDeviceCmd,
   ) -> ButtplugDeviceResultFuture {
     let ok_return = messages::Ok::new(message.id());
-    let fut_vec: Vec<ButtplugDeviceResultFuture> = self
+    let mut fut_vec: Vec<ButtplugDeviceResultFuture> = self
       .stop_commands()
       .iter()
       .map(|cmd| self.handle_command(device.clone(), cmd.clone()))
       .collect();
+    if let Some(fut) = self.handle_linear_stop(device) {
+      fut_vec.push(fut);
+    }
     Box::pin(async move {
       // TODO We should be able to run these concurrently, and should return any error we get.
       for fut in fut_vec {
@@ -342,6 +420,16 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     })
   }
 
+  /// Holds linear actuators in place as part of a StopDeviceCmd.
+  ///
+  /// Linear actuators can't be stopped with a static command like vibrators
+  /// can, as where to stop depends on where they were last sent. Protocols
+  /// that track linear position via [GenericCommandManager::stop_linear] should
+  /// implement this.
+  fn handle_linear_stop(&self, _device: Arc<DeviceImpl>) -> Option<ButtplugDeviceResultFuture> {
+    None
+  }
+
   fn handle_single_motor_vibrate_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -386,6 +474,100 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     self.handle_command(device, vibrate_cmd.into())
   }
 
+  fn handle_scalar_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::ScalarCmd,
+  ) -> ButtplugDeviceResultFuture {
+    // Much like SingleMotorVibrateCmd, ScalarCmd subcommands for vibrators and
+    // rotators can be translated into VibrateCmd and RotateCmd, so protocols
+    // only need to implement those. Everything else goes to
+    // handle_scalar_actuator_cmd.
+    let attributes = self.message_attributes();
+    let actuator_types = attributes
+      .get(&ButtplugDeviceMessageType::ScalarCmd)
+      .and_then(|attr| attr.actuator_type.clone())
+      .unwrap_or_default();
+    let mut vibrations = vec![];
+    let mut rotations = vec![];
+    let mut actuators = vec![];
+    for scalar in message.scalars() {
+      let index = scalar.index() as usize;
+      let actuator_type = if let Some(actuator_type) = actuator_types.get(index) {
+        *actuator_type
+      } else {
+        return ButtplugDeviceError::ProtocolRequirementError(format!(
+          "ScalarCmd index {} is invalid, device has {} features.",
+          index,
+          actuator_types.len()
+        ))
+        .into();
+      };
+      if actuator_type != scalar.actuator_type() {
+        return ButtplugDeviceError::ProtocolRequirementError(format!(
+          "ScalarCmd index {} is actuator type {}, but command was for {}.",
+          index,
+          actuator_type,
+          scalar.actuator_type()
+        ))
+        .into();
+      }
+      // Features of the same actuator type are in the same order as in
+      // VibrateCmd/RotateCmd, so count our way to the matching index.
+      let generic_index = actuator_types[..index]
+        .iter()
+        .filter(|x| **x == actuator_type)
+        .count() as u32;
+      match actuator_type {
+        ActuatorType::Vibrate => {
+          vibrations.push(VibrateSubcommand::new(generic_index, scalar.scalar()))
+        }
+        // ScalarCmd has no concept of direction, so always rotate clockwise.
+        ActuatorType::Rotate => rotations.push(RotationSubcommand::new(
+          generic_index,
+          scalar.scalar(),
+          true,
+        )),
+        _ => actuators.push(scalar.clone()),
+      }
+    }
+    let mut fut_vec = vec![];
+    if !actuators.is_empty() {
+      let mut scalar_cmd = messages::ScalarCmd::new(message.device_index(), actuators);
+      scalar_cmd.set_id(message.id());
+      fut_vec.push(self.handle_scalar_actuator_cmd(device.clone(), scalar_cmd));
+    }
+    if !vibrations.is_empty() {
+      let mut vibrate_cmd = VibrateCmd::new(message.device_index(), vibrations);
+      vibrate_cmd.set_id(message.id());
+      fut_vec.push(self.handle_command(device.clone(), vibrate_cmd.into()));
+    }
+    if !rotations.is_empty() {
+      let mut rotate_cmd = RotateCmd::new(message.device_index(), rotations);
+      rotate_cmd.set_id(message.id());
+      fut_vec.push(self.handle_command(device, rotate_cmd.into()));
+    }
+    let ok_return = messages::Ok::new(message.id());
+    Box::pin(async move {
+      for fut in fut_vec {
+        fut.await?;
+      }
+      Ok(ok_return.into())
+    })
+  }
+
+  /// Handles ScalarCmd subcommands for actuators other than vibrators and
+  /// rotators, which have no generic command to be translated into. Indexes
+  /// are the same as in the original ScalarCmd. Protocols can track these with
+  /// [GenericCommandManager::update_scalar].
+  fn handle_scalar_actuator_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::ScalarCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
+
   fn handle_raw_write_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -523,6 +705,151 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
   ) -> ButtplugDeviceResultFuture {
     self.command_unimplemented(print_type_of(&message))
   }
+
+  /// Endpoint that data for a sensor type is read from or subscribed to. The
+  /// defaults are the standard sensor endpoints, protocols that keep sensors
+  /// elsewhere should override this.
+  fn sensor_endpoint(&self, sensor_type: SensorType) -> Option<Endpoint> {
+    match sensor_type {
+      SensorType::Battery => Some(Endpoint::RxBLEBattery),
+      SensorType::Pressure => Some(Endpoint::RxPressure),
+      SensorType::Button => Some(Endpoint::RxTouch),
+      SensorType::Accelerometer => Some(Endpoint::RxAccel),
+      // RSSI comes from the connection, not from an endpoint.
+      SensorType::RSSI => None,
+    }
+  }
+
+  /// Parser used to turn data from sensor endpoints, either from reads or
+  /// subscription notifications, into sensor values.
+  fn sensor_data_parser(&self) -> SensorDataParserFunc {
+    parse_sensor_data_as_bytes
+  }
+
+  fn handle_sensor_read_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorReadCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if let Err(err) = check_sensor_support(
+      &ButtplugDeviceMessageType::SensorReadCmd,
+      message.sensor_index(),
+      message.sensor_type(),
+      &self.message_attributes(),
+    ) {
+      return Box::pin(future::ready(Err(err)));
+    }
+    let id = message.id();
+    let device_index = message.device_index();
+    let sensor_index = message.sensor_index();
+    let sensor_type = message.sensor_type();
+    let create_reading = move |data: Vec<i32>| -> ButtplugServerMessage {
+      let mut reading = SensorReading::new(device_index, sensor_index, sensor_type, data);
+      reading.set_id(id);
+      reading.into()
+    };
+    match sensor_type {
+      // Battery and RSSI already have their own handlers, which protocols may
+      // override, so go through those instead of reading endpoints directly.
+      SensorType::Battery => {
+        let mut battery_cmd = BatteryLevelCmd::new(device_index);
+        battery_cmd.set_id(id);
+        let fut = self.handle_battery_level_cmd(device, battery_cmd);
+        Box::pin(async move {
+          match fut.await? {
+            ButtplugServerMessage::BatteryLevelReading(reading) => Ok(create_reading(vec![
+              (reading.battery_level() * 100f64).round() as i32,
+            ])),
+            msg => Err(
+              ButtplugDeviceError::ProtocolRequirementError(format!(
+                "Expected BatteryLevelReading, got {:?}",
+                msg
+              ))
+              .into(),
+            ),
+          }
+        })
+      }
+      SensorType::RSSI => {
+        let mut rssi_cmd = RSSILevelCmd::new(device_index);
+        rssi_cmd.set_id(id);
+        let fut = self.handle_rssi_level_cmd(device, rssi_cmd);
+        Box::pin(async move {
+          match fut.await? {
+            ButtplugServerMessage::RSSILevelReading(reading) => {
+              Ok(create_reading(vec![reading.rssi_level()]))
+            }
+            msg => Err(
+              ButtplugDeviceError::ProtocolRequirementError(format!(
+                "Expected RSSILevelReading, got {:?}",
+                msg
+              ))
+              .into(),
+            ),
+          }
+        })
+      }
+      _ => {
+        let endpoint = if let Some(endpoint) = self.sensor_endpoint(sensor_type) {
+          endpoint
+        } else {
+          return self.command_unimplemented(print_type_of(&message));
+        };
+        let parser = self.sensor_data_parser();
+        let fut = device.read_value(DeviceReadCmd::new(endpoint, 0, 0));
+        Box::pin(async move {
+          let raw_reading = fut.await?;
+          Ok(create_reading(parser(sensor_type, raw_reading.data())))
+        })
+      }
+    }
+  }
+
+  fn handle_sensor_subscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if let Err(err) = check_sensor_support(
+      &ButtplugDeviceMessageType::SensorSubscribeCmd,
+      message.sensor_index(),
+      message.sensor_type(),
+      &self.message_attributes(),
+    ) {
+      return Box::pin(future::ready(Err(err)));
+    }
+    let endpoint = if let Some(endpoint) = self.sensor_endpoint(message.sensor_type()) {
+      endpoint
+    } else {
+      return self.command_unimplemented(print_type_of(&message));
+    };
+    let id = message.id();
+    let fut = device.subscribe(DeviceSubscribeCmd::new(endpoint));
+    Box::pin(async move { fut.await.map(|_| messages::Ok::new(id).into()) })
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if let Err(err) = check_sensor_support(
+      &ButtplugDeviceMessageType::SensorSubscribeCmd,
+      message.sensor_index(),
+      message.sensor_type(),
+      &self.message_attributes(),
+    ) {
+      return Box::pin(future::ready(Err(err)));
+    }
+    let endpoint = if let Some(endpoint) = self.sensor_endpoint(message.sensor_type()) {
+      endpoint
+    } else {
+      return self.command_unimplemented(print_type_of(&message));
+    };
+    let id = message.id();
+    let fut = device.unsubscribe(DeviceUnsubscribeCmd::new(endpoint));
+    Box::pin(async move { fut.await.map(|_| messages::Ok::new(id).into()) })
+  }
 }
 
 #[macro_export]
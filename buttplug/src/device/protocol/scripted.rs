@@ -0,0 +1,985 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Protocols written in [Rhai](https://rhai.rs), registered at runtime.
+//!
+//! For devices that need more than a [template](super::template) (handshakes,
+//! keepalives, parsing notifications), a protocol can be written as a script
+//! and registered with
+//! [DeviceConfigurationManager::add_protocol_script](crate::device::configuration_manager::DeviceConfigurationManager::add_protocol_script).
+//! The device still needs a protocol definition with the same name, so it can
+//! be found and its attributes are known. Compiled in protocols with the same
+//! name take precedence over scripts.
+//!
+//! Scripts implement any of these functions, all of which are optional:
+//!
+//! - `initialize(device)`: Called once when the device connects. May return a
+//!   string, which is used as the identifier to look up the device's
+//!   attributes, the same way Lovense devices report their `DeviceType;`.
+//! - `vibrate(device, speeds)`: Speeds of every vibrator, scaled to the
+//!   StepCount of the device's VibrateCmd attributes.
+//! - `rotate(device, speeds, clockwise)`: Speeds and directions of every
+//!   rotator, scaled to the StepCount of the RotateCmd attributes.
+//! - `linear(device, vectors)`: LinearCmd vectors, as maps with `index`,
+//!   `duration` (in milliseconds) and `position` (0.0 to 1.0).
+//! - `scalar(device, values)`: Actuators other than vibrators and rotators
+//!   whose values changed, as maps with `index` (the ScalarCmd feature index),
+//!   `actuator` (like `"Oscillate"`) and `value`, scaled to the StepCount of
+//!   the ScalarCmd attributes.
+//! - `battery_level(device)`: Returns the battery level, from 0.0 to 1.0.
+//! - `keepalive(device)`: Called every `keepalive_interval()` milliseconds
+//!   (1000 if that isn't implemented, at least 10) until the device
+//!   disconnects.
+//! - `notification(device, endpoint, data)`: Called for every notification
+//!   the device sends after `initialize()`, including ones another function
+//!   is waiting for with `wait_for_notification()`. Useful for keeping track
+//!   of things the device reports on its own, like battery levels. Scripts
+//!   can't send messages to clients on their own, so anything a notification
+//!   reports only reaches clients through a later command, like
+//!   `battery_level()` returning a level stored in `this`.
+//!
+//! `device` can `write(endpoint, blob)`, `write(endpoint, blob,
+//! with_response)`, `read(endpoint, length)`, `subscribe(endpoint)`,
+//! `unsubscribe(endpoint)`, and `wait_for_notification(endpoint,
+//! timeout_ms)`, which returns the data of the next notification from the
+//! endpoint, or `()` on timeout. Timeouts are capped at
+//! [MAX_NOTIFICATION_TIMEOUT_MS], since no other function can run for the
+//! device while one is waiting. `this` is a map that's kept between calls
+//! for the same device, for things like packet counters. For instance:
+//!
+//! ```rhai
+//! fn initialize(device) {
+//!   device.subscribe("rx");
+//!   device.write("tx", "DeviceType;".to_blob());
+//!   let reply = device.wait_for_notification("rx", 1000);
+//!   if reply != () {
+//!     return reply.as_string().split(":")[0];
+//!   }
+//! }
+//!
+//! fn vibrate(device, speeds) {
+//!   device.write("tx", `Vibrate:${speeds[0]};`.to_blob());
+//! }
+//! ```
+//!
+//! Scripts run on a blocking thread, so device calls in them simply wait for
+//! the device. Every call is limited to [MAX_OPERATIONS] operations, and
+//! strings, arrays and maps are limited in size, so a runaway script fails
+//! its call instead of hanging the device or using up memory. Time spent
+//! waiting on the device doesn't count against the limits.
+
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    configuration_manager::DeviceProtocolConfiguration,
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    ButtplugDeviceEvent,
+    DeviceImpl,
+    DeviceReadCmd,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+  util::async_manager,
+};
+use futures::{future::BoxFuture, select, FutureExt};
+use futures_timer::Delay;
+use rhai::{Array, Blob, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::{
+  fmt,
+  str::FromStr,
+  sync::{Arc, Mutex, Weak},
+  time::Duration,
+};
+use tokio::{runtime::Handle, sync::broadcast};
+
+const DEFAULT_KEEPALIVE_INTERVAL_MS: u64 = 1000;
+/// Shortest keepalive interval a script can ask for.
+pub const MIN_KEEPALIVE_INTERVAL_MS: u64 = 10;
+/// Longest a script can wait for a notification in one call.
+pub const MAX_NOTIFICATION_TIMEOUT_MS: u64 = 5000;
+/// Most operations a single call into a script can run.
+pub const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 64 * 1024;
+const MAX_MAP_SIZE: usize = 1024;
+
+/// A compiled protocol script.
+pub struct ProtocolScript {
+  name: String,
+  engine: Engine,
+  ast: AST,
+}
+
+impl fmt::Debug for ProtocolScript {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ProtocolScript")
+      .field("name", &self.name)
+      .finish()
+  }
+}
+
+impl ProtocolScript {
+  /// Compiles a script for the protocol with the given name.
+  pub fn new(name: &str, source: &str) -> Result<Self, ButtplugError> {
+    let engine = create_engine(name);
+    let ast = engine
+      .compile(source)
+      .map_err(|err| script_error(name, format!("Cannot compile protocol script: {}", err)))?;
+    Ok(Self {
+      name: name.to_owned(),
+      engine,
+      ast,
+    })
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Whether the script implements a function with this name and number of
+  /// arguments.
+  pub fn has_hook(&self, hook: &str, arg_count: usize) -> bool {
+    self
+      .ast
+      .iter_functions()
+      .any(|function| function.name == hook && function.params.len() == arg_count)
+  }
+
+  /// Runs `keepalive_interval()` on a blocking thread, like any other call
+  /// into the script.
+  async fn keepalive_interval(self: Arc<Self>) -> Result<Duration, ButtplugError> {
+    if !self.has_hook("keepalive_interval", 0) {
+      return Ok(Duration::from_millis(DEFAULT_KEEPALIVE_INTERVAL_MS));
+    }
+    let name = self.name.clone();
+    let interval = tokio::task::spawn_blocking(move || {
+      self
+        .engine
+        .call_fn::<i64>(&mut Scope::new(), &self.ast, "keepalive_interval", ())
+        .map_err(|err| script_error(&self.name, err.to_string()))
+    })
+    .await
+    .map_err(|err| {
+      script_error(
+        &name,
+        format!("keepalive_interval() did not finish: {}", err),
+      )
+    })??;
+    match u64::try_from(interval) {
+      Ok(interval) if interval >= MIN_KEEPALIVE_INTERVAL_MS => Ok(Duration::from_millis(interval)),
+      _ => Err(script_error(
+        &name,
+        format!(
+          "keepalive_interval() returned {}, needs to be at least {}.",
+          interval, MIN_KEEPALIVE_INTERVAL_MS
+        ),
+      )),
+    }
+  }
+}
+
+fn script_error(name: &str, msg: impl Into<String>) -> ButtplugError {
+  ButtplugDeviceError::ProtocolSpecificError(name.to_owned(), msg.into()).into()
+}
+
+fn create_engine(name: &str) -> Engine {
+  let mut engine = Engine::new();
+  engine
+    .set_max_operations(MAX_OPERATIONS)
+    .set_max_call_levels(MAX_CALL_LEVELS)
+    .set_max_string_size(MAX_STRING_SIZE)
+    .set_max_array_size(MAX_ARRAY_SIZE)
+    .set_max_map_size(MAX_MAP_SIZE);
+  let print_name = name.to_owned();
+  engine.on_print(move |text| info!("Protocol script {}: {}", print_name, text));
+  let debug_name = name.to_owned();
+  engine.on_debug(move |text, _, pos| debug!("Protocol script {} ({}): {}", debug_name, pos, text));
+  engine
+    .register_type_with_name::<ScriptDevice>("Device")
+    .register_get("name", |device: &mut ScriptDevice| {
+      device.device.name().to_owned()
+    })
+    .register_get("address", |device: &mut ScriptDevice| {
+      device.device.address().to_owned()
+    })
+    .register_fn(
+      "write",
+      |device: &mut ScriptDevice, endpoint: &str, data: Blob| device.write(endpoint, data, false),
+    )
+    .register_fn("write", ScriptDevice::write)
+    .register_fn("read", ScriptDevice::read)
+    .register_fn("subscribe", ScriptDevice::subscribe)
+    .register_fn("unsubscribe", ScriptDevice::unsubscribe)
+    .register_fn("wait_for_notification", ScriptDevice::wait_for_notification);
+  engine
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// The `device` handed to scripts. Only used on the blocking thread a script
+/// runs on.
+#[derive(Clone)]
+struct ScriptDevice {
+  device: Arc<DeviceImpl>,
+  // Taken before the script starts, so notifications the script causes
+  // aren't missed.
+  events: Arc<tokio::sync::Mutex<broadcast::Receiver<ButtplugDeviceEvent>>>,
+  runtime: Handle,
+}
+
+impl ScriptDevice {
+  fn endpoint(endpoint: &str) -> ScriptResult<Endpoint> {
+    Endpoint::from_str(endpoint).map_err(|_| format!("Unknown endpoint {}", endpoint).into())
+  }
+
+  fn wait<T>(&self, fut: BoxFuture<'static, Result<T, ButtplugError>>) -> ScriptResult<T> {
+    self
+      .runtime
+      .block_on(fut)
+      .map_err(|err| err.to_string().into())
+  }
+
+  fn write(&mut self, endpoint: &str, data: Blob, with_response: bool) -> ScriptResult<()> {
+    let msg = DeviceWriteCmd::new(Self::endpoint(endpoint)?, data, with_response);
+    self.wait(self.device.write_value(msg))
+  }
+
+  fn read(&mut self, endpoint: &str, length: i64) -> ScriptResult<Blob> {
+    let length = u32::try_from(length).map_err(|_| format!("Invalid read length {}", length))?;
+    let msg = DeviceReadCmd::new(Self::endpoint(endpoint)?, length, 0);
+    self
+      .wait(self.device.read_value(msg))
+      .map(|reading| reading.data().clone())
+  }
+
+  fn subscribe(&mut self, endpoint: &str) -> ScriptResult<()> {
+    let msg = DeviceSubscribeCmd::new(Self::endpoint(endpoint)?);
+    self.wait(self.device.subscribe(msg))
+  }
+
+  fn unsubscribe(&mut self, endpoint: &str) -> ScriptResult<()> {
+    let msg = DeviceUnsubscribeCmd::new(Self::endpoint(endpoint)?);
+    self.wait(self.device.unsubscribe(msg))
+  }
+
+  fn wait_for_notification(&mut self, endpoint: &str, timeout_ms: i64) -> ScriptResult<Dynamic> {
+    let endpoint = Self::endpoint(endpoint)?;
+    // The script holds its state while it waits, so other calls for the device
+    // can't run until this returns.
+    let timeout =
+      Duration::from_millis((timeout_ms.max(0) as u64).min(MAX_NOTIFICATION_TIMEOUT_MS));
+    let events = self.events.clone();
+    self.runtime.block_on(async move {
+      let mut events = events.lock().await;
+      let mut timeout = Delay::new(timeout).fuse();
+      loop {
+        select! {
+          event = events.recv().fuse() => match event {
+            Ok(ButtplugDeviceEvent::Notification(_, event_endpoint, data)) if event_endpoint == endpoint => {
+              return Ok(Dynamic::from_blob(data));
+            }
+            Ok(ButtplugDeviceEvent::Removed(_)) | Err(broadcast::error::RecvError::Closed) => {
+              return Err("Device disconnected while waiting for notification".into());
+            }
+            _ => continue,
+          },
+          _ = timeout => return Ok(Dynamic::UNIT),
+        }
+      }
+    })
+  }
+}
+
+/// Runs a script function on a blocking thread. `device` is prepended to
+/// `args`, and `state` is bound as `this`.
+fn call_hook(
+  script: Arc<ProtocolScript>,
+  state: Arc<Mutex<Dynamic>>,
+  device: Arc<DeviceImpl>,
+  hook: &'static str,
+  args: Vec<Dynamic>,
+) -> BoxFuture<'static, Result<Dynamic, ButtplugError>> {
+  Box::pin(async move {
+    let script_device = ScriptDevice {
+      events: Arc::new(tokio::sync::Mutex::new(device.event_stream())),
+      device,
+      runtime: Handle::current(),
+    };
+    let name = script.name.clone();
+    tokio::task::spawn_blocking(move || {
+      let mut args = args;
+      args.insert(0, Dynamic::from(script_device));
+      // Holding the state for the whole call also keeps calls for the same
+      // device from running at the same time.
+      let mut state = state.lock().expect("Lock should never be poisoned");
+      let options = CallFnOptions::new()
+        .eval_ast(false)
+        .bind_this_ptr(&mut state);
+      script
+        .engine
+        .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &script.ast, hook, args)
+        .map_err(|err| script_error(&script.name, format!("{}(): {}", hook, err)))
+    })
+    .await
+    .map_err(|err| script_error(&name, format!("{}() did not finish: {}", hook, err)))?
+  })
+}
+
+#[derive(ButtplugProtocolProperties)]
+pub struct Scripted {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<tokio::sync::Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  script: Arc<ProtocolScript>,
+  state: Arc<Mutex<Dynamic>>,
+}
+
+impl Scripted {
+  fn new(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+    script: Arc<ProtocolScript>,
+    state: Arc<Mutex<Dynamic>>,
+  ) -> Self {
+    let manager = GenericCommandManager::new(&message_attributes);
+    Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(tokio::sync::Mutex::new(manager)),
+      script,
+      state,
+    }
+  }
+
+  fn call(
+    &self,
+    device: Arc<DeviceImpl>,
+    hook: &'static str,
+    args: Vec<Dynamic>,
+  ) -> BoxFuture<'static, Result<Dynamic, ButtplugError>> {
+    call_hook(self.script.clone(), self.state.clone(), device, hook, args)
+  }
+}
+
+/// Calls the keepalive hook until the device disconnects or the protocol is
+/// dropped.
+async fn run_keepalive(
+  script: Arc<ProtocolScript>,
+  state: Weak<Mutex<Dynamic>>,
+  device: Arc<DeviceImpl>,
+  interval: Duration,
+) {
+  loop {
+    Delay::new(interval).await;
+    let state = match state.upgrade() {
+      Some(state) if device.connected() => state,
+      _ => break,
+    };
+    if let Err(err) = call_hook(script.clone(), state, device.clone(), "keepalive", vec![]).await {
+      warn!("Protocol script keepalive failed: {}", err);
+    }
+  }
+  debug!("Stopping keepalive for protocol script {}.", script.name());
+}
+
+/// Hands notifications to the notification hook until the device disconnects
+/// or the protocol is dropped.
+async fn run_notifications(
+  script: Arc<ProtocolScript>,
+  state: Weak<Mutex<Dynamic>>,
+  device: Arc<DeviceImpl>,
+) {
+  let mut events = device.event_stream();
+  loop {
+    let (endpoint, data) = match events.recv().await {
+      Ok(ButtplugDeviceEvent::Notification(_, endpoint, data)) => (endpoint, data),
+      Ok(ButtplugDeviceEvent::Removed(_)) | Err(broadcast::error::RecvError::Closed) => break,
+      Err(broadcast::error::RecvError::Lagged(count)) => {
+        warn!(
+          "Protocol script {} missed {} notifications.",
+          script.name(),
+          count
+        );
+        continue;
+      }
+      Ok(_) => continue,
+    };
+    let state = if let Some(state) = state.upgrade() {
+      state
+    } else {
+      break;
+    };
+    let args = vec![endpoint.to_string().into(), Dynamic::from_blob(data)];
+    if let Err(err) = call_hook(script.clone(), state, device.clone(), "notification", args).await {
+      warn!("Protocol script notification handler failed: {}", err);
+    }
+  }
+  debug!(
+    "Stopping notification handling for protocol script {}.",
+    script.name()
+  );
+}
+
+impl ButtplugProtocol for Scripted {
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    Box::pin(async move {
+      let script = config.script().clone().ok_or_else(|| {
+        ButtplugError::from(ButtplugDeviceError::ProtocolRequirementError(
+          "Scripted protocol needs a registered protocol script.".to_owned(),
+        ))
+      })?;
+      let state = Arc::new(Mutex::new(Dynamic::from_map(Map::new())));
+      let identifier = if script.has_hook("initialize", 1) {
+        call_hook(
+          script.clone(),
+          state.clone(),
+          device_impl.clone(),
+          "initialize",
+          vec![],
+        )
+        .await?
+        .into_string()
+        .ok()
+      } else {
+        None
+      };
+      let (name, attrs) = super::get_protocol_features(device_impl.clone(), identifier, config)?;
+      if script.has_hook("keepalive", 1) {
+        let interval = script.clone().keepalive_interval().await?;
+        async_manager::spawn(run_keepalive(
+          script.clone(),
+          Arc::downgrade(&state),
+          device_impl.clone(),
+          interval,
+        ));
+      }
+      if script.has_hook("notification", 3) {
+        async_manager::spawn(run_notifications(
+          script.clone(),
+          Arc::downgrade(&state),
+          device_impl,
+        ));
+      }
+      Ok(Box::new(Scripted::new(&name, attrs, script, state)) as Box<dyn ButtplugProtocol>)
+    })
+  }
+}
+
+impl ButtplugProtocolCommandHandler for Scripted {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if !self.script.has_hook("vibrate", 2) {
+      return self.command_unimplemented("VibrateCmd");
+    }
+    let manager = self.manager.clone();
+    let script = self.script.clone();
+    let state = self.state.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_vibration(&message, true)?;
+      if let Some(cmds) = result {
+        let speeds: Array = cmds
+          .iter()
+          .map(|cmd| Dynamic::from_int(cmd.unwrap_or(0) as i64))
+          .collect();
+        // Command hooks don't return anything useful.
+        let _ = call_hook(script, state, device, "vibrate", vec![speeds.into()]).await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_rotate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::RotateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if !self.script.has_hook("rotate", 3) {
+      return self.command_unimplemented("RotateCmd");
+    }
+    let manager = self.manager.clone();
+    let script = self.script.clone();
+    let state = self.state.clone();
+    Box::pin(async move {
+      let cmds = manager.lock().await.update_rotation(&message)?;
+      if cmds.iter().any(|cmd| cmd.is_some()) {
+        let (speeds, clockwise): (Array, Array) = cmds
+          .iter()
+          .map(|cmd| {
+            let (speed, clockwise) = cmd.unwrap_or((0, true));
+            (
+              Dynamic::from_int(speed as i64),
+              Dynamic::from_bool(clockwise),
+            )
+          })
+          .unzip();
+        let _ = call_hook(
+          script,
+          state,
+          device,
+          "rotate",
+          vec![speeds.into(), clockwise.into()],
+        )
+        .await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if !self.script.has_hook("linear", 2) {
+      return self.command_unimplemented("LinearCmd");
+    }
+    let vectors: Array = message
+      .vectors()
+      .iter()
+      .map(|vector| {
+        let mut map = Map::new();
+        map.insert("index".into(), Dynamic::from_int(vector.index as i64));
+        map.insert("duration".into(), Dynamic::from_int(vector.duration as i64));
+        map.insert("position".into(), Dynamic::from_float(vector.position));
+        Dynamic::from_map(map)
+      })
+      .collect();
+    let fut = self.call(device, "linear", vec![vectors.into()]);
+    Box::pin(async move {
+      let _ = fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_scalar_actuator_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::ScalarCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if !self.script.has_hook("scalar", 2) {
+      return self.command_unimplemented("ScalarCmd");
+    }
+    let manager = self.manager.clone();
+    let script = self.script.clone();
+    let state = self.state.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_scalar(&message)?;
+      if let Some(cmds) = result {
+        let values: Array = cmds
+          .iter()
+          .enumerate()
+          .filter_map(|(index, cmd)| {
+            cmd.map(|(actuator, value)| {
+              let mut map = Map::new();
+              map.insert("index".into(), Dynamic::from_int(index as i64));
+              map.insert("actuator".into(), actuator.to_string().into());
+              map.insert("value".into(), Dynamic::from_int(value as i64));
+              Dynamic::from_map(map)
+            })
+          })
+          .collect();
+        let _ = call_hook(script, state, device, "scalar", vec![values.into()]).await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_battery_level_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::BatteryLevelCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if !self.script.has_hook("battery_level", 1) {
+      return self.command_unimplemented("BatteryLevelCmd");
+    }
+    let name = self.script.name().to_owned();
+    let fut = self.call(device, "battery_level", vec![]);
+    Box::pin(async move {
+      let level = fut.await?;
+      let level = level
+        .as_float()
+        .or_else(|_| level.as_int().map(|level| level as f64))
+        .map_err(|_| script_error(&name, "battery_level() did not return a number."))?;
+      Ok(messages::BatteryLevelReading::new(message.device_index(), level.clamp(0.0, 1.0)).into())
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{
+    core::messages::{
+      ActuatorType,
+      BatteryLevelCmd,
+      ButtplugDeviceMessageType,
+      ButtplugServerMessage,
+      ScalarCmd,
+      ScalarSubcommand,
+      VibrateCmd,
+      VibrateSubcommand,
+    },
+    device::{
+      configuration_manager::{DeviceConfigurationManager, ProtocolDefinition},
+      ButtplugDeviceEvent,
+      DeviceImplCommand,
+      DeviceWriteCmd,
+      Endpoint,
+    },
+    server::comm_managers::test::{check_test_recv_value, new_bluetoothle_test_device_with_cfg},
+    util::{async_manager, device_configuration::create_test_dcm},
+  };
+  use futures_timer::Delay;
+  use std::{sync::Arc, time::Duration};
+
+  fn script_dcm(script: &str) -> DeviceConfigurationManager {
+    let definition: ProtocolDefinition = serde_json::from_str(
+      r#"{
+        "btle": {
+          "names": ["Script Test"],
+          "services": {
+            "0000fff0-0000-1000-8000-00805f9b34fb": {
+              "tx": "0000fff1-0000-1000-8000-00805f9b34fb",
+              "rx": "0000fff2-0000-1000-8000-00805f9b34fb"
+            }
+          }
+        },
+        "defaults": {
+          "name": { "en-us": "Script Test Device" },
+          "messages": {
+            "VibrateCmd": { "FeatureCount": 1, "StepCount": [20] },
+            "ScalarCmd": { "FeatureCount": 1, "StepCount": [10], "ActuatorType": ["Constrict"] },
+            "BatteryLevelCmd": {}
+          }
+        },
+        "configurations": [
+          {
+            "identifier": ["P"],
+            "name": { "en-us": "Script Test Device P" },
+            "messages": {
+              "VibrateCmd": { "FeatureCount": 2, "StepCount": [20, 20] }
+            }
+          }
+        ]
+      }"#,
+    )
+    .expect("Test, assuming infallible");
+    let dcm = create_test_dcm(false);
+    dcm.add_protocol_definition("script-test", definition);
+    dcm
+      .add_protocol_script("script-test", script)
+      .expect("Test, assuming infallible");
+    dcm
+  }
+
+  #[test]
+  pub fn test_scripted_protocol() {
+    async_manager::block_on(async move {
+      let dcm = script_dcm(
+        r#"
+          fn initialize(device) {
+            this.counter = 0;
+            device.write("tx", "Init;".to_blob());
+            "P"
+          }
+
+          fn vibrate(device, speeds) {
+            this.counter += 1;
+            let packet = blob();
+            packet.push(this.counter);
+            for speed in speeds {
+              packet.push(speed);
+            }
+            device.write("tx", packet, true);
+          }
+        "#,
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      assert_eq!(device.protocol_name(), "script-test");
+      // initialize() picked the configuration with two vibrators.
+      assert_eq!(device.name(), "Script Test Device P");
+      assert_eq!(
+        device.message_attributes()[&ButtplugDeviceMessageType::VibrateCmd].feature_count,
+        Some(2)
+      );
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"Init;".to_vec(), false)),
+      );
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![1, 10, 0], true)),
+      );
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 1.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![2, 10, 20], true)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_scalar() {
+    async_manager::block_on(async move {
+      let dcm = script_dcm(
+        r#"
+          fn scalar(device, values) {
+            for value in values {
+              if value.actuator == "Constrict" {
+                let packet = blob();
+                packet.push(value.index);
+                packet.push(value.value);
+                device.write("tx", packet);
+              }
+            }
+          }
+        "#,
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(
+          ScalarCmd::new(
+            0,
+            vec![ScalarSubcommand::new(1, 0.5, ActuatorType::Constrict)],
+          )
+          .into(),
+        )
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![1, 5], false)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_notifications() {
+    async_manager::block_on(async move {
+      let dcm = script_dcm(
+        r#"
+          fn battery_level(device) {
+            device.subscribe("rx");
+            device.write("tx", "Battery;".to_blob());
+            let reply = device.wait_for_notification("rx", 1000);
+            if reply == () {
+              throw "No battery reply";
+            }
+            parse_int(reply.as_string().split(";")[0]) / 100.0
+          }
+        "#,
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      assert_eq!(device.name(), "Script Test Device");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      // Answer the battery query like the device would.
+      async_manager::spawn(async move {
+        loop {
+          let received = command_receiver
+            .lock()
+            .expect("Test, assuming infallible")
+            .try_recv();
+          if let Ok(command) = received {
+            assert_eq!(
+              command,
+              DeviceImplCommand::Write(DeviceWriteCmd::new(
+                Endpoint::Tx,
+                b"Battery;".to_vec(),
+                false
+              ))
+            );
+            test_device.send_event(ButtplugDeviceEvent::Notification(
+              test_device.address(),
+              Endpoint::Rx,
+              b"85;".to_vec(),
+            ));
+            break;
+          }
+          Delay::new(Duration::from_millis(10)).await;
+        }
+      });
+      let reading = device
+        .parse_message(BatteryLevelCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      if let ButtplugServerMessage::BatteryLevelReading(reading) = reading {
+        assert!((reading.battery_level() - 0.85).abs() < f64::EPSILON);
+      } else {
+        panic!("Expected a battery level reading, got {:?}", reading);
+      }
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_keepalive() {
+    async_manager::block_on(async move {
+      let dcm = script_dcm(
+        r#"
+          fn keepalive_interval() {
+            10
+          }
+
+          fn keepalive(device) {
+            device.write("tx", "Ping;".to_blob());
+          }
+        "#,
+      );
+      let (_device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      Delay::new(Duration::from_millis(100)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"Ping;".to_vec(), false)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_notification_hook() {
+    async_manager::block_on(async move {
+      let dcm = script_dcm(
+        r#"
+          fn notification(device, endpoint, data) {
+            if endpoint == "rx" {
+              this.battery = data[0];
+            }
+          }
+
+          fn battery_level(device) {
+            this.battery / 100.0
+          }
+        "#,
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::Rx,
+        vec![42],
+      ));
+      Delay::new(Duration::from_millis(100)).await;
+      let reading = device
+        .parse_message(BatteryLevelCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      if let ButtplugServerMessage::BatteryLevelReading(reading) = reading {
+        assert!((reading.battery_level() - 0.42).abs() < f64::EPSILON);
+      } else {
+        panic!("Expected a battery level reading, got {:?}", reading);
+      }
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_runaway_script() {
+    async_manager::block_on(async move {
+      let dcm = script_dcm(
+        r#"
+          fn vibrate(device, speeds) {
+            loop {}
+          }
+
+          fn battery_level(device) {
+            let text = "x";
+            loop {
+              text += text;
+            }
+          }
+        "#,
+      );
+      let (device, _test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      assert!(device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .is_err());
+      assert!(device
+        .parse_message(BatteryLevelCmd::new(0).into())
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_script_errors() {
+    async_manager::block_on(async move {
+      let dcm = create_test_dcm(false);
+      assert!(dcm
+        .add_protocol_script("script-test", "fn vibrate(device, speeds) {")
+        .is_err());
+      let dcm = script_dcm(r#"fn initialize(device) { throw "Handshake failed"; }"#);
+      assert!(
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .is_err()
+      );
+      let dcm = script_dcm(
+        r#"
+          fn keepalive_interval() { 0 }
+          fn keepalive(device) {}
+        "#,
+      );
+      assert!(
+        new_bluetoothle_test_device_with_cfg("Script Test", Some(Arc::new(dcm)))
+          .await
+          .is_err()
+      );
+    });
+  }
+}
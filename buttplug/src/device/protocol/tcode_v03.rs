@@ -5,38 +5,63 @@ use super::{
   ButtplugProtocolCommandHandler,
 };
 use crate::{
-  core::messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+  core::{
+    errors::ButtplugError,
+    messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+  },
   device::{protocol::ButtplugProtocolProperties, DeviceImpl, DeviceWriteCmd, Endpoint},
 };
 use std::sync::Arc;
 
 super::default_protocol_declaration!(TCodeV03);
 
+async fn write_linear(
+  device: &DeviceImpl,
+  cmds: Vec<Option<(u32, u32)>>,
+) -> Result<(), ButtplugError> {
+  for (index, cmd) in cmds.iter().enumerate() {
+    if let Some((position, duration)) = cmd {
+      let command = format!("L{}{:02}I{}\n", index, position, duration);
+      device
+        .write_value(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          command.as_bytes().to_vec(),
+          false,
+        ))
+        .await?;
+    }
+  }
+  Ok(())
+}
+
 impl ButtplugProtocolCommandHandler for TCodeV03 {
   fn handle_linear_cmd(
     &self,
     device: Arc<DeviceImpl>,
     msg: messages::LinearCmd,
   ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
     Box::pin(async move {
-      let mut fut_vec = vec![];
-      for v in msg.vectors() {
-        let position = (v.position * 99f64) as u32;
-
-        let command = format!("L{}{:02}I{}\n", v.index, position, v.duration);
-        fut_vec.push(device.write_value(DeviceWriteCmd::new(
-          Endpoint::Tx,
-          command.as_bytes().to_vec(),
-          false,
-        )));
-      }
-      for fut in fut_vec {
-        fut.await?;
+      // Store off result before the match, so we drop the lock ASAP.
+      let result = manager.lock().await.update_linear(&msg)?;
+      if let Some(cmds) = result {
+        write_linear(&device, cmds).await?;
       }
       Ok(messages::Ok::default().into())
     })
   }
 
+  fn handle_linear_stop(&self, device: Arc<DeviceImpl>) -> Option<ButtplugDeviceResultFuture> {
+    let manager = self.manager.clone();
+    Some(Box::pin(async move {
+      let result = manager.lock().await.stop_linear();
+      if let Some(cmds) = result {
+        write_linear(&device, cmds).await?;
+      }
+      Ok(messages::Ok::default().into())
+    }))
+  }
+
   fn handle_vibrate_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -0,0 +1,650 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Protocol for simple vibrators whose packets are described in the device
+//! configuration, instead of being compiled in.
+//!
+//! A lot of devices just take a fixed packet with a speed byte dropped in
+//! somewhere. Protocol definitions for those can include a `template` block
+//! describing the packet, and if no compiled in protocol has the same name,
+//! this protocol is used for them. For instance, a vibrator taking
+//! `[0x55, 0x04, speed, checksum]` on its tx endpoint would be:
+//!
+//! ```json
+//! "template": {
+//!   "packet": [85, 4, {"speed": 0}, "sum-checksum"]
+//! }
+//! ```
+//!
+//! Speeds are scaled to the StepCount of the device's VibrateCmd attributes,
+//! same as for compiled in protocols. Other actuators, like oscillators or
+//! inflators, are listed in the device's ScalarCmd attributes and can be put
+//! in packets with `{"scalar": index}`.
+
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self,
+      ActuatorType,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessageType,
+      DeviceMessageAttributes,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    configuration_manager::DeviceProtocolConfiguration,
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+};
+use futures::future::{self, BoxFuture};
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+  atomic::{AtomicU8, Ordering},
+  Arc,
+};
+
+/// A single byte of a template packet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum TemplateByte {
+  /// Sent as is.
+  Literal(u8),
+  /// Filled in when the packet is built.
+  Value(TemplateValue),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TemplateValue {
+  /// Speed of the vibrator with this index.
+  Speed(u32),
+  /// Speed of the vibrator the packet is for. Only valid with
+  /// `packet-per-feature`.
+  FeatureSpeed,
+  /// Index of the vibrator the packet is for, plus an offset. Only valid with
+  /// `packet-per-feature`.
+  FeatureIndex(u8),
+  /// One value while a vibrator is running, another while it's stopped.
+  Active(TemplateActive),
+  /// Value of the actuator with this index, counting only ScalarCmd features
+  /// that aren't vibrators or rotators. Can't be used with
+  /// `packet-per-feature`.
+  Scalar(u32),
+  /// Goes up by one with every packet sent, wrapping around.
+  PacketCounter,
+  /// XOR of every byte before this one.
+  XorChecksum,
+  /// Wrapping sum of every byte before this one.
+  SumChecksum,
+}
+
+fn default_active_on() -> u8 {
+  1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct TemplateActive {
+  /// Vibrator to check. If not set, the vibrator the packet is for, which is
+  /// only valid with `packet-per-feature`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  feature: Option<u32>,
+  #[serde(default = "default_active_on")]
+  on: u8,
+  #[serde(default)]
+  off: u8,
+}
+
+fn default_endpoint() -> Endpoint {
+  Endpoint::Tx
+}
+
+/// Describes the packets a template protocol device takes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct ProtocolTemplate {
+  #[getset(get_copy = "pub")]
+  #[serde(default = "default_endpoint")]
+  endpoint: Endpoint,
+  #[getset(get_copy = "pub")]
+  #[serde(rename = "write-with-response", default)]
+  write_with_response: bool,
+  /// Send a separate packet for every vibrator whose speed changed, instead
+  /// of one packet with every speed in it.
+  #[getset(get_copy = "pub")]
+  #[serde(rename = "packet-per-feature", default)]
+  packet_per_feature: bool,
+  #[getset(get = "pub")]
+  packet: Vec<TemplateByte>,
+}
+
+impl ProtocolTemplate {
+  /// Makes sure the template can be built for a device whose vibrators and
+  /// other actuators (see [actuator_step_counts]) have the given step counts.
+  /// Every value the template fills in has to fit in its byte.
+  pub fn validate(
+    &self,
+    step_counts: &[u32],
+    actuator_step_counts: &[u32],
+  ) -> Result<(), ButtplugDeviceError> {
+    let error = |msg: String| Err(ButtplugDeviceError::ProtocolRequirementError(msg));
+    let vibrator_count = step_counts.len() as u32;
+    if self.packet.is_empty() {
+      return error("Template packet is empty.".to_owned());
+    }
+    for byte in &self.packet {
+      match byte {
+        TemplateByte::Value(TemplateValue::Scalar(_)) if self.packet_per_feature => {
+          return error(format!(
+            "Template value {:?} can't be used with packet-per-feature.",
+            byte
+          ));
+        }
+        TemplateByte::Value(TemplateValue::Scalar(index)) => {
+          match actuator_step_counts.get(*index as usize) {
+            None => {
+              return error(format!(
+                "Template refers to actuator {}, but device has {} actuators.",
+                index,
+                actuator_step_counts.len()
+              ))
+            }
+            Some(step_count) if *step_count > u8::MAX as u32 => {
+              return error(format!(
+                "Actuator {} has a step count of {}, which doesn't fit in a template byte.",
+                index, step_count
+              ))
+            }
+            _ => {}
+          }
+        }
+        TemplateByte::Value(TemplateValue::Speed(feature)) => {
+          if let Some(step_count) = step_counts.get(*feature as usize) {
+            if *step_count > u8::MAX as u32 {
+              return error(format!(
+                "Vibrator {} has a step count of {}, which doesn't fit in a template byte.",
+                feature, step_count
+              ));
+            }
+          }
+        }
+        TemplateByte::Value(TemplateValue::FeatureSpeed) => {
+          if let Some(step_count) = step_counts.iter().find(|count| **count > u8::MAX as u32) {
+            return error(format!(
+              "Device has a step count of {}, which doesn't fit in a template byte.",
+              step_count
+            ));
+          }
+        }
+        TemplateByte::Value(TemplateValue::FeatureIndex(offset))
+          if *offset as u32 + vibrator_count.saturating_sub(1) > u8::MAX as u32 =>
+        {
+          return error(format!(
+            "Feature index offset {} is too large for a device with {} vibrators.",
+            offset, vibrator_count
+          ));
+        }
+        _ => {}
+      }
+      let (feature, per_feature_only) = match byte {
+        TemplateByte::Value(TemplateValue::Speed(feature)) => (Some(*feature), false),
+        TemplateByte::Value(TemplateValue::Active(active)) => {
+          (active.feature, active.feature.is_none())
+        }
+        TemplateByte::Value(TemplateValue::FeatureSpeed)
+        | TemplateByte::Value(TemplateValue::FeatureIndex(_)) => (None, true),
+        _ => (None, false),
+      };
+      if let Some(feature) = feature {
+        if self.packet_per_feature {
+          return error(format!(
+            "Template value {:?} refers to a specific vibrator, which can't be used with packet-per-feature.",
+            byte
+          ));
+        }
+        if feature >= vibrator_count {
+          return error(format!(
+            "Template refers to vibrator {}, but device has {} vibrators.",
+            feature, vibrator_count
+          ));
+        }
+      }
+      if per_feature_only && !self.packet_per_feature {
+        return error(format!(
+          "Template value {:?} can only be used with packet-per-feature.",
+          byte
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  /// Builds a packet. `speeds` holds the speed of every vibrator, `actuators`
+  /// the value of every other actuator, and `feature` is the vibrator the
+  /// packet is for, with packet-per-feature. Assumes the template has been
+  /// validated, so values and feature indexes always fit in a byte.
+  fn build_packet(
+    &self,
+    speeds: &[u32],
+    actuators: &[u32],
+    feature: usize,
+    packet_counter: &AtomicU8,
+  ) -> Vec<u8> {
+    let mut packet: Vec<u8> = Vec::with_capacity(self.packet.len());
+    for byte in &self.packet {
+      let value = match byte {
+        TemplateByte::Literal(value) => *value,
+        TemplateByte::Value(TemplateValue::Speed(index)) => speeds[*index as usize] as u8,
+        TemplateByte::Value(TemplateValue::FeatureSpeed) => speeds[feature] as u8,
+        TemplateByte::Value(TemplateValue::FeatureIndex(offset)) => offset + feature as u8,
+        TemplateByte::Value(TemplateValue::Scalar(index)) => actuators[*index as usize] as u8,
+        TemplateByte::Value(TemplateValue::Active(active)) => {
+          let index = active.feature.map_or(feature, |index| index as usize);
+          if speeds[index] > 0 {
+            active.on
+          } else {
+            active.off
+          }
+        }
+        TemplateByte::Value(TemplateValue::PacketCounter) => {
+          packet_counter.fetch_add(1, Ordering::SeqCst)
+        }
+        TemplateByte::Value(TemplateValue::XorChecksum) => packet.iter().fold(0, |crc, b| crc ^ b),
+        TemplateByte::Value(TemplateValue::SumChecksum) => {
+          packet.iter().fold(0u8, |crc, b| crc.wrapping_add(*b))
+        }
+      };
+      packet.push(value);
+    }
+    packet
+  }
+}
+
+fn is_actuator(actuator_type: ActuatorType) -> bool {
+  actuator_type != ActuatorType::Vibrate && actuator_type != ActuatorType::Rotate
+}
+
+/// Step counts of the actuators in ScalarCmd attributes that aren't vibrators
+/// or rotators, which is what `{"scalar": index}` indexes into.
+pub fn actuator_step_counts(scalar_attributes: &DeviceMessageAttributes) -> Vec<u32> {
+  scalar_attributes
+    .actuator_type
+    .iter()
+    .flatten()
+    .zip(scalar_attributes.step_count.iter().flatten())
+    .filter(|(actuator_type, _)| is_actuator(**actuator_type))
+    .map(|(_, step_count)| *step_count)
+    .collect()
+}
+
+/// Current values of the actuators `{"scalar": index}` indexes into.
+fn actuator_values(manager: &GenericCommandManager) -> Vec<u32> {
+  manager
+    .get_scalar()
+    .into_iter()
+    .filter(|(actuator_type, _)| is_actuator(*actuator_type))
+    .map(|(_, value)| value)
+    .collect()
+}
+
+#[derive(ButtplugProtocolProperties)]
+pub struct Template {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<tokio::sync::Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  template: Arc<ProtocolTemplate>,
+  packet_counter: Arc<AtomicU8>,
+}
+
+impl Template {
+  pub fn new(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+    template: ProtocolTemplate,
+  ) -> Result<Self, ButtplugDeviceError> {
+    let step_counts = message_attributes
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .and_then(|attrs| attrs.step_count.clone())
+      .unwrap_or_default();
+    let actuator_step_counts = message_attributes
+      .get(&ButtplugDeviceMessageType::ScalarCmd)
+      .map(actuator_step_counts)
+      .unwrap_or_default();
+    if step_counts.is_empty() && actuator_step_counts.is_empty() {
+      return Err(ButtplugDeviceError::ProtocolRequirementError(format!(
+        "{} uses a template, so it needs VibrateCmd or ScalarCmd attributes with step counts.",
+        name
+      )));
+    }
+    template.validate(&step_counts, &actuator_step_counts)?;
+    let manager = GenericCommandManager::new(&message_attributes);
+    Ok(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(tokio::sync::Mutex::new(manager)),
+      template: Arc::new(template),
+      packet_counter: Arc::new(AtomicU8::new(0)),
+    })
+  }
+}
+
+impl ButtplugProtocol for Template {
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    let template = if let Some(template) = config.template() {
+      template.clone()
+    } else {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::ProtocolRequirementError(
+          "Template protocol needs a template in its protocol definition.".to_owned(),
+        )
+        .into(),
+      )));
+    };
+    let device =
+      super::get_protocol_features(device_impl, None, config).and_then(|(name, attrs)| {
+        Template::new(&name, attrs, template)
+          .map(|protocol| Box::new(protocol) as Box<dyn ButtplugProtocol>)
+          .map_err(|err| err.into())
+      });
+    Box::pin(future::ready(device))
+  }
+}
+
+impl ButtplugProtocolCommandHandler for Template {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    let template = self.template.clone();
+    let packet_counter = self.packet_counter.clone();
+    Box::pin(async move {
+      // Packets with every speed in them always need every speed, even if
+      // only one changed.
+      let mut manager = manager.lock().await;
+      let result = manager.update_vibration(&message, !template.packet_per_feature())?;
+      let actuators = actuator_values(&manager);
+      drop(manager);
+      if let Some(cmds) = result {
+        let speeds: Vec<u32> = cmds.iter().map(|cmd| cmd.unwrap_or(0)).collect();
+        let features: Vec<usize> = if template.packet_per_feature() {
+          cmds
+            .iter()
+            .enumerate()
+            .filter(|(_, cmd)| cmd.is_some())
+            .map(|(index, _)| index)
+            .collect()
+        } else {
+          vec![0]
+        };
+        for feature in features {
+          let packet = template.build_packet(&speeds, &actuators, feature, &packet_counter);
+          device
+            .write_value(DeviceWriteCmd::new(
+              template.endpoint(),
+              packet,
+              template.write_with_response(),
+            ))
+            .await?;
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_scalar_actuator_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::ScalarCmd,
+  ) -> ButtplugDeviceResultFuture {
+    // Packet-per-feature packets are built for a single vibrator, so there's
+    // nowhere to put other actuators.
+    if self.template.packet_per_feature() {
+      return self.command_unimplemented("ScalarCmd");
+    }
+    let manager = self.manager.clone();
+    let template = self.template.clone();
+    let packet_counter = self.packet_counter.clone();
+    Box::pin(async move {
+      let mut manager = manager.lock().await;
+      if manager.update_scalar(&message)?.is_none() {
+        return Ok(messages::Ok::default().into());
+      }
+      let speeds: Vec<u32> = manager
+        .get_vibration()
+        .iter()
+        .map(|speed| speed.unwrap_or(0))
+        .collect();
+      let actuators = actuator_values(&manager);
+      drop(manager);
+      let packet = template.build_packet(&speeds, &actuators, 0, &packet_counter);
+      device
+        .write_value(DeviceWriteCmd::new(
+          template.endpoint(),
+          packet,
+          template.write_with_response(),
+        ))
+        .await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::{ProtocolTemplate, TemplateByte, TemplateValue};
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::ProtocolDefinition,
+      DeviceImplCommand,
+      DeviceWriteCmd,
+      Endpoint,
+    },
+    server::comm_managers::test::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      new_bluetoothle_test_device_with_cfg,
+    },
+    util::{
+      async_manager,
+      device_configuration::{create_test_dcm, load_protocol_config_from_json},
+    },
+  };
+  use std::sync::Arc;
+
+  fn template_definition(template: &str, vibrators: u32) -> ProtocolDefinition {
+    serde_json::from_str(&format!(
+      r#"{{
+        "btle": {{
+          "names": ["Template Test"],
+          "services": {{
+            "0000fff0-0000-1000-8000-00805f9b34fb": {{
+              "tx": "0000fff1-0000-1000-8000-00805f9b34fb"
+            }}
+          }}
+        }},
+        "defaults": {{
+          "name": {{ "en-us": "Template Test Device" }},
+          "messages": {{
+            "VibrateCmd": {{ "FeatureCount": {}, "StepCount": [{}] }}
+          }}
+        }},
+        "template": {}
+      }}"#,
+      vibrators,
+      vec!["100"; vibrators as usize].join(", "),
+      template
+    ))
+    .expect("Test, assuming infallible")
+  }
+
+  #[test]
+  pub fn test_template_deserialization() {
+    let template: ProtocolTemplate = serde_json::from_str(
+      r#"{"packet": [85, {"speed": 1}, {"active": {"feature": 0, "off": 255}}, "xor-checksum"]}"#,
+    )
+    .expect("Test, assuming infallible");
+    assert_eq!(template.endpoint(), Endpoint::Tx);
+    assert!(!template.write_with_response());
+    assert_eq!(template.packet()[0], TemplateByte::Literal(0x55));
+    assert_eq!(
+      template.packet()[1],
+      TemplateByte::Value(TemplateValue::Speed(1))
+    );
+    assert_eq!(
+      template.packet()[3],
+      TemplateByte::Value(TemplateValue::XorChecksum)
+    );
+    assert!(template.validate(&[20, 20], &[]).is_ok());
+    // Vibrator 1 doesn't exist on a single vibrator device.
+    assert!(template.validate(&[20], &[]).is_err());
+
+    let template: ProtocolTemplate =
+      serde_json::from_str(r#"{"packet": ["feature-speed"]}"#).expect("Test, assuming infallible");
+    assert!(template.validate(&[20], &[]).is_err());
+  }
+
+  #[test]
+  pub fn test_template_values_must_fit_in_a_byte() {
+    let template: ProtocolTemplate =
+      serde_json::from_str(r#"{"packet": [{"speed": 0}]}"#).expect("Test, assuming infallible");
+    assert!(template.validate(&[255], &[]).is_ok());
+    assert!(template.validate(&[256], &[]).is_err());
+
+    let template: ProtocolTemplate = serde_json::from_str(
+      r#"{"packet": [{"feature-index": 254}, "feature-speed"], "packet-per-feature": true}"#,
+    )
+    .expect("Test, assuming infallible");
+    assert!(template.validate(&[100, 100], &[]).is_ok());
+    // Vibrator 2 would have an index of 256.
+    assert!(template.validate(&[100, 100, 100], &[]).is_err());
+    // Vibrator 1 speeds don't fit in a byte.
+    assert!(template.validate(&[100, 1000], &[]).is_err());
+  }
+
+  #[test]
+  pub fn test_template_in_user_config() {
+    let config = load_protocol_config_from_json(
+      &format!(
+        r#"{{"version": 1, "protocols": {{"template-test": {}}}}}"#,
+        serde_json::to_string(&template_definition(
+          r#"{"packet": [1, {"active": {"feature": 0, "off": 255}}, {"speed": 0}]}"#,
+          1
+        ))
+        .expect("Test, assuming infallible")
+      ),
+      true,
+    )
+    .expect("Test, assuming infallible");
+    assert!(config.protocols["template-test"].template().is_some());
+  }
+
+  #[test]
+  pub fn test_template_protocol() {
+    async_manager::block_on(async move {
+      let dcm = create_test_dcm(false);
+      dcm.add_protocol_definition(
+        "template-test",
+        template_definition(
+          r#"{"packet": [85, 4, {"speed": 0}, {"active": {"feature": 0}}, "packet-counter", "sum-checksum"], "write-with-response": true}"#,
+          1,
+        ),
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Template Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      assert_eq!(device.protocol_name(), "template-test");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x55, 0x04, 50, 0x01, 0x00, 0x55 + 0x04 + 50 + 0x01],
+          true,
+        )),
+      );
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x55, 0x04, 0x00, 0x00, 0x01, 0x55 + 0x04 + 0x01],
+          true,
+        )),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_template_protocol_packet_per_feature() {
+    async_manager::block_on(async move {
+      let dcm = create_test_dcm(false);
+      dcm.add_protocol_definition(
+        "template-test",
+        template_definition(
+          r#"{"packet": [{"feature-index": 241}, "feature-speed"], "packet-per-feature": true}"#,
+          2,
+        ),
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Template Test", Some(Arc::new(dcm)))
+          .await
+          .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 50], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
+  #[test]
+  pub fn test_template_protocol_rejects_invalid_template() {
+    async_manager::block_on(async move {
+      let dcm = create_test_dcm(false);
+      dcm.add_protocol_definition(
+        "template-test",
+        template_definition(r#"{"packet": [{"speed": 1}]}"#, 1),
+      );
+      assert!(
+        new_bluetoothle_test_device_with_cfg("Template Test", Some(Arc::new(dcm)))
+          .await
+          .is_err()
+      );
+    });
+  }
+}
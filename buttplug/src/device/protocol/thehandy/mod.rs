@@ -6,7 +6,7 @@ use super::{
 };
 use crate::{
   core::{
-    errors::ButtplugDeviceError,
+    errors::ButtplugError,
     messages::{
       self,
       ButtplugDeviceCommandMessageUnion,
@@ -23,12 +23,9 @@ use crate::{
     Endpoint,
   },
 };
-use futures::future;
 use prost::Message;
-use std::sync::{
-  atomic::{AtomicU8, Ordering},
-  Arc,
-};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 mod protocomm {
   include!(concat!(env!("OUT_DIR"), "/protocomm.rs"));
@@ -43,10 +40,9 @@ pub struct TheHandy {
   name: String,
   message_attributes: DeviceMessageAttributesMap,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  // The generic command manager would normally handle this storage, but the only reason we're
-  // retaining tracking information is to build our fucking timing calculation for the fleshlight
-  // command backport. I am so mad right now.
-  previous_position: Arc<AtomicU8>,
+  // We need position tracking to build our fucking timing calculation for the fleshlight command
+  // backport. I am so mad right now.
+  manager: Arc<Mutex<GenericCommandManager>>,
 }
 
 impl TheHandy {
@@ -54,11 +50,13 @@ impl TheHandy {
   where
     Self: Sized,
   {
+    let manager = GenericCommandManager::new(&message_attributes);
+
     Self {
       name: name.to_owned(),
-      stop_commands: GenericCommandManager::new(&message_attributes).get_stop_commands(),
       message_attributes,
-      previous_position: Arc::new(AtomicU8::new(0)),
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
     }
   }
 }
@@ -132,6 +130,63 @@ impl ButtplugProtocol for TheHandy {
   }
 }
 
+async fn write_linear(
+  device: &DeviceImpl,
+  cmds: Vec<Option<(u32, u32)>>,
+) -> Result<(), ButtplugError> {
+  // What is "How not to implement a command structure for your device that
+  // does one thing", Alex?
+  let (position, duration) = if let Some(Some(cmd)) = cmds.first() {
+    *cmd
+  } else {
+    return Ok(());
+  };
+  let linear = handyplug::LinearCmd {
+    // You know when message IDs are important? When you have a protocol that
+    // handles multiple asynchronous commands. You know what doesn't handle
+    // multiple asynchronous commands? The handyplug protocol.
+    //
+    // Do you know where you'd pack those? In the top level container, as
+    // they should then be separate from the message context, in order to
+    // allow multiple sorters. Do you know what doesn't need multiple
+    // sorters? The handyplug protocol.
+    //
+    // Please do not cargo cult protocols.
+    id: 2,
+    // You know when multiple device indicies are important? WHEN YOU HAVE
+    // MULTIPLE DEVICE CONNECTI... oh fuck it. I am so tired. I am going to
+    // bed.
+    device_index: 0,
+    // AND I'M BACK AND WELL RESTED. You know when multiple axes are
+    // important? When you have to support arbitrary devices with multiple
+    // axes. You know what device doesn't have multiple axes?
+    //
+    // Guess.
+    //
+    // I'll wait.
+    //
+    // The handy. It's the handy.
+    vectors: vec![handyplug::linear_cmd::Vector {
+      index: 0,
+      duration,
+      // Our step count for the Handy is 100, so this gets us back to 0.0-1.0.
+      position: position as f64 / 100f64,
+    }],
+  };
+  let linear_payload = handyplug::Payload {
+    messages: vec![handyplug::Message {
+      message: Some(handyplug::message::Message::LinearCmd(linear)),
+    }],
+  };
+  let mut linear_buf = vec![];
+  linear_payload
+    .encode(&mut linear_buf)
+    .expect("Infallible encode.");
+  device
+    .write_value(DeviceWriteCmd::new(Endpoint::Tx, linear_buf, true))
+    .await
+}
+
 impl ButtplugProtocolCommandHandler for TheHandy {
   fn handle_fleshlight_launch_fw12_cmd(
     &self,
@@ -142,21 +197,31 @@ impl ButtplugProtocolCommandHandler for TheHandy {
     // work backward from fleshlight to my own Linear format that Handy uses.
     //
     // Building this library was a mistake.
-    let goal_position = message.position() as f64 / 100f64;
-    let previous_position = self.previous_position.load(Ordering::SeqCst) as f64 / 100f64;
-    self
-      .previous_position
-      .store(message.position(), Ordering::SeqCst);
-    let distance = (goal_position - previous_position).abs();
-    let duration =
-      fleshlight_launch_helper::get_duration(distance, message.speed() as f64 / 99f64) as u32;
-    self.handle_linear_cmd(
-      device,
-      messages::LinearCmd::new(
-        message.device_index(),
-        vec![messages::VectorSubcommand::new(0, duration, goal_position)],
-      ),
-    )
+    let manager = self.manager.clone();
+    Box::pin(async move {
+      let result = {
+        let mut manager = manager.lock().await;
+        let goal_position = message.position() as f64 / 100f64;
+        let previous_position = manager
+          .get_linear()
+          .first()
+          .cloned()
+          .flatten()
+          .map(|(position, _)| position as f64 / 100f64)
+          .unwrap_or(0f64);
+        let distance = (goal_position - previous_position).abs();
+        let duration =
+          fleshlight_launch_helper::get_duration(distance, message.speed() as f64 / 99f64);
+        manager.update_linear(&messages::LinearCmd::new(
+          message.device_index(),
+          vec![messages::VectorSubcommand::new(0, duration, goal_position)],
+        ))?
+      };
+      if let Some(cmds) = result {
+        write_linear(&device, cmds).await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
   }
 
   fn handle_linear_cmd(
@@ -164,63 +229,26 @@ impl ButtplugProtocolCommandHandler for TheHandy {
     device: Arc<DeviceImpl>,
     message: messages::LinearCmd,
   ) -> ButtplugDeviceResultFuture {
-    // What is "How not to implement a command structure for your device that
-    // does one thing", Alex?
-
-    // First make sure we only have one vector.
-    //
-    // TODO Use the command manager to check this.
-    if message.vectors().len() != 1 {
-      return Box::pin(future::ready(Err(
-        ButtplugDeviceError::DeviceFeatureCountMismatch(1, message.vectors().len() as u32).into(),
-      )));
-    }
-
-    let linear = handyplug::LinearCmd {
-      // You know when message IDs are important? When you have a protocol that
-      // handles multiple asynchronous commands. You know what doesn't handle
-      // multiple asynchronous commands? The handyplug protocol.
-      //
-      // Do you know where you'd pack those? In the top level container, as
-      // they should then be separate from the message context, in order to
-      // allow multiple sorters. Do you know what doesn't need multiple
-      // sorters? The handyplug protocol.
-      //
-      // Please do not cargo cult protocols.
-      id: 2,
-      // You know when multiple device indicies are important? WHEN YOU HAVE
-      // MULTIPLE DEVICE CONNECTI... oh fuck it. I am so tired. I am going to
-      // bed.
-      device_index: 0,
-      // AND I'M BACK AND WELL RESTED. You know when multiple axes are
-      // important? When you have to support arbitrary devices with multiple
-      // axes. You know what device doesn't have multiple axes?
-      //
-      // Guess.
-      //
-      // I'll wait.
-      //
-      // The handy. It's the handy.
-      vectors: vec![handyplug::linear_cmd::Vector {
-        index: 0,
-        duration: message.vectors()[0].duration(),
-        position: *message.vectors()[0].position(),
-      }],
-    };
-    let linear_payload = handyplug::Payload {
-      messages: vec![handyplug::Message {
-        message: Some(handyplug::message::Message::LinearCmd(linear)),
-      }],
-    };
-    let mut linear_buf = vec![];
-    linear_payload
-      .encode(&mut linear_buf)
-      .expect("Infallible encode.");
+    let manager = self.manager.clone();
     Box::pin(async move {
-      device
-        .write_value(DeviceWriteCmd::new(Endpoint::Tx, linear_buf, true))
-        .await?;
+      // The command manager makes sure we only have the one vector the Handy
+      // can take.
+      let result = manager.lock().await.update_linear(&message)?;
+      if let Some(cmds) = result {
+        write_linear(&device, cmds).await?;
+      }
       Ok(messages::Ok::default().into())
     })
   }
+
+  fn handle_linear_stop(&self, device: Arc<DeviceImpl>) -> Option<ButtplugDeviceResultFuture> {
+    let manager = self.manager.clone();
+    Some(Box::pin(async move {
+      let result = manager.lock().await.stop_linear();
+      if let Some(cmds) = result {
+        write_linear(&device, cmds).await?;
+      }
+      Ok(messages::Ok::default().into())
+    }))
+  }
 }
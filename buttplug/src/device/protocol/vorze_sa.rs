@@ -1,10 +1,13 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
-  core::messages::{
-    self,
-    ButtplugDeviceCommandMessageUnion,
-    ButtplugDeviceMessage,
-    DeviceMessageAttributesMap,
+  core::{
+    errors::ButtplugError,
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
+      DeviceMessageAttributesMap,
+    },
   },
   device::{
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
@@ -13,7 +16,6 @@ use crate::{
     Endpoint,
   },
 };
-use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -23,7 +25,6 @@ pub struct VorzeSA {
   message_attributes: DeviceMessageAttributesMap,
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  previous_position: Arc<AtomicU8>,
 }
 
 impl VorzeSA {
@@ -35,7 +36,6 @@ impl VorzeSA {
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
-      previous_position: Arc::new(AtomicU8::new(0)),
     }
   }
 }
@@ -83,6 +83,31 @@ pub fn get_piston_speed(mut distance: f64, mut duration: f64) -> u8 {
   speed as u8
 }
 
+async fn write_linear(
+  device: &DeviceImpl,
+  previous: Vec<Option<(u32, u32)>>,
+  cmds: Vec<Option<(u32, u32)>>,
+) -> Result<(), ButtplugError> {
+  if let Some(Some((position, duration))) = cmds.first() {
+    let previous_position = previous
+      .first()
+      .cloned()
+      .flatten()
+      .map(|(position, _)| position)
+      .unwrap_or(0);
+    let distance = (previous_position as f64 - *position as f64).abs();
+    let speed = get_piston_speed(distance, *duration as f64);
+    device
+      .write_value(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![VorzeDevices::Piston as u8, *position as u8, speed],
+        true,
+      ))
+      .await?;
+  }
+  Ok(())
+}
+
 impl ButtplugProtocolCommandHandler for VorzeSA {
   fn handle_vibrate_cmd(
     &self,
@@ -149,28 +174,33 @@ impl ButtplugProtocolCommandHandler for VorzeSA {
     device: Arc<DeviceImpl>,
     msg: messages::LinearCmd,
   ) -> ButtplugDeviceResultFuture {
-    let v = msg.vectors()[0].clone();
-
-    let previous_position = self.previous_position.load(SeqCst);
-    let position = v.position * 200f64;
-    let distance = (previous_position as f64 - position).abs();
-
-    let speed = get_piston_speed(distance, v.duration as f64);
-
-    self.previous_position.store(position as u8, SeqCst);
-
-    let fut = device.write_value(DeviceWriteCmd::new(
-      Endpoint::Tx,
-      vec![VorzeDevices::Piston as u8, position as u8, speed as u8],
-      true,
-    ));
-
+    let manager = self.manager.clone();
     Box::pin(async move {
-      fut.await?;
+      let (previous, result) = {
+        let mut manager = manager.lock().await;
+        (manager.get_linear(), manager.update_linear(&msg)?)
+      };
+      if let Some(cmds) = result {
+        write_linear(&device, previous, cmds).await?;
+      }
       Ok(messages::Ok::default().into())
     })
   }
 
+  fn handle_linear_stop(&self, device: Arc<DeviceImpl>) -> Option<ButtplugDeviceResultFuture> {
+    let manager = self.manager.clone();
+    Some(Box::pin(async move {
+      let (previous, result) = {
+        let mut manager = manager.lock().await;
+        (manager.get_linear(), manager.stop_linear())
+      };
+      if let Some(cmds) = result {
+        write_linear(&device, previous, cmds).await?;
+      }
+      Ok(messages::Ok::default().into())
+    }))
+  }
+
   fn handle_vorze_a10_cyclone_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -361,18 +391,11 @@ mod test {
       );
       assert!(check_test_recv_empty(&command_receiver));
 
+      // Repeating the same move shouldn't send anything.
       device
         .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 150, 0.95)]).into())
         .await
         .expect("Test, assuming infallible");
-      check_test_recv_value(
-        &command_receiver,
-        DeviceImplCommand::Write(DeviceWriteCmd::new(
-          Endpoint::Tx,
-          vec![0x03, 190, 100],
-          true,
-        )),
-      );
       assert!(check_test_recv_empty(&command_receiver));
 
       device
@@ -389,10 +412,19 @@ mod test {
       );
       assert!(check_test_recv_empty(&command_receiver));
 
+      // Stopping holds the piston at the last position it was sent to.
       device
         .parse_message(StopDeviceCmd::new(0).into())
         .await
         .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x03, 100, 100],
+          true,
+        )),
+      );
       assert!(check_test_recv_empty(&command_receiver));
     });
   }
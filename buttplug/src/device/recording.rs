@@ -0,0 +1,586 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Records traffic between the server and devices, so protocol bugs can be
+//! reproduced without the hardware.
+//!
+//! Recordings are JSON Lines files. The first line describes the device, and
+//! every line after it is a command sent to the device or an event from it,
+//! with the time in milliseconds since the device connected:
+//!
+//! ```json
+//! {"name":"LVS-Test","address":"AA:BB","endpoints":["tx","rx"],"specifier":{...}}
+//! {"time-ms":0,"type":"subscribe","endpoint":"rx"}
+//! {"time-ms":2,"type":"write","endpoint":"tx","data":[68,101,118],"write-with-response":false}
+//! {"time-ms":40,"type":"notification","endpoint":"rx","data":[90,58,49]}
+//! {"time-ms":55,"type":"write","endpoint":"tx","data":[1],"write-with-response":true,"error":"..."}
+//! ```
+//!
+//! Commands are recorded in the order they're sent, with the time they were
+//! sent. Their lines are written once the device has handled them, since
+//! reads need the data the device returned and failed commands get the error
+//! they failed with. Commands that take longer than [COMMAND_TIMEOUT] are
+//! written with an error instead, so they don't hold up the lines after them.
+//! See the replay device communication manager for playing recordings back.
+
+use super::{
+  configuration_manager::{DeviceSpecifier, ProtocolDefinition},
+  ButtplugDeviceEvent,
+  ButtplugDeviceImplCreator,
+  DeviceImpl,
+  DeviceImplInternal,
+  DeviceReadCmd,
+  DeviceSubscribeCmd,
+  DeviceUnsubscribeCmd,
+  DeviceWriteCmd,
+  Endpoint,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  util::async_manager,
+};
+use async_trait::async_trait;
+use futures::{future::BoxFuture, select, FutureExt};
+use futures_timer::Delay;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{
+  fmt::{self, Debug},
+  fs::{self, File},
+  io::Write,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// Longest the recorder waits for a command to finish before writing it.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First line of a recording, describing the device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Getters)]
+#[getset(get = "pub")]
+pub struct RecordingHeader {
+  name: String,
+  address: String,
+  endpoints: Vec<Endpoint>,
+  /// Specifier the device was found with, so a replayed device matches the
+  /// same protocol.
+  specifier: DeviceSpecifier,
+}
+
+impl RecordingHeader {
+  pub fn new(
+    name: &str,
+    address: &str,
+    endpoints: &[Endpoint],
+    specifier: DeviceSpecifier,
+  ) -> Self {
+    Self {
+      name: name.to_owned(),
+      address: address.to_owned(),
+      endpoints: endpoints.into(),
+      specifier,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RecordedEvent {
+  Write {
+    endpoint: Endpoint,
+    data: Vec<u8>,
+    #[serde(rename = "write-with-response")]
+    write_with_response: bool,
+  },
+  /// A read, with the data the device returned.
+  Read {
+    endpoint: Endpoint,
+    length: u32,
+    data: Vec<u8>,
+  },
+  Subscribe {
+    endpoint: Endpoint,
+  },
+  Unsubscribe {
+    endpoint: Endpoint,
+  },
+  Notification {
+    endpoint: Endpoint,
+    data: Vec<u8>,
+  },
+  /// The device went away.
+  Disconnected,
+}
+
+impl RecordedEvent {
+  /// Whether this came from the device, instead of being sent to it.
+  pub fn is_device_event(&self) -> bool {
+    matches!(
+      self,
+      RecordedEvent::Notification { .. } | RecordedEvent::Disconnected
+    )
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct RecordedEntry {
+  /// Milliseconds since the device connected.
+  #[getset(get_copy = "pub")]
+  #[serde(rename = "time-ms")]
+  time_ms: u64,
+  #[getset(get = "pub")]
+  #[serde(flatten)]
+  event: RecordedEvent,
+  /// Error the command failed with, if it did.
+  #[getset(get = "pub")]
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+impl RecordedEntry {
+  pub fn new(time_ms: u64, event: RecordedEvent) -> Self {
+    Self {
+      time_ms,
+      event,
+      error: None,
+    }
+  }
+}
+
+/// A recording loaded from a file.
+#[derive(Debug, Clone, PartialEq, Getters)]
+#[getset(get = "pub")]
+pub struct DeviceRecording {
+  header: RecordingHeader,
+  entries: Vec<RecordedEntry>,
+}
+
+impl DeviceRecording {
+  pub fn new(header: RecordingHeader, entries: Vec<RecordedEntry>) -> Self {
+    Self { header, entries }
+  }
+
+  pub fn from_json_lines(recording: &str) -> Result<Self, ButtplugError> {
+    let error = |line: usize, err: serde_json::Error| {
+      ButtplugDeviceError::DeviceConfigurationFileError(format!(
+        "Cannot parse line {} of device recording: {}",
+        line + 1,
+        err
+      ))
+    };
+    let mut lines = recording
+      .lines()
+      .enumerate()
+      .filter(|(_, line)| !line.trim().is_empty());
+    let header = match lines.next() {
+      Some((index, line)) => serde_json::from_str(line).map_err(|err| error(index, err))?,
+      None => {
+        return Err(
+          ButtplugDeviceError::DeviceConfigurationFileError(
+            "Device recording is empty.".to_owned(),
+          )
+          .into(),
+        )
+      }
+    };
+    let entries = lines
+      .map(|(index, line)| serde_json::from_str(line).map_err(|err| error(index, err)))
+      .collect::<Result<Vec<RecordedEntry>, ButtplugDeviceError>>()?;
+    Ok(Self { header, entries })
+  }
+
+  /// Number of commands sent to the device in the recording.
+  pub fn command_count(&self) -> usize {
+    self
+      .entries
+      .iter()
+      .filter(|entry| !entry.event.is_device_event())
+      .count()
+  }
+
+  pub fn load(path: &Path) -> Result<Self, ButtplugError> {
+    let recording = fs::read_to_string(path).map_err(|err| {
+      ButtplugDeviceError::DeviceConfigurationFileError(format!(
+        "Cannot read device recording {}: {}",
+        path.display(),
+        err
+      ))
+    })?;
+    Self::from_json_lines(&recording)
+  }
+
+  pub fn to_json_lines(&self) -> String {
+    std::iter::once(serde_json::to_string(&self.header))
+      .chain(self.entries.iter().map(serde_json::to_string))
+      .map(|line| line.expect("All types below this are Serialize, so this should be infallible."))
+      .map(|line| line + "\n")
+      .collect()
+  }
+}
+
+/// Entry waiting to be written. Commands are queued when they're sent, so
+/// they keep their order, and finished once the device has handled them.
+struct PendingEntry {
+  entry: RecordedEntry,
+  finished: Option<oneshot::Receiver<RecordedEntry>>,
+}
+
+/// Appends entries to a recording file as they happen, so recordings survive
+/// crashes. Writing happens on its own task, so recording never holds up the
+/// device.
+struct DeviceRecorder {
+  path: PathBuf,
+  start: Instant,
+  entry_sender: mpsc::UnboundedSender<PendingEntry>,
+}
+
+impl DeviceRecorder {
+  fn create(
+    path: PathBuf,
+    header: &RecordingHeader,
+    command_timeout: Duration,
+  ) -> Result<Self, std::io::Error> {
+    let mut file = File::create(&path)?;
+    let header = serde_json::to_string(header)
+      .expect("All types below this are Serialize, so this should be infallible.");
+    writeln!(file, "{}", header)?;
+    let (entry_sender, entry_receiver) = mpsc::unbounded_channel();
+    async_manager::spawn(write_entries(
+      path.clone(),
+      file,
+      entry_receiver,
+      command_timeout,
+    ));
+    Ok(Self {
+      path,
+      start: Instant::now(),
+      entry_sender,
+    })
+  }
+
+  fn queue(&self, entry: RecordedEntry, finished: Option<oneshot::Receiver<RecordedEntry>>) {
+    // The writer only stops once every recorder is gone.
+    let _ = self.entry_sender.send(PendingEntry { entry, finished });
+  }
+
+  fn new_entry(&self, event: RecordedEvent) -> RecordedEntry {
+    RecordedEntry::new(self.start.elapsed().as_millis() as u64, event)
+  }
+
+  fn record(&self, event: RecordedEvent) {
+    self.queue(self.new_entry(event), None);
+  }
+
+  /// Records a command as it's sent, and fills in how it went once `fut`
+  /// finishes. `update` adds what the device returned to the event.
+  fn record_command<T: Send + 'static>(
+    &self,
+    event: RecordedEvent,
+    fut: BoxFuture<'static, Result<T, ButtplugError>>,
+    update: impl FnOnce(&mut RecordedEvent, &T) + Send + 'static,
+  ) -> BoxFuture<'static, Result<T, ButtplugError>> {
+    let mut entry = self.new_entry(event);
+    let (finished_sender, finished_receiver) = oneshot::channel();
+    self.queue(entry.clone(), Some(finished_receiver));
+    Box::pin(async move {
+      let result = fut.await;
+      match &result {
+        Ok(value) => update(&mut entry.event, value),
+        Err(err) => entry.error = Some(err.to_string()),
+      }
+      let _ = finished_sender.send(entry);
+      result
+    })
+  }
+}
+
+async fn write_entries(
+  path: PathBuf,
+  mut file: File,
+  mut entry_receiver: mpsc::UnboundedReceiver<PendingEntry>,
+  command_timeout: Duration,
+) {
+  while let Some(PendingEntry {
+    mut entry,
+    finished,
+  }) = entry_receiver.recv().await
+  {
+    if let Some(finished) = finished {
+      select! {
+        finished = finished.fuse() => match finished {
+          Ok(finished) => entry = finished,
+          Err(_) => entry.error = Some("Command was dropped before it finished.".to_owned()),
+        },
+        _ = Delay::new(command_timeout).fuse() => {
+          entry.error = Some(format!(
+            "Command didn't finish within {} ms while recording.",
+            command_timeout.as_millis()
+          ));
+        }
+      }
+    }
+    let line = serde_json::to_string(&entry)
+      .expect("All types below this are Serialize, so this should be infallible.");
+    // Failing to record isn't fatal, the device still works.
+    if let Err(err) = writeln!(file, "{}", line) {
+      error!("Cannot write device recording {}: {}", path.display(), err);
+    }
+  }
+}
+
+/// Wraps a device implementation, recording everything sent to and received
+/// from it.
+struct RecordingDeviceImpl {
+  internal_impl: Box<dyn DeviceImplInternal>,
+  recorder: Arc<DeviceRecorder>,
+}
+
+impl RecordingDeviceImpl {
+  fn new(internal_impl: Box<dyn DeviceImplInternal>, recorder: DeviceRecorder) -> Self {
+    let recorder = Arc::new(recorder);
+    let recorder_clone = recorder.clone();
+    let mut event_stream = internal_impl.event_stream();
+    async_manager::spawn(async move {
+      loop {
+        match event_stream.recv().await {
+          Ok(ButtplugDeviceEvent::Notification(_, endpoint, data)) => {
+            recorder_clone.record(RecordedEvent::Notification { endpoint, data })
+          }
+          Ok(ButtplugDeviceEvent::Removed(_)) => {
+            recorder_clone.record(RecordedEvent::Disconnected);
+            break;
+          }
+          Ok(ButtplugDeviceEvent::Connected(_)) => {}
+          Err(broadcast::error::RecvError::Lagged(count)) => warn!(
+            "Device recording {} missed {} device events.",
+            recorder_clone.path.display(),
+            count
+          ),
+          Err(broadcast::error::RecvError::Closed) => break,
+        }
+      }
+    });
+    Self {
+      internal_impl,
+      recorder,
+    }
+  }
+
+  fn record_command(
+    &self,
+    fut: ButtplugResultFuture,
+    event: RecordedEvent,
+  ) -> ButtplugResultFuture {
+    self.recorder.record_command(event, fut, |_, _| {})
+  }
+}
+
+impl DeviceImplInternal for RecordingDeviceImpl {
+  fn connected(&self) -> bool {
+    self.internal_impl.connected()
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.internal_impl.disconnect()
+  }
+
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.internal_impl.event_stream()
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let event = RecordedEvent::Read {
+      endpoint: msg.endpoint,
+      length: msg.length,
+      data: vec![],
+    };
+    let fut = self.internal_impl.read_value(msg);
+    self
+      .recorder
+      .record_command(event, fut, |event, reading: &RawReading| {
+        if let RecordedEvent::Read { data, .. } = event {
+          *data = reading.data().clone();
+        }
+      })
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    let event = RecordedEvent::Write {
+      endpoint: msg.endpoint,
+      data: msg.data.clone(),
+      write_with_response: msg.write_with_response,
+    };
+    self.record_command(self.internal_impl.write_value(msg), event)
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    let event = RecordedEvent::Subscribe {
+      endpoint: msg.endpoint,
+    };
+    self.record_command(self.internal_impl.subscribe(msg), event)
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    let event = RecordedEvent::Unsubscribe {
+      endpoint: msg.endpoint,
+    };
+    self.record_command(self.internal_impl.unsubscribe(msg), event)
+  }
+}
+
+/// Wraps a device implementation creator, so devices it creates are recorded
+/// to a new file in `directory`.
+pub struct RecordingDeviceImplCreator {
+  creator: Box<dyn ButtplugDeviceImplCreator>,
+  directory: PathBuf,
+}
+
+impl RecordingDeviceImplCreator {
+  pub fn new(creator: Box<dyn ButtplugDeviceImplCreator>, directory: &Path) -> Self {
+    Self {
+      creator,
+      directory: directory.to_owned(),
+    }
+  }
+
+  fn recording_path(&self, address: &str) -> PathBuf {
+    let address: String = address
+      .chars()
+      .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+      .collect();
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|time| time.as_millis())
+      .unwrap_or_default();
+    self
+      .directory
+      .join(format!("{}-{}.jsonl", address, timestamp))
+  }
+}
+
+impl Debug for RecordingDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("RecordingDeviceImplCreator")
+      .field("creator", &self.creator)
+      .field("directory", &self.directory)
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for RecordingDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    self.creator.get_specifier()
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    let device_impl = self.creator.try_create_device_impl(protocol).await?;
+    let header = RecordingHeader::new(
+      &device_impl.name,
+      &device_impl.address,
+      &device_impl.endpoints,
+      self.creator.get_specifier(),
+    );
+    let path = self.recording_path(&device_impl.address);
+    match DeviceRecorder::create(path.clone(), &header, COMMAND_TIMEOUT) {
+      Ok(recorder) => {
+        info!(
+          "Recording traffic for device {} to {}",
+          device_impl.address,
+          path.display()
+        );
+        let DeviceImpl {
+          name,
+          address,
+          endpoints,
+          internal_impl,
+          ..
+        } = device_impl;
+        Ok(DeviceImpl::new(
+          &name,
+          &address,
+          &endpoints,
+          Box::new(RecordingDeviceImpl::new(internal_impl, recorder)),
+        ))
+      }
+      Err(err) => {
+        // Not being able to record shouldn't keep the device from working.
+        error!(
+          "Cannot create device recording {}, device won't be recorded: {}",
+          path.display(),
+          err
+        );
+        Ok(device_impl)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{DeviceRecorder, DeviceRecording, RecordedEvent};
+  use crate::{device::Endpoint, util::async_manager};
+  use futures::future;
+  use futures_timer::Delay;
+  use std::time::Duration;
+
+  #[test]
+  pub fn test_recording_does_not_wait_forever_for_commands() {
+    async_manager::block_on(async move {
+      let header = DeviceRecording::from_json_lines(
+        r#"{"name":"Recording Test","address":"RecordingAddress","endpoints":["tx","rx"],"specifier":{"BluetoothLE":{"names":["Recording Test"],"services":{}}}}"#,
+      )
+      .expect("Test, assuming infallible")
+      .header()
+      .clone();
+      let path = std::env::temp_dir().join(format!(
+        "buttplug-recording-timeout-test-{}.jsonl",
+        std::process::id()
+      ));
+      let recorder = DeviceRecorder::create(path.clone(), &header, Duration::from_millis(50))
+        .expect("Test, assuming infallible");
+      // Kept around without being polled, so the command never finishes.
+      let _command = recorder.record_command(
+        RecordedEvent::Write {
+          endpoint: Endpoint::Tx,
+          data: vec![1],
+          write_with_response: true,
+        },
+        Box::pin(future::pending::<Result<(), _>>()),
+        |_, _| {},
+      );
+      recorder.record(RecordedEvent::Notification {
+        endpoint: Endpoint::Rx,
+        data: vec![2],
+      });
+      Delay::new(Duration::from_millis(200)).await;
+      let recording = DeviceRecording::load(&path).expect("Test, assuming infallible");
+      let _ = std::fs::remove_file(&path);
+      assert_eq!(recording.entries().len(), 2);
+      assert!(recording.entries()[0].error().is_some());
+      assert_eq!(
+        recording.entries()[1].event(),
+        &RecordedEvent::Notification {
+          endpoint: Endpoint::Rx,
+          data: vec![2],
+        }
+      );
+    });
+  }
+}
@@ -12,6 +12,7 @@ pub mod xinput;
 #[cfg(feature = "websocket-server-manager")]
 pub mod websocket_server;
 
+pub mod replay;
 pub mod test;
 
 use crate::{core::ButtplugResultFuture, device::ButtplugDeviceImplCreator};
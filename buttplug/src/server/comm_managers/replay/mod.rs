@@ -0,0 +1,8 @@
+mod replay_device;
+mod replay_device_comm_manager;
+
+pub use replay_device::{ReplayDevice, ReplayDeviceImplCreator, ReplayReport};
+pub use replay_device_comm_manager::{
+  ReplayDeviceCommunicationManager,
+  ReplayDeviceCommunicationManagerBuilder,
+};
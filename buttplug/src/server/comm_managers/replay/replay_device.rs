@@ -0,0 +1,521 @@
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{DeviceSpecifier, ProtocolDefinition},
+    recording::{DeviceRecording, RecordedEntry, RecordedEvent},
+    ButtplugDeviceEvent,
+    ButtplugDeviceImplCreator,
+    DeviceImpl,
+    DeviceImplInternal,
+    DeviceReadCmd,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
+    DeviceWriteCmd,
+  },
+  util::async_manager,
+};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::future::{self, BoxFuture};
+use futures_timer::Delay;
+use std::{
+  collections::VecDeque,
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    Mutex,
+  },
+  time::Duration,
+};
+use tokio::sync::{broadcast, mpsc};
+
+fn command_count(entries: &VecDeque<RecordedEntry>) -> usize {
+  entries
+    .iter()
+    .filter(|entry| !entry.event().is_device_event())
+    .count()
+}
+
+/// How replays went, shared by every device of a replay comm manager.
+#[derive(Clone, Default)]
+pub struct ReplayReport {
+  mismatches: Arc<Mutex<Vec<String>>>,
+  /// Recorded commands that haven't been sent yet, keyed by recording index,
+  /// since the same device may be replayed more than once.
+  remaining_commands: Arc<DashMap<usize, usize>>,
+}
+
+impl ReplayReport {
+  /// Commands that didn't match the recording.
+  pub fn mismatches(&self) -> Vec<String> {
+    self
+      .mismatches
+      .lock()
+      .expect("Lock should never be poisoned")
+      .clone()
+  }
+
+  /// Recorded commands that haven't been sent yet, over all devices.
+  pub fn remaining_commands(&self) -> usize {
+    self
+      .remaining_commands
+      .iter()
+      .map(|remaining| *remaining.value())
+      .sum()
+  }
+
+  /// True once every recorded command has been sent, and nothing else was.
+  pub fn is_complete(&self) -> bool {
+    self.remaining_commands() == 0 && self.mismatches().is_empty()
+  }
+
+  /// Adds a recording with this many commands, returning its index.
+  pub(super) fn add_recording(&self, command_count: usize) -> usize {
+    let index = self.remaining_commands.len();
+    self.remaining_commands.insert(index, command_count);
+    index
+  }
+
+  fn set_remaining_commands(&self, recording_index: usize, remaining: usize) {
+    self.remaining_commands.insert(recording_index, remaining);
+  }
+
+  fn add_mismatch(&self, mismatch: String) {
+    warn!("{}", mismatch);
+    self
+      .mismatches
+      .lock()
+      .expect("Lock should never be poisoned")
+      .push(mismatch);
+  }
+}
+
+/// Device that expects the commands of a recording, in order, and plays back
+/// the notifications that followed them.
+pub struct ReplayDevice {
+  address: String,
+  recording_index: usize,
+  entries: Arc<Mutex<VecDeque<RecordedEntry>>>,
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  /// Device events to play back, with the time of the command they followed.
+  /// Played back by a single task, so they keep their recorded order.
+  playback_sender: mpsc::UnboundedSender<(u64, Vec<RecordedEntry>)>,
+  connected: Arc<AtomicBool>,
+  started: AtomicBool,
+  report: ReplayReport,
+}
+
+impl ReplayDevice {
+  fn new(recording: &DeviceRecording, recording_index: usize, report: ReplayReport) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    let (playback_sender, playback_receiver) = mpsc::unbounded_channel();
+    let address = recording.header().address().clone();
+    let connected = Arc::new(AtomicBool::new(true));
+    async_manager::spawn(play_device_events(
+      address.clone(),
+      event_sender.clone(),
+      connected.clone(),
+      playback_receiver,
+    ));
+    Self {
+      address,
+      recording_index,
+      entries: Arc::new(Mutex::new(recording.entries().iter().cloned().collect())),
+      event_sender,
+      playback_sender,
+      connected,
+      started: AtomicBool::new(false),
+      report,
+    }
+  }
+
+  /// Plays back anything the device sent before the first command. Waits
+  /// until someone listens for events or sends a command, so those events
+  /// aren't sent before anyone can get them.
+  fn start(&self, entries: &mut VecDeque<RecordedEntry>) {
+    if !self.started.swap(true, Ordering::SeqCst) {
+      self.play_device_events(entries, 0);
+    }
+  }
+
+  /// Queues the device events at the front of `entries` for playback.
+  /// `time_ms` is when the last command was sent.
+  fn play_device_events(&self, entries: &mut VecDeque<RecordedEntry>, time_ms: u64) {
+    let mut events = vec![];
+    while matches!(entries.front(), Some(entry) if entry.event().is_device_event()) {
+      events.extend(entries.pop_front());
+    }
+    if !events.is_empty() {
+      // The playback task only stops once the device is gone.
+      let _ = self.playback_sender.send((time_ms, events));
+    }
+  }
+
+  /// Checks a command against the next one in the recording, returning the
+  /// recorded command if it matches, or the error it failed with when it was
+  /// recorded.
+  fn replay(&self, command: RecordedEvent) -> Result<RecordedEvent, ButtplugError> {
+    let mut entries = self.entries.lock().expect("Lock should never be poisoned");
+    self.start(&mut entries);
+    let expected = entries.front().cloned();
+    let mismatch = match &expected {
+      None => Some(format!(
+        "Replay of {} got {:?} after the end of the recording.",
+        self.address, command
+      )),
+      Some(expected) => {
+        let matches = match (expected.event(), &command) {
+          // Reads only return data in the recording.
+          (
+            RecordedEvent::Read {
+              endpoint, length, ..
+            },
+            RecordedEvent::Read {
+              endpoint: actual_endpoint,
+              length: actual_length,
+              ..
+            },
+          ) => endpoint == actual_endpoint && length == actual_length,
+          (expected, actual) => expected == actual,
+        };
+        if matches {
+          None
+        } else {
+          Some(format!(
+            "Replay of {} expected {:?}, got {:?}.",
+            self.address,
+            expected.event(),
+            command
+          ))
+        }
+      }
+    };
+    if let Some(mismatch) = mismatch {
+      self.report.add_mismatch(mismatch.clone());
+      return Err(ButtplugDeviceError::DeviceCommunicationError(mismatch).into());
+    }
+    let expected = entries
+      .pop_front()
+      .expect("Already checked that there's an entry");
+    self
+      .report
+      .set_remaining_commands(self.recording_index, command_count(&entries));
+    self.play_device_events(&mut entries, expected.time_ms());
+    if let Some(error) = expected.error() {
+      return Err(ButtplugDeviceError::DeviceCommunicationError(error.clone()).into());
+    }
+    Ok(expected.event().clone())
+  }
+
+  fn replay_command(&self, command: RecordedEvent) -> ButtplugResultFuture {
+    Box::pin(future::ready(self.replay(command).map(|_| ())))
+  }
+}
+
+impl DeviceImplInternal for ReplayDevice {
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.connected.store(false, Ordering::SeqCst);
+    let _ = self
+      .event_sender
+      .send(ButtplugDeviceEvent::Removed(self.address.clone()));
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    let receiver = self.event_sender.subscribe();
+    self.start(&mut self.entries.lock().expect("Lock should never be poisoned"));
+    receiver
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let reading = self
+      .replay(RecordedEvent::Read {
+        endpoint: msg.endpoint,
+        length: msg.length,
+        data: vec![],
+      })
+      .map(|recorded| match recorded {
+        RecordedEvent::Read { data, .. } => RawReading::new(0, msg.endpoint, data),
+        _ => unreachable!("Only reads match reads"),
+      });
+    Box::pin(future::ready(reading))
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    self.replay_command(RecordedEvent::Write {
+      endpoint: msg.endpoint,
+      data: msg.data,
+      write_with_response: msg.write_with_response,
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    self.replay_command(RecordedEvent::Subscribe {
+      endpoint: msg.endpoint,
+    })
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    self.replay_command(RecordedEvent::Unsubscribe {
+      endpoint: msg.endpoint,
+    })
+  }
+}
+
+/// Plays back queued device events in order, keeping the recorded time
+/// between each event and the command before it.
+async fn play_device_events(
+  address: String,
+  sender: broadcast::Sender<ButtplugDeviceEvent>,
+  connected: Arc<AtomicBool>,
+  mut playback_receiver: mpsc::UnboundedReceiver<(u64, Vec<RecordedEntry>)>,
+) {
+  while let Some((time_ms, events)) = playback_receiver.recv().await {
+    let mut last_time_ms = time_ms;
+    for entry in events {
+      Delay::new(Duration::from_millis(
+        entry.time_ms().saturating_sub(last_time_ms),
+      ))
+      .await;
+      last_time_ms = entry.time_ms();
+      let event = match entry.event() {
+        RecordedEvent::Notification { endpoint, data } => {
+          ButtplugDeviceEvent::Notification(address.clone(), *endpoint, data.clone())
+        }
+        _ => {
+          connected.store(false, Ordering::SeqCst);
+          ButtplugDeviceEvent::Removed(address.clone())
+        }
+      };
+      // Nobody may be listening yet, which is fine.
+      let _ = sender.send(event);
+    }
+  }
+}
+
+pub struct ReplayDeviceImplCreator {
+  recording: DeviceRecording,
+  recording_index: usize,
+  report: ReplayReport,
+}
+
+impl ReplayDeviceImplCreator {
+  pub fn new(recording: DeviceRecording, recording_index: usize, report: ReplayReport) -> Self {
+    Self {
+      recording,
+      recording_index,
+      report,
+    }
+  }
+}
+
+impl Debug for ReplayDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ReplayDeviceImplCreator")
+      .field("header", self.recording.header())
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for ReplayDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    self.recording.header().specifier().clone()
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    _protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    let header = self.recording.header();
+    Ok(DeviceImpl::new(
+      header.name(),
+      header.address(),
+      header.endpoints(),
+      Box::new(ReplayDevice::new(
+        &self.recording,
+        self.recording_index,
+        self.report.clone(),
+      )),
+    ))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ReplayDevice, ReplayReport};
+  use crate::{
+    device::{
+      recording::DeviceRecording,
+      ButtplugDeviceEvent,
+      DeviceImplInternal,
+      DeviceReadCmd,
+      DeviceSubscribeCmd,
+      DeviceWriteCmd,
+      Endpoint,
+    },
+    util::async_manager,
+  };
+  use futures_timer::Delay;
+  use std::time::Duration;
+
+  fn replay_device(recording: &str) -> (ReplayDevice, ReplayReport) {
+    let recording = DeviceRecording::from_json_lines(recording).expect("Test, assuming infallible");
+    let report = ReplayReport::default();
+    let index = report.add_recording(recording.command_count());
+    (ReplayDevice::new(&recording, index, report.clone()), report)
+  }
+
+  const RECORDING: &str = r#"
+    {"name":"Replay Test","address":"ReplayAddress","endpoints":["tx","rx"],"specifier":{"BluetoothLE":{"names":["Replay Test"],"services":{}}}}
+    {"time-ms":0,"type":"subscribe","endpoint":"rx"}
+    {"time-ms":5,"type":"write","endpoint":"tx","data":[1,2],"write-with-response":false}
+    {"time-ms":10,"type":"notification","endpoint":"rx","data":[3]}
+    {"time-ms":20,"type":"read","endpoint":"rx","length":1,"data":[4]}
+  "#;
+
+  #[test]
+  pub fn test_replay_device() {
+    async_manager::block_on(async move {
+      let recording =
+        DeviceRecording::from_json_lines(RECORDING).expect("Test, assuming infallible");
+      assert_eq!(
+        DeviceRecording::from_json_lines(&recording.to_json_lines())
+          .expect("Test, assuming infallible"),
+        recording
+      );
+      let (device, report) = replay_device(RECORDING);
+      let mut events = device.event_stream();
+      device
+        .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
+        .await
+        .expect("Test, assuming infallible");
+      // Writes have to match exactly.
+      assert!(device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![1, 3], false))
+        .await
+        .is_err());
+      device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![1, 2], false))
+        .await
+        .expect("Test, assuming infallible");
+      match events.recv().await.expect("Test, assuming infallible") {
+        ButtplugDeviceEvent::Notification(address, endpoint, data) => {
+          assert_eq!(address, "ReplayAddress");
+          assert_eq!(endpoint, Endpoint::Rx);
+          assert_eq!(data, vec![3]);
+        }
+        event => panic!("Expected a notification, got {:?}", event),
+      }
+      let reading = device
+        .read_value(DeviceReadCmd::new(Endpoint::Rx, 1, 0))
+        .await
+        .expect("Test, assuming infallible");
+      assert_eq!(reading.data(), &vec![4]);
+      assert_eq!(report.remaining_commands(), 0);
+      assert_eq!(report.mismatches().len(), 1);
+      // Nothing's left to replay.
+      assert!(device
+        .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_replay_device_events_before_first_command() {
+    async_manager::block_on(async move {
+      let (device, _report) = replay_device(
+        r#"
+          {"name":"Replay Test","address":"ReplayAddress","endpoints":["tx","rx"],"specifier":{"BluetoothLE":{"names":["Replay Test"],"services":{}}}}
+          {"time-ms":0,"type":"notification","endpoint":"rx","data":[1]}
+          {"time-ms":5,"type":"subscribe","endpoint":"rx"}
+        "#,
+      );
+      // Nothing's played back until someone's listening.
+      Delay::new(Duration::from_millis(50)).await;
+      let mut events = device.event_stream();
+      match events.recv().await.expect("Test, assuming infallible") {
+        ButtplugDeviceEvent::Notification(_, endpoint, data) => {
+          assert_eq!(endpoint, Endpoint::Rx);
+          assert_eq!(data, vec![1]);
+        }
+        event => panic!("Expected a notification, got {:?}", event),
+      }
+    });
+  }
+
+  #[test]
+  pub fn test_replay_device_keeps_event_order() {
+    async_manager::block_on(async move {
+      let (device, _report) = replay_device(
+        r#"
+          {"name":"Replay Test","address":"ReplayAddress","endpoints":["tx","rx"],"specifier":{"BluetoothLE":{"names":["Replay Test"],"services":{}}}}
+          {"time-ms":0,"type":"write","endpoint":"tx","data":[1],"write-with-response":false}
+          {"time-ms":50,"type":"notification","endpoint":"rx","data":[1]}
+          {"time-ms":60,"type":"write","endpoint":"tx","data":[2],"write-with-response":false}
+          {"time-ms":61,"type":"notification","endpoint":"rx","data":[2]}
+        "#,
+      );
+      let mut events = device.event_stream();
+      // Sending the second command right away doesn't let its notification
+      // overtake the first one's.
+      device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![1], false))
+        .await
+        .expect("Test, assuming infallible");
+      device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![2], false))
+        .await
+        .expect("Test, assuming infallible");
+      for expected in [vec![1], vec![2]] {
+        match events.recv().await.expect("Test, assuming infallible") {
+          ButtplugDeviceEvent::Notification(_, _, data) => assert_eq!(data, expected),
+          event => panic!("Expected a notification, got {:?}", event),
+        }
+      }
+    });
+  }
+
+  #[test]
+  pub fn test_replay_device_errors_and_disconnect() {
+    async_manager::block_on(async move {
+      let (device, report) = replay_device(
+        r#"
+          {"name":"Replay Test","address":"ReplayAddress","endpoints":["tx","rx"],"specifier":{"BluetoothLE":{"names":["Replay Test"],"services":{}}}}
+          {"time-ms":0,"type":"write","endpoint":"tx","data":[1],"write-with-response":true,"error":"Write failed"}
+          {"time-ms":5,"type":"write","endpoint":"tx","data":[1],"write-with-response":true}
+          {"time-ms":10,"type":"disconnected"}
+        "#,
+      );
+      let mut events = device.event_stream();
+      // Commands that failed when recorded fail again, without a mismatch.
+      assert!(device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![1], true))
+        .await
+        .is_err());
+      device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![1], true))
+        .await
+        .expect("Test, assuming infallible");
+      assert!(report.is_complete());
+      match events.recv().await.expect("Test, assuming infallible") {
+        ButtplugDeviceEvent::Removed(address) => assert_eq!(address, "ReplayAddress"),
+        event => panic!("Expected a removal, got {:?}", event),
+      }
+      assert!(!device.connected());
+    });
+  }
+}
@@ -0,0 +1,108 @@
+use super::replay_device::{ReplayDeviceImplCreator, ReplayReport};
+use crate::{
+  core::ButtplugResultFuture,
+  device::recording::DeviceRecording,
+  server::comm_managers::{
+    DeviceCommunicationEvent,
+    DeviceCommunicationManager,
+    DeviceCommunicationManagerBuilder,
+  },
+};
+use futures::future;
+use std::sync::Arc;
+use tokio::sync::{mpsc::Sender, Mutex};
+
+#[derive(Default)]
+pub struct ReplayDeviceCommunicationManagerBuilder {
+  sender: Option<Sender<DeviceCommunicationEvent>>,
+  recordings: Vec<(usize, DeviceRecording)>,
+  report: ReplayReport,
+}
+
+impl ReplayDeviceCommunicationManagerBuilder {
+  /// Adds a device to replay. It's found on the next scan.
+  pub fn recording(mut self, recording: DeviceRecording) -> Self {
+    let index = self.report.add_recording(recording.command_count());
+    self.recordings.push((index, recording));
+    self
+  }
+
+  /// Report to check once the replay is done.
+  pub fn report(&self) -> ReplayReport {
+    self.report.clone()
+  }
+}
+
+impl DeviceCommunicationManagerBuilder for ReplayDeviceCommunicationManagerBuilder {
+  fn event_sender(mut self, sender: Sender<DeviceCommunicationEvent>) -> Self {
+    self.sender = Some(sender);
+    self
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    Box::new(ReplayDeviceCommunicationManager {
+      device_sender: self.sender.take().expect("We always have this."),
+      recordings: Arc::new(Mutex::new(self.recordings)),
+      report: self.report,
+    })
+  }
+}
+
+/// Stands up fake devices from recordings, see
+/// [recording](crate::device::recording). Every recorded command has to be
+/// sent to the device in the same order, otherwise the command fails and a
+/// mismatch is added to the [ReplayReport].
+pub struct ReplayDeviceCommunicationManager {
+  device_sender: Sender<DeviceCommunicationEvent>,
+  recordings: Arc<Mutex<Vec<(usize, DeviceRecording)>>>,
+  report: ReplayReport,
+}
+
+impl DeviceCommunicationManager for ReplayDeviceCommunicationManager {
+  fn name(&self) -> &'static str {
+    "ReplayDeviceCommunicationManager"
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    let recordings = self.recordings.clone();
+    let device_sender = self.device_sender.clone();
+    let report = self.report.clone();
+    Box::pin(async move {
+      // Each recording can only be replayed once.
+      let recordings: Vec<(usize, DeviceRecording)> = recordings.lock().await.drain(..).collect();
+      for (index, recording) in recordings {
+        if device_sender
+          .send(DeviceCommunicationEvent::DeviceFound {
+            name: recording.header().name().clone(),
+            address: recording.header().address().clone(),
+            creator: Box::new(ReplayDeviceImplCreator::new(
+              recording,
+              index,
+              report.clone(),
+            )),
+          })
+          .await
+          .is_err()
+        {
+          error!("Device channel no longer open.");
+        }
+      }
+      if device_sender
+        .send(DeviceCommunicationEvent::ScanningFinished)
+        .await
+        .is_err()
+      {
+        error!("Error sending scanning finished. Scanning may not register as finished now!");
+      }
+      Ok(())
+    })
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn can_scan(&self) -> bool {
+    true
+  }
+}
@@ -16,6 +16,7 @@ pub use test_device::{
 #[cfg(feature = "server")]
 pub use test_device_comm_manager::{
   new_bluetoothle_test_device,
+  new_bluetoothle_test_device_with_cfg,
   TestDeviceCommunicationManager,
   TestDeviceCommunicationManagerBuilder,
   TestDeviceCommunicationManagerHelper,
@@ -19,7 +19,7 @@ use crate::{
   },
 };
 use async_trait::async_trait;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use futures::future::{self, BoxFuture};
 use std::{
   fmt::{self, Debug},
@@ -114,6 +114,8 @@ pub struct TestDeviceInternal {
   name: String,
   address: String,
   endpoint_channels: Arc<DashMap<Endpoint, TestDeviceEndpointChannel>>,
+  subscribed_endpoints: Arc<DashSet<Endpoint>>,
+  read_values: Arc<DashMap<Endpoint, Vec<u8>>>,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
 }
 
@@ -124,6 +126,8 @@ impl TestDeviceInternal {
       name: name.to_owned(),
       address: address.to_owned(),
       endpoint_channels: Arc::new(DashMap::new()),
+      subscribed_endpoints: Arc::new(DashSet::new()),
+      read_values: Arc::new(DashMap::new()),
       event_sender,
     }
   }
@@ -144,6 +148,16 @@ impl TestDeviceInternal {
     self.address.clone()
   }
 
+  pub fn is_subscribed(&self, endpoint: &Endpoint) -> bool {
+    self.subscribed_endpoints.contains(endpoint)
+  }
+
+  /// Sets the data returned by reads from the endpoint. Endpoints without data
+  /// set return empty readings.
+  pub fn set_read_value(&self, endpoint: Endpoint, data: Vec<u8>) {
+    self.read_values.insert(endpoint, data);
+  }
+
   pub fn get_endpoint_receiver(
     &self,
     endpoint: &Endpoint,
@@ -182,6 +196,8 @@ pub struct TestDevice {
   // for creation in ButtplugDevice, so initialization and cloning order
   // matters here.
   pub endpoint_channels: Arc<DashMap<Endpoint, TestDeviceEndpointChannel>>,
+  subscribed_endpoints: Arc<DashSet<Endpoint>>,
+  read_values: Arc<DashMap<Endpoint, Vec<u8>>>,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
 }
 
@@ -191,6 +207,8 @@ impl TestDevice {
     Self {
       address: internal_device.address(),
       endpoint_channels: internal_device.endpoint_channels.clone(),
+      subscribed_endpoints: internal_device.subscribed_endpoints.clone(),
+      read_values: internal_device.read_values.clone(),
       event_sender: internal_device.sender(),
     }
   }
@@ -220,7 +238,12 @@ impl DeviceImplInternal for TestDevice {
     &self,
     msg: DeviceReadCmd,
   ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
-    Box::pin(future::ready(Ok(RawReading::new(0, msg.endpoint, vec![]))))
+    let data = self
+      .read_values
+      .get(&msg.endpoint)
+      .map(|data| data.value().clone())
+      .unwrap_or_default();
+    Box::pin(future::ready(Ok(RawReading::new(0, msg.endpoint, data))))
   }
 
   fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
@@ -238,11 +261,13 @@ impl DeviceImplInternal for TestDevice {
     })
   }
 
-  fn subscribe(&self, _msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    self.subscribed_endpoints.insert(msg.endpoint);
     Box::pin(future::ready(Ok(())))
   }
 
-  fn unsubscribe(&self, _msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    self.subscribed_endpoints.remove(&msg.endpoint);
     Box::pin(future::ready(Ok(())))
   }
 }
@@ -42,7 +42,7 @@ fn new_uninitialized_ble_test_device(
   (device_impl_clone, device_impl_creator)
 }
 
-async fn new_bluetoothle_test_device_with_cfg(
+pub async fn new_bluetoothle_test_device_with_cfg(
   name: &str,
   device_config_mgr: Option<Arc<DeviceConfigurationManager>>,
 ) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
@@ -52,8 +52,7 @@ async fn new_bluetoothle_test_device_with_cfg(
   let err_str = &format!("No protocol found for device {}", name);
   let device: ButtplugDevice =
     ButtplugDevice::try_create_device(config_mgr, Box::new(device_impl_creator))
-      .await
-      .expect("Empty option shouldn't be possible")
+      .await?
       .expect(err_str);
   Ok((device, device_impl_clone))
 }
@@ -0,0 +1,153 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Decides which client session gets to command a device when more than one
+//! session is connected to the same server.
+
+use crate::core::errors::ButtplugDeviceError;
+use dashmap::{mapref::entry::Entry, DashMap};
+
+/// Rules for sharing devices between client sessions.
+///
+/// Every session that commands a device becomes that device's owner. The
+/// policy decides what happens when a different session then tries to command
+/// the same device. Ownership is released when the owning session disconnects
+/// or pings out, or when the device is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceArbitrationPolicy {
+  /// Any session can command any device, and the most recent command wins.
+  /// This is how the server behaves with a single client.
+  #[default]
+  LastWriterWins,
+  /// The first session to command a device keeps it until it disconnects.
+  /// Commands from other sessions are rejected.
+  ExclusiveLock,
+  /// A session can take a device from another session with the same or lower
+  /// priority. Commands from lower priority sessions are rejected.
+  Priority,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DeviceOwner {
+  session_id: u32,
+  priority: u32,
+}
+
+pub(super) struct DeviceArbiter {
+  policy: DeviceArbitrationPolicy,
+  owners: DashMap<u32, DeviceOwner>,
+}
+
+impl DeviceArbiter {
+  pub fn new(policy: DeviceArbitrationPolicy) -> Self {
+    Self {
+      policy,
+      owners: DashMap::new(),
+    }
+  }
+
+  fn allowed(&self, owner: &DeviceOwner, session_id: u32, priority: u32) -> bool {
+    if owner.session_id == session_id {
+      return true;
+    }
+    match self.policy {
+      DeviceArbitrationPolicy::LastWriterWins => true,
+      DeviceArbitrationPolicy::ExclusiveLock => false,
+      DeviceArbitrationPolicy::Priority => priority >= owner.priority,
+    }
+  }
+
+  /// Returns true if the session could command the device right now, without
+  /// taking ownership of it.
+  pub fn can_control(&self, session_id: u32, priority: u32, device_index: u32) -> bool {
+    self
+      .owners
+      .get(&device_index)
+      .is_none_or(|owner| self.allowed(&owner, session_id, priority))
+  }
+
+  /// Makes the session the owner of the device, if the policy allows it.
+  pub fn claim(
+    &self,
+    session_id: u32,
+    priority: u32,
+    device_index: u32,
+  ) -> Result<(), ButtplugDeviceError> {
+    let owner = DeviceOwner {
+      session_id,
+      priority,
+    };
+    match self.owners.entry(device_index) {
+      Entry::Occupied(mut entry) => {
+        if !self.allowed(entry.get(), session_id, priority) {
+          return Err(ButtplugDeviceError::DeviceInUse(device_index));
+        }
+        entry.insert(owner);
+      }
+      Entry::Vacant(entry) => {
+        entry.insert(owner);
+      }
+    }
+    Ok(())
+  }
+
+  /// Device indexes currently owned by the session.
+  pub fn owned_devices(&self, session_id: u32) -> Vec<u32> {
+    self
+      .owners
+      .iter()
+      .filter(|owner| owner.value().session_id == session_id)
+      .map(|owner| *owner.key())
+      .collect()
+  }
+
+  pub fn release_session(&self, session_id: u32) {
+    self
+      .owners
+      .retain(|_, owner| owner.session_id != session_id);
+  }
+
+  pub fn release_device(&self, device_index: u32) {
+    self.owners.remove(&device_index);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{DeviceArbiter, DeviceArbitrationPolicy};
+
+  #[test]
+  fn test_last_writer_wins() {
+    let arbiter = DeviceArbiter::new(DeviceArbitrationPolicy::LastWriterWins);
+    assert!(arbiter.claim(1, 0, 0).is_ok());
+    assert!(arbiter.claim(2, 0, 0).is_ok());
+    assert_eq!(arbiter.owned_devices(1), Vec::<u32>::new());
+    assert_eq!(arbiter.owned_devices(2), vec![0]);
+  }
+
+  #[test]
+  fn test_exclusive_lock() {
+    let arbiter = DeviceArbiter::new(DeviceArbitrationPolicy::ExclusiveLock);
+    assert!(arbiter.claim(1, 0, 0).is_ok());
+    assert!(arbiter.claim(2, 10, 0).is_err());
+    assert!(!arbiter.can_control(2, 10, 0));
+    assert!(arbiter.claim(2, 0, 1).is_ok());
+    arbiter.release_session(1);
+    assert!(arbiter.claim(2, 0, 0).is_ok());
+  }
+
+  #[test]
+  fn test_priority() {
+    let arbiter = DeviceArbiter::new(DeviceArbitrationPolicy::Priority);
+    assert!(arbiter.claim(1, 5, 0).is_ok());
+    assert!(arbiter.claim(2, 1, 0).is_err());
+    assert!(arbiter.claim(3, 5, 0).is_ok());
+    assert!(arbiter.claim(1, 5, 0).is_ok());
+    arbiter.release_device(0);
+    assert!(arbiter.claim(2, 1, 0).is_ok());
+  }
+}
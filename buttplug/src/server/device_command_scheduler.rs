@@ -0,0 +1,454 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Rate limiting of actuator commands to devices.
+//!
+//! Some clients send commands far faster than devices can handle them, and
+//! some protocols (Lovense being the main offender) will queue up everything
+//! they're sent, meaning devices can end up running seconds behind. For
+//! protocols that have a `min-command-interval` set in the device
+//! configuration, the device manager runs commands through a
+//! [DeviceCommandScheduler], which sends at most one command of each type per
+//! interval.
+//!
+//! Vibrate, rotate and scalar commands set a level, so only the latest value
+//! requested for each feature matters, and values waiting to be sent are merged
+//! together. Requests whose values are all overwritten by newer requests
+//! before being sent are resolved with Ok, since the device will end up in the
+//! state the client most recently asked for either way.
+//!
+//! Linear commands are movements, and skipping one changes the path the device
+//! takes, so they're never merged. Instead they're queued and sent in the
+//! order they came in, one per interval.
+
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessageType,
+      ButtplugServerMessage,
+      LinearCmd,
+      RotateCmd,
+      RotationSubcommand,
+      ScalarCmd,
+      ScalarSubcommand,
+      VibrateCmd,
+      VibrateSubcommand,
+    },
+  },
+  device::{ButtplugDevice, ButtplugDeviceResultFuture},
+  util::async_manager,
+};
+use dashmap::DashMap;
+use futures_timer::Delay;
+use std::{
+  collections::{HashMap, VecDeque},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::sync::oneshot;
+
+type SchedulerResult = Result<ButtplugServerMessage, ButtplugError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ActuatorCommandKind {
+  Vibrate,
+  Rotate,
+  Scalar,
+}
+
+impl ActuatorCommandKind {
+  fn message_type(&self) -> ButtplugDeviceMessageType {
+    match self {
+      Self::Vibrate => ButtplugDeviceMessageType::VibrateCmd,
+      Self::Rotate => ButtplugDeviceMessageType::RotateCmd,
+      Self::Scalar => ButtplugDeviceMessageType::ScalarCmd,
+    }
+  }
+}
+
+/// Latest value requested for a single feature.
+#[derive(Clone, Debug)]
+enum FeatureValue {
+  Vibrate(VibrateSubcommand),
+  Rotate(RotationSubcommand),
+  Scalar(ScalarSubcommand),
+}
+
+/// How the scheduler treats an incoming message.
+enum ScheduledMessage {
+  /// Actuator command whose values can be merged with other commands of the
+  /// same kind.
+  Coalesce(ActuatorCommandKind, Vec<(u32, FeatureValue)>),
+  /// Linear command, which is queued behind any other linear commands waiting
+  /// to be sent.
+  Sequence(LinearCmd),
+  /// Command that changes device output on its own terms, so anything still
+  /// pending should be thrown out before it is sent.
+  Reset(ButtplugDeviceCommandMessageUnion),
+  /// Command that doesn't affect device output, and can skip scheduling.
+  Passthrough(ButtplugDeviceCommandMessageUnion),
+}
+
+impl From<ButtplugDeviceCommandMessageUnion> for ScheduledMessage {
+  fn from(msg: ButtplugDeviceCommandMessageUnion) -> Self {
+    match msg {
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) if !msg.speeds().is_empty() => {
+        ScheduledMessage::Coalesce(
+          ActuatorCommandKind::Vibrate,
+          msg
+            .speeds()
+            .iter()
+            .map(|cmd| (cmd.index(), FeatureValue::Vibrate(cmd.clone())))
+            .collect(),
+        )
+      }
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) if !msg.rotations.is_empty() => {
+        ScheduledMessage::Coalesce(
+          ActuatorCommandKind::Rotate,
+          msg
+            .rotations
+            .iter()
+            .map(|cmd| (cmd.index(), FeatureValue::Rotate(cmd.clone())))
+            .collect(),
+        )
+      }
+      ButtplugDeviceCommandMessageUnion::LinearCmd(msg) if !msg.vectors().is_empty() => {
+        ScheduledMessage::Sequence(msg)
+      }
+      ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) if !msg.scalars().is_empty() => {
+        ScheduledMessage::Coalesce(
+          ActuatorCommandKind::Scalar,
+          msg
+            .scalars()
+            .iter()
+            .map(|cmd| (cmd.index(), FeatureValue::Scalar(cmd.clone())))
+            .collect(),
+        )
+      }
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_)
+      | ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(_)
+      | ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(_)
+      | ButtplugDeviceCommandMessageUnion::KiirooCmd(_) => ScheduledMessage::Reset(msg),
+      msg => ScheduledMessage::Passthrough(msg),
+    }
+  }
+}
+
+struct PendingRequest {
+  kind: ActuatorCommandKind,
+  sender: oneshot::Sender<SchedulerResult>,
+  /// Number of pending feature values that came from this request. Once this
+  /// hits 0, all of the request's values have been superseded.
+  remaining: usize,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+  values: HashMap<(ActuatorCommandKind, u32), (FeatureValue, u32)>,
+  requests: HashMap<u32, PendingRequest>,
+  request_id: u32,
+  /// Linear commands waiting to be sent, oldest first.
+  linear_queue: VecDeque<(LinearCmd, oneshot::Sender<SchedulerResult>)>,
+  /// True while the flush task is running, either sending commands or waiting
+  /// out the interval after sending.
+  flushing: bool,
+}
+
+impl SchedulerState {
+  fn resolve_all_ok(&mut self) {
+    self.values.clear();
+    for (_, request) in self.requests.drain() {
+      let _ = request.sender.send(Ok(messages::Ok::default().into()));
+    }
+    for (_, sender) in self.linear_queue.drain(..) {
+      let _ = sender.send(Ok(messages::Ok::default().into()));
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.values.is_empty() && self.linear_queue.is_empty()
+  }
+}
+
+/// Coalesces actuator commands for a single device, so they're sent no more
+/// often than the device's protocol can handle.
+pub struct DeviceCommandScheduler {
+  device_index: u32,
+  device: Arc<ButtplugDevice>,
+  interval: Duration,
+  state: Arc<Mutex<SchedulerState>>,
+  /// Held while commands are being sent to the device, so commands that skip
+  /// the queue still arrive in order relative to queued ones.
+  send_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl DeviceCommandScheduler {
+  pub fn new(device_index: u32, device: Arc<ButtplugDevice>, interval: Duration) -> Self {
+    Self {
+      device_index,
+      device,
+      interval,
+      state: Arc::new(Mutex::new(SchedulerState::default())),
+      send_lock: Arc::new(tokio::sync::Mutex::new(())),
+    }
+  }
+
+  pub fn parse_message(
+    &self,
+    msg: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceResultFuture {
+    match ScheduledMessage::from(msg) {
+      ScheduledMessage::Coalesce(kind, values) => self.queue(kind, values),
+      ScheduledMessage::Sequence(msg) => self.queue_linear(msg),
+      ScheduledMessage::Reset(msg) => {
+        self
+          .state
+          .lock()
+          .expect("Scheduler state lock should never be poisoned.")
+          .resolve_all_ok();
+        let send_lock = self.send_lock.clone();
+        let device = self.device.clone();
+        Box::pin(async move {
+          let _guard = send_lock.lock().await;
+          device.parse_message(msg).await
+        })
+      }
+      ScheduledMessage::Passthrough(msg) => self.device.parse_message(msg),
+    }
+  }
+
+  fn queue(
+    &self,
+    kind: ActuatorCommandKind,
+    values: Vec<(u32, FeatureValue)>,
+  ) -> ButtplugDeviceResultFuture {
+    // Let anything addressing features the device doesn't have go straight
+    // through, so the protocol can error on it without taking the rest of the
+    // queued values down with it.
+    let feature_count = self
+      .device
+      .message_attributes()
+      .get(&kind.message_type())
+      .and_then(|attrs| attrs.feature_count)
+      .unwrap_or(0);
+    if values.iter().any(|(index, _)| *index >= feature_count) {
+      return self.device.parse_message(build_message(
+        self.device_index,
+        kind,
+        values.into_iter().map(|(_, v)| v),
+      ));
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let mut state = self
+      .state
+      .lock()
+      .expect("Scheduler state lock should never be poisoned.");
+    state.request_id = state.request_id.wrapping_add(1);
+    let request_id = state.request_id;
+    let mut remaining = 0;
+    for (index, value) in values {
+      match state.values.insert((kind, index), (value, request_id)) {
+        Some((_, old_id)) if old_id == request_id => {}
+        Some((_, old_id)) => {
+          remaining += 1;
+          let superseded = state.requests.get_mut(&old_id).is_some_and(|request| {
+            request.remaining -= 1;
+            request.remaining == 0
+          });
+          if superseded {
+            if let Some(request) = state.requests.remove(&old_id) {
+              let _ = request.sender.send(Ok(messages::Ok::default().into()));
+            }
+          }
+        }
+        None => remaining += 1,
+      }
+    }
+    state.requests.insert(
+      request_id,
+      PendingRequest {
+        kind,
+        sender,
+        remaining,
+      },
+    );
+    self.start_flush(&mut state);
+    self.wait_for_reply(receiver)
+  }
+
+  fn queue_linear(&self, msg: LinearCmd) -> ButtplugDeviceResultFuture {
+    // Same as with other commands, let invalid feature indexes go through to
+    // the protocol to be errored on.
+    let feature_count = self
+      .device
+      .message_attributes()
+      .get(&ButtplugDeviceMessageType::LinearCmd)
+      .and_then(|attrs| attrs.feature_count)
+      .unwrap_or(0);
+    if msg.vectors().iter().any(|cmd| cmd.index() >= feature_count) {
+      return self.device.parse_message(msg.into());
+    }
+    let (sender, receiver) = oneshot::channel();
+    let mut state = self
+      .state
+      .lock()
+      .expect("Scheduler state lock should never be poisoned.");
+    state.linear_queue.push_back((msg, sender));
+    self.start_flush(&mut state);
+    self.wait_for_reply(receiver)
+  }
+
+  fn start_flush(&self, state: &mut SchedulerState) {
+    if !state.flushing {
+      state.flushing = true;
+      async_manager::spawn(flush(
+        self.device_index,
+        self.device.clone(),
+        self.interval,
+        self.state.clone(),
+        self.send_lock.clone(),
+      ));
+    }
+  }
+
+  fn wait_for_reply(
+    &self,
+    receiver: oneshot::Receiver<SchedulerResult>,
+  ) -> ButtplugDeviceResultFuture {
+    let device_index = self.device_index;
+    Box::pin(async move {
+      receiver
+        .await
+        .unwrap_or_else(|_| Err(ButtplugDeviceError::DeviceNotAvailable(device_index).into()))
+    })
+  }
+}
+
+fn build_message(
+  device_index: u32,
+  kind: ActuatorCommandKind,
+  values: impl Iterator<Item = FeatureValue>,
+) -> ButtplugDeviceCommandMessageUnion {
+  match kind {
+    ActuatorCommandKind::Vibrate => VibrateCmd::new(
+      device_index,
+      values
+        .filter_map(|value| match value {
+          FeatureValue::Vibrate(cmd) => Some(cmd),
+          _ => None,
+        })
+        .collect(),
+    )
+    .into(),
+    ActuatorCommandKind::Rotate => RotateCmd::new(
+      device_index,
+      values
+        .filter_map(|value| match value {
+          FeatureValue::Rotate(cmd) => Some(cmd),
+          _ => None,
+        })
+        .collect(),
+    )
+    .into(),
+    ActuatorCommandKind::Scalar => ScalarCmd::new(
+      device_index,
+      values
+        .filter_map(|value| match value {
+          FeatureValue::Scalar(cmd) => Some(cmd),
+          _ => None,
+        })
+        .collect(),
+    )
+    .into(),
+  }
+}
+
+/// Sends whatever is pending, along with the oldest queued linear command, then
+/// waits out the interval, until there's nothing left to send.
+async fn flush(
+  device_index: u32,
+  device: Arc<ButtplugDevice>,
+  interval: Duration,
+  state: Arc<Mutex<SchedulerState>>,
+  send_lock: Arc<tokio::sync::Mutex<()>>,
+) {
+  loop {
+    {
+      let _guard = send_lock.lock().await;
+      let (values, mut requests, linear) = {
+        let mut state = state
+          .lock()
+          .expect("Scheduler state lock should never be poisoned.");
+        if state.is_empty() {
+          state.flushing = false;
+          return;
+        }
+        (
+          std::mem::take(&mut state.values),
+          std::mem::take(&mut state.requests),
+          state.linear_queue.pop_front(),
+        )
+      };
+      let mut batches: HashMap<ActuatorCommandKind, Vec<(u32, FeatureValue)>> = HashMap::new();
+      for ((kind, index), (value, _)) in values {
+        batches.entry(kind).or_default().push((index, value));
+      }
+      for (kind, mut batch) in batches {
+        batch.sort_by_key(|(index, _)| *index);
+        let result = device
+          .parse_message(build_message(
+            device_index,
+            kind,
+            batch.into_iter().map(|(_, value)| value),
+          ))
+          .await;
+        if let Err(e) = &result {
+          error!("Error sending scheduled command to device: {:?}", e);
+        }
+        let ids: Vec<u32> = requests
+          .iter()
+          .filter(|(_, request)| request.kind == kind)
+          .map(|(id, _)| *id)
+          .collect();
+        for id in ids {
+          if let Some(request) = requests.remove(&id) {
+            let _ = request.sender.send(result.clone());
+          }
+        }
+      }
+      if let Some((msg, sender)) = linear {
+        let result = device.parse_message(msg.into()).await;
+        if let Err(e) = &result {
+          error!("Error sending scheduled command to device: {:?}", e);
+        }
+        let _ = sender.send(result);
+      }
+    }
+    Delay::new(interval).await;
+  }
+}
+
+/// Sends a message to a device, going through its scheduler if it has one.
+pub(super) fn schedule_device_message(
+  schedulers: &DashMap<u32, Arc<DeviceCommandScheduler>>,
+  device_index: u32,
+  device: &Arc<ButtplugDevice>,
+  msg: ButtplugDeviceCommandMessageUnion,
+) -> ButtplugDeviceResultFuture {
+  let scheduler = schedulers
+    .get(&device_index)
+    .map(|scheduler| scheduler.value().clone());
+  match scheduler {
+    Some(scheduler) => scheduler.parse_message(msg),
+    None => device.parse_message(msg),
+  }
+}
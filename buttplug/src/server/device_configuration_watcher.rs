@@ -0,0 +1,113 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Reloads device configuration files when they change on disk.
+
+use super::device_manager::DeviceManager;
+use crate::{
+  core::errors::{ButtplugDeviceError, ButtplugError},
+  util::device_configuration::{
+    load_protocol_config,
+    load_protocol_config_from_json,
+    ProtocolConfiguration,
+    ProtocolConfigurationFormat,
+    DEVICE_CONFIGURATION_JSON,
+  },
+};
+use futures_timer::Delay;
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::Weak,
+  time::{Duration, SystemTime},
+};
+
+fn load_file(
+  path: &Path,
+  skip_version_check: bool,
+) -> Result<ProtocolConfiguration, ButtplugError> {
+  let config = fs::read_to_string(path).map_err(|err| {
+    ButtplugDeviceError::DeviceConfigurationFileError(format!(
+      "Cannot read {}: {}",
+      path.display(),
+      err
+    ))
+  })?;
+  load_protocol_config(
+    &config,
+    ProtocolConfigurationFormat::from_path(path)?,
+    skip_version_check,
+  )
+}
+
+/// Loads the device configuration (or the built in one, if there's no file)
+/// and merges the user device configuration into it, the same way the server
+/// builder does.
+fn load_configuration(
+  device_configuration: &Option<PathBuf>,
+  user_device_configuration: &Option<PathBuf>,
+) -> Result<ProtocolConfiguration, ButtplugError> {
+  let mut config = match device_configuration {
+    Some(path) => load_file(path, false)?,
+    None => load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON, false)?,
+  };
+  if let Some(path) = user_device_configuration {
+    // Skip checking the version of user device config files, same as the
+    // builder.
+    config.merge(load_file(path, true)?);
+  }
+  Ok(config)
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+  paths
+    .iter()
+    .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+    .collect()
+}
+
+/// Polls the configuration files for changes, reloading them into the device
+/// manager whenever one changes. Runs until the device manager is dropped.
+pub(super) async fn watch_device_configuration(
+  device_manager: Weak<DeviceManager>,
+  device_configuration: Option<PathBuf>,
+  user_device_configuration: Option<PathBuf>,
+  poll_interval: Duration,
+) {
+  let paths: Vec<PathBuf> = device_configuration
+    .iter()
+    .chain(user_device_configuration.iter())
+    .cloned()
+    .collect();
+  let mut last_modified = modified_times(&paths);
+  loop {
+    Delay::new(poll_interval).await;
+    let device_manager = if let Some(device_manager) = device_manager.upgrade() {
+      device_manager
+    } else {
+      debug!("Device manager dropped, stopping device configuration watcher.");
+      return;
+    };
+    let modified = modified_times(&paths);
+    if modified == last_modified {
+      continue;
+    }
+    last_modified = modified;
+    info!("Device configuration files changed, reloading.");
+    match load_configuration(&device_configuration, &user_device_configuration) {
+      Ok(config) => {
+        if let Err(err) = device_manager.update_configuration(config).await {
+          error!("Error updating device configuration: {}", err);
+        }
+      }
+      Err(err) => error!(
+        "Cannot reload device configuration, keeping the current one: {}",
+        err
+      ),
+    }
+  }
+}
@@ -0,0 +1,251 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Remembers which index each device was given, so devices keep the same index
+//! whenever they reconnect.
+//!
+//! By default this only lasts as long as the server. With a store file, known
+//! devices are saved whenever they change and loaded again on startup, so
+//! indexes stay stable across server restarts, and clients can safely save
+//! things like "index 2 is my partner's toy". Display names for devices live
+//! in the device user configuration, not here.
+
+use super::ButtplugServerError;
+use crate::{
+  core::errors::{ButtplugDeviceError, ButtplugError},
+  util::{async_manager, file::write_atomically},
+};
+use dashmap::DashMap;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashMap,
+  fs,
+  io::ErrorKind,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+    Mutex,
+  },
+};
+use tokio::sync::mpsc;
+
+/// A device the server has seen at some point.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct KnownDevice {
+  /// Index the device is given whenever it connects.
+  #[getset(get_copy = "pub")]
+  index: u32,
+  /// Device name from the last time the device connected.
+  #[getset(get = "pub")]
+  name: String,
+  /// Protocol used the last time the device connected.
+  #[getset(get = "pub")]
+  protocol: String,
+}
+
+/// On disk format of the store file.
+#[derive(Serialize, Deserialize, Default)]
+struct DeviceIndexStoreFile {
+  /// Kept separately from the devices, so indexes of forgotten devices aren't
+  /// handed out again.
+  #[serde(rename = "next-index", default)]
+  next_index: u32,
+  #[serde(default)]
+  devices: HashMap<String, KnownDevice>,
+}
+
+/// Known devices, keyed by address.
+pub struct DeviceIndexStore {
+  path: Option<PathBuf>,
+  devices: Arc<DashMap<String, KnownDevice>>,
+  next_index: Arc<AtomicU32>,
+  /// Tells the writer task there are changes to save. Only holds one message,
+  /// so changes made while a save is pending are picked up by that save.
+  save_sender: mpsc::Sender<()>,
+  /// Taken by the writer task when it's started, on the first save. Files are
+  /// only ever written from that task, so saves never block whoever changed
+  /// the store, and never interleave.
+  save_receiver: Mutex<Option<mpsc::Receiver<()>>>,
+}
+
+impl Default for DeviceIndexStore {
+  fn default() -> Self {
+    Self::new(None, HashMap::new(), 0)
+  }
+}
+
+impl DeviceIndexStore {
+  /// Loads the store from a file. A missing file is treated as an empty store,
+  /// and will be created once a device connects.
+  pub fn load(path: PathBuf) -> Result<Self, ButtplugError> {
+    let file = match fs::read_to_string(&path) {
+      Ok(contents) => serde_json::from_str(&contents).map_err(|err| {
+        ButtplugDeviceError::DeviceConfigurationFileError(format!(
+          "Cannot parse device index store {}: {}",
+          path.display(),
+          err
+        ))
+      })?,
+      Err(err) if err.kind() == ErrorKind::NotFound => {
+        info!(
+          "Device index store {} does not exist yet, starting empty.",
+          path.display()
+        );
+        DeviceIndexStoreFile::default()
+      }
+      Err(err) => {
+        return Err(
+          ButtplugDeviceError::DeviceConfigurationFileError(format!(
+            "Cannot read device index store {}: {}",
+            path.display(),
+            err
+          ))
+          .into(),
+        )
+      }
+    };
+    // Never trust next-index alone, in case the file was edited by hand.
+    let next_index = file
+      .devices
+      .values()
+      .map(|device| device.index + 1)
+      .chain(std::iter::once(file.next_index))
+      .max()
+      .unwrap_or_default();
+    Ok(Self::new(Some(path), file.devices, next_index))
+  }
+
+  fn new(path: Option<PathBuf>, devices: HashMap<String, KnownDevice>, next_index: u32) -> Self {
+    let (save_sender, save_receiver) = mpsc::channel(1);
+    Self {
+      path,
+      devices: Arc::new(devices.into_iter().collect()),
+      next_index: Arc::new(AtomicU32::new(next_index)),
+      save_sender,
+      save_receiver: Mutex::new(Some(save_receiver)),
+    }
+  }
+
+  /// Index of a known device.
+  pub fn index(&self, address: &str) -> Option<u32> {
+    self.devices.get(address).map(|device| device.index)
+  }
+
+  /// Records that a device connected, returning its index. Devices that haven't
+  /// been seen before are given the next free index.
+  pub fn register(&self, address: &str, name: &str, protocol: &str) -> u32 {
+    let (index, changed) = {
+      let mut device = self.devices.entry(address.to_owned()).or_insert_with(|| {
+        let index = self.next_index.fetch_add(1, Ordering::SeqCst);
+        info!("Assigning new index {} to device {}.", index, address);
+        KnownDevice {
+          index,
+          name: String::new(),
+          protocol: String::new(),
+        }
+      });
+      let changed = device.name != name || device.protocol != protocol;
+      device.name = name.to_owned();
+      device.protocol = protocol.to_owned();
+      (device.index, changed)
+    };
+    if changed {
+      self.save();
+    }
+    index
+  }
+
+  /// A single known device.
+  pub fn known_device(&self, address: &str) -> Option<KnownDevice> {
+    self
+      .devices
+      .get(address)
+      .map(|device| device.value().clone())
+  }
+
+  /// All known devices, keyed by address.
+  pub fn known_devices(&self) -> HashMap<String, KnownDevice> {
+    self
+      .devices
+      .iter()
+      .map(|device| (device.key().clone(), device.value().clone()))
+      .collect()
+  }
+
+  /// Forgets a device, so it's treated as new the next time it connects.
+  /// `connected` is called with the device's index while the device is held,
+  /// so the device can't be registered in between checking and forgetting it.
+  pub fn forget(
+    &self,
+    address: &str,
+    connected: impl FnOnce(u32) -> bool,
+  ) -> Result<(), ButtplugServerError> {
+    let mut still_connected = false;
+    let forgotten = self
+      .devices
+      .remove_if(address, |_, device| {
+        still_connected = connected(device.index);
+        !still_connected
+      })
+      .is_some();
+    if forgotten {
+      self.save();
+      Ok(())
+    } else if still_connected {
+      Err(ButtplugServerError::DeviceStillConnected(
+        address.to_owned(),
+      ))
+    } else {
+      Err(ButtplugServerError::UnknownDevice(address.to_owned()))
+    }
+  }
+
+  /// Queues writing the store to its file, if it has one.
+  fn save(&self) {
+    let path = if let Some(path) = &self.path {
+      path.clone()
+    } else {
+      return;
+    };
+    if let Some(mut save_receiver) = self
+      .save_receiver
+      .lock()
+      .expect("Lock should never be poisoned")
+      .take()
+    {
+      let devices = self.devices.clone();
+      let next_index = self.next_index.clone();
+      // Runs until the store is dropped, after writing any save still pending.
+      async_manager::spawn(async move {
+        while save_receiver.recv().await.is_some() {
+          write_store(&path, &devices, &next_index);
+        }
+      });
+    }
+    // If this is full, a save is already pending and will include this change.
+    let _ = self.save_sender.try_send(());
+  }
+}
+
+/// Writes the current state of the store to its file. Failing to save isn't
+/// fatal, since the store still works in memory, so errors are only logged.
+fn write_store(path: &Path, devices: &DashMap<String, KnownDevice>, next_index: &AtomicU32) {
+  let file = DeviceIndexStoreFile {
+    next_index: next_index.load(Ordering::SeqCst),
+    devices: devices
+      .iter()
+      .map(|device| (device.key().clone(), device.value().clone()))
+      .collect(),
+  };
+  let json = serde_json::to_string_pretty(&file)
+    .expect("All types below this are Serialize, so this should be infallible.");
+  if let Err(err) = write_atomically(path, json.as_bytes()) {
+    error!("Cannot save device index store {}: {}", path.display(), err);
+  }
+}
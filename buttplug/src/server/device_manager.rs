@@ -14,13 +14,13 @@ use super::{
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
   },
+  device_index_store::{DeviceIndexStore, KnownDevice},
   device_manager_event_loop::DeviceManagerEventLoop,
-  ping_timer::PingTimer,
   ButtplugServerError,
 };
 use crate::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugMessageError, ButtplugUnknownError},
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError, ButtplugUnknownError},
     messages::{
       self,
       ButtplugClientMessage,
@@ -29,30 +29,43 @@ use crate::{
       ButtplugDeviceMessage,
       ButtplugMessage,
       ButtplugServerMessage,
+      DeviceAdded,
       DeviceList,
       DeviceMessageInfo,
+      DeviceRemoved,
+      KnownDeviceInfo,
     },
+    ButtplugResultFuture,
   },
   device::{
     configuration_manager::{DeviceConfigurationManager, ProtocolDefinition},
+    output_limits::DeviceOutputLimits,
     protocol::ButtplugProtocol,
     ButtplugDevice,
   },
-  server::ButtplugServerResultFuture,
-  util::async_manager,
+  server::{
+    device_command_scheduler::{schedule_device_message, DeviceCommandScheduler},
+    ButtplugServerResultFuture,
+  },
+  util::{
+    async_manager,
+    device_configuration::{save_user_config, ProtocolConfiguration},
+  },
 };
-use dashmap::DashMap;
-use futures::future;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::future::{self, BoxFuture};
 use getset::{Getters, Setters};
 use serde::{Deserialize, Serialize};
 use std::{
+  collections::HashMap,
   convert::TryFrom,
+  path::PathBuf,
   sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
   },
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 #[derive(Serialize, Deserialize, Debug, Getters, Setters, Default, Clone, PartialEq)]
 #[getset(get = "pub", set = "pub")]
@@ -67,6 +80,52 @@ pub struct DeviceUserConfig {
   #[serde(skip_serializing_if = "Option::is_none")]
   #[serde(default)]
   deny: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  limits: Option<DeviceOutputLimits>,
+}
+
+/// Device user configuration to write back, and where to send the result.
+type UserConfigSave = (
+  HashMap<String, DeviceUserConfig>,
+  oneshot::Sender<Result<(), ButtplugError>>,
+);
+
+/// Checks a device address against the deny list, and against the allow list
+/// if one is active.
+pub(super) fn is_device_allowed(
+  device_user_config: &DashMap<String, DeviceUserConfig>,
+  address: &str,
+) -> bool {
+  if let Some(config) = device_user_config.get(address) {
+    info!(
+      "Device {} has a user configuration entry, checking.",
+      address
+    );
+    if let Some(true) = config.deny() {
+      info!("Denied device address {} found, ignoring.", address);
+      return false;
+    }
+  } else {
+    info!("Device {} has no user configuration entry.", address);
+  }
+
+  // Make sure allow list isn't active, or that the device is in the allow list if it is.
+  let mut allow_list = device_user_config
+    .iter()
+    .filter(|x| *x.value().allow() == Some(true))
+    .peekable();
+  if allow_list.peek().is_some() {
+    if !allow_list.any(|x| *x.key() == address) {
+      info!(
+        "Allow list active and device address {} not found, ignoring.",
+        address
+      );
+      return false;
+    }
+    info!("Allow list active and device address {} found.", address);
+  }
+  true
 }
 
 #[derive(Debug)]
@@ -80,40 +139,68 @@ pub struct DeviceManager {
   // register. Also means we can do lockless access since it's a Dashmap.
   comm_managers: Arc<DashMap<String, Box<dyn DeviceCommunicationManager>>>,
   devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+  /// Command schedulers for devices whose protocols have a minimum command
+  /// interval, keyed by device index.
+  device_schedulers: Arc<DashMap<u32, Arc<DeviceCommandScheduler>>>,
   device_user_config: Arc<DashMap<String, DeviceUserConfig>>,
+  device_index_store: Arc<DeviceIndexStore>,
   device_event_sender: mpsc::Sender<DeviceCommunicationEvent>,
+  /// Used to re-announce devices to clients when a configuration update
+  /// changes what they can do.
+  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Indexes of devices that have disconnected, see
+  /// [removed_devices][Self::removed_devices].
+  removed_device_sender: broadcast::Sender<u32>,
   config: Arc<DeviceConfigurationManager>,
+  /// Sends device user configuration to the task that writes it back to the
+  /// user configuration file, if there is one.
+  user_config_save_sender: Option<mpsc::UnboundedSender<UserConfigSave>>,
   has_run_first_scan_status: Arc<AtomicBool>,
+  /// Span the device manager was created in, so comm managers added later log
+  /// in the same place as everything else on the server.
+  log_span: tracing::Span,
 }
 
 impl DeviceManager {
   pub fn new(
     output_sender: broadcast::Sender<ButtplugServerMessage>,
-    ping_timer: Arc<PingTimer>,
     allow_raw_messages: bool,
+    device_index_store: DeviceIndexStore,
+    device_recording_directory: Option<PathBuf>,
   ) -> Self {
     let config = Arc::new(DeviceConfigurationManager::new(allow_raw_messages));
     let devices = Arc::new(DashMap::new());
+    let device_schedulers = Arc::new(DashMap::new());
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
     let device_user_config = Arc::new(DashMap::new());
+    let device_index_store = Arc::new(device_index_store);
     let mut event_loop = DeviceManagerEventLoop::new(
       config.clone(),
-      output_sender,
+      output_sender.clone(),
       devices.clone(),
+      device_schedulers.clone(),
       device_user_config.clone(),
-      ping_timer,
+      device_index_store.clone(),
       device_event_receiver,
     );
+    event_loop.set_device_recording_directory(device_recording_directory);
+    let removed_device_sender = event_loop.removed_device_sender();
     async_manager::spawn(async move {
       event_loop.run().await;
     });
     Self {
       device_event_sender,
       devices,
+      device_schedulers,
       device_user_config,
+      device_index_store,
+      output_sender,
+      removed_device_sender,
       comm_managers: Arc::new(DashMap::new()),
       config,
+      user_config_save_sender: None,
       has_run_first_scan_status: Arc::new(AtomicBool::new(false)),
+      log_span: tracing::Span::current(),
     }
   }
 
@@ -217,28 +304,73 @@ impl DeviceManager {
   }
 
   fn stop_all_devices(&self) -> ButtplugServerResultFuture {
-    let device_map = self.devices.clone();
+    self.stop_devices(&self.device_indexes())
+  }
+
+  /// Indexes of devices as they disconnect. Unlike DeviceRemoved messages,
+  /// this doesn't include devices being re-announced to clients after a
+  /// configuration update.
+  pub(crate) fn removed_devices(&self) -> broadcast::Receiver<u32> {
+    self.removed_device_sender.subscribe()
+  }
+
+  pub(crate) fn device_indexes(&self) -> Vec<u32> {
+    self.devices.iter().map(|dev| *dev.key()).collect()
+  }
+
+  /// Stops the given devices, skipping any that are no longer connected.
+  pub(crate) fn stop_devices(&self, device_indexes: &[u32]) -> ButtplugServerResultFuture {
     // TODO This could use some error reporting.
-    Box::pin(async move {
-      let fut_vec: Vec<_> = device_map
-        .iter()
-        .map(|dev| {
-          let device = dev.value();
-          device.parse_message(messages::StopDeviceCmd::new(1).into())
+    let fut_vec: Vec<_> = device_indexes
+      .iter()
+      .filter_map(|index| {
+        self.devices.get(index).map(|dev| {
+          schedule_device_message(
+            &self.device_schedulers,
+            *index,
+            dev.value(),
+            messages::StopDeviceCmd::new(*index).into(),
+          )
         })
-        .collect();
+      })
+      .collect();
+    Box::pin(async move {
       future::join_all(fut_vec).await;
       Ok(messages::Ok::default().into())
     })
   }
 
+  /// Removes all raw endpoint and sensor subscriptions on all devices. Called
+  /// when a client disconnects, so notifications aren't left streaming to
+  /// nobody.
+  pub fn unsubscribe_all_devices(&self) -> ButtplugResultFuture {
+    let fut_vec: Vec<_> = self
+      .devices
+      .iter()
+      .map(|dev| dev.value().unsubscribe_all())
+      .collect();
+    Box::pin(async move {
+      for result in future::join_all(fut_vec).await {
+        if let Err(err) = result {
+          error!("Error removing subscription: {:?}", err);
+        }
+      }
+      Ok(())
+    })
+  }
+
   fn parse_device_message(
     &self,
     device_msg: ButtplugDeviceCommandMessageUnion,
   ) -> ButtplugServerResultFuture {
     match self.devices.get(&device_msg.device_index()) {
       Some(device) => {
-        let fut = device.parse_message(device_msg);
+        let fut = schedule_device_message(
+          &self.device_schedulers,
+          device_msg.device_index(),
+          device.value(),
+          device_msg,
+        );
         // Create a future to run the message through the device, then handle adding the id to the result.
         Box::pin(async move { fut.await })
       }
@@ -286,6 +418,7 @@ impl DeviceManager {
   where
     T: DeviceCommunicationManagerBuilder,
   {
+    let _log_span = self.log_span.enter();
     let mgr = builder
       .event_sender(self.device_event_sender.clone())
       .finish();
@@ -350,6 +483,22 @@ impl DeviceManager {
     self.config.remove_protocol_definition(name);
   }
 
+  #[cfg(feature = "scripted-protocols")]
+  pub fn add_protocol_script(
+    &self,
+    name: &str,
+    source: &str,
+  ) -> Result<(), crate::core::errors::ButtplugError> {
+    info!("Adding protocol script {}", name);
+    self.config.add_protocol_script(name, source)
+  }
+
+  #[cfg(feature = "scripted-protocols")]
+  pub fn remove_protocol_script(&self, name: &str) {
+    info!("Removing protocol script {}", name);
+    self.config.remove_protocol_script(name);
+  }
+
   pub fn add_device_user_config(&self, address: &str, config: DeviceUserConfig) {
     info!(
       "Adding device user config for address {} with values {:?}.",
@@ -363,11 +512,228 @@ impl DeviceManager {
     self.device_user_config.remove(address);
   }
 
+  /// Starts writing device user configuration changes back to a file. Files
+  /// are only written from a task started here, one save at a time in the
+  /// order they were asked for, so saving never blocks whoever made the change.
+  pub(crate) fn set_user_device_configuration_save_path(&mut self, path: Option<PathBuf>) {
+    self.user_config_save_sender = path.map(|path| {
+      let (sender, mut receiver) = mpsc::unbounded_channel::<UserConfigSave>();
+      async_manager::spawn(async move {
+        while let Some((user_config, result_sender)) = receiver.recv().await {
+          let _ = result_sender.send(save_user_config(&path, user_config));
+        }
+      });
+      sender
+    });
+  }
+
+  /// Writes the device user configuration, as it is now, back to the user
+  /// configuration file, if there is one. Resolves once it's written.
+  pub(crate) fn save_device_user_config(&self) -> BoxFuture<'static, Result<(), ButtplugError>> {
+    let save_sender = if let Some(sender) = &self.user_config_save_sender {
+      sender
+    } else {
+      return Box::pin(future::ready(Ok(())));
+    };
+    let (result_sender, result_receiver) = oneshot::channel();
+    let writer_gone = || -> ButtplugError {
+      ButtplugDeviceError::DeviceConfigurationFileError(
+        "User configuration writer has stopped".to_owned(),
+      )
+      .into()
+    };
+    if save_sender
+      .send((self.device_user_config(), result_sender))
+      .is_err()
+    {
+      return Box::pin(future::ready(Err(writer_gone())));
+    }
+    Box::pin(async move { result_receiver.await.unwrap_or_else(|_| Err(writer_gone())) })
+  }
+
+  /// Replaces the protocol definitions and device user configuration, e.g.
+  /// after the configuration files have been edited.
+  ///
+  /// Devices found after this use the new protocol definitions, while
+  /// connected devices keep the protocol they connected with. User
+  /// configuration is applied to connected devices right away: devices that
+  /// are now denied (or missing from an active allow list) are disconnected,
+  /// and devices whose output limits changed are re-announced to clients via
+  /// DeviceRemoved and DeviceAdded, since that's the only way clients learn
+  /// about device capabilities.
+  pub fn update_configuration(&self, config: ProtocolConfiguration) -> ButtplugResultFuture {
+    info!("Updating device configuration.");
+    self.config.replace_protocol_definitions(config.protocols);
+    // Insert before removing, so entries that are in both the old and new
+    // configuration never go missing for devices connecting right now.
+    for (address, user_config) in &config.user_config {
+      self
+        .device_user_config
+        .insert(address.clone(), user_config.clone());
+    }
+    self
+      .device_user_config
+      .retain(|address, _| config.user_config.contains_key(address));
+    self.apply_device_user_config()
+  }
+
+  /// Applies the current user device configuration to connected devices.
+  /// Devices that are no longer allowed are disconnected, and devices whose
+  /// output limits changed are removed and added again, so clients see the
+  /// new limits.
+  pub fn apply_device_user_config(&self) -> ButtplugResultFuture {
+    let mut disconnect_futs = vec![];
+    for entry in self.devices.iter() {
+      let (device_index, device) = (*entry.key(), entry.value());
+      if !is_device_allowed(&self.device_user_config, device.address()) {
+        info!(
+          "Device {} ({}) no longer allowed, disconnecting.",
+          device.name(),
+          device.address()
+        );
+        disconnect_futs.push(device.disconnect());
+        continue;
+      }
+      let limits = self
+        .device_user_config
+        .get(device.address())
+        .and_then(|config| *config.limits());
+      if limits == device.output_limits() {
+        continue;
+      }
+      match limits {
+        Some(limits) => device.set_output_limits(limits),
+        None => device.clear_output_limits(),
+      }
+      let device_added =
+        DeviceAdded::new(device_index, &device.name(), &device.message_attributes());
+      if self
+        .output_sender
+        .send(DeviceRemoved::new(device_index).into())
+        .is_err()
+        || self.output_sender.send(device_added.into()).is_err()
+      {
+        debug!("Server not currently available, dropping device update events.");
+      }
+    }
+    Box::pin(async move {
+      for result in future::join_all(disconnect_futs).await {
+        if let Err(err) = result {
+          error!("Error disconnecting device: {:?}", err);
+        }
+      }
+      Ok(())
+    })
+  }
+
+  /// Snapshot of the user device configuration, keyed by address.
+  pub fn device_user_config(&self) -> HashMap<String, DeviceUserConfig> {
+    self
+      .device_user_config
+      .iter()
+      .map(|entry| (entry.key().clone(), entry.value().clone()))
+      .collect()
+  }
+
+  /// Everything the server knows about devices by address: devices it has
+  /// seen, plus any address with a user configuration entry.
+  pub fn known_device_list(&self) -> Vec<KnownDeviceInfo> {
+    let known_devices = self.device_index_store.known_devices();
+    let mut addresses: Vec<&String> = known_devices.keys().collect();
+    let user_config = self.device_user_config();
+    addresses.extend(
+      user_config
+        .keys()
+        .filter(|address| !known_devices.contains_key(*address)),
+    );
+    addresses.sort();
+    addresses
+      .into_iter()
+      .map(|address| {
+        let known_device = known_devices.get(address);
+        let config = user_config.get(address).cloned().unwrap_or_default();
+        let connected = self
+          .devices
+          .iter()
+          .any(|device| device.value().address() == address);
+        KnownDeviceInfo::new(
+          address,
+          known_device.map(|device| device.index()),
+          known_device.map(|device| device.name().clone()),
+          connected,
+          config.display_name().clone(),
+          *config.allow(),
+          *config.deny(),
+        )
+      })
+      .collect()
+  }
+
+  /// Devices the server has seen, keyed by address, along with the index
+  /// they're given whenever they connect.
+  pub fn known_devices(&self) -> HashMap<String, KnownDevice> {
+    self.device_index_store.known_devices()
+  }
+
+  /// Sets or clears the display name of a known device. This is the display
+  /// name in the device's user configuration, so it's written back to the
+  /// user configuration file if the server has one to save to.
+  pub fn rename_known_device(
+    &self,
+    address: &str,
+    display_name: Option<String>,
+  ) -> Result<(), ButtplugServerError> {
+    match self.device_user_config.entry(address.to_owned()) {
+      Entry::Occupied(mut entry) => {
+        entry.get_mut().set_display_name(display_name.clone());
+        if *entry.get() == DeviceUserConfig::default() {
+          entry.remove();
+        }
+      }
+      Entry::Vacant(entry) => {
+        if self.device_index_store.index(address).is_none() {
+          return Err(ButtplugServerError::UnknownDevice(address.to_owned()));
+        }
+        if display_name.is_some() {
+          let mut config = DeviceUserConfig::default();
+          config.set_display_name(display_name.clone());
+          entry.insert(config);
+        }
+      }
+    }
+    info!("Renamed known device {} to {:?}.", address, display_name);
+    let save_fut = self.save_device_user_config();
+    let address = address.to_owned();
+    async_manager::spawn(async move {
+      if let Err(err) = save_fut.await {
+        error!("Cannot save display name for {}: {}", address, err);
+      }
+    });
+    Ok(())
+  }
+
+  /// Forgets a device, so it's given a new index the next time it connects.
+  /// Connected devices can't be forgotten.
+  pub fn forget_known_device(&self, address: &str) -> Result<(), ButtplugServerError> {
+    self
+      .device_index_store
+      .forget(address, |index| self.devices.contains_key(&index))?;
+    info!("Forgot known device {}.", address);
+    Ok(())
+  }
+
   pub fn device_info(&self, index: u32) -> Result<DeviceInfo, ButtplugDeviceError> {
     if let Some(device) = self.devices.get(&index) {
+      let address = device.value().address().to_owned();
+      // Read the display name from the user configuration, rather than the
+      // device, so changes show up without reconnecting.
+      let display_name = self
+        .device_user_config
+        .get(&address)
+        .and_then(|config| config.display_name().clone());
       Ok(DeviceInfo {
-        address: device.value().address().to_owned(),
-        display_name: device.value().display_name(),
+        address,
+        display_name,
       })
     } else {
       Err(ButtplugDeviceError::DeviceNotAvailable(index))
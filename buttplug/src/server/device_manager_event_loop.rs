@@ -1,18 +1,21 @@
 use super::{
   comm_managers::DeviceCommunicationEvent,
-  device_manager::DeviceUserConfig,
-  ping_timer::PingTimer,
+  device_command_scheduler::DeviceCommandScheduler,
+  device_index_store::DeviceIndexStore,
+  device_manager::{is_device_allowed, DeviceUserConfig},
 };
 use crate::{
   core::messages::{
+    ButtplugMessage,
     ButtplugServerMessage,
     DeviceAdded,
     DeviceRemoved,
+    RawReading,
     ScanningFinished,
-    StopDeviceCmd,
   },
   device::{
     configuration_manager::DeviceConfigurationManager,
+    recording::RecordingDeviceImplCreator,
     ButtplugDevice,
     ButtplugDeviceEvent,
     ButtplugDeviceImplCreator,
@@ -20,10 +23,13 @@ use crate::{
   util::async_manager,
 };
 use dashmap::{DashMap, DashSet};
-use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use futures::FutureExt;
+use std::{
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
 };
 use tokio::sync::{broadcast, mpsc};
 use tracing;
@@ -31,15 +37,20 @@ use tracing_futures::Instrument;
 
 pub struct DeviceManagerEventLoop {
   device_config_manager: Arc<DeviceConfigurationManager>,
-  device_index_generator: u32,
   device_map: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+  device_schedulers: Arc<DashMap<u32, Arc<DeviceCommandScheduler>>>,
   device_user_config: Arc<DashMap<String, DeviceUserConfig>>,
-  ping_timer: Arc<PingTimer>,
   /// Maps device addresses to indexes, so they can be reused on reconnect.
-  device_index_map: Arc<DashMap<String, u32>>,
+  device_index_store: Arc<DeviceIndexStore>,
+  /// If set, traffic with every device is recorded to a file in here.
+  device_recording_directory: Option<PathBuf>,
   /// Broadcaster that relays device events in the form of Buttplug Messages to
   /// whoever owns the Buttplug Server.
   server_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Indexes of devices that have actually gone away, unlike DeviceRemoved
+  /// messages, which are also sent when devices are re-announced after a
+  /// configuration update.
+  removed_device_sender: broadcast::Sender<u32>,
   /// As the device manager owns the Device Communication Managers, it will have
   /// a receiver that the comm managers all send thru.
   device_comm_receiver: mpsc::Receiver<DeviceCommunicationEvent>,
@@ -61,20 +72,23 @@ impl DeviceManagerEventLoop {
     device_config_manager: Arc<DeviceConfigurationManager>,
     server_sender: broadcast::Sender<ButtplugServerMessage>,
     device_map: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+    device_schedulers: Arc<DashMap<u32, Arc<DeviceCommandScheduler>>>,
     device_user_config: Arc<DashMap<String, DeviceUserConfig>>,
-    ping_timer: Arc<PingTimer>,
+    device_index_store: Arc<DeviceIndexStore>,
     device_comm_receiver: mpsc::Receiver<DeviceCommunicationEvent>,
   ) -> Self {
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
+    let (removed_device_sender, _) = broadcast::channel(256);
     Self {
       device_config_manager,
       server_sender,
+      removed_device_sender,
       device_map,
+      device_schedulers,
       device_user_config,
-      ping_timer,
       device_comm_receiver,
-      device_index_generator: 0,
-      device_index_map: Arc::new(DashMap::new()),
+      device_index_store,
+      device_recording_directory: None,
       device_event_sender,
       device_event_receiver,
       scanning_in_progress: false,
@@ -83,11 +97,26 @@ impl DeviceManagerEventLoop {
     }
   }
 
+  /// Records traffic with every device created from now on to a file in this
+  /// directory.
+  pub fn set_device_recording_directory(&mut self, directory: Option<PathBuf>) {
+    self.device_recording_directory = directory;
+  }
+
+  pub fn removed_device_sender(&self) -> broadcast::Sender<u32> {
+    self.removed_device_sender.clone()
+  }
+
   fn try_create_new_device(
     &mut self,
     device_address: String,
     device_creator: Box<dyn ButtplugDeviceImplCreator>,
   ) {
+    let device_creator: Box<dyn ButtplugDeviceImplCreator> = match &self.device_recording_directory
+    {
+      Some(directory) => Box::new(RecordingDeviceImplCreator::new(device_creator, directory)),
+      None => device_creator,
+    };
     let device_event_sender_clone = self.device_event_sender.clone();
     let create_device_future =
       ButtplugDevice::try_create_device(self.device_config_manager.clone(), device_creator);
@@ -96,12 +125,13 @@ impl DeviceManagerEventLoop {
     async_manager::spawn(async move {
       match create_device_future.await {
         Ok(option_dev) => match option_dev {
-          Some(mut device) => {
+          Some(device) => {
             // The device was created, now we need to customize it before handing it to the system.
+            // Display names aren't kept on the device, since they can change while it's
+            // connected. They're read from the user config whenever they're needed.
             if let Some(device_config) = device_user_config.get(device.address()) {
-              if let Some(device_name) = device_config.display_name() {
-                info!("Display name found for {} ({}), setting to {}", device.name(), device.address(), device_name);
-                device.set_display_name(device_name);
+              if let Some(limits) = device_config.limits() {
+                device.set_output_limits(*limits);
               }
             }
 
@@ -188,46 +218,15 @@ impl DeviceManagerEventLoop {
           return;
         }
 
-        self.connecting_devices.insert(address.clone());
-
-        // Make sure the device isn't on the deny list
-        if let Some(config) = self.device_user_config.get(&address) {
-          info!(
-            "Device {} has a user configuration entry, checking.",
-            address
-          );
-          if let Some(true) = config.deny() {
-            info!("Denied device address {} found, ignoring.", address);
-            return;
-          }
-        } else {
-          info!("Device {} has no user configuration entry.", address);
-        }
-
-        let mut is_allowed = true;
-        {
-          // Make sure allow list isn't active, or that the device is in the allow list if it is.
-          let mut allow_list = self
-            .device_user_config
-            .iter()
-            .filter(|x| *x.value().allow() == Some(true))
-            .peekable();
-          if allow_list.peek().is_some() {
-            if !allow_list.any(|x| *x.key() == address) {
-              info!(
-                "Allow list active and device address {} not found, ignoring.",
-                address
-              );
-              is_allowed = false;
-            } else {
-              info!("Allow list active and device address {} found.", address);
-            }
-          }
-        }
-        if !is_allowed {
+        // Check the deny and allow lists before marking the device as
+        // connecting, so it can still connect if the user configuration
+        // changes later.
+        if !is_device_allowed(&self.device_user_config, &address) {
           return;
         }
 
+        self.connecting_devices.insert(address.clone());
+
         self.try_create_new_device(address, creator);
       }
       DeviceCommunicationEvent::DeviceManagerAdded(status) => {
@@ -246,17 +245,12 @@ impl DeviceManagerEventLoop {
           address = tracing::field::display(device.address())
         );
         let _enter = span.enter();
-        let generated_device_index = self.device_index_generator;
-        self.device_index_generator += 1;
-        // See if we have a reusable device index here.
-        let device_index = if let Some(id) = self.device_index_map.get(device.address()) {
-          *id.value()
-        } else {
-          self
-            .device_index_map
-            .insert(device.address().to_owned(), generated_device_index);
-          generated_device_index
-        };
+        // Reuses the device's index if we've seen it before.
+        let device_index = self.device_index_store.register(
+          device.address(),
+          &device.name(),
+          device.protocol_name(),
+        );
         // Since we can now reuse device indexes, this means we might possibly
         // stomp on devices already in the map if they don't register a
         // disconnect before we try to insert the new device. If we have a
@@ -266,6 +260,7 @@ impl DeviceManagerEventLoop {
         // message goes out, so timing matters here.
         if let Some((_, old_device)) = self.device_map.remove(&device_index) {
           info!("Device map contains key {}.", device_index);
+          self.device_schedulers.remove(&device_index);
           // After removing the device from the array, manually disconnect it to
           // make sure the event is thrown.
           if let Err(err) = old_device.disconnect().await {
@@ -292,6 +287,16 @@ impl DeviceManagerEventLoop {
         info!("Assigning index {} to {}", device_index, device.name());
         let device_added_message =
           DeviceAdded::new(device_index, &device.name(), &device.message_attributes());
+        if let Some(interval) = device.min_command_interval() {
+          self.device_schedulers.insert(
+            device_index,
+            Arc::new(DeviceCommandScheduler::new(
+              device_index,
+              device.clone(),
+              interval,
+            )),
+          );
+        }
         self.device_map.insert(device_index, device);
         // After that, we can send out to the server's event listeners to let
         // them know a device has been added.
@@ -304,15 +309,22 @@ impl DeviceManagerEventLoop {
         }
       }
       ButtplugDeviceEvent::Removed(address) => {
-        let device_index = *self
-          .device_index_map
-          .get(&address)
-          .expect("Index must exist to get here.")
-          .value();
-        self
-          .device_map
-          .remove(&device_index)
-          .expect("Remove will always work.");
+        let device_index = if let Some(index) = self.device_index_store.index(&address) {
+          index
+        } else {
+          warn!("Got removal for unknown device {}, ignoring.", address);
+          return;
+        };
+        if self.device_map.remove(&device_index).is_none() {
+          warn!(
+            "Got removal for device {} that isn't connected, ignoring.",
+            address
+          );
+          return;
+        }
+        self.device_schedulers.remove(&device_index);
+        // Nobody may be listening, which is fine.
+        let _ = self.removed_device_sender.send(device_index);
         if self
           .server_sender
           .send(DeviceRemoved::new(device_index).into())
@@ -321,38 +333,50 @@ impl DeviceManagerEventLoop {
           debug!("Server not currently available, dropping Device Removed event.");
         }
       }
-      ButtplugDeviceEvent::Notification(_address, _endpoint, _data) => {
-        // TODO At some point here we need to fill this in for RawSubscribe and
-        // other sensor subscriptions.
-      }
-    }
-  }
-
-  async fn handle_ping_timeout(&self) {
-    error!("Pinged out, stopping devices");
-    let mut fut_vec = FuturesUnordered::new();
-    self.device_map.iter().for_each(|dev| {
-      let device = dev.value();
-      fut_vec.push(device.parse_message(StopDeviceCmd::new(1).into()))
-    });
-    async_manager::spawn(async move {
-      while let Some(val) = fut_vec.next().await {
-        // Device index doesn't matter here, since we're sending the
-        // message directly to the device itself.
-        if let Err(e) = val {
-          error!("Error stopping device on ping timeout: {}", e);
+      ButtplugDeviceEvent::Notification(address, endpoint, data) => {
+        let device_index = if let Some(index) = self.device_index_store.index(&address) {
+          index
+        } else {
+          warn!(
+            "Got notification from unknown device {}, ignoring.",
+            address
+          );
+          return;
+        };
+        let device = if let Some(device) = self.device_map.get(&device_index) {
+          device.value().clone()
+        } else {
+          warn!(
+            "Got notification for device index {} that is not in the device map, ignoring.",
+            device_index
+          );
+          return;
+        };
+        // Only forward notifications for endpoints the client has subscribed
+        // to, either raw or as a sensor. Protocols may subscribe to endpoints
+        // on their own (for things like handshakes), and those shouldn't leak
+        // out. Readings from subscriptions are events, not replies, so they
+        // get the system id.
+        if device.is_raw_subscribed(&endpoint) {
+          let mut raw_reading = RawReading::new(device_index, endpoint, data.clone());
+          raw_reading.set_id(0);
+          if self.server_sender.send(raw_reading.into()).is_err() {
+            debug!("Server not currently available, dropping Raw Reading event.");
+          }
+        }
+        for mut sensor_reading in device.sensor_readings(device_index, &endpoint, &data) {
+          sensor_reading.set_id(0);
+          if self.server_sender.send(sensor_reading.into()).is_err() {
+            debug!("Server not currently available, dropping Sensor Reading event.");
+          }
         }
       }
-    });
+    }
   }
 
   pub async fn run(&mut self) {
     loop {
       select! {
-        // If we have a ping timeout, stop all devices
-        _ = self.ping_timer.ping_timeout_waiter().fuse() => {
-          self.handle_ping_timeout().await;
-        },
         device_comm_msg = self.device_comm_receiver.recv().fuse() => {
           if let Some(msg) = device_comm_msg {
             self.handle_device_communication(msg).await;
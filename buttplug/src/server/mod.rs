@@ -8,47 +8,54 @@
 //! Handles client sessions, as well as discovery and communication with hardware.
 
 pub mod comm_managers;
+mod device_arbiter;
+mod device_command_scheduler;
+mod device_configuration_watcher;
+pub mod device_index_store;
 pub mod device_manager;
 mod device_manager_event_loop;
 mod ping_timer;
 pub mod remote_server;
+mod session;
 
+pub use device_arbiter::DeviceArbitrationPolicy;
 pub use remote_server::ButtplugRemoteServer;
+pub use session::ButtplugServerSession;
 
 use crate::{
   core::{
     errors::*,
-    messages::{
-      self,
-      ButtplugClientMessage,
-      ButtplugDeviceCommandMessageUnion,
-      ButtplugDeviceManagerMessageUnion,
-      ButtplugMessage,
-      ButtplugServerMessage,
-      StopAllDevices,
-      StopScanning,
-      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
-    },
+    messages::{self, ButtplugClientMessage, ButtplugServerMessage},
+    ButtplugResultFuture,
   },
   util::{
     async_manager,
-    device_configuration::{load_protocol_config_from_json, DEVICE_CONFIGURATION_JSON},
-    stream::convert_broadcast_receiver_to_stream,
+    device_configuration::{
+      check_user_config_save_path,
+      load_protocol_config,
+      ProtocolConfiguration,
+      ProtocolConfigurationFormat,
+      DEVICE_CONFIGURATION_JSON,
+    },
+    logging::LogScope,
   },
 };
+use dashmap::{DashMap, DashSet};
+use device_arbiter::DeviceArbiter;
+use device_index_store::DeviceIndexStore;
 use device_manager::DeviceManager;
-use futures::{
-  future::{self, BoxFuture},
-  Stream,
-};
-use ping_timer::PingTimer;
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use futures::{future::BoxFuture, Stream};
+use session::{ButtplugServerContext, ReadingSubscription};
+use std::{
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
 use thiserror::Error;
 use tokio::sync::broadcast;
-use tracing_futures::Instrument;
 
 pub type ButtplugServerResult = Result<ButtplugServerMessage, ButtplugError>;
 pub type ButtplugServerResultFuture = BoxFuture<'static, ButtplugServerResult>;
@@ -61,6 +68,10 @@ pub enum ButtplugServerError {
   ProtocolAlreadyAdded(String),
   #[error("Buttplug Protocol of type {0} does not exist in the system and cannot be removed.")]
   ProtocolDoesNotExist(String),
+  #[error("Device with address {0} is not known to the server.")]
+  UnknownDevice(String),
+  #[error("Device with address {0} is currently connected.")]
+  DeviceStillConnected(String),
 }
 
 #[derive(Debug, Clone)]
@@ -68,8 +79,21 @@ pub struct ButtplugServerBuilder {
   pub name: String,
   pub max_ping_time: Option<u32>,
   pub allow_raw_messages: bool,
+  /// Device configuration, in the format given by
+  /// [device_configuration_format][Self::device_configuration_format]. Named
+  /// for when JSON was the only format.
   pub device_configuration_json: Option<String>,
+  pub device_configuration_format: ProtocolConfigurationFormat,
+  /// User device configuration, in the format given by
+  /// [user_device_configuration_format][Self::user_device_configuration_format].
   pub user_device_configuration_json: Option<String>,
+  pub user_device_configuration_format: ProtocolConfigurationFormat,
+  pub device_arbitration_policy: DeviceArbitrationPolicy,
+  pub auth_token: Option<String>,
+  pub device_index_store: Option<PathBuf>,
+  pub allow_device_configuration_messages: bool,
+  pub user_device_configuration_save_path: Option<PathBuf>,
+  pub device_recording_directory: Option<PathBuf>,
 }
 
 impl Default for ButtplugServerBuilder {
@@ -79,7 +103,15 @@ impl Default for ButtplugServerBuilder {
       max_ping_time: None,
       allow_raw_messages: false,
       device_configuration_json: Some(DEVICE_CONFIGURATION_JSON.to_owned()),
+      device_configuration_format: ProtocolConfigurationFormat::Json,
       user_device_configuration_json: None,
+      user_device_configuration_format: ProtocolConfigurationFormat::Json,
+      device_arbitration_policy: DeviceArbitrationPolicy::default(),
+      auth_token: None,
+      device_index_store: None,
+      allow_device_configuration_messages: false,
+      user_device_configuration_save_path: None,
+      device_recording_directory: None,
     }
   }
 }
@@ -101,12 +133,76 @@ impl ButtplugServerBuilder {
   }
 
   pub fn device_configuration_json(&mut self, config_json: Option<String>) -> &mut Self {
-    self.device_configuration_json = config_json;
+    self.device_configuration(config_json, ProtocolConfigurationFormat::Json)
+  }
+
+  /// Sets the device configuration, in any supported format.
+  pub fn device_configuration(
+    &mut self,
+    config: Option<String>,
+    format: ProtocolConfigurationFormat,
+  ) -> &mut Self {
+    self.device_configuration_json = config;
+    self.device_configuration_format = format;
     self
   }
 
   pub fn user_device_configuration_json(&mut self, config_json: Option<String>) -> &mut Self {
-    self.user_device_configuration_json = config_json;
+    self.user_device_configuration(config_json, ProtocolConfigurationFormat::Json)
+  }
+
+  /// Sets the user device configuration, in any supported format.
+  pub fn user_device_configuration(
+    &mut self,
+    config: Option<String>,
+    format: ProtocolConfigurationFormat,
+  ) -> &mut Self {
+    self.user_device_configuration_json = config;
+    self.user_device_configuration_format = format;
+    self
+  }
+
+  pub fn device_arbitration_policy(&mut self, policy: DeviceArbitrationPolicy) -> &mut Self {
+    self.device_arbitration_policy = policy;
+    self
+  }
+
+  /// Requires clients to send this token with RequestServerInfo before they
+  /// can do anything else.
+  pub fn auth_token(&mut self, token: &str) -> &mut Self {
+    self.auth_token = Some(token.to_owned());
+    self
+  }
+
+  /// Saves which index each device was given to a JSON file, and loads it
+  /// again on startup, so devices keep their indexes across server restarts.
+  /// The file is created if it doesn't exist.
+  pub fn device_index_store(&mut self, path: PathBuf) -> &mut Self {
+    self.device_index_store = Some(path);
+    self
+  }
+
+  /// Lets clients list known devices and change their display names and
+  /// allow/deny entries. Any client can do this once it's on, so it should
+  /// usually be paired with an [auth token][ButtplugServerBuilder::auth_token].
+  pub fn allow_device_configuration_messages(&mut self, allow: bool) -> &mut Self {
+    self.allow_device_configuration_messages = allow;
+    self
+  }
+
+  /// File that user device configuration changes made by clients are written
+  /// back to, usually the same file given as the user device configuration.
+  /// Written as JSON, so YAML and TOML files can't be used.
+  pub fn user_device_configuration_save_path(&mut self, path: PathBuf) -> &mut Self {
+    self.user_device_configuration_save_path = Some(path);
+    self
+  }
+
+  /// Records traffic with every device that connects to a file in this
+  /// directory, so it can be replayed later. See
+  /// [recording](crate::device::recording).
+  pub fn device_recording_directory(&mut self, path: PathBuf) -> &mut Self {
+    self.device_recording_directory = Some(path);
     self
   }
 
@@ -114,14 +210,19 @@ impl ButtplugServerBuilder {
     // If the user config string exists, parse it.
     let user_config = if let Some(user_device_config) = &self.user_device_configuration_json {
       // Skip checking the version of user device config files for now.
-      Some(load_protocol_config_from_json(user_device_config, true)?)
+      Some(load_protocol_config(
+        user_device_config,
+        self.user_device_configuration_format,
+        true,
+      )?)
     } else {
       None
     };
 
     // If the device config string exists, parse it.
     let device_config = if let Some(main_device_config) = &self.device_configuration_json {
-      let mut main_config = load_protocol_config_from_json(main_device_config, false)?;
+      let mut main_config =
+        load_protocol_config(main_device_config, self.device_configuration_format, false)?;
       if let Some(user_config) = user_config {
         main_config.merge(user_config);
       }
@@ -130,34 +231,32 @@ impl ButtplugServerBuilder {
       user_config
     };
 
+    if let Some(path) = &self.user_device_configuration_save_path {
+      check_user_config_save_path(path)?;
+    }
+
+    // Everything the server does from here on, including the tasks it spawns,
+    // logs to its own clients.
+    let log_scope = LogScope::new_server();
+    let log_span = log_scope.span();
+    let _log_span = log_span.enter();
+
     // Create the server
     debug!("Creating server '{}'", self.name);
     info!("Buttplug Server Operating System Info: {}", os_info::get());
     let (send, _) = broadcast::channel(256);
-    let output_sender_clone = send.clone();
-    let connected = Arc::new(AtomicBool::new(false));
-    let ping_time = self.max_ping_time.unwrap_or(0);
-    let ping_timer = Arc::new(PingTimer::new(ping_time));
-    let ping_timeout_notifier = ping_timer.ping_timeout_waiter();
-    let connected_clone = connected.clone();
-    async_manager::spawn(
-      async move {
-        // This will only exit if we've pinged out.
-        ping_timeout_notifier.await;
-        error!("Ping out signal received, stopping server");
-        connected_clone.store(false, Ordering::SeqCst);
-        // TODO Should the event sender return a result instead of an error message?
-        if output_sender_clone
-          .send(messages::Error::from(ButtplugError::from(ButtplugPingError::PingedOut)).into())
-          .is_err()
-        {
-          error!("Server disappeared, cannot update about ping out.");
-        };
-      }
-      .instrument(tracing::info_span!("Buttplug Server Ping Timeout Task")),
+    let device_index_store = match &self.device_index_store {
+      Some(path) => DeviceIndexStore::load(path.clone())?,
+      None => DeviceIndexStore::default(),
+    };
+    let mut device_manager = DeviceManager::new(
+      send.clone(),
+      self.allow_raw_messages,
+      device_index_store,
+      self.device_recording_directory.clone(),
     );
-    let device_manager =
-      DeviceManager::new(send.clone(), ping_timer.clone(), self.allow_raw_messages);
+    device_manager
+      .set_user_device_configuration_save_path(self.user_device_configuration_save_path.clone());
 
     if let Some(devices) = device_config {
       for (name, def) in devices.protocols {
@@ -168,32 +267,66 @@ impl ButtplugServerBuilder {
       }
     }
 
-    let server = ButtplugServer {
+    // Devices that go away shouldn't stay claimed or subscribed to by whoever
+    // last used them. This doesn't listen for DeviceRemoved, since that's also
+    // sent when devices are re-announced after a configuration update.
+    let device_arbiter = Arc::new(DeviceArbiter::new(self.device_arbitration_policy));
+    let device_arbiter_clone = device_arbiter.clone();
+    let reading_subscriptions = Arc::new(DashSet::new());
+    let reading_subscriptions_clone = reading_subscriptions.clone();
+    let mut removed_device_receiver = device_manager.removed_devices();
+    async_manager::spawn(async move {
+      loop {
+        match removed_device_receiver.recv().await {
+          Ok(device_index) => {
+            device_arbiter_clone.release_device(device_index);
+            reading_subscriptions_clone.retain(|(_, subscription): &(u32, ReadingSubscription)| {
+              subscription.device_index() != device_index
+            });
+          }
+          Err(broadcast::error::RecvError::Lagged(_)) => {}
+          Err(broadcast::error::RecvError::Closed) => break,
+        }
+      }
+    });
+
+    let context = Arc::new(ButtplugServerContext {
       server_name: self.name.clone(),
-      max_ping_time: ping_time,
-      device_manager,
-      ping_timer,
-      connected,
+      max_ping_time: self.max_ping_time.unwrap_or(0),
+      auth_token: self.auth_token.clone(),
+      allow_device_configuration_messages: self.allow_device_configuration_messages,
+      device_manager: Arc::new(device_manager),
+      device_arbiter,
+      log_scope,
+      log_span: log_span.clone(),
+      sessions: DashMap::new(),
+      reading_subscriptions,
+      session_senders: DashMap::new(),
       output_sender: send,
+    });
+    context.start_reading_router();
+    let server = ButtplugServer {
+      session: ButtplugServerSession::new(0, 0, context.clone()),
+      next_session_id: AtomicU32::new(1),
+      context,
     };
 
-    // Add the device config
-
-    // Update with the user config
-
     // Assuming everything passed, return the server.
     Ok(server)
   }
 }
 
 /// Represents a ButtplugServer.
+///
+/// A server can be used directly as a single client connection, via
+/// [parse_message][ButtplugServer::parse_message] and friends, or can hand out
+/// any number of [ButtplugServerSession]s, one per client, that share its
+/// devices.
 pub struct ButtplugServer {
-  server_name: String,
-  max_ping_time: u32,
-  device_manager: DeviceManager,
-  ping_timer: Arc<PingTimer>,
-  connected: Arc<AtomicBool>,
-  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  context: Arc<ButtplugServerContext>,
+  next_session_id: AtomicU32,
+  /// Session used by the single client methods on the server itself.
+  session: ButtplugServerSession,
 }
 
 impl Default for ButtplugServer {
@@ -209,148 +342,59 @@ impl ButtplugServer {
   pub fn event_stream(&self) -> impl Stream<Item = ButtplugServerMessage> {
     // Unlike the client API, we can expect anyone using the server to pin this
     // themselves.
-    convert_broadcast_receiver_to_stream(self.output_sender.subscribe())
+    self.session.event_stream()
   }
 
   pub fn device_manager(&self) -> &DeviceManager {
-    &self.device_manager
+    &self.context.device_manager
   }
 
-  pub fn connected(&self) -> bool {
-    self.connected.load(Ordering::SeqCst)
+  /// Swaps in a new device configuration while the server is running. See
+  /// [DeviceManager::update_configuration] for how this affects connected
+  /// devices.
+  pub fn update_device_configuration(&self, config: ProtocolConfiguration) -> ButtplugResultFuture {
+    self.context.device_manager.update_configuration(config)
   }
 
-  pub fn disconnect(&self) -> BoxFuture<Result<(), messages::Error>> {
-    debug!("Buttplug Server {} disconnect requested", self.server_name);
-    let ping_timer = self.ping_timer.clone();
-    let stop_scanning_fut =
-      self.parse_message(ButtplugClientMessage::StopScanning(StopScanning::default()));
-    let stop_fut = self.parse_message(ButtplugClientMessage::StopAllDevices(
-      StopAllDevices::default(),
+  /// Reloads device configuration files whenever they change, checking every
+  /// `poll_interval`. Without a device configuration file, the built in one is
+  /// used. If a changed file can't be loaded, the error is logged and the
+  /// current configuration is kept. Watching stops when the server is dropped.
+  pub fn watch_device_configuration(
+    &self,
+    device_configuration: Option<PathBuf>,
+    user_device_configuration: Option<PathBuf>,
+    poll_interval: Duration,
+  ) {
+    async_manager::spawn(device_configuration_watcher::watch_device_configuration(
+      Arc::downgrade(&self.context.device_manager),
+      device_configuration,
+      user_device_configuration,
+      poll_interval,
     ));
-    let connected = self.connected.clone();
-    Box::pin(async move {
-      connected.store(false, Ordering::SeqCst);
-      ping_timer.stop_ping_timer().await;
-      // Ignore returns here, we just want to stop.
-      info!("Server disconnected, stopping device scanning if it was started...");
-      let _ = stop_scanning_fut.await;
-      info!("Server disconnected, stopping all devices...");
-      let _ = stop_fut.await;
-      Ok(())
-    })
   }
 
-  // This is the only method that returns ButtplugServerResult, as it handles
-  // the packing of the message ID.
-  pub fn parse_message(
-    &self,
-    msg: ButtplugClientMessage,
-  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
-    trace!(
-      "Buttplug Server {} received message to client parse: {:?}",
-      self.server_name,
-      msg
-    );
-    let id = msg.id();
-    if !self.connected() {
-      // Check for ping timeout first! There's no way we should've pinged out if
-      // we haven't received RequestServerInfo first, but we do want to know if
-      // we pinged out.
-      let error = if self.ping_timer.pinged_out() {
-        Some(messages::Error::from(ButtplugError::from(
-          ButtplugPingError::PingedOut,
-        )))
-      } else if !matches!(msg, ButtplugClientMessage::RequestServerInfo(_)) {
-        Some(messages::Error::from(ButtplugError::from(
-          ButtplugHandshakeError::RequestServerInfoExpected,
-        )))
-      } else {
-        None
-      };
-      if let Some(mut return_error) = error {
-        return_error.set_id(msg.id());
-        return Box::pin(future::ready(Err(return_error)));
-      }
-      // If we haven't pinged out and we got an RSI message, fall thru.
-    }
-    // Produce whatever future is needed to reply to the message, this may be a
-    // device command future, or something the server handles. All futures will
-    // return Result<ButtplugServerMessage, ButtplugError>, and we'll handle
-    // tagging the result with the message id in the future we put out as the
-    // return value from this method.
-    let out_fut = if ButtplugDeviceManagerMessageUnion::try_from(msg.clone()).is_ok()
-      || ButtplugDeviceCommandMessageUnion::try_from(msg.clone()).is_ok()
-    {
-      self.device_manager.parse_message(msg.clone())
-    } else {
-      match msg {
-        ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
-        ButtplugClientMessage::Ping(p) => self.handle_ping(p),
-        _ => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
-      }
-    };
-    // Simple way to set the ID on the way out. Just rewrap
-    // the returned future to make sure it happens.
-    Box::pin(
-      async move {
-        out_fut
-          .await
-          .map(|mut ok_msg| {
-            ok_msg.set_id(id);
-            ok_msg
-          })
-          .map_err(|err| {
-            let mut error = messages::Error::from(err);
-            error.set_id(id);
-            error
-          })
-      }
-      .instrument(info_span!("Buttplug Server Message", id = id)),
-    )
+  /// Creates a new client session on this server. Sessions with a higher
+  /// priority can take devices away from lower priority sessions, if the
+  /// server is using [DeviceArbitrationPolicy::Priority].
+  pub fn new_session(&self, priority: u32) -> ButtplugServerSession {
+    let id = self.next_session_id.fetch_add(1, Ordering::SeqCst);
+    ButtplugServerSession::new(id, priority, self.context.clone())
   }
 
-  fn perform_handshake(&self, msg: messages::RequestServerInfo) -> ButtplugServerResultFuture {
-    if self.connected() {
-      return ButtplugHandshakeError::HandshakeAlreadyHappened.into();
-    }
-    info!(
-      "Performing server handshake check with client {} at message version {}.",
-      msg.client_name(),
-      msg.message_version()
-    );
-    if BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION < msg.message_version() {
-      return ButtplugHandshakeError::MessageSpecVersionMismatch(
-        BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
-        msg.message_version(),
-      )
-      .into();
-    }
-    // Only start the ping timer after we've received the handshake.
-    let ping_timer = self.ping_timer.clone();
-    let out_msg = messages::ServerInfo::new(
-      &self.server_name,
-      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
-      self.max_ping_time,
-    );
-    let connected = self.connected.clone();
-    Box::pin(async move {
-      ping_timer.start_ping_timer().await;
-      connected.store(true, Ordering::SeqCst);
-      debug!("Server handshake check successful.");
-      Result::Ok(out_msg.into())
-    })
+  pub fn connected(&self) -> bool {
+    self.session.connected()
   }
 
-  fn handle_ping(&self, msg: messages::Ping) -> ButtplugServerResultFuture {
-    if self.max_ping_time == 0 {
-      return ButtplugPingError::PingTimerNotRunning.into();
-    }
-    let fut = self.ping_timer.update_ping_time();
-    Box::pin(async move {
-      fut.await;
-      Result::Ok(messages::Ok::new(msg.id()).into())
-    })
+  pub fn disconnect(&self) -> BoxFuture<'static, Result<(), messages::Error>> {
+    self.session.disconnect()
+  }
+
+  pub fn parse_message(
+    &self,
+    msg: ButtplugClientMessage,
+  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
+    self.session.parse_message(msg)
   }
 }
 
@@ -1,4 +1,4 @@
-use super::{ButtplugServer, ButtplugServerBuilder, DeviceManager};
+use super::{ButtplugServer, ButtplugServerBuilder, ButtplugServerSession, DeviceManager};
 use crate::{
   connector::ButtplugConnector,
   core::{
@@ -13,18 +13,45 @@ use crate::{
   },
   util::{async_manager, stream::convert_broadcast_receiver_to_stream},
 };
-use futures::{future::Future, select, FutureExt, Stream, StreamExt};
+use futures::{
+  future::{BoxFuture, Future},
+  select,
+  FutureExt,
+  Stream,
+  StreamExt,
+};
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, Notify};
 
 // Clone derived here to satisfy tokio broadcast requirements.
+//
+// Several connectors can be running at once, so every connection sends both
+// Connected/Disconnected, and SessionConnected/SessionDisconnected, which also
+// carry the id of the server session the connector was given.
 #[derive(Clone, Debug)]
 pub enum ButtplugRemoteServerEvent {
   Connected(String),
   DeviceAdded(u32, String),
   DeviceRemoved(u32),
   Disconnected,
+  SessionConnected(u32, String),
+  SessionDisconnected(u32),
+}
+
+/// Sends connection events to the owner, if anyone is listening.
+fn send_remote_events(
+  remote_event_sender: &broadcast::Sender<ButtplugRemoteServerEvent>,
+  events: Vec<ButtplugRemoteServerEvent>,
+) {
+  if remote_event_sender.receiver_count() == 0 {
+    return;
+  }
+  for event in events {
+    if remote_event_sender.send(event).is_err() {
+      error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
+    }
+  }
 }
 
 #[derive(Error, Debug)]
@@ -44,7 +71,7 @@ pub struct ButtplugRemoteServer {
 }
 
 async fn run_server<ConnectorType>(
-  server: Arc<ButtplugServer>,
+  session: Arc<ButtplugServerSession>,
   remote_event_sender: broadcast::Sender<ButtplugRemoteServerEvent>,
   connector: ConnectorType,
   mut connector_receiver: mpsc::Receiver<ButtplugClientMessage>,
@@ -52,9 +79,9 @@ async fn run_server<ConnectorType>(
 ) where
   ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
 {
-  info!("Starting remote server loop");
+  info!("Starting remote server loop for session {}", session.id());
   let shared_connector = Arc::new(connector);
-  let server_receiver = server.event_stream();
+  let server_receiver = session.event_stream();
   pin_mut!(server_receiver);
   loop {
     select! {
@@ -65,7 +92,7 @@ async fn run_server<ConnectorType>(
         }
         Some(client_message) => {
           trace!("Got message from connector: {:?}", client_message);
-          let server_clone = server.clone();
+          let session_clone = session.clone();
           let connector_clone = shared_connector.clone();
           let remote_event_sender_clone = remote_event_sender.clone();
           async_manager::spawn(async move {
@@ -76,12 +103,13 @@ async fn run_server<ConnectorType>(
               connector_clone.send(err_msg.into());
               return;
             }
-            match server_clone.parse_message(client_message.clone()).await {
+            match session_clone.parse_message(client_message.clone()).await {
               Ok(ret_msg) => {
                 if let ButtplugClientMessage::RequestServerInfo(rsi) = client_message {
-                  if remote_event_sender_clone.receiver_count() > 0 && remote_event_sender_clone.send(ButtplugRemoteServerEvent::Connected(rsi.client_name().clone())).is_err() {
-                    error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
-                  }
+                  send_remote_events(&remote_event_sender_clone, vec![
+                    ButtplugRemoteServerEvent::Connected(rsi.client_name().clone()),
+                    ButtplugRemoteServerEvent::SessionConnected(session_clone.id(), rsi.client_name().clone()),
+                  ]);
                 }
                 if connector_clone.send(ret_msg).await.is_err() {
                   error!("Cannot send reply to server, dropping and assuming remote server thread has exited.");
@@ -106,21 +134,6 @@ async fn run_server<ConnectorType>(
           break;
         }
         Some(msg) => {
-          if remote_event_sender.receiver_count() > 0 {
-            match &msg {
-              ButtplugServerMessage::DeviceAdded(da) => {
-                if remote_event_sender.send(ButtplugRemoteServerEvent::DeviceAdded(da.device_index(), da.device_name().clone())).is_err() {
-                  error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
-                }
-              },
-              ButtplugServerMessage::DeviceRemoved(dr) => {
-               if remote_event_sender.send(ButtplugRemoteServerEvent::DeviceRemoved(dr.device_index())).is_err() {
-                 error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
-               }
-              },
-              _ => {}
-            }
-          }
           let connector_clone = shared_connector.clone();
           if connector_clone.send(msg).await.is_err() {
             error!("Server disappeared, exiting remote server thread.");
@@ -129,10 +142,47 @@ async fn run_server<ConnectorType>(
       },
     };
   }
-  if let Err(err) = server.disconnect().await {
+  if let Err(err) = session.disconnect().await {
     error!("Error disconnecting server: {:?}", err);
   }
-  info!("Exiting remote server loop");
+  send_remote_events(
+    &remote_event_sender,
+    vec![
+      ButtplugRemoteServerEvent::Disconnected,
+      ButtplugRemoteServerEvent::SessionDisconnected(session.id()),
+    ],
+  );
+  info!("Exiting remote server loop for session {}", session.id());
+}
+
+/// Forwards device events to the remote server's event stream. This runs once
+/// per server, instead of once per connection, so owners don't see duplicate
+/// events when more than one client is connected.
+async fn forward_device_events(
+  server_receiver: impl Stream<Item = ButtplugServerMessage>,
+  remote_event_sender: broadcast::Sender<ButtplugRemoteServerEvent>,
+) {
+  pin_mut!(server_receiver);
+  while let Some(msg) = server_receiver.next().await {
+    if remote_event_sender.receiver_count() == 0 {
+      continue;
+    }
+    let event = match &msg {
+      ButtplugServerMessage::DeviceAdded(da) => Some(ButtplugRemoteServerEvent::DeviceAdded(
+        da.device_index(),
+        da.device_name().clone(),
+      )),
+      ButtplugServerMessage::DeviceRemoved(dr) => {
+        Some(ButtplugRemoteServerEvent::DeviceRemoved(dr.device_index()))
+      }
+      _ => None,
+    };
+    if let Some(event) = event {
+      if remote_event_sender.send(event).is_err() {
+        error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
+      }
+    }
+  }
 }
 
 impl Default for ButtplugRemoteServer {
@@ -148,6 +198,10 @@ impl Default for ButtplugRemoteServer {
 impl ButtplugRemoteServer {
   pub fn new(server: ButtplugServer) -> Self {
     let (event_sender, _) = broadcast::channel(256);
+    async_manager::spawn(forward_device_events(
+      server.event_stream(),
+      event_sender.clone(),
+    ));
     Self {
       event_sender,
       server: Arc::new(server),
@@ -159,14 +213,61 @@ impl ButtplugRemoteServer {
     convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
   }
 
+  /// Runs a client connection until it disconnects. Can be called again, even
+  /// while other connections are still running, to serve several clients at
+  /// once.
   pub fn start<ConnectorType>(
     &self,
-    mut connector: ConnectorType,
+    connector: ConnectorType,
   ) -> impl Future<Output = Result<(), ButtplugServerConnectorError>>
   where
     ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
   {
-    let server_clone = self.server.clone();
+    self.start_with_priority(connector, 0)
+  }
+
+  /// Same as [start][ButtplugRemoteServer::start], but gives the connection a
+  /// priority for device arbitration.
+  pub fn start_with_priority<ConnectorType>(
+    &self,
+    connector: ConnectorType,
+    priority: u32,
+  ) -> impl Future<Output = Result<(), ButtplugServerConnectorError>>
+  where
+    ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
+  {
+    let accept_fut = self.accept_with_priority(connector, priority);
+    async move {
+      accept_fut.await?.await;
+      Ok(())
+    }
+  }
+
+  /// Waits for the connector to connect, then returns a future that runs the
+  /// connection until it disconnects. Unlike [start][ButtplugRemoteServer::start],
+  /// this lets the caller get ready for the next client as soon as one has
+  /// connected, instead of after it has left.
+  pub fn accept<ConnectorType>(
+    &self,
+    connector: ConnectorType,
+  ) -> impl Future<Output = Result<BoxFuture<'static, ()>, ButtplugServerConnectorError>>
+  where
+    ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
+  {
+    self.accept_with_priority(connector, 0)
+  }
+
+  /// Same as [accept][ButtplugRemoteServer::accept], but gives the connection
+  /// a priority for device arbitration.
+  pub fn accept_with_priority<ConnectorType>(
+    &self,
+    mut connector: ConnectorType,
+    priority: u32,
+  ) -> impl Future<Output = Result<BoxFuture<'static, ()>, ButtplugServerConnectorError>>
+  where
+    ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
+  {
+    let session = Arc::new(self.server.new_session(priority));
     let event_sender_clone = self.event_sender.clone();
     let disconnect_notifier = self.disconnect_notifier.clone();
     async move {
@@ -175,15 +276,14 @@ impl ButtplugRemoteServer {
         .connect(connector_sender)
         .await
         .map_err(|e| ButtplugServerConnectorError::ConnectorError(format!("{:?}", e)))?;
-      run_server(
-        server_clone,
+      let run_fut: BoxFuture<'static, ()> = Box::pin(run_server(
+        session,
         event_sender_clone,
         connector,
         connector_receiver,
         disconnect_notifier,
-      )
-      .await;
-      Ok(())
+      ));
+      Ok(run_fut)
     }
   }
 
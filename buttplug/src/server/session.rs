@@ -0,0 +1,790 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Per-client connection state for a server shared between several clients.
+
+use super::{
+  device_arbiter::DeviceArbiter,
+  device_manager::DeviceManager,
+  ping_timer::PingTimer,
+  ButtplugServerResultFuture,
+};
+use crate::{
+  core::{
+    errors::*,
+    messages::{
+      self,
+      ButtplugClientMessage,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceManagerMessageUnion,
+      ButtplugDeviceMessage,
+      ButtplugMessage,
+      ButtplugMessageSpecVersion,
+      ButtplugServerMessage,
+      KnownDeviceList,
+      LogLevel,
+      RawUnsubscribeCmd,
+      SensorType,
+      SensorUnsubscribeCmd,
+      StopScanning,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    },
+  },
+  device::Endpoint,
+  server::device_manager::DeviceUserConfig,
+  util::{
+    async_manager,
+    logging::{register_log_sink, LogScope, LogSinkHandle},
+    stream::convert_broadcast_receiver_to_stream,
+  },
+};
+use dashmap::{DashMap, DashSet};
+use futures::{
+  future::{self, BoxFuture},
+  stream,
+  FutureExt,
+  Stream,
+  StreamExt,
+};
+use std::{
+  convert::TryFrom,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing_futures::Instrument;
+
+/// Compares auth tokens without bailing out at the first differing byte, so
+/// response timing doesn't leak how much of a guessed token was right.
+fn tokens_match(token: &str, expected: &str) -> bool {
+  token.len() == expected.len()
+    && token
+      .bytes()
+      .zip(expected.bytes())
+      .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+      == 0
+}
+
+/// A raw endpoint or sensor subscription made by a session. Devices only keep
+/// one subscription for everyone, so the server tracks which sessions asked
+/// for each one, and only sends readings to those sessions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) enum ReadingSubscription {
+  /// Device index and endpoint of a RawSubscribeCmd.
+  Raw(u32, Endpoint),
+  /// Device index, sensor index and sensor type of a SensorSubscribeCmd.
+  Sensor(u32, u32, SensorType),
+}
+
+impl ReadingSubscription {
+  pub(super) fn device_index(&self) -> u32 {
+    match self {
+      Self::Raw(device_index, _) | Self::Sensor(device_index, _, _) => *device_index,
+    }
+  }
+
+  /// Subscription a RawReading or SensorReading was sent for.
+  fn from_reading(msg: &ButtplugServerMessage) -> Option<Self> {
+    match msg {
+      ButtplugServerMessage::RawReading(m) => Some(Self::Raw(m.device_index(), m.endpoint())),
+      ButtplugServerMessage::SensorReading(m) => Some(Self::Sensor(
+        m.device_index(),
+        m.sensor_index(),
+        m.sensor_type(),
+      )),
+      _ => None,
+    }
+  }
+
+  /// Subscription a device command changes, and whether it subscribes (true)
+  /// or unsubscribes (false).
+  fn from_command(msg: &ButtplugDeviceCommandMessageUnion) -> Option<(Self, bool)> {
+    match msg {
+      ButtplugDeviceCommandMessageUnion::RawSubscribeCmd(m) => {
+        Some((Self::Raw(m.device_index(), m.endpoint()), true))
+      }
+      ButtplugDeviceCommandMessageUnion::RawUnsubscribeCmd(m) => {
+        Some((Self::Raw(m.device_index(), m.endpoint()), false))
+      }
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(m) => Some((
+        Self::Sensor(m.device_index(), m.sensor_index(), m.sensor_type()),
+        true,
+      )),
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(m) => Some((
+        Self::Sensor(m.device_index(), m.sensor_index(), m.sensor_type()),
+        false,
+      )),
+      _ => None,
+    }
+  }
+
+  fn unsubscribe_message(&self) -> ButtplugClientMessage {
+    match *self {
+      Self::Raw(device_index, endpoint) => RawUnsubscribeCmd::new(device_index, endpoint).into(),
+      Self::Sensor(device_index, sensor_index, sensor_type) => {
+        SensorUnsubscribeCmd::new(device_index, sensor_index, sensor_type).into()
+      }
+    }
+  }
+}
+
+/// Server state shared by every session.
+pub(super) struct ButtplugServerContext {
+  pub server_name: String,
+  pub max_ping_time: u32,
+  pub auth_token: Option<String>,
+  pub allow_device_configuration_messages: bool,
+  pub device_manager: Arc<DeviceManager>,
+  pub device_arbiter: Arc<DeviceArbiter>,
+  pub log_scope: LogScope,
+  /// Span for work done for the whole server, rather than a single session.
+  pub log_span: tracing::Span,
+  /// Connection status of every live session, keyed by session id.
+  pub sessions: DashMap<u32, Arc<AtomicBool>>,
+  /// Reading subscriptions, along with the id of the session that made them.
+  pub reading_subscriptions: Arc<DashSet<(u32, ReadingSubscription)>>,
+  /// Senders for events meant only for a single session, keyed by session id.
+  pub session_senders: DashMap<u32, broadcast::Sender<ButtplugServerMessage>>,
+  pub output_sender: broadcast::Sender<ButtplugServerMessage>,
+}
+
+impl ButtplugServerContext {
+  /// Sends readings to the sessions subscribed to them. This happens as
+  /// readings come in, rather than as each session reads its event stream, so
+  /// sessions don't get readings from before they subscribed.
+  pub(super) fn start_reading_router(self: &Arc<Self>) {
+    let mut receiver = self.output_sender.subscribe();
+    let context = Arc::downgrade(self);
+    async_manager::spawn(async move {
+      loop {
+        let msg = match receiver.recv().await {
+          Ok(msg) => msg,
+          Err(broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let subscription = if let Some(subscription) = ReadingSubscription::from_reading(&msg) {
+          subscription
+        } else {
+          continue;
+        };
+        let context = if let Some(context) = context.upgrade() {
+          context
+        } else {
+          break;
+        };
+        for entry in context.reading_subscriptions.iter() {
+          if entry.1 != subscription {
+            continue;
+          }
+          if let Some(sender) = context.session_senders.get(&entry.0) {
+            // No receivers just means nobody is listening to the session yet.
+            let _ = sender.send(msg.clone());
+          }
+        }
+      }
+    });
+  }
+
+  fn other_sessions_connected(&self, session_id: u32) -> bool {
+    self
+      .sessions
+      .iter()
+      .any(|session| *session.key() != session_id && session.value().load(Ordering::SeqCst))
+  }
+
+  fn subscribed_elsewhere(&self, session_id: u32, subscription: ReadingSubscription) -> bool {
+    self
+      .reading_subscriptions
+      .iter()
+      .any(|entry| entry.0 != session_id && entry.1 == subscription)
+  }
+
+  /// Forgets a session's reading subscriptions, returning the ones that no
+  /// other session is using.
+  fn release_session_subscriptions(&self, session_id: u32) -> Vec<ReadingSubscription> {
+    let subscriptions: Vec<ReadingSubscription> = self
+      .reading_subscriptions
+      .iter()
+      .filter(|entry| entry.0 == session_id)
+      .map(|entry| entry.1)
+      .collect();
+    self
+      .reading_subscriptions
+      .retain(|(id, _)| *id != session_id);
+    subscriptions
+      .into_iter()
+      .filter(|subscription| !self.subscribed_elsewhere(session_id, *subscription))
+      .collect()
+  }
+
+  /// Stops and releases everything a session was using.
+  ///
+  /// If no other session is connected, this stops every device, same as a
+  /// single client server would. Otherwise, only devices the session owns are
+  /// stopped, so other clients can keep going.
+  fn release_session_devices(&self, session_id: u32) -> ButtplugServerResultFuture {
+    let device_indexes = if self.other_sessions_connected(session_id) {
+      self.device_arbiter.owned_devices(session_id)
+    } else {
+      self.device_manager.device_indexes()
+    };
+    self.device_arbiter.release_session(session_id);
+    self.device_manager.stop_devices(&device_indexes)
+  }
+
+  /// Hands a message to the device manager, logging to every session.
+  fn device_manager_parse_message(&self, msg: ButtplugClientMessage) -> ButtplugServerResultFuture {
+    let fut = self
+      .log_span
+      .in_scope(|| self.device_manager.parse_message(msg));
+    Box::pin(fut.instrument(self.log_span.clone()))
+  }
+}
+
+/// A single client's connection to a [ButtplugServer][super::ButtplugServer].
+///
+/// Each session does its own handshake, and has its own ping timer and log
+/// forwarding, while devices and device events are shared with every other
+/// session on the same server. Which session gets to command a device is
+/// decided by the server's
+/// [DeviceArbitrationPolicy][super::DeviceArbitrationPolicy].
+pub struct ButtplugServerSession {
+  id: u32,
+  priority: u32,
+  context: Arc<ButtplugServerContext>,
+  ping_timer: Arc<PingTimer>,
+  connected: Arc<AtomicBool>,
+  client_info: Arc<std::sync::Mutex<Option<(String, ButtplugMessageSpecVersion)>>>,
+  /// Events only meant for this session, like logs, ping errors and readings.
+  session_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Registration for forwarding tracing output to the client, if it has
+  /// requested logs via RequestLog.
+  log_sink: Arc<Mutex<Option<LogSinkHandle>>>,
+  log_scope: LogScope,
+  /// Span that keeps this session's logs from going to other sessions.
+  log_span: tracing::Span,
+  ping_task_token: CancellationToken,
+}
+
+impl ButtplugServerSession {
+  pub(super) fn new(id: u32, priority: u32, context: Arc<ButtplugServerContext>) -> Self {
+    let (session_sender, _) = broadcast::channel(256);
+    let connected = Arc::new(AtomicBool::new(false));
+    let ping_timer = Arc::new(PingTimer::new(context.max_ping_time));
+    let ping_task_token = CancellationToken::new();
+    let log_scope = context.log_scope.session(id);
+    let log_span = log_scope.span();
+    context.sessions.insert(id, connected.clone());
+    context.session_senders.insert(id, session_sender.clone());
+
+    let ping_timeout_notifier = ping_timer.ping_timeout_waiter();
+    let connected_clone = connected.clone();
+    let context_clone = context.clone();
+    let session_sender_clone = session_sender.clone();
+    let token = ping_task_token.child_token();
+    async_manager::spawn(
+      async move {
+        select! {
+          _ = ping_timeout_notifier.fuse() => {},
+          _ = token.cancelled().fuse() => return,
+        };
+        error!("Ping out signal received, stopping session devices");
+        connected_clone.store(false, Ordering::SeqCst);
+        if let Err(e) = context_clone.release_session_devices(id).await {
+          error!("Error stopping devices on ping timeout: {}", e);
+        }
+        // TODO Should the event sender return a result instead of an error message?
+        if session_sender_clone
+          .send(messages::Error::from(ButtplugError::from(ButtplugPingError::PingedOut)).into())
+          .is_err()
+        {
+          error!("Server disappeared, cannot update about ping out.");
+        };
+      }
+      .instrument(tracing::info_span!(
+        "Buttplug Server Ping Timeout Task",
+        session = id
+      ))
+      .instrument(log_span.clone()),
+    );
+
+    Self {
+      id,
+      priority,
+      context,
+      ping_timer,
+      connected,
+      client_info: Arc::new(std::sync::Mutex::new(None)),
+      session_sender,
+      log_sink: Arc::new(Mutex::new(None)),
+      log_scope,
+      log_span,
+      ping_task_token,
+    }
+  }
+
+  pub fn id(&self) -> u32 {
+    self.id
+  }
+
+  pub fn priority(&self) -> u32 {
+    self.priority
+  }
+
+  /// Name the client sent during the handshake, if the handshake has happened.
+  pub fn client_name(&self) -> Option<String> {
+    self
+      .client_info
+      .lock()
+      .expect("Lock should never be poisoned")
+      .as_ref()
+      .map(|(name, _)| name.clone())
+  }
+
+  /// Message spec version the client asked for during the handshake, if the
+  /// handshake has happened.
+  pub fn spec_version(&self) -> Option<ButtplugMessageSpecVersion> {
+    self
+      .client_info
+      .lock()
+      .expect("Lock should never be poisoned")
+      .as_ref()
+      .map(|(_, version)| *version)
+  }
+
+  /// Device events shared by all sessions, along with events meant only for
+  /// this session. Readings only come through for subscriptions this session
+  /// made.
+  pub fn event_stream(&self) -> impl Stream<Item = ButtplugServerMessage> {
+    let device_events =
+      convert_broadcast_receiver_to_stream(self.context.output_sender.subscribe())
+        .filter(|msg| future::ready(ReadingSubscription::from_reading(msg).is_none()));
+    stream::select(
+      device_events,
+      convert_broadcast_receiver_to_stream(self.session_sender.subscribe()),
+    )
+  }
+
+  pub fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  pub fn disconnect(&self) -> BoxFuture<'static, Result<(), messages::Error>> {
+    let _log_span = self.log_span.enter();
+    debug!(
+      "Buttplug Server {} session {} disconnect requested",
+      self.context.server_name, self.id
+    );
+    let ping_timer = self.ping_timer.clone();
+    let was_connected = self.connected.swap(false, Ordering::SeqCst);
+    // Scanning and subscriptions are shared, so only shut them down when the
+    // last client leaves.
+    let last_session = !self.context.other_sessions_connected(self.id);
+    let stop_scanning_fut = if was_connected && last_session {
+      Some(
+        self
+          .context
+          .device_manager
+          .parse_message(ButtplugClientMessage::StopScanning(StopScanning::default())),
+      )
+    } else {
+      None
+    };
+    let stop_fut = if was_connected {
+      Some(self.context.release_session_devices(self.id))
+    } else {
+      None
+    };
+    // Other sessions may still want readings from some of this session's
+    // subscriptions, so only remove the ones nobody else is using.
+    let released_subscriptions = self.context.release_session_subscriptions(self.id);
+    let unsubscribe_fut = if last_session {
+      Some(self.context.device_manager.unsubscribe_all_devices())
+    } else {
+      let unsubscribe_futs: Vec<_> = released_subscriptions
+        .iter()
+        .map(|subscription| {
+          self
+            .context
+            .device_manager_parse_message(subscription.unsubscribe_message())
+        })
+        .collect();
+      Some(
+        async move {
+          for result in future::join_all(unsubscribe_futs).await {
+            if let Err(err) = result {
+              error!("Error removing subscription: {:?}", err);
+            }
+          }
+          Ok(())
+        }
+        .boxed(),
+      )
+    };
+    *self
+      .client_info
+      .lock()
+      .expect("Lock should never be poisoned") = None;
+    let log_sink = self.log_sink.clone();
+    Box::pin(
+      async move {
+        ping_timer.stop_ping_timer().await;
+        // Stop forwarding logs, the next client will have to ask for them again.
+        *log_sink.lock().await = None;
+        // Ignore returns here, we just want to stop.
+        if let Some(fut) = stop_scanning_fut {
+          info!("Server disconnected, stopping device scanning if it was started...");
+          let _ = fut.await;
+        }
+        if let Some(fut) = stop_fut {
+          info!("Server disconnected, stopping devices...");
+          let _ = fut.await;
+        }
+        if let Some(fut) = unsubscribe_fut {
+          info!("Server disconnected, removing subscriptions...");
+          let _ = fut.await;
+        }
+        Ok(())
+      }
+      .instrument(self.log_span.clone()),
+    )
+  }
+
+  // This is the only method that returns ButtplugServerResult, as it handles
+  // the packing of the message ID.
+  pub fn parse_message(
+    &self,
+    msg: ButtplugClientMessage,
+  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
+    let _log_span = self.log_span.enter();
+    trace!(
+      "Buttplug Server {} session {} received message to client parse: {:?}",
+      self.context.server_name,
+      self.id,
+      msg
+    );
+    let id = msg.id();
+    if !self.connected() {
+      // Check for ping timeout first! There's no way we should've pinged out if
+      // we haven't received RequestServerInfo first, but we do want to know if
+      // we pinged out.
+      let error = if self.ping_timer.pinged_out() {
+        Some(messages::Error::from(ButtplugError::from(
+          ButtplugPingError::PingedOut,
+        )))
+      } else if !matches!(msg, ButtplugClientMessage::RequestServerInfo(_)) {
+        Some(messages::Error::from(ButtplugError::from(
+          ButtplugHandshakeError::RequestServerInfoExpected,
+        )))
+      } else {
+        None
+      };
+      if let Some(mut return_error) = error {
+        return_error.set_id(msg.id());
+        return Box::pin(future::ready(Err(return_error)));
+      }
+      // If we haven't pinged out and we got an RSI message, fall thru.
+    }
+    // Produce whatever future is needed to reply to the message, this may be a
+    // device command future, or something the server handles. All futures will
+    // return Result<ButtplugServerMessage, ButtplugError>, and we'll handle
+    // tagging the result with the message id in the future we put out as the
+    // return value from this method.
+    let out_fut = if let Ok(device_msg) = ButtplugDeviceCommandMessageUnion::try_from(msg.clone()) {
+      match self.arbitrate(&device_msg) {
+        Ok(()) => self.handle_device_command(&device_msg, msg.clone()),
+        Err(err) => err.into(),
+      }
+    } else if let ButtplugClientMessage::StopAllDevices(_) = msg {
+      self.stop_all_devices()
+    } else if ButtplugDeviceManagerMessageUnion::try_from(msg.clone()).is_ok() {
+      self.context.device_manager_parse_message(msg.clone())
+    } else {
+      match msg {
+        ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
+        ButtplugClientMessage::Ping(p) => self.handle_ping(p),
+        ButtplugClientMessage::RequestLog(l) => self.handle_request_log(l),
+        ButtplugClientMessage::RequestKnownDeviceList(_) => self.handle_request_known_device_list(),
+        ButtplugClientMessage::SetDeviceUserConfig(m) => self.handle_set_device_user_config(m),
+        _ => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
+      }
+    };
+    // Simple way to set the ID on the way out. Just rewrap
+    // the returned future to make sure it happens.
+    Box::pin(
+      async move {
+        out_fut
+          .await
+          .map(|mut ok_msg| {
+            ok_msg.set_id(id);
+            ok_msg
+          })
+          .map_err(|err| {
+            let mut error = messages::Error::from(err);
+            error.set_id(id);
+            error
+          })
+      }
+      .instrument(info_span!("Buttplug Server Message", id = id))
+      .instrument(self.log_span.clone()),
+    )
+  }
+
+  /// Checks the device command against the arbitration policy. Only commands
+  /// that drive device output take ownership of the device. Stopping, reading
+  /// and subscribing are allowed for anyone who could command the device, but
+  /// don't take ownership of it.
+  fn arbitrate(
+    &self,
+    device_msg: &ButtplugDeviceCommandMessageUnion,
+  ) -> Result<(), ButtplugDeviceError> {
+    let device_index = device_msg.device_index();
+    let takes_ownership = matches!(
+      device_msg,
+      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(_)
+        | ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_)
+        | ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(_)
+        | ButtplugDeviceCommandMessageUnion::KiirooCmd(_)
+        | ButtplugDeviceCommandMessageUnion::VibrateCmd(_)
+        | ButtplugDeviceCommandMessageUnion::LinearCmd(_)
+        | ButtplugDeviceCommandMessageUnion::RotateCmd(_)
+        | ButtplugDeviceCommandMessageUnion::ScalarCmd(_)
+        | ButtplugDeviceCommandMessageUnion::RawWriteCmd(_)
+    );
+    if takes_ownership {
+      self
+        .context
+        .device_arbiter
+        .claim(self.id, self.priority, device_index)
+    } else if self
+      .context
+      .device_arbiter
+      .can_control(self.id, self.priority, device_index)
+    {
+      Ok(())
+    } else {
+      Err(ButtplugDeviceError::DeviceInUse(device_index))
+    }
+  }
+
+  /// Passes a device command on to the device manager, keeping track of the
+  /// reading subscriptions this session makes. If another session already has
+  /// the same subscription, the device is left alone and only this session's
+  /// tracking changes.
+  fn handle_device_command(
+    &self,
+    device_msg: &ButtplugDeviceCommandMessageUnion,
+    msg: ButtplugClientMessage,
+  ) -> ButtplugServerResultFuture {
+    let (subscription, subscribe) =
+      if let Some(change) = ReadingSubscription::from_command(device_msg) {
+        change
+      } else {
+        return self.context.device_manager_parse_message(msg);
+      };
+    let key = (self.id, subscription);
+    if self.context.subscribed_elsewhere(self.id, subscription) {
+      if subscribe {
+        self.context.reading_subscriptions.insert(key);
+      } else {
+        self.context.reading_subscriptions.remove(&key);
+      }
+      return Box::pin(future::ready(Ok(messages::Ok::new(msg.id()).into())));
+    }
+    // Track new subscriptions before sending, so readings that show up right
+    // away aren't dropped.
+    let newly_subscribed = subscribe && self.context.reading_subscriptions.insert(key);
+    let fut = self.context.device_manager_parse_message(msg);
+    let reading_subscriptions = self.context.reading_subscriptions.clone();
+    Box::pin(async move {
+      let result = fut.await;
+      match (&result, subscribe) {
+        (Ok(_), false) => {
+          reading_subscriptions.remove(&key);
+        }
+        (Err(_), true) if newly_subscribed => {
+          reading_subscriptions.remove(&key);
+        }
+        _ => {}
+      }
+      result
+    })
+  }
+
+  /// Stops every device this session is allowed to command.
+  fn stop_all_devices(&self) -> ButtplugServerResultFuture {
+    let device_indexes: Vec<u32> = self
+      .context
+      .device_manager
+      .device_indexes()
+      .into_iter()
+      .filter(|index| {
+        self
+          .context
+          .device_arbiter
+          .can_control(self.id, self.priority, *index)
+      })
+      .collect();
+    self.context.device_manager.stop_devices(&device_indexes)
+  }
+
+  fn perform_handshake(&self, msg: messages::RequestServerInfo) -> ButtplugServerResultFuture {
+    if self.connected() {
+      return ButtplugHandshakeError::HandshakeAlreadyHappened.into();
+    }
+    info!(
+      "Performing server handshake check with client {} at message version {}.",
+      msg.client_name(),
+      msg.message_version()
+    );
+    if let Some(expected_token) = &self.context.auth_token {
+      match msg.auth_token() {
+        None => return ButtplugHandshakeError::AuthenticationRequired.into(),
+        Some(token) if !tokens_match(token, expected_token) => {
+          warn!(
+            "Client {} sent an invalid auth token, rejecting handshake.",
+            msg.client_name()
+          );
+          return ButtplugHandshakeError::InvalidCredentials.into();
+        }
+        Some(_) => {}
+      }
+    }
+    if BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION < msg.message_version() {
+      return ButtplugHandshakeError::MessageSpecVersionMismatch(
+        BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+        msg.message_version(),
+      )
+      .into();
+    }
+    // Only start the ping timer after we've received the handshake.
+    let ping_timer = self.ping_timer.clone();
+    let out_msg = messages::ServerInfo::new(
+      &self.context.server_name,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      self.context.max_ping_time,
+    );
+    let connected = self.connected.clone();
+    let client_info = self.client_info.clone();
+    Box::pin(async move {
+      ping_timer.start_ping_timer().await;
+      *client_info.lock().expect("Lock should never be poisoned") =
+        Some((msg.client_name().clone(), msg.message_version()));
+      connected.store(true, Ordering::SeqCst);
+      debug!("Server handshake check successful.");
+      Result::Ok(out_msg.into())
+    })
+  }
+
+  fn handle_ping(&self, msg: messages::Ping) -> ButtplugServerResultFuture {
+    if self.ping_timer.max_ping_time() == 0 {
+      return ButtplugPingError::PingTimerNotRunning.into();
+    }
+    let fut = self.ping_timer.update_ping_time();
+    Box::pin(async move {
+      fut.await;
+      Result::Ok(messages::Ok::new(msg.id()).into())
+    })
+  }
+
+  fn handle_request_log(&self, msg: messages::RequestLog) -> ButtplugServerResultFuture {
+    let log_sink = self.log_sink.clone();
+    let log_scope = self.log_scope;
+    let session_sender = self.session_sender.clone();
+    Box::pin(async move {
+      let mut sink = log_sink.lock().await;
+      // Always drop the old sink first. This closes its channel, which will
+      // shut down the forwarding task attached to it.
+      *sink = None;
+      if *msg.log_level() == LogLevel::Off {
+        info!("Client requested log level Off, stopping log forwarding.");
+        return Result::Ok(messages::Ok::new(msg.id()).into());
+      }
+      info!(
+        "Client requested log level {:?}, starting log forwarding.",
+        msg.log_level()
+      );
+      let (log_sender, mut log_receiver) = mpsc::channel(256);
+      *sink = register_log_sink(log_scope, msg.log_level().clone(), log_sender);
+      async_manager::spawn(async move {
+        while let Some(log_msg) = log_receiver.recv().await {
+          if session_sender.send(log_msg.into()).is_err() {
+            break;
+          }
+        }
+      });
+      Result::Ok(messages::Ok::new(msg.id()).into())
+    })
+  }
+
+  fn check_device_configuration_allowed(&self) -> Result<(), ButtplugDeviceError> {
+    if self.context.allow_device_configuration_messages {
+      Ok(())
+    } else {
+      Err(ButtplugDeviceError::DevicePermissionError(
+        "Device configuration messages are not enabled on this server.".to_owned(),
+      ))
+    }
+  }
+
+  fn handle_request_known_device_list(&self) -> ButtplugServerResultFuture {
+    if let Err(err) = self.check_device_configuration_allowed() {
+      return err.into();
+    }
+    let device_list = KnownDeviceList::new(self.context.device_manager.known_device_list());
+    Box::pin(future::ready(Ok(device_list.into())))
+  }
+
+  /// Replaces the display name and allow/deny entries for an address, keeping
+  /// any output limits, then applies the change to connected devices and
+  /// writes it back to the user configuration file.
+  fn handle_set_device_user_config(
+    &self,
+    msg: messages::SetDeviceUserConfig,
+  ) -> ButtplugServerResultFuture {
+    if let Err(err) = self.check_device_configuration_allowed() {
+      return err.into();
+    }
+    let device_manager = &self.context.device_manager;
+    let address = msg.address();
+    let mut config = device_manager
+      .device_user_config()
+      .remove(address)
+      .unwrap_or_default();
+    config.set_display_name(msg.display_name().clone());
+    config.set_allow(msg.allow());
+    config.set_deny(msg.deny());
+    if config == DeviceUserConfig::default() {
+      device_manager.remove_device_user_config(address);
+    } else {
+      device_manager.add_device_user_config(address, config);
+    }
+    let apply_fut = device_manager.apply_device_user_config();
+    let save_fut = device_manager.save_device_user_config();
+    Box::pin(async move {
+      apply_fut.await?;
+      save_fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+impl Drop for ButtplugServerSession {
+  fn drop(&mut self) {
+    self.ping_task_token.cancel();
+    self.context.sessions.remove(&self.id);
+    self.context.session_senders.remove(&self.id);
+    self
+      .context
+      .reading_subscriptions
+      .retain(|(id, _)| *id != self.id);
+    self.context.device_arbiter.release_session(self.id);
+  }
+}
@@ -3,6 +3,7 @@ use futures::{
   task::{FutureObj, Spawn, SpawnError, SpawnExt},
 };
 use tokio;
+use tracing_futures::Instrument;
 
 #[derive(Default)]
 pub struct TokioAsyncManager {}
@@ -14,12 +15,13 @@ impl Spawn for TokioAsyncManager {
   }
 }
 
+/// Spawns a task, which stays in the span it was spawned from.
 pub fn spawn<Fut>(future: Fut)
 where
   Fut: Future<Output = ()> + Send + 'static,
 {
   TokioAsyncManager::default()
-    .spawn(future)
+    .spawn(future.in_current_span())
     .expect("Infallible, only returns result to match trait")
 }
 
@@ -28,7 +30,7 @@ where
   Fut: Future + Send + 'static,
   Fut::Output: Send,
 {
-  TokioAsyncManager::default().spawn_with_handle(future)
+  TokioAsyncManager::default().spawn_with_handle(future.in_current_span())
 }
 
 pub fn block_on<F>(f: F) -> <F as Future>::Output
@@ -2,7 +2,7 @@ use futures::{
   future::{Future, RemoteHandle},
   task::{FutureObj, Spawn, SpawnError, SpawnExt},
 };
-
+use tracing_futures::Instrument;
 use wasm_bindgen_futures::spawn_local;
 
 #[derive(Default)]
@@ -15,11 +15,12 @@ impl Spawn for WasmBindgenAsyncManager {
   }
 }
 
+/// Spawns a task, which stays in the span it was spawned from.
 pub fn spawn<Fut>(future: Fut)
 where
   Fut: Future<Output = ()> + 'static,
 {
-  spawn_local(future);
+  spawn_local(future.in_current_span());
 }
 
 pub fn spawn_with_handle<Fut>(future: Fut) -> Result<RemoteHandle<Fut::Output>, SpawnError>
@@ -27,7 +28,7 @@ where
   Fut: Future + Send + 'static,
   Fut::Output: Send,
 {
-  WasmBindgenAsyncManager::default().spawn_with_handle(future)
+  WasmBindgenAsyncManager::default().spawn_with_handle(future.in_current_span())
 }
 
 pub fn block_on<F>(_: F) -> <F as Future>::Output
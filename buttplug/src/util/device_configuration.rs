@@ -1,18 +1,18 @@
-use super::json::JSONValidator;
+use super::{file::write_atomically, json::JSONValidator};
 use crate::{
   core::errors::{ButtplugDeviceError, ButtplugError},
   device::configuration_manager::{DeviceConfigurationManager, ProtocolDefinition},
   server::device_manager::DeviceUserConfig,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
 pub static DEVICE_CONFIGURATION_JSON: &str =
   include_str!("../../buttplug-device-config/buttplug-device-config.json");
 static DEVICE_CONFIGURATION_JSON_SCHEMA: &str =
   include_str!("../../buttplug-device-config/buttplug-device-config-schema.json");
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ProtocolConfiguration {
   pub version: u32,
   #[serde(default)]
@@ -75,6 +75,7 @@ pub fn load_protocol_config_from_json(
             internal_config_version
           )).into())
         } else {
+          validate_user_config(&protocol_config)?;
           Ok(protocol_config)
         }
       }
@@ -84,6 +85,145 @@ pub fn load_protocol_config_from_json(
   }
 }
 
+/// Checks the parts of the user configuration that the schema can't.
+fn validate_user_config(config: &ProtocolConfiguration) -> Result<(), ButtplugError> {
+  for user_config in config.user_config.values() {
+    if let Some(limits) = user_config.limits() {
+      limits.validate()?;
+    }
+  }
+  Ok(())
+}
+
+/// Formats device configuration files can be written in. Everything is
+/// converted to JSON before loading, so all formats go through the same schema
+/// validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolConfigurationFormat {
+  #[default]
+  Json,
+  #[cfg(feature = "yaml-device-config")]
+  Yaml,
+  #[cfg(feature = "toml-device-config")]
+  Toml,
+}
+
+impl ProtocolConfigurationFormat {
+  /// Picks a format based on a file's extension, defaulting to JSON for
+  /// anything unknown. YAML and TOML files are an error if support for them
+  /// wasn't compiled in, rather than being misread as JSON.
+  pub fn from_path(path: &Path) -> Result<Self, ButtplugError> {
+    match path
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_lowercase())
+      .as_deref()
+    {
+      #[cfg(feature = "yaml-device-config")]
+      Some("yml") | Some("yaml") => Ok(Self::Yaml),
+      #[cfg(not(feature = "yaml-device-config"))]
+      Some("yml") | Some("yaml") => Err(format_not_enabled(path, "yaml-device-config")),
+      #[cfg(feature = "toml-device-config")]
+      Some("toml") => Ok(Self::Toml),
+      #[cfg(not(feature = "toml-device-config"))]
+      Some("toml") => Err(format_not_enabled(path, "toml-device-config")),
+      _ => Ok(Self::Json),
+    }
+  }
+}
+
+#[cfg(not(all(feature = "yaml-device-config", feature = "toml-device-config")))]
+fn format_not_enabled(path: &Path, feature: &str) -> ButtplugError {
+  ButtplugDeviceError::DeviceConfigurationFileError(format!(
+    "Cannot load {}, the {} feature is not enabled.",
+    path.display(),
+    feature
+  ))
+  .into()
+}
+
+#[cfg(any(feature = "yaml-device-config", feature = "toml-device-config"))]
+fn convert_to_json<E>(
+  format_name: &str,
+  config: Result<serde_json::Value, E>,
+) -> Result<String, ButtplugError>
+where
+  E: std::fmt::Display,
+{
+  config.map(|value| value.to_string()).map_err(|err| {
+    ButtplugDeviceError::DeviceConfigurationFileError(format!(
+      "Cannot parse {}: {}",
+      format_name, err
+    ))
+    .into()
+  })
+}
+
+pub fn load_protocol_config(
+  config_str: &str,
+  format: ProtocolConfigurationFormat,
+  skip_version_check: bool,
+) -> Result<ProtocolConfiguration, ButtplugError> {
+  match format {
+    ProtocolConfigurationFormat::Json => {
+      load_protocol_config_from_json(config_str, skip_version_check)
+    }
+    #[cfg(feature = "yaml-device-config")]
+    ProtocolConfigurationFormat::Yaml => load_protocol_config_from_json(
+      &convert_to_json("YAML", serde_yaml::from_str(config_str))?,
+      skip_version_check,
+    ),
+    #[cfg(feature = "toml-device-config")]
+    ProtocolConfigurationFormat::Toml => load_protocol_config_from_json(
+      &convert_to_json("TOML", toml::from_str(config_str))?,
+      skip_version_check,
+    ),
+  }
+}
+
+/// Checks that user device configuration can be written back to a file.
+/// Everything is written as JSON, so YAML and TOML files would lose their
+/// comments and formatting, and are rejected.
+pub fn check_user_config_save_path(path: &Path) -> Result<(), ButtplugError> {
+  if ProtocolConfigurationFormat::from_path(path)? != ProtocolConfigurationFormat::Json {
+    return Err(
+      ButtplugDeviceError::DeviceConfigurationFileError(format!(
+        "Cannot write user configuration back to {}, only JSON files can be written back.",
+        path.display()
+      ))
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+/// Writes device user configuration back to a JSON user configuration file,
+/// keeping everything else in the file (like user protocol definitions) as it
+/// is. If the file doesn't exist yet, it's created. The file is replaced in one
+/// go, so it's never left half written.
+pub fn save_user_config(
+  path: &Path,
+  user_config: HashMap<String, DeviceUserConfig>,
+) -> Result<(), ButtplugError> {
+  check_user_config_save_path(path)?;
+  let file_error = |action: &str, err: &dyn std::fmt::Display| -> ButtplugError {
+    ButtplugDeviceError::DeviceConfigurationFileError(format!(
+      "Cannot {} {}: {}",
+      action,
+      path.display(),
+      err
+    ))
+    .into()
+  };
+  let mut config = match std::fs::read_to_string(path) {
+    Ok(config_str) => load_protocol_config_from_json(&config_str, true)?,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => ProtocolConfiguration::default(),
+    Err(err) => return Err(file_error("read", &err)),
+  };
+  config.user_config = user_config;
+  write_atomically(path, config.to_json().as_bytes()).map_err(|err| file_error("write", &err))
+}
+
 pub fn create_test_dcm(allow_raw_messages: bool) -> DeviceConfigurationManager {
   let devices = load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON, false)
     .expect("If this fails, the whole library goes with it.");
@@ -0,0 +1,930 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Semantic checks for device configuration files.
+//!
+//! [load_protocol_config_from_json] only makes sure a file matches the schema.
+//! The functions here catch the mistakes that still get through, like two
+//! protocols claiming the same Bluetooth name (whichever the server happens to
+//! check first wins), or user configurations that quietly replace parts of the
+//! main configuration.
+
+#[cfg(doc)]
+use super::device_configuration::load_protocol_config_from_json;
+use super::device_configuration::ProtocolConfiguration;
+use crate::{
+  core::messages::{ButtplugDeviceMessageType, DeviceMessageAttributesMap},
+  device::{
+    configuration_manager::{ProtocolAttributes, ProtocolDefinition},
+    protocol::{get_default_protocol_map, template},
+  },
+};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt,
+  hash::Hash,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigurationIssueSeverity {
+  /// Probably a mistake, but the configuration will still do something sane.
+  Warning,
+  /// The configuration won't work as written.
+  Error,
+}
+
+/// A problem found in a device configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationIssue {
+  pub severity: ConfigurationIssueSeverity,
+  /// Protocol the issue was found in. For issues involving more than one
+  /// protocol, this is the first one alphabetically.
+  pub protocol: String,
+  pub message: String,
+}
+
+impl ConfigurationIssue {
+  fn error(protocol: &str, message: String) -> Self {
+    Self {
+      severity: ConfigurationIssueSeverity::Error,
+      protocol: protocol.to_owned(),
+      message,
+    }
+  }
+
+  fn warning(protocol: &str, message: String) -> Self {
+    Self {
+      severity: ConfigurationIssueSeverity::Warning,
+      protocol: protocol.to_owned(),
+      message,
+    }
+  }
+}
+
+impl fmt::Display for ConfigurationIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let severity = match self.severity {
+      ConfigurationIssueSeverity::Warning => "warning",
+      ConfigurationIssueSeverity::Error => "error",
+    };
+    write!(f, "{}: {}: {}", severity, self.protocol, self.message)
+  }
+}
+
+/// Returns true if a device with a matching name could be picked up by both
+/// Bluetooth name patterns. Mirrors the wildcard handling in
+/// [BluetoothLESpecifier][crate::device::configuration_manager::BluetoothLESpecifier]'s
+/// PartialEq.
+fn ble_names_overlap(a: &str, b: &str) -> bool {
+  match (a.strip_suffix('*'), b.strip_suffix('*')) {
+    (None, None) => a == b,
+    (Some(prefix), None) => b.starts_with(prefix),
+    (None, Some(prefix)) => a.starts_with(prefix),
+    (Some(a_prefix), Some(b_prefix)) => {
+      a_prefix.starts_with(b_prefix) || b_prefix.starts_with(a_prefix)
+    }
+  }
+}
+
+fn sorted_protocols(
+  protocols: &HashMap<String, ProtocolDefinition>,
+) -> Vec<(&String, &ProtocolDefinition)> {
+  let mut sorted: Vec<_> = protocols.iter().collect();
+  sorted.sort_by(|a, b| a.0.cmp(b.0));
+  sorted
+}
+
+/// Reports identifiers (USB/HID ids, serial ports, websocket names) that more
+/// than one protocol claims.
+fn check_shared_identifiers<T, F>(
+  protocols: &[(&String, &ProtocolDefinition)],
+  bus: &str,
+  identifiers: F,
+  issues: &mut Vec<ConfigurationIssue>,
+) where
+  T: Eq + Hash + fmt::Debug,
+  F: Fn(&ProtocolDefinition) -> Vec<T>,
+{
+  let mut owners: HashMap<T, &str> = HashMap::new();
+  for (protocol, definition) in protocols {
+    for identifier in identifiers(definition) {
+      match owners.get(&identifier) {
+        Some(owner) if owner != protocol => issues.push(ConfigurationIssue::error(
+          owner,
+          format!(
+            "{} identifier {:?} is also used by protocol {}",
+            bus, identifier, protocol
+          ),
+        )),
+        Some(_) => {}
+        None => {
+          owners.insert(identifier, protocol);
+        }
+      }
+    }
+  }
+}
+
+fn check_ble_names(
+  protocols: &[(&String, &ProtocolDefinition)],
+  issues: &mut Vec<ConfigurationIssue>,
+) {
+  let mut names: Vec<(&str, &str)> = vec![];
+  for (protocol, definition) in protocols {
+    if let Some(btle) = definition.btle() {
+      let mut protocol_names: Vec<&str> = btle.names().iter().map(|name| name.as_str()).collect();
+      protocol_names.sort_unstable();
+      names.extend(
+        protocol_names
+          .into_iter()
+          .map(|name| (protocol.as_str(), name)),
+      );
+    }
+  }
+  for (i, (protocol, name)) in names.iter().enumerate() {
+    for (other_protocol, other_name) in &names[i + 1..] {
+      if protocol != other_protocol && ble_names_overlap(name, other_name) {
+        issues.push(ConfigurationIssue::error(
+          protocol,
+          format!(
+            "Bluetooth name {} overlaps with {} from protocol {}",
+            name, other_name, other_protocol
+          ),
+        ));
+      }
+    }
+  }
+}
+
+fn check_message_attributes(
+  protocol: &str,
+  context: &str,
+  messages: &DeviceMessageAttributesMap,
+  issues: &mut Vec<ConfigurationIssue>,
+) {
+  let mut messages: Vec<_> = messages.iter().collect();
+  messages.sort_by_key(|(message_type, _)| format!("{:?}", message_type));
+  for (message_type, attributes) in messages {
+    let feature_count = match attributes.feature_count {
+      Some(count) => count as usize,
+      None => continue,
+    };
+    let lengths = [
+      ("StepCount", attributes.step_count.as_ref().map(|v| v.len())),
+      (
+        "ActuatorType",
+        attributes.actuator_type.as_ref().map(|v| v.len()),
+      ),
+      (
+        "FeatureDescriptor",
+        attributes.feature_descriptor.as_ref().map(|v| v.len()),
+      ),
+      (
+        "SensorType",
+        attributes.sensor_type.as_ref().map(|v| v.len()),
+      ),
+    ];
+    for (field, length) in lengths {
+      if let Some(length) = length {
+        if length != feature_count {
+          issues.push(ConfigurationIssue::error(
+            protocol,
+            format!(
+              "{} {:?} has {} {} entries for a FeatureCount of {}",
+              context, message_type, length, field, feature_count
+            ),
+          ));
+        }
+      }
+    }
+  }
+}
+
+fn check_protocol_attributes(
+  protocol: &str,
+  definition: &ProtocolDefinition,
+  issues: &mut Vec<ConfigurationIssue>,
+) {
+  if let Some(messages) = definition
+    .defaults()
+    .as_ref()
+    .and_then(|d| d.messages().as_ref())
+  {
+    check_message_attributes(protocol, "defaults", messages, issues);
+  }
+  let mut seen_identifiers = HashSet::new();
+  for configuration in definition.configurations() {
+    let identifiers = configuration.identifier().clone().unwrap_or_default();
+    let context = format!("configuration {:?}", identifiers);
+    if let Some(messages) = configuration.messages() {
+      check_message_attributes(protocol, &context, messages, issues);
+    }
+    for identifier in identifiers {
+      if !seen_identifiers.insert(identifier.clone()) {
+        issues.push(ConfigurationIssue::warning(
+          protocol,
+          format!(
+            "identifier {} appears in more than one configuration, only the first is used",
+            identifier
+          ),
+        ));
+      }
+    }
+  }
+}
+
+/// Makes sure a protocol's template works with every device the protocol
+/// describes.
+fn check_template(
+  protocol: &str,
+  definition: &ProtocolDefinition,
+  issues: &mut Vec<ConfigurationIssue>,
+) {
+  let template = if let Some(template) = definition.template() {
+    template
+  } else {
+    return;
+  };
+  let step_counts = |attributes: &ProtocolAttributes| {
+    attributes
+      .messages()
+      .as_ref()
+      .and_then(|messages| messages.get(&ButtplugDeviceMessageType::VibrateCmd))
+      .and_then(|attrs| attrs.step_count.clone())
+  };
+  let actuator_step_counts = |attributes: &ProtocolAttributes| {
+    attributes
+      .messages()
+      .as_ref()
+      .and_then(|messages| messages.get(&ButtplugDeviceMessageType::ScalarCmd))
+      .map(template::actuator_step_counts)
+  };
+  let default_counts = definition.defaults().as_ref().and_then(step_counts);
+  let default_actuator_counts = definition
+    .defaults()
+    .as_ref()
+    .and_then(actuator_step_counts);
+  let mut devices = vec![];
+  if definition.defaults().is_some() {
+    devices.push((
+      "defaults".to_owned(),
+      default_counts.clone(),
+      default_actuator_counts.clone(),
+    ));
+  }
+  for configuration in definition.configurations() {
+    devices.push((
+      format!(
+        "configuration {:?}",
+        configuration.identifier().clone().unwrap_or_default()
+      ),
+      step_counts(configuration).or_else(|| default_counts.clone()),
+      actuator_step_counts(configuration).or_else(|| default_actuator_counts.clone()),
+    ));
+  }
+  for (context, counts, actuator_counts) in devices {
+    let counts = counts.unwrap_or_default();
+    let actuator_counts = actuator_counts.unwrap_or_default();
+    let result = if counts.is_empty() && actuator_counts.is_empty() {
+      Err("template needs VibrateCmd or ScalarCmd with a StepCount".to_owned())
+    } else {
+      template
+        .validate(&counts, &actuator_counts)
+        .map_err(|err| err.to_string())
+    };
+    if let Err(err) = result {
+      issues.push(ConfigurationIssue::error(
+        protocol,
+        format!("{} template is invalid: {}", context, err),
+      ));
+    }
+  }
+}
+
+/// Checks a device configuration for semantic errors that pass schema
+/// validation.
+///
+/// Issues are returned sorted by protocol name, so output is stable across
+/// runs.
+pub fn lint_protocol_configuration(config: &ProtocolConfiguration) -> Vec<ConfigurationIssue> {
+  let mut issues = check_protocols(&config.protocols);
+  issues.extend(check_cross_protocol(&config.protocols));
+  issues.sort_by(|a, b| a.protocol.cmp(&b.protocol));
+  issues
+}
+
+/// Checks that only involve one protocol at a time.
+fn check_protocols(protocols: &HashMap<String, ProtocolDefinition>) -> Vec<ConfigurationIssue> {
+  let protocol_map = get_default_protocol_map();
+  let mut issues = vec![];
+  for (protocol, definition) in sorted_protocols(protocols) {
+    // The main configuration file is shared with other Buttplug
+    // implementations, so it's expected to have a few protocols we don't.
+    if protocol_map.contains_key(protocol.as_str()) {
+      if definition.template().is_some() {
+        issues.push(ConfigurationIssue::warning(
+          protocol,
+          "template is ignored, since a protocol implementation with this name exists".to_owned(),
+        ));
+      }
+    } else if definition.template().is_none() {
+      issues.push(ConfigurationIssue::warning(
+        protocol,
+        "no protocol implementation with this name exists, matching devices will be ignored"
+          .to_owned(),
+      ));
+    }
+    check_protocol_attributes(protocol, definition, &mut issues);
+    check_template(protocol, definition, &mut issues);
+  }
+  issues
+}
+
+/// Checks for devices that more than one protocol could claim.
+fn check_cross_protocol(
+  protocols: &HashMap<String, ProtocolDefinition>,
+) -> Vec<ConfigurationIssue> {
+  let protocols = sorted_protocols(protocols);
+  let mut issues = vec![];
+  check_ble_names(&protocols, &mut issues);
+  check_shared_identifiers(
+    &protocols,
+    "Bluetooth advertised service",
+    |def| {
+      def
+        .btle()
+        .iter()
+        .flat_map(|btle| btle.advertised_services().iter().copied())
+        .collect()
+    },
+    &mut issues,
+  );
+  check_shared_identifiers(
+    &protocols,
+    "USB",
+    |def| {
+      def
+        .usb()
+        .iter()
+        .flatten()
+        .map(|usb| (*usb.vendor_id(), *usb.product_id()))
+        .collect()
+    },
+    &mut issues,
+  );
+  check_shared_identifiers(
+    &protocols,
+    "HID",
+    |def| {
+      def
+        .hid()
+        .iter()
+        .flatten()
+        .map(|hid| (*hid.vendor_id(), *hid.product_id()))
+        .collect()
+    },
+    &mut issues,
+  );
+  check_shared_identifiers(
+    &protocols,
+    "Serial",
+    |def| {
+      def
+        .serial()
+        .iter()
+        .flatten()
+        .map(|serial| serial.port().clone())
+        // Placeholder port, meant to be replaced by user configurations.
+        .filter(|port| port != "default")
+        .collect()
+    },
+    &mut issues,
+  );
+  check_shared_identifiers(
+    &protocols,
+    "Websocket",
+    |def| {
+      def
+        .websocket()
+        .iter()
+        .flat_map(|websocket| websocket.names.iter().cloned())
+        .collect()
+    },
+    &mut issues,
+  );
+  issues
+}
+
+/// Checks a user configuration against the main configuration it will be
+/// merged into.
+///
+/// Reports the places where the user configuration replaces or ignores parts of
+/// the main configuration, along with any issues the merge introduces that
+/// weren't already in the main configuration.
+pub fn lint_user_configuration(
+  main_config: &ProtocolConfiguration,
+  user_config: &ProtocolConfiguration,
+) -> Vec<ConfigurationIssue> {
+  let protocol_map = get_default_protocol_map();
+  let mut issues = vec![];
+  for (protocol, user_definition) in sorted_protocols(&user_config.protocols) {
+    check_protocol_attributes(protocol, user_definition, &mut issues);
+    let main_definition = match main_config.protocols.get(protocol) {
+      Some(definition) => definition,
+      None => {
+        // Unlike the main configuration, this is almost certainly a typo.
+        if !protocol_map.contains_key(protocol.as_str()) {
+          issues.push(ConfigurationIssue::error(
+            protocol,
+            "no protocol with this name exists".to_owned(),
+          ));
+        }
+        continue;
+      }
+    };
+    if user_definition.xinput().is_some() {
+      issues.push(ConfigurationIssue::warning(
+        protocol,
+        "XInput specifiers can't be set in user configurations and will be ignored".to_owned(),
+      ));
+    }
+    if user_definition.lovense_connect_service().is_some() {
+      issues.push(ConfigurationIssue::warning(
+        protocol,
+        "Lovense Connect specifiers can't be set in user configurations and will be ignored"
+          .to_owned(),
+      ));
+    }
+    if user_definition.defaults().is_some() && main_definition.defaults().is_some() {
+      issues.push(ConfigurationIssue::warning(
+        protocol,
+        "user defaults replace the built in defaults entirely".to_owned(),
+      ));
+    }
+    if let (Some(user_btle), Some(main_btle)) = (user_definition.btle(), main_definition.btle()) {
+      let mut replaced: Vec<String> = user_btle
+        .services()
+        .keys()
+        .filter(|service| main_btle.services().contains_key(service))
+        .map(|service| service.to_string())
+        .collect();
+      replaced.sort();
+      for service in replaced {
+        issues.push(ConfigurationIssue::warning(
+          protocol,
+          format!(
+            "user Bluetooth service {} replaces the built in endpoints for it",
+            service
+          ),
+        ));
+      }
+    }
+    let main_identifiers: HashSet<&String> = main_definition
+      .configurations()
+      .iter()
+      .flat_map(|configuration| configuration.identifier().iter().flatten())
+      .collect();
+    let mut shadowed: Vec<&String> = user_definition
+      .configurations()
+      .iter()
+      .flat_map(|configuration| configuration.identifier().iter().flatten())
+      .filter(|identifier| main_identifiers.contains(identifier))
+      .collect();
+    shadowed.sort_unstable();
+    shadowed.dedup();
+    for identifier in shadowed {
+      issues.push(ConfigurationIssue::warning(
+        protocol,
+        format!(
+          "user configuration for identifier {} shadows the built in one",
+          identifier
+        ),
+      ));
+    }
+  }
+  if !main_config.user_config.is_empty() {
+    issues.push(ConfigurationIssue::warning(
+      "user-config",
+      format!(
+        "user configuration replaces all {} device entries from the main configuration",
+        main_config.user_config.len()
+      ),
+    ));
+  }
+
+  // Only report clashes between protocols that the merge adds, the main
+  // configuration's own are its problem.
+  let mut merged = main_config.clone();
+  merged.merge(user_config.clone());
+  let main_issues = check_cross_protocol(&main_config.protocols);
+  issues.extend(
+    check_cross_protocol(&merged.protocols)
+      .into_iter()
+      .filter(|issue| !main_issues.contains(issue)),
+  );
+  issues.sort_by(|a, b| a.protocol.cmp(&b.protocol));
+  issues
+}
+
+/// A single difference between two configurations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationChange {
+  /// Protocol name, or `user-config/<address>` for per-device user
+  /// configuration.
+  pub target: String,
+  pub description: String,
+}
+
+impl fmt::Display for ConfigurationChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.target, self.description)
+  }
+}
+
+fn set_changes<T>(field: &str, before: &HashSet<T>, after: &HashSet<T>, changes: &mut Vec<String>)
+where
+  T: Eq + Hash + fmt::Display,
+{
+  let mut added: Vec<String> = after.difference(before).map(|x| x.to_string()).collect();
+  let mut removed: Vec<String> = before.difference(after).map(|x| x.to_string()).collect();
+  added.sort();
+  removed.sort();
+  if !added.is_empty() {
+    changes.push(format!("added {} {}", field, added.join(", ")));
+  }
+  if !removed.is_empty() {
+    changes.push(format!("removed {} {}", field, removed.join(", ")));
+  }
+}
+
+fn debug_set<T: fmt::Debug>(items: &Option<Vec<T>>) -> HashSet<String> {
+  items
+    .iter()
+    .flatten()
+    .map(|item| format!("{:?}", item))
+    .collect()
+}
+
+fn ble_services(definition: &ProtocolDefinition) -> HashSet<String> {
+  definition
+    .btle()
+    .iter()
+    .flat_map(|btle| btle.services().iter())
+    .map(|(service, endpoints)| {
+      let mut endpoints: Vec<String> = endpoints
+        .iter()
+        .map(|(endpoint, characteristic)| format!("{}: {}", endpoint, characteristic))
+        .collect();
+      endpoints.sort();
+      format!("{} ({})", service, endpoints.join(", "))
+    })
+    .collect()
+}
+
+fn attributes_json(attributes: &ProtocolAttributes) -> serde_json::Value {
+  serde_json::to_value(attributes).expect("Infallible serialization")
+}
+
+fn describe_configuration(attributes: &ProtocolAttributes) -> String {
+  format!(
+    "configuration for identifiers {:?}",
+    attributes.identifier().clone().unwrap_or_default()
+  )
+}
+
+fn protocol_changes(before: &ProtocolDefinition, after: &ProtocolDefinition) -> Vec<String> {
+  let mut changes = vec![];
+  let empty = HashSet::new();
+  set_changes(
+    "Bluetooth names",
+    before.btle().as_ref().map_or(&empty, |btle| btle.names()),
+    after.btle().as_ref().map_or(&empty, |btle| btle.names()),
+    &mut changes,
+  );
+  let before_services = ble_services(before);
+  let after_services = ble_services(after);
+  set_changes(
+    "Bluetooth services",
+    &before_services,
+    &after_services,
+    &mut changes,
+  );
+  set_changes(
+    "USB ids",
+    &debug_set(before.usb()),
+    &debug_set(after.usb()),
+    &mut changes,
+  );
+  set_changes(
+    "HID ids",
+    &debug_set(before.hid()),
+    &debug_set(after.hid()),
+    &mut changes,
+  );
+  let serial_ports = |definition: &ProtocolDefinition| -> HashSet<String> {
+    definition
+      .serial()
+      .iter()
+      .flatten()
+      .map(|serial| serial.port().clone())
+      .collect()
+  };
+  set_changes(
+    "serial ports",
+    &serial_ports(before),
+    &serial_ports(after),
+    &mut changes,
+  );
+  set_changes(
+    "websocket names",
+    before.websocket().as_ref().map_or(&empty, |ws| &ws.names),
+    after.websocket().as_ref().map_or(&empty, |ws| &ws.names),
+    &mut changes,
+  );
+  if before.defaults().as_ref().map(attributes_json)
+    != after.defaults().as_ref().map(attributes_json)
+  {
+    changes.push("changed defaults".to_owned());
+  }
+  let before_configurations: Vec<serde_json::Value> = before
+    .configurations()
+    .iter()
+    .map(attributes_json)
+    .collect();
+  let after_configurations: Vec<serde_json::Value> =
+    after.configurations().iter().map(attributes_json).collect();
+  for (configuration, json) in after.configurations().iter().zip(&after_configurations) {
+    if !before_configurations.contains(json) {
+      changes.push(format!("added {}", describe_configuration(configuration)));
+    }
+  }
+  for (configuration, json) in before.configurations().iter().zip(&before_configurations) {
+    if !after_configurations.contains(json) {
+      changes.push(format!("removed {}", describe_configuration(configuration)));
+    }
+  }
+  if before.min_command_interval() != after.min_command_interval() {
+    changes.push(format!(
+      "min-command-interval changed from {:?} to {:?}",
+      before.min_command_interval(),
+      after.min_command_interval()
+    ));
+  }
+  if before.template() != after.template() {
+    changes.push("template changed".to_owned());
+  }
+  changes
+}
+
+/// Lists everything that differs between two configurations. Mostly useful for
+/// seeing what a user configuration actually does, by diffing the main
+/// configuration against the result of
+/// [ProtocolConfiguration::merge]ing the user configuration into it.
+pub fn diff_protocol_configuration(
+  before: &ProtocolConfiguration,
+  after: &ProtocolConfiguration,
+) -> Vec<ConfigurationChange> {
+  let mut changes = vec![];
+  let mut protocols: Vec<&String> = before
+    .protocols
+    .keys()
+    .chain(after.protocols.keys())
+    .collect();
+  protocols.sort_unstable();
+  protocols.dedup();
+  for protocol in protocols {
+    let descriptions = match (
+      before.protocols.get(protocol),
+      after.protocols.get(protocol),
+    ) {
+      (None, Some(_)) => vec!["added protocol".to_owned()],
+      (Some(_), None) => vec!["removed protocol".to_owned()],
+      (Some(before), Some(after)) => protocol_changes(before, after),
+      (None, None) => unreachable!("Protocol names come from one of the two maps"),
+    };
+    changes.extend(
+      descriptions
+        .into_iter()
+        .map(|description| ConfigurationChange {
+          target: protocol.clone(),
+          description,
+        }),
+    );
+  }
+  let mut addresses: Vec<&String> = before
+    .user_config
+    .keys()
+    .chain(after.user_config.keys())
+    .collect();
+  addresses.sort_unstable();
+  addresses.dedup();
+  for address in addresses {
+    let description = match (
+      before.user_config.get(address),
+      after.user_config.get(address),
+    ) {
+      (None, Some(config)) => format!("added {:?}", config),
+      (Some(_), None) => "removed".to_owned(),
+      (Some(before), Some(after)) if before != after => {
+        format!("changed from {:?} to {:?}", before, after)
+      }
+      _ => continue,
+    };
+    changes.push(ConfigurationChange {
+      target: format!("user-config/{}", address),
+      description,
+    });
+  }
+  changes
+}
+
+#[cfg(test)]
+mod test {
+  use super::{
+    diff_protocol_configuration,
+    lint_protocol_configuration,
+    lint_user_configuration,
+    ConfigurationIssueSeverity,
+  };
+  use crate::util::device_configuration::{
+    load_protocol_config_from_json,
+    ProtocolConfiguration,
+    DEVICE_CONFIGURATION_JSON,
+  };
+
+  fn config(json: &str) -> ProtocolConfiguration {
+    serde_json::from_str(json).expect("Test, assuming infallible.")
+  }
+
+  fn messages(issues: &[super::ConfigurationIssue]) -> Vec<String> {
+    issues.iter().map(|issue| issue.to_string()).collect()
+  }
+
+  #[test]
+  fn test_builtin_config_has_no_errors() {
+    let config = load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON, false)
+      .expect("Test, assuming infallible.");
+    let issues = lint_protocol_configuration(&config);
+    assert!(
+      issues
+        .iter()
+        .all(|issue| issue.severity == ConfigurationIssueSeverity::Warning),
+      "{:?}",
+      messages(&issues)
+    );
+  }
+
+  #[test]
+  fn test_lint_protocol_configuration() {
+    let issues = lint_protocol_configuration(&config(
+      r#"{
+        "version": 1,
+        "protocols": {
+          "lovense": {
+            "btle": { "names": ["LVS-*"], "services": {} },
+            "defaults": {
+              "name": { "en-us": "Lovense" },
+              "messages": { "VibrateCmd": { "FeatureCount": 2, "StepCount": [20] } }
+            }
+          },
+          "wevibe": {
+            "btle": { "names": ["LVS-Special"], "services": {} }
+          },
+          "not-a-protocol": {
+            "usb": [{ "vendor-id": 1, "product-id": 2 }]
+          },
+          "aneros": {
+            "usb": [{ "vendor-id": 1, "product-id": 2 }]
+          },
+          "templated": {
+            "usb": [{ "vendor-id": 3, "product-id": 4 }],
+            "defaults": {
+              "name": { "en-us": "Templated" },
+              "messages": { "VibrateCmd": { "FeatureCount": 2, "StepCount": [10, 10] } }
+            },
+            "configurations": [
+              {
+                "identifier": ["Single"],
+                "name": { "en-us": "Templated Single" },
+                "messages": { "VibrateCmd": { "FeatureCount": 1, "StepCount": [10] } }
+              }
+            ],
+            "template": { "packet": [1, { "speed": 0 }, { "speed": 1 }] }
+          }
+        }
+      }"#,
+    ));
+    assert_eq!(
+      messages(&issues),
+      vec![
+        "error: aneros: USB identifier (1, 2) is also used by protocol not-a-protocol",
+        "error: lovense: defaults VibrateCmd has 1 StepCount entries for a FeatureCount of 2",
+        "error: lovense: Bluetooth name LVS-* overlaps with LVS-Special from protocol wevibe",
+        "warning: not-a-protocol: no protocol implementation with this name exists, matching devices will be ignored",
+        "error: templated: configuration [\"Single\"] template is invalid: Template refers to vibrator 1, but device has 1 vibrators.",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_lint_user_configuration() {
+    let main_config = config(
+      r#"{
+        "version": 1,
+        "protocols": {
+          "lovense": {
+            "btle": { "names": ["LVS-*"], "services": {} },
+            "defaults": { "name": { "en-us": "Lovense" } },
+            "configurations": [{ "identifier": ["P"], "name": { "en-us": "Edge" } }]
+          },
+          "wevibe": {
+            "btle": { "names": ["Cougar"], "services": {} }
+          }
+        }
+      }"#,
+    );
+    let user_config = config(
+      r#"{
+        "version": 1,
+        "protocols": {
+          "lovense": {
+            "defaults": { "name": { "en-us": "My Lovense" } },
+            "configurations": [{ "identifier": ["P"], "name": { "en-us": "My Edge" } }]
+          },
+          "wevibe": {
+            "btle": { "names": ["LVS-Mine"], "services": {} }
+          },
+          "lovenes": {
+            "btle": { "names": ["Typo"], "services": {} }
+          }
+        }
+      }"#,
+    );
+    let issues = lint_user_configuration(&main_config, &user_config);
+    assert_eq!(
+      messages(&issues),
+      vec![
+        "error: lovenes: no protocol with this name exists",
+        "warning: lovense: user defaults replace the built in defaults entirely",
+        "warning: lovense: user configuration for identifier P shadows the built in one",
+        "error: lovense: Bluetooth name LVS-* overlaps with LVS-Mine from protocol wevibe",
+      ]
+    );
+    // Nothing in the main config clashes with itself.
+    assert!(lint_user_configuration(&main_config, &config(r#"{ "version": 1 }"#)).is_empty());
+  }
+
+  #[test]
+  fn test_diff_protocol_configuration() {
+    let main_config = config(
+      r#"{
+        "version": 1,
+        "protocols": {
+          "lovense": {
+            "btle": { "names": ["LVS-*"], "services": {} },
+            "min-command-interval": 100
+          },
+          "nobra": {
+            "serial": [{ "port": "default", "baud-rate": 19200, "data-bits": 8, "parity": "N", "stop-bits": 1 }]
+          }
+        }
+      }"#,
+    );
+    let user_config = config(
+      r#"{
+        "version": 1,
+        "protocols": {
+          "lovense": {
+            "btle": { "names": ["Custom"], "services": {} },
+            "min-command-interval": 50
+          },
+          "nobra": {
+            "serial": [{ "port": "COM7", "baud-rate": 19200, "data-bits": 8, "parity": "N", "stop-bits": 1 }]
+          }
+        },
+        "user-config": {
+          "AA:BB": { "display-name": "Bedroom" }
+        }
+      }"#,
+    );
+    let mut merged = main_config.clone();
+    merged.merge(user_config);
+    let changes: Vec<String> = diff_protocol_configuration(&main_config, &merged)
+      .iter()
+      .map(|change| change.to_string())
+      .collect();
+    assert_eq!(changes.len(), 4, "{:?}", changes);
+    assert_eq!(changes[0], "lovense: added Bluetooth names Custom");
+    assert_eq!(
+      changes[1],
+      "lovense: min-command-interval changed from Some(100) to Some(50)"
+    );
+    assert_eq!(changes[2], "nobra: added serial ports COM7");
+    assert!(changes[3].starts_with("user-config/AA:BB: added "));
+    assert!(diff_protocol_configuration(&merged, &merged).is_empty());
+  }
+}
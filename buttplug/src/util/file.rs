@@ -0,0 +1,22 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Helpers for files the server writes back to while it's running.
+
+use std::{fs, io, path::Path};
+
+/// Writes a file by writing a temporary file next to it, then renaming it into
+/// place, so a crash or full disk part way through never leaves a truncated
+/// file behind.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let mut temp_path = path.as_os_str().to_owned();
+  temp_path.push(".tmp");
+  fs::write(&temp_path, contents)?;
+  fs::rename(&temp_path, path).inspect_err(|_| {
+    let _ = fs::remove_file(&temp_path);
+  })
+}
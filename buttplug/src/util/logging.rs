@@ -1,7 +1,27 @@
-use crate::util::async_manager;
+use crate::{
+  core::messages::{Log, LogLevel},
+  util::async_manager,
+};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::{
+  fmt::Debug,
+  sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
 use tokio::sync::mpsc::Sender;
-
-use tracing_subscriber::fmt::MakeWriter;
+use tracing::{
+  field::{Field, Visit},
+  span::{Attributes, Id},
+  Event,
+  Level,
+  Span,
+  Subscriber,
+};
+use tracing_subscriber::{
+  fmt::MakeWriter,
+  layer::{Context, Layer},
+  registry::LookupSpan,
+};
 
 /// Convenience struct for handling tracing output from Buttplug.
 ///
@@ -44,3 +64,225 @@ impl MakeWriter<'_> for ChannelWriter {
     ChannelWriter::new(self.log_sender.clone())
   }
 }
+
+const SERVER_FIELD: &str = "buttplug_server";
+const SESSION_FIELD: &str = "buttplug_session";
+
+static LOG_SCOPE_SERVER_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Which server, and which session on it, tracing output belongs to.
+///
+/// Output is only forwarded to log sinks with a matching scope. Events that
+/// belong to a server but no particular session, like device events, go to
+/// every session on that server. Events outside of any scope aren't forwarded
+/// at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogScope {
+  server: u32,
+  session: Option<u32>,
+}
+
+impl LogScope {
+  /// Scope for a new server, distinct from every other server in the process.
+  pub fn new_server() -> Self {
+    Self {
+      server: LOG_SCOPE_SERVER_ID.fetch_add(1, Ordering::SeqCst),
+      session: None,
+    }
+  }
+
+  /// Scope for a session on this scope's server.
+  pub fn session(&self, session: u32) -> Self {
+    Self {
+      server: self.server,
+      session: Some(session),
+    }
+  }
+
+  /// Span that puts everything recorded inside it into this scope. Spans
+  /// nested in it can switch to another scope.
+  ///
+  /// The span is at error level so that it exists no matter how verbose the
+  /// subscriber is.
+  pub fn span(&self) -> Span {
+    match self.session {
+      Some(session) => tracing::error_span!(
+        "Buttplug Log Scope",
+        buttplug_server = self.server,
+        buttplug_session = session
+      ),
+      None => tracing::error_span!("Buttplug Log Scope", buttplug_server = self.server),
+    }
+  }
+
+  fn includes(&self, event_scope: &LogScope) -> bool {
+    self.server == event_scope.server
+      && (event_scope.session.is_none() || event_scope.session == self.session)
+  }
+}
+
+/// Registered receiver for a [RequestLog][crate::core::messages::RequestLog]
+/// session, along with the most verbose level it wants to hear about.
+struct LogSink {
+  scope: LogScope,
+  level: Level,
+  sender: Sender<Log>,
+}
+
+static LOG_SINKS: Lazy<DashMap<u32, LogSink>> = Lazy::new(DashMap::new);
+static LOG_SINK_ID: AtomicU32 = AtomicU32::new(0);
+static LOG_LAYER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Handle for a registered log sink. The sink is removed from the forwarding
+/// layer once the handle is dropped, which will also close the channel handed
+/// to [register_log_sink].
+pub struct LogSinkHandle {
+  id: u32,
+}
+
+impl Drop for LogSinkHandle {
+  fn drop(&mut self) {
+    LOG_SINKS.remove(&self.id);
+  }
+}
+
+/// Registers a channel to receive tracing events in `scope` at or above
+/// `level`, packed as [Log] messages.
+///
+/// Returns None if `level` is [LogLevel::Off], as there's nothing to send.
+pub fn register_log_sink(
+  scope: LogScope,
+  level: LogLevel,
+  sender: Sender<Log>,
+) -> Option<LogSinkHandle> {
+  if level == LogLevel::Off {
+    return None;
+  }
+  if !LOG_LAYER_INSTALLED.load(Ordering::SeqCst) {
+    warn!("Log sink registered, but ButtplugLogLayer has not been added to the tracing subscriber. No log messages will be forwarded.");
+  }
+  let id = LOG_SINK_ID.fetch_add(1, Ordering::SeqCst);
+  LOG_SINKS.insert(
+    id,
+    LogSink {
+      scope,
+      level: level.into(),
+      sender,
+    },
+  );
+  Some(LogSinkHandle { id })
+}
+
+/// Tracing layer for forwarding log output to clients that have sent
+/// [RequestLog][crate::core::messages::RequestLog] messages.
+///
+/// As with all other logging in the library, we don't set up subscribers
+/// ourselves. Executables that want servers to be able to answer RequestLog
+/// need to add this layer to whatever subscriber they build, i.e.
+///
+/// ```ignore
+/// tracing_subscriber::registry()
+///   .with(tracing_subscriber::fmt::layer())
+///   .with(ButtplugLogLayer::default())
+///   .init();
+/// ```
+///
+/// Events are only forwarded to clients of the server and session they were
+/// recorded in, see [LogScope]. If no server has requested logs, events are
+/// dropped immediately.
+#[derive(Default)]
+pub struct ButtplugLogLayer {}
+
+impl<S> Layer<S> for ButtplugLogLayer
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  fn on_layer(&mut self, _subscriber: &mut S) {
+    LOG_LAYER_INSTALLED.store(true, Ordering::SeqCst);
+  }
+
+  fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+    let mut visitor = LogScopeVisitor::default();
+    attrs.record(&mut visitor);
+    if let Some(server) = visitor.server {
+      if let Some(span) = ctx.span(id) {
+        span.extensions_mut().insert(LogScope {
+          server,
+          session: visitor.session,
+        });
+      }
+    }
+  }
+
+  fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+    if LOG_SINKS.is_empty() {
+      return;
+    }
+    // The innermost scope wins, so server wide work done on behalf of a
+    // session goes to every session.
+    let scope = match ctx
+      .event_scope(event)
+      .and_then(|mut spans| spans.find_map(|span| span.extensions().get::<LogScope>().copied()))
+    {
+      Some(scope) => scope,
+      None => return,
+    };
+    let metadata = event.metadata();
+    // Don't forward anything coming out of the forwarding code itself, so we
+    // don't end up feeding ourselves.
+    if metadata.target().starts_with(module_path!()) {
+      return;
+    }
+    let level = *metadata.level();
+    let mut visitor = LogMessageVisitor::default();
+    event.record(&mut visitor);
+    let log_message = format!(
+      "{}: {}{}",
+      metadata.target(),
+      visitor.message,
+      visitor.fields
+    );
+    for sink in LOG_SINKS.iter() {
+      if level <= sink.level && sink.scope.includes(&scope) {
+        // If the channel is full, drop the message instead of blocking whatever
+        // task emitted the event.
+        let _ = sink.sender.try_send(Log::new(level.into(), &log_message));
+      }
+    }
+  }
+}
+
+#[derive(Default)]
+struct LogMessageVisitor {
+  message: String,
+  fields: String,
+}
+
+impl Visit for LogMessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+    if field.name() == "message" {
+      self.message = format!("{:?}", value);
+    } else {
+      self.fields += &format!(" {}={:?}", field.name(), value);
+    }
+  }
+}
+
+#[derive(Default)]
+struct LogScopeVisitor {
+  server: Option<u32>,
+  session: Option<u32>,
+}
+
+impl Visit for LogScopeVisitor {
+  fn record_u64(&mut self, field: &Field, value: u64) {
+    match field.name() {
+      SERVER_FIELD => self.server = u32::try_from(value).ok(),
+      SESSION_FIELD => self.session = u32::try_from(value).ok(),
+      _ => {}
+    }
+  }
+
+  fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {
+  }
+}
@@ -10,6 +10,8 @@
 
 pub mod async_manager;
 pub mod device_configuration;
+pub mod device_configuration_lint;
+pub(crate) mod file;
 pub mod future;
 pub mod json;
 pub mod logging;
@@ -2,7 +2,13 @@ mod util;
 extern crate buttplug;
 
 use buttplug::{
-  client::{ButtplugClient, ButtplugClientError, ButtplugClientEvent, VibrateCommand},
+  client::{
+    ButtplugClient,
+    ButtplugClientError,
+    ButtplugClientEvent,
+    ButtplugClientReconnectPolicy,
+    VibrateCommand,
+  },
   connector::{
     ButtplugConnector,
     ButtplugConnectorError,
@@ -15,14 +21,23 @@ use buttplug::{
   },
   device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
   server::comm_managers::test::{check_test_recv_value, TestDeviceCommunicationManagerBuilder},
-  server::ButtplugServerBuilder,
+  server::{remote_server::ButtplugRemoteServerEvent, ButtplugRemoteServer, ButtplugServerBuilder},
   util::async_manager,
 };
-use futures::{future::BoxFuture, StreamExt};
+use futures::{future::BoxFuture, pin_mut, StreamExt};
 use futures_timer::Delay;
-use std::time::Duration;
-use tokio::sync::mpsc::Sender;
-use util::DelayDeviceCommunicationManagerBuilder;
+use std::{
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::{
+  mpsc::{channel, Sender},
+  Notify,
+};
+use util::{connected_channel_connectors, DelayDeviceCommunicationManagerBuilder};
 
 #[derive(Default)]
 struct ButtplugFailingConnector {}
@@ -46,6 +61,66 @@ impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServ
   }
 }
 
+// Wraps an in-process connector, but can simulate the server going away by
+// dropping its side of the message channel.
+struct ButtplugDroppableConnector {
+  inner: ButtplugInProcessClientConnector,
+  drop_notifier: Arc<Notify>,
+  fail_connect: bool,
+}
+
+impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+  for ButtplugDroppableConnector
+{
+  fn connect(
+    &mut self,
+    message_sender: Sender<ButtplugCurrentSpecServerMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    if self.fail_connect {
+      return ButtplugConnectorError::ConnectorNotConnected.into();
+    }
+    let (inner_sender, mut inner_receiver) = channel(256);
+    let connect_fut = self.inner.connect(inner_sender);
+    let drop_notifier = self.drop_notifier.clone();
+    Box::pin(async move {
+      connect_fut.await?;
+      async_manager::spawn(async move {
+        loop {
+          tokio::select! {
+            msg = inner_receiver.recv() => match msg {
+              Some(msg) => {
+                if message_sender.send(msg).await.is_err() {
+                  break;
+                }
+              }
+              None => break,
+            },
+            _ = drop_notifier.notified() => break,
+          }
+        }
+      });
+      Ok(())
+    })
+  }
+
+  fn disconnect(&self) -> ButtplugConnectorResultFuture {
+    self.inner.disconnect()
+  }
+
+  fn send(&self, msg: ButtplugCurrentSpecClientMessage) -> ButtplugConnectorResultFuture {
+    self.inner.send(msg)
+  }
+}
+
+fn test_reconnect_policy(max_attempts: u32) -> ButtplugClientReconnectPolicy {
+  ButtplugClientReconnectPolicy {
+    initial_delay: Duration::from_millis(10),
+    max_delay: Duration::from_millis(50),
+    max_attempts: Some(max_attempts),
+    ..Default::default()
+  }
+}
+
 #[cfg(feature = "server")]
 #[test]
 fn test_failing_connection() {
@@ -299,6 +374,200 @@ fn test_stop_all_devices_and_device_command_range() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_reconnect() {
+  async_manager::block_on(async {
+    let drop_notifier = Arc::new(Notify::new());
+    let factory_notifier = drop_notifier.clone();
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    client
+      .connect_with_reconnect(
+        move || ButtplugDroppableConnector {
+          inner: ButtplugInProcessClientConnector::default(),
+          drop_notifier: factory_notifier.clone(),
+          fail_connect: false,
+        },
+        test_reconnect_policy(3),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(client.stop_all_devices().await.is_ok());
+    drop_notifier.notify_waiters();
+    assert!(matches!(
+      event_stream
+        .next()
+        .await
+        .expect("Test, assuming infallible."),
+      ButtplugClientEvent::Reconnecting
+    ));
+    assert!(matches!(
+      event_stream
+        .next()
+        .await
+        .expect("Test, assuming infallible."),
+      ButtplugClientEvent::Reconnected
+    ));
+    assert!(client.connected());
+    assert!(client.stop_all_devices().await.is_ok());
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_reconnect_rebinds_devices() {
+  async_manager::block_on(async {
+    let server = Arc::new(ButtplugRemoteServer::default());
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let test_device = helper.add_ble_device("Massage Demo").await;
+    let factory_server = server.clone();
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    client
+      .connect_with_reconnect(
+        move || {
+          let (client_connector, server_connector) = connected_channel_connectors();
+          let server_clone = factory_server.clone();
+          async_manager::spawn(async move {
+            server_clone
+              .start(server_connector)
+              .await
+              .expect("Test, assuming infallible.");
+          });
+          client_connector
+        },
+        test_reconnect_policy(3),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(client.start_scanning().await.is_ok());
+    let dev = loop {
+      if let ButtplugClientEvent::DeviceAdded(dev) = event_stream
+        .next()
+        .await
+        .expect("Test, assuming infallible.")
+      {
+        break dev;
+      }
+    };
+    let index = dev.index();
+    assert!(dev.vibrate(VibrateCommand::Speed(0.5)).await.is_ok());
+    let command_receiver = test_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+    // Dropping every session on the server closes our connection, but the
+    // device itself stays connected to the server at the same address.
+    server
+      .disconnect()
+      .await
+      .expect("Test, assuming infallible.");
+    // Devices that come back shouldn't be removed and added again.
+    let mut events = vec![];
+    while let Some(event) = event_stream.next().await {
+      if let ButtplugClientEvent::ScanningFinished = event {
+        continue;
+      }
+      let reconnected = matches!(event, ButtplugClientEvent::Reconnected);
+      events.push(event);
+      if reconnected {
+        break;
+      }
+    }
+    assert!(
+      matches!(
+        events.as_slice(),
+        [
+          ButtplugClientEvent::Reconnecting,
+          ButtplugClientEvent::Reconnected
+        ]
+      ),
+      "{:?}",
+      events
+    );
+    let devices = client.devices();
+    assert_eq!(devices.len(), 1);
+    assert!(Arc::ptr_eq(&devices[0], &dev));
+    assert_eq!(dev.index(), index);
+    assert!(dev.connected());
+    // The old session stopped the device on its way out, and the existing
+    // handle drives the same device over the new connection.
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    assert!(dev.vibrate(VibrateCommand::Speed(1.0)).await.is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 127], false)),
+    );
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_reconnect_gives_up() {
+  async_manager::block_on(async {
+    let drop_notifier = Arc::new(Notify::new());
+    let factory_notifier = drop_notifier.clone();
+    let connect_count = Arc::new(AtomicU32::new(0));
+    let factory_count = connect_count.clone();
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    client
+      .connect_with_reconnect(
+        move || ButtplugDroppableConnector {
+          inner: ButtplugInProcessClientConnector::default(),
+          drop_notifier: factory_notifier.clone(),
+          fail_connect: factory_count.fetch_add(1, Ordering::SeqCst) > 0,
+        },
+        test_reconnect_policy(2),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    drop_notifier.notify_waiters();
+    assert!(matches!(
+      event_stream
+        .next()
+        .await
+        .expect("Test, assuming infallible."),
+      ButtplugClientEvent::Reconnecting
+    ));
+    assert!(matches!(
+      event_stream
+        .next()
+        .await
+        .expect("Test, assuming infallible."),
+      ButtplugClientEvent::ServerDisconnect
+    ));
+    assert!(!client.connected());
+    assert!(client.stop_all_devices().await.is_err());
+    // One initial connection, then two failed attempts.
+    assert_eq!(connect_count.load(Ordering::SeqCst), 3);
+  });
+}
+
 // TODO Test calling connect twice
 // TODO Test calling disconnect twice w/o connection
 // TODO Test invalid return on RequestServerInfo
@@ -307,3 +576,82 @@ fn test_stop_all_devices_and_device_command_range() {
 // TODO Test receiving unmatched DeviceRemoved
 // TODO Test receiving Error when expecting Ok (i.e. StartScanning returns an error)
 // TODO Test receiving wrong message expecting Ok (i.e. StartScanning returns DeviceList)
+
+#[test]
+fn test_client_remote_server_accepts_concurrent_clients() {
+  async_manager::block_on(async {
+    let server = ButtplugRemoteServer::default();
+    let events = server.event_stream();
+    pin_mut!(events);
+    let mut clients = vec![];
+    for name in ["First Client", "Second Client"] {
+      let (client_connector, server_connector) = connected_channel_connectors();
+      let connection = server
+        .accept(server_connector)
+        .await
+        .expect("Test, assuming infallible.");
+      async_manager::spawn(connection);
+      let client = ButtplugClient::new(name);
+      client
+        .connect(client_connector)
+        .await
+        .expect("Test, assuming infallible.");
+      clients.push(client);
+    }
+    let mut session_ids = vec![];
+    let mut names = vec![];
+    while session_ids.len() < 2 || names.len() < 2 {
+      match events.next().await.expect("Test, assuming infallible.") {
+        ButtplugRemoteServerEvent::Connected(name) => names.push(name),
+        ButtplugRemoteServerEvent::SessionConnected(id, _) => session_ids.push(id),
+        _ => {}
+      }
+    }
+    assert_eq!(names, vec!["First Client", "Second Client"]);
+    assert_ne!(session_ids[0], session_ids[1]);
+    for client in &clients {
+      assert!(client.connected());
+      assert!(client.stop_all_devices().await.is_ok());
+    }
+  });
+}
+
+#[test]
+fn test_client_remote_auth_token() {
+  async_manager::block_on(async {
+    let server = Arc::new(ButtplugRemoteServer::new(
+      ButtplugServerBuilder::default()
+        .auth_token("secret")
+        .finish()
+        .expect("Test, assuming infallible."),
+    ));
+    for (token, should_connect) in [
+      (None, false),
+      (Some("wrong"), false),
+      (Some("secret"), true),
+    ] {
+      let (client_connector, server_connector) = connected_channel_connectors();
+      let server_clone = server.clone();
+      async_manager::spawn(async move {
+        server_clone
+          .start(server_connector)
+          .await
+          .expect("Test, assuming infallible.");
+      });
+      let client_connector = match token {
+        Some(token) => client_connector.with_auth_token(token),
+        None => client_connector,
+      };
+      let client = ButtplugClient::new("Test Client");
+      let result = client.connect(client_connector).await;
+      assert_eq!(result.is_ok(), should_connect, "{:?}", result);
+      assert_eq!(client.connected(), should_connect);
+      if should_connect {
+        client
+          .disconnect()
+          .await
+          .expect("Test, assuming infallible.");
+      }
+    }
+  });
+}
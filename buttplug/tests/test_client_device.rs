@@ -1,6 +1,16 @@
 mod util;
 use buttplug::{
   client::{
+    funscript::{Funscript, FunscriptAction, FunscriptPlayer, FunscriptPlayerOptions},
+    pattern::{
+      play_pattern,
+      Pattern,
+      PatternCurve,
+      PatternFeature,
+      PatternInterpolation,
+      PatternKeyframe,
+      PatternPlayerOptions,
+    },
     ButtplugClient,
     ButtplugClientDeviceEvent,
     ButtplugClientError,
@@ -12,7 +22,12 @@ use buttplug::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
     messages::{self, ButtplugClientMessage},
   },
-  server::comm_managers::test::TestDeviceCommunicationManagerBuilder,
+  device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+  server::comm_managers::test::{
+    check_test_recv_empty,
+    check_test_recv_value,
+    TestDeviceCommunicationManagerBuilder,
+  },
   util::async_manager,
 };
 use futures::StreamExt;
@@ -327,6 +342,175 @@ fn test_client_repeated_deviceremoved_message() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_pattern_playback() {
+  async_manager::block_on(async {
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::default();
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    connector
+      .server_ref()
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    client
+      .connect(connector)
+      .await
+      .expect("Test, assuming infallible.");
+    client
+      .start_scanning()
+      .await
+      .expect("Test, assuming infallible.");
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let test_device = client_device.expect("Test, assuming infallible.");
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let curve = PatternCurve::new(
+      PatternInterpolation::Step,
+      vec![
+        PatternKeyframe::new(Duration::from_millis(0), 0.5),
+        PatternKeyframe::new(Duration::from_millis(100), 1.0),
+      ],
+    );
+    let pattern = Pattern::default()
+      .track(PatternFeature::Vibrate(0), curve.clone())
+      .track(PatternFeature::Vibrate(1), curve);
+    // Both motors are sent in a single command per tick, and the tick at 50ms
+    // doesn't change anything, so shouldn't send anything either.
+    play_pattern(
+      test_device.clone(),
+      pattern.clone(),
+      PatternPlayerOptions::default(),
+    )
+    .expect("Test, assuming infallible.")
+    .finished()
+    .await
+    .expect("Test, assuming infallible.");
+    for value in [64, 127] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, value], false)),
+      );
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, value], false)),
+      );
+    }
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Looping patterns play until stopped, and stop the device when they are.
+    // The last keyframe is the start of the next loop, so this only ever sends
+    // the first keyframe.
+    let handle = play_pattern(
+      test_device,
+      pattern,
+      PatternPlayerOptions {
+        looping: true,
+        ..Default::default()
+      },
+    )
+    .expect("Test, assuming infallible.");
+    Delay::new(Duration::from_millis(300)).await;
+    handle.stop();
+    handle.finished().await.expect("Test, assuming infallible.");
+    for value in [64, 0] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, value], false)),
+      );
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, value], false)),
+      );
+    }
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_funscript_vibrate_playback() {
+  async_manager::block_on(async {
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::default();
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    connector
+      .server_ref()
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    client
+      .connect(connector)
+      .await
+      .expect("Test, assuming infallible.");
+    client
+      .start_scanning()
+      .await
+      .expect("Test, assuming infallible.");
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let test_device = client_device.expect("Test, assuming infallible.");
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let check_vibrate = |value: u8| {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, value], false)),
+      );
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, value], false)),
+      );
+    };
+    let script = Funscript {
+      actions: vec![
+        FunscriptAction::new(0, 0),
+        FunscriptAction::new(100, 100),
+        FunscriptAction::new(200, 100),
+      ],
+      ..Default::default()
+    };
+    // Massage Demo has no linear features, so movement speed becomes vibration.
+    // A full stroke in 100ms is as fast as it gets, and not moving at all means
+    // no vibration.
+    let player = FunscriptPlayer::play(test_device, script, FunscriptPlayerOptions::default())
+      .expect("Test, assuming infallible.");
+    Delay::new(Duration::from_millis(400)).await;
+    check_vibrate(127);
+    check_vibrate(0);
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Seeking back restarts the movement, even after the end of the script.
+    player.seek(Duration::ZERO);
+    Delay::new(Duration::from_millis(30)).await;
+    player.stop();
+    player.finished().await.expect("Test, assuming infallible.");
+    check_vibrate(127);
+    check_vibrate(0);
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
 // TODO Test invalid messages to device
 // TODO Test invalid parameters in message
 // TODO Test device invalidation across client connections (i.e. a device shouldn't be allowed to reconnect even if index is the same)
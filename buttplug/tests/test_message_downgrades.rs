@@ -199,4 +199,144 @@ mod test {
       );
     });
   }
+
+  #[test]
+  fn test_version2_device_list_scalarcmd() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::default();
+      let recv = server.event_stream();
+      pin_mut!(recv);
+      let serializer = ButtplugServerJSONSerializer::default();
+      let builder = TestDeviceCommunicationManagerBuilder::default();
+      let helper = builder.helper();
+      server
+        .device_manager()
+        .add_comm_manager(builder)
+        .expect("Test, assuming infallible.");
+      helper.add_ble_device("Massage Demo").await;
+      let rsi =
+        r#"[{"RequestServerInfo":{"Id": 1, "ClientName": "Test Client", "MessageVersion": 2}}]"#;
+      server
+        .parse_message(
+          serializer
+            .deserialize(rsi.to_owned().into())
+            .expect("Test, assuming infallible.")[0]
+            .clone(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+      server
+        .parse_message(messages::StartScanning::default().into())
+        .await
+        .expect("Test, assuming infallible.");
+      while let Some(msg) = recv.next().await {
+        if let messages::ButtplugServerMessage::DeviceAdded(_) = msg {
+          break;
+        }
+      }
+      // ScalarCmd was added in v3, so v2 clients shouldn't see it.
+      let rdl = serializer
+        .deserialize(r#"[{"RequestDeviceList": { "Id": 1}}]"#.to_owned().into())
+        .expect("Test, assuming infallible.");
+      let output = server
+        .parse_message(rdl[0].clone())
+        .await
+        .expect("Test, assuming infallible.");
+      if let ButtplugSerializedMessage::Text(text) = serializer.serialize(vec![output]) {
+        assert!(text.contains(r#""VibrateCmd":{"FeatureCount":2,"StepCount":[127,127]}"#));
+        assert!(!text.contains("ScalarCmd"));
+        assert!(!text.contains("ActuatorType"));
+      } else {
+        panic!("Should get text back from JSON serializer.");
+      }
+      // v2 clients also can't send ScalarCmd.
+      assert!(serializer
+        .deserialize(
+          r#"[{"ScalarCmd": { "Id": 2, "DeviceIndex": 0, "Scalars": [{"Index": 0, "Scalar": 0.5, "ActuatorType": "Vibrate"}]}}]"#
+            .to_owned()
+            .into(),
+        )
+        .is_err());
+    });
+  }
+
+  #[test]
+  fn test_version3_scalarcmd() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::default();
+      let recv = server.event_stream();
+      pin_mut!(recv);
+      let serializer = ButtplugServerJSONSerializer::default();
+      let builder = TestDeviceCommunicationManagerBuilder::default();
+      let helper = builder.helper();
+      server
+        .device_manager()
+        .add_comm_manager(builder)
+        .expect("Test, assuming infallible.");
+      let device = helper.add_ble_device("Massage Demo").await;
+      let rsi =
+        r#"[{"RequestServerInfo":{"Id": 1, "ClientName": "Test Client", "MessageVersion": 3}}]"#;
+      server
+        .parse_message(
+          serializer
+            .deserialize(rsi.to_owned().into())
+            .expect("Test, assuming infallible.")[0]
+            .clone(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+      server
+        .parse_message(messages::StartScanning::default().into())
+        .await
+        .expect("Test, assuming infallible.");
+      while let Some(msg) = recv.next().await {
+        if let messages::ButtplugServerMessage::DeviceAdded(_) = msg {
+          if let ButtplugSerializedMessage::Text(text) = serializer.serialize(vec![msg]) {
+            assert!(text.contains(r#""ScalarCmd":{"FeatureCount":2,"StepCount":[127,127],"ActuatorType":["Vibrate","Vibrate"]}"#));
+          } else {
+            panic!("Should get text back from JSON serializer.");
+          }
+          break;
+        }
+      }
+      let output = server
+        .parse_message(
+          serializer
+            .deserialize(
+              r#"[{"ScalarCmd": { "Id": 2, "DeviceIndex": 0, "Scalars": [{"Index": 1, "Scalar": 0.5, "ActuatorType": "Vibrate"}]}}]"#
+                .to_owned()
+                .into(),
+            )
+            .expect("Test, assuming infallible.")[0]
+            .clone(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+      assert_eq!(
+        serializer.serialize(vec!(output)),
+        r#"[{"Ok":{"Id":2}}]"#.to_owned().into()
+      );
+      let command_receiver = device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible.");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+      );
+      // Asking for an actuator type the feature doesn't have is an error.
+      assert!(server
+        .parse_message(
+          serializer
+            .deserialize(
+              r#"[{"ScalarCmd": { "Id": 3, "DeviceIndex": 0, "Scalars": [{"Index": 0, "Scalar": 0.5, "ActuatorType": "Inflate"}]}}]"#
+                .to_owned()
+                .into(),
+            )
+            .expect("Test, assuming infallible.")[0]
+            .clone(),
+        )
+        .await
+        .is_err());
+    });
+  }
 }
@@ -89,3 +89,79 @@ fn test_serialized_error_relay() {
 // TODO Test deserialization of concatenated messages
 // TODO Test message with negative message id
 // TODO Test device message with negative device id
+
+#[cfg(feature = "serialize-msgpack")]
+#[test]
+fn test_negotiated_serializer_remote_clients() {
+  use buttplug::{
+    client::ButtplugClient,
+    connector::{
+      ButtplugRemoteClientConnector,
+      ButtplugRemoteServerConnector,
+      ButtplugStreamFraming,
+      ButtplugTcpClientTransportBuilder,
+      ButtplugTcpServerTransportBuilder,
+    },
+    core::messages::serializer::{
+      ButtplugClientJSONSerializer,
+      ButtplugClientMessagePackSerializer,
+      ButtplugServerNegotiatedSerializer,
+    },
+    server::ButtplugRemoteServer,
+  };
+  use std::time::Duration;
+
+  async fn connect_client<S>(address: &str) -> ButtplugClient
+  where
+    S: buttplug::core::messages::serializer::ButtplugMessageSerializer<
+        Inbound = messages::ButtplugCurrentSpecServerMessage,
+        Outbound = messages::ButtplugCurrentSpecClientMessage,
+      > + 'static,
+  {
+    let client = ButtplugClient::new("Test Client");
+    client
+      .connect(ButtplugRemoteClientConnector::<_, S>::new(
+        ButtplugTcpClientTransportBuilder::new(address)
+          .framing(ButtplugStreamFraming::LengthPrefixed)
+          .finish(),
+      ))
+      .await
+      .expect("Test, assuming infallible.");
+    client
+  }
+
+  async_manager::block_on(async move {
+    let address = "127.0.0.1:12370";
+    let server = Arc::new(ButtplugRemoteServer::default());
+    for use_msgpack in [true, false] {
+      let server_clone = server.clone();
+      let server_task = async_manager::spawn_with_handle(async move {
+        server_clone
+          .start(ButtplugRemoteServerConnector::<
+            _,
+            ButtplugServerNegotiatedSerializer,
+          >::new(
+            ButtplugTcpServerTransportBuilder::new(address)
+              .framing(ButtplugStreamFraming::LengthPrefixed)
+              .finish(),
+          ))
+          .await
+          .expect("Test, assuming infallible.");
+      })
+      .expect("Test, assuming infallible.");
+      futures_timer::Delay::new(Duration::from_millis(100)).await;
+      let client = if use_msgpack {
+        connect_client::<ButtplugClientMessagePackSerializer>(address).await
+      } else {
+        connect_client::<ButtplugClientJSONSerializer>(address).await
+      };
+      assert!(client.connected());
+      assert!(client.stop_all_devices().await.is_ok());
+      client
+        .disconnect()
+        .await
+        .expect("Test, assuming infallible.");
+      server_task.await;
+    }
+  });
+}
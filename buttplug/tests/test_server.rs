@@ -1,23 +1,38 @@
 mod util;
 
+use buttplug::util::device_configuration::ProtocolConfigurationFormat;
 use buttplug::{
   core::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugHandshakeError},
     messages::{
       self,
+      ButtplugDeviceMessageType,
       ButtplugMessageSpecVersion,
       ButtplugServerMessage,
       BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
-  device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
-  server::comm_managers::test::{check_test_recv_value, TestDeviceCommunicationManagerBuilder},
-  server::{ButtplugServer, ButtplugServerBuilder},
-  util::async_manager,
+  device::{recording::DeviceRecording, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+  server::comm_managers::replay::ReplayDeviceCommunicationManagerBuilder,
+  server::comm_managers::test::{
+    check_test_recv_empty,
+    check_test_recv_value,
+    TestDeviceCommunicationManagerBuilder,
+    TestDeviceInternal,
+  },
+  server::{ButtplugServer, ButtplugServerBuilder, DeviceArbitrationPolicy},
+  util::{
+    async_manager,
+    device_configuration::{
+      load_protocol_config_from_json,
+      ProtocolConfiguration,
+      DEVICE_CONFIGURATION_JSON,
+    },
+  },
 };
-use futures::{pin_mut, Stream, StreamExt};
+use futures::{future, pin_mut, select, FutureExt, Stream, StreamExt};
 use futures_timer::Delay;
-use std::time::Duration;
+use std::{path::Path, sync::Arc, time::Duration};
 
 async fn setup_test_server(
   msg_union: messages::ButtplugClientMessage,
@@ -32,7 +47,7 @@ async fn setup_test_server(
   {
     ButtplugServerMessage::ServerInfo(s) => assert_eq!(
       s,
-      messages::ServerInfo::new("Buttplug Server", ButtplugMessageSpecVersion::Version2, 0)
+      messages::ServerInfo::new("Buttplug Server", ButtplugMessageSpecVersion::Version3, 0)
     ),
     _ => panic!("Should've received ok"),
   }
@@ -501,9 +516,1337 @@ fn test_server_builder_user_device_config_old_config_version() {
   });
 }
 
-// TODO Test sending system message (Id 0)
-// TODO Test sending system message (Ok but Id > 0)
-// TODO Test repeated handshake
-// TODO Test scan with no comm managers
-// TODO Test message with no RequestServerInfo first
-// TODO Test sending device command for device that doesn't exist (in server)
+#[cfg(feature = "yaml-device-config")]
+#[test]
+fn test_server_builder_yaml_device_config() {
+  async_manager::block_on(async {
+    let user_config_yaml = r#"
+# Comments are the whole point.
+version: 63
+user-config:
+  LimitedDevice:
+    limits:
+      vibrate-max: 0.5
+"#;
+    let server = ButtplugServerBuilder::default()
+      .device_configuration(
+        Some(include_str!("../buttplug-device-config/buttplug-device-config.yml").to_owned()),
+        ProtocolConfigurationFormat::Yaml,
+      )
+      .user_device_configuration(
+        Some(user_config_yaml.to_owned()),
+        ProtocolConfigurationFormat::Yaml,
+      )
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let _ = helper
+      .add_ble_device_with_address("Massage Demo", "LimitedDevice")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(
+          da.device_messages()[&ButtplugDeviceMessageType::VibrateCmd].output_range,
+          Some(vec![(0.0, 0.5), (0.0, 0.5)])
+        );
+        break;
+      }
+    }
+  });
+}
+
+#[cfg(feature = "yaml-device-config")]
+#[test]
+fn test_server_builder_yaml_device_config_errors() {
+  async_manager::block_on(async {
+    let mut builder = ButtplugServerBuilder::default();
+    assert!(builder
+      .user_device_configuration(
+        Some("version: [63".to_owned()),
+        ProtocolConfigurationFormat::Yaml
+      )
+      .finish()
+      .is_err());
+    // Parses fine, but still has to pass schema validation.
+    assert!(builder
+      .user_device_configuration(
+        Some("protocols:\n  lovense: {}\n".to_owned()),
+        ProtocolConfigurationFormat::Yaml
+      )
+      .finish()
+      .is_err());
+  });
+}
+
+#[cfg(feature = "toml-device-config")]
+#[test]
+fn test_server_builder_toml_device_config() {
+  async_manager::block_on(async {
+    let user_config_toml = r#"
+# Comments work here too.
+version = 63
+
+[protocols.aneros]
+min-command-interval = 100
+
+[user-config.LimitedDevice.limits]
+vibrate-max = 0.5
+"#;
+    let mut builder = ButtplugServerBuilder::default();
+    assert!(builder
+      .user_device_configuration(
+        Some(user_config_toml.to_owned()),
+        ProtocolConfigurationFormat::Toml
+      )
+      .finish()
+      .is_ok());
+    assert!(builder
+      .user_device_configuration(
+        Some("version = ".to_owned()),
+        ProtocolConfigurationFormat::Toml
+      )
+      .finish()
+      .is_err());
+  });
+}
+
+#[test]
+fn test_protocol_configuration_format_from_path() {
+  let format = |path: &str| ProtocolConfigurationFormat::from_path(Path::new(path));
+  assert_eq!(
+    format("config.json").expect("Test, assuming infallible."),
+    ProtocolConfigurationFormat::Json
+  );
+  assert_eq!(
+    format("config").expect("Test, assuming infallible."),
+    ProtocolConfigurationFormat::Json
+  );
+  #[cfg(feature = "yaml-device-config")]
+  assert_eq!(
+    format("config.YML").expect("Test, assuming infallible."),
+    ProtocolConfigurationFormat::Yaml
+  );
+  #[cfg(not(feature = "yaml-device-config"))]
+  assert!(format("config.yml").is_err());
+  #[cfg(feature = "toml-device-config")]
+  assert_eq!(
+    format("config.toml").expect("Test, assuming infallible."),
+    ProtocolConfigurationFormat::Toml
+  );
+  #[cfg(not(feature = "toml-device-config"))]
+  assert!(format("config.toml").is_err());
+}
+
+#[test]
+fn test_server_user_config_output_limits() {
+  async_manager::block_on(async {
+    let user_config_json = r#"{
+      "version": 63,
+      "user-config": {
+        "LimitedDevice": {
+          "limits": {
+            "vibrate-max": 0.5
+          }
+        }
+      }
+    }"#;
+    let server = ButtplugServerBuilder::default()
+      .user_device_configuration_json(Some(user_config_json.to_owned()))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper
+      .add_ble_device_with_address("Massage Demo", "LimitedDevice")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(
+          da.device_messages()[&ButtplugDeviceMessageType::VibrateCmd].output_range,
+          Some(vec![(0.0, 0.5), (0.0, 0.5)])
+        );
+        device_index = da.device_index();
+        break;
+      }
+    }
+    server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 1.0)])
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+  });
+}
+
+#[test]
+fn test_server_user_config_rejects_reversed_linear_range() {
+  let user_config_json = r#"{
+    "version": 63,
+    "user-config": {
+      "LimitedDevice": {
+        "limits": {
+          "linear-range": [0.8, 0.2]
+        }
+      }
+    }
+  }"#;
+  assert!(load_protocol_config_from_json(user_config_json, true).is_err());
+  assert!(ButtplugServerBuilder::default()
+    .user_device_configuration_json(Some(user_config_json.to_owned()))
+    .finish()
+    .is_err());
+}
+
+/// Builds a configuration from the built in device config plus a user config.
+fn protocol_config_with_user_config(user_config_json: &str) -> ProtocolConfiguration {
+  let mut config = load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON, false)
+    .expect("Test, assuming infallible.");
+  config.merge(
+    load_protocol_config_from_json(user_config_json, true).expect("Test, assuming infallible."),
+  );
+  config
+}
+
+/// Starts a server with a single connected test device, returning the
+/// device's index.
+async fn setup_reload_test_server(
+  address: &str,
+) -> (
+  ButtplugServer,
+  impl Stream<Item = ButtplugServerMessage> + Unpin,
+  u32,
+) {
+  let server = ButtplugServer::default();
+  let mut recv = Box::pin(server.event_stream());
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+  helper
+    .add_ble_device_with_address("Massage Demo", address)
+    .await;
+  assert!(server
+    .parse_message(
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into()
+    )
+    .await
+    .is_ok());
+  assert!(server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .is_ok());
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      let device_index = da.device_index();
+      return (server, recv, device_index);
+    }
+  }
+  panic!("Device should have been added.");
+}
+
+/// Waits for the next event, skipping the ScanningFinished the test device
+/// comm manager sends once it's added its devices.
+async fn next_device_event(
+  recv: &mut (impl Stream<Item = ButtplugServerMessage> + Unpin),
+) -> Option<ButtplugServerMessage> {
+  loop {
+    match recv.next().await {
+      Some(ButtplugServerMessage::ScanningFinished(_)) => continue,
+      msg => return msg,
+    }
+  }
+}
+
+#[test]
+fn test_server_update_device_configuration() {
+  async_manager::block_on(async {
+    let (server, mut recv, device_index) = setup_reload_test_server("ReloadDevice").await;
+    assert_eq!(
+      server
+        .device_manager()
+        .device_info(device_index)
+        .expect("Test, assuming infallible.")
+        .display_name,
+      None
+    );
+
+    // Changing limits should re-announce the device with its new ranges.
+    let config = protocol_config_with_user_config(
+      r#"{
+        "version": 63,
+        "user-config": {
+          "ReloadDevice": {
+            "display-name": "Reloaded",
+            "limits": {
+              "vibrate-max": 0.5
+            }
+          }
+        }
+      }"#,
+    );
+    server
+      .update_device_configuration(config)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(matches!(
+      next_device_event(&mut recv).await,
+      Some(ButtplugServerMessage::DeviceRemoved(msg)) if msg.device_index() == device_index
+    ));
+    match next_device_event(&mut recv).await {
+      Some(ButtplugServerMessage::DeviceAdded(da)) => {
+        assert_eq!(da.device_index(), device_index);
+        assert_eq!(
+          da.device_messages()[&ButtplugDeviceMessageType::VibrateCmd].output_range,
+          Some(vec![(0.0, 0.5), (0.0, 0.5)])
+        );
+      }
+      msg => panic!("Expected DeviceAdded, got {:?}", msg),
+    }
+    assert_eq!(
+      server
+        .device_manager()
+        .device_info(device_index)
+        .expect("Test, assuming infallible.")
+        .display_name
+        .as_deref(),
+      Some("Reloaded")
+    );
+
+    // Denying the device should disconnect it.
+    let config = protocol_config_with_user_config(
+      r#"{
+        "version": 63,
+        "user-config": {
+          "ReloadDevice": {
+            "deny": true
+          }
+        }
+      }"#,
+    );
+    server
+      .update_device_configuration(config)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(matches!(
+      next_device_event(&mut recv).await,
+      Some(ButtplugServerMessage::DeviceRemoved(msg)) if msg.device_index() == device_index
+    ));
+    assert!(server.device_manager().device_info(device_index).is_err());
+  });
+}
+
+#[test]
+fn test_server_watch_device_configuration() {
+  async_manager::block_on(async {
+    let (server, mut recv, device_index) = setup_reload_test_server("WatchedDevice").await;
+    let path =
+      std::env::temp_dir().join(format!("buttplug-watch-test-{}.json", std::process::id()));
+    std::fs::write(&path, r#"{ "version": 63 }"#).expect("Test, assuming infallible.");
+    server.watch_device_configuration(None, Some(path.clone()), Duration::from_millis(10));
+    // Give the watcher time to see the original file before changing it.
+    Delay::new(Duration::from_millis(50)).await;
+    std::fs::write(
+      &path,
+      r#"{ "version": 63, "user-config": { "WatchedDevice": { "deny": true } } }"#,
+    )
+    .expect("Test, assuming infallible.");
+    select! {
+      msg = next_device_event(&mut recv).fuse() => assert!(matches!(
+        msg,
+        Some(ButtplugServerMessage::DeviceRemoved(msg)) if msg.device_index() == device_index
+      )),
+      _ = Delay::new(Duration::from_secs(5)).fuse() => panic!("Device should have been removed."),
+    }
+    let _ = std::fs::remove_file(&path);
+  });
+}
+
+/// Waits for the index store file to be written in the background, until it
+/// does (or doesn't) mention an address.
+async fn wait_for_index_store(store: &std::path::Path, address: &str, present: bool) {
+  for _ in 0..100u8 {
+    let contents = std::fs::read_to_string(store).unwrap_or_default();
+    if contents.contains(&format!("\"{}\"", address)) == present {
+      return;
+    }
+    Delay::new(Duration::from_millis(10)).await;
+  }
+  panic!("Index store was never updated for {}.", address);
+}
+
+/// Starts a server using the given index store, connects a single test
+/// device, and returns the index it was given once the store has saved it.
+async fn connect_with_index_store(store: &std::path::Path, address: &str) -> (ButtplugServer, u32) {
+  let server = ButtplugServerBuilder::default()
+    .device_index_store(store.to_owned())
+    .finish()
+    .expect("Test, assuming infallible.");
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+  helper
+    .add_ble_device_with_address("Massage Demo", address)
+    .await;
+  assert!(server
+    .parse_message(
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into()
+    )
+    .await
+    .is_ok());
+  assert!(server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .is_ok());
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      let device_index = da.device_index();
+      wait_for_index_store(store, address, true).await;
+      return (server, device_index);
+    }
+  }
+  panic!("Device should have been added.");
+}
+
+#[test]
+fn test_server_device_index_store() {
+  async_manager::block_on(async {
+    let store = std::env::temp_dir().join(format!(
+      "buttplug-index-store-test-{}.json",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_file(&store);
+
+    let (server, first_index) = connect_with_index_store(&store, "StoredDeviceA").await;
+    assert_eq!(first_index, 0);
+    drop(server);
+
+    // A different device on a new server shouldn't get the first one's index.
+    let (server, second_index) = connect_with_index_store(&store, "StoredDeviceB").await;
+    assert_eq!(second_index, 1);
+    drop(server);
+
+    let (server, index) = connect_with_index_store(&store, "StoredDeviceA").await;
+    assert_eq!(index, first_index);
+    let device_manager = server.device_manager();
+    let known_devices = device_manager.known_devices();
+    assert_eq!(known_devices.len(), 2);
+    assert_eq!(known_devices["StoredDeviceB"].index(), second_index);
+    assert_eq!(known_devices["StoredDeviceA"].protocol(), "aneros");
+
+    device_manager
+      .rename_known_device("StoredDeviceA", Some("Partner's Toy".to_owned()))
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      device_manager
+        .device_info(index)
+        .expect("Test, assuming infallible.")
+        .display_name
+        .as_deref(),
+      Some("Partner's Toy")
+    );
+    assert!(device_manager
+      .rename_known_device("NotADevice", None)
+      .is_err());
+    // Display names live in the user configuration, not the index store.
+    assert_eq!(
+      device_manager.device_user_config()["StoredDeviceA"]
+        .display_name()
+        .as_deref(),
+      Some("Partner's Toy")
+    );
+
+    // Connected devices can't be forgotten, disconnected ones can.
+    assert!(device_manager.forget_known_device("StoredDeviceA").is_err());
+    device_manager
+      .forget_known_device("StoredDeviceB")
+      .expect("Test, assuming infallible.");
+    assert!(device_manager.forget_known_device("StoredDeviceB").is_err());
+    wait_for_index_store(&store, "StoredDeviceB", false).await;
+    drop(server);
+
+    // Forgotten indexes aren't handed out again.
+    let (_server, index) = connect_with_index_store(&store, "StoredDeviceB").await;
+    assert_eq!(index, 2);
+    let _ = std::fs::remove_file(&store);
+  });
+}
+
+/// Handshakes, scans, and returns the index of the first device added.
+async fn scan_for_device(server: &ButtplugServer) -> u32 {
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  assert!(server
+    .parse_message(
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into()
+    )
+    .await
+    .is_ok());
+  assert!(server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .is_ok());
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      return da.device_index();
+    }
+  }
+  panic!("Device should have been added.");
+}
+
+#[test]
+fn test_server_device_recording_and_replay() {
+  async_manager::block_on(async {
+    let directory =
+      std::env::temp_dir().join(format!("buttplug-recording-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).expect("Test, assuming infallible.");
+    let vibrate = |device_index, speed| -> messages::ButtplugClientMessage {
+      messages::VibrateCmd::new(
+        device_index,
+        vec![messages::VibrateSubcommand::new(0, speed)],
+      )
+      .into()
+    };
+
+    let server = ButtplugServerBuilder::default()
+      .device_recording_directory(directory.clone())
+      .finish()
+      .expect("Test, assuming infallible.");
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "RecordedDevice")
+      .await;
+    let device_index = scan_for_device(&server).await;
+    for speed in [0.5, 1.0] {
+      assert!(server
+        .parse_message(vibrate(device_index, speed))
+        .await
+        .is_ok());
+    }
+    drop(server);
+
+    let recordings: Vec<_> = std::fs::read_dir(&directory)
+      .expect("Test, assuming infallible.")
+      .map(|entry| entry.expect("Test, assuming infallible.").path())
+      .collect();
+    assert_eq!(recordings.len(), 1);
+    // Entries are written in the background.
+    let mut recording = DeviceRecording::load(&recordings[0]).expect("Test, assuming infallible.");
+    for _ in 0..100u8 {
+      if recording.command_count() == 2 {
+        break;
+      }
+      Delay::new(Duration::from_millis(10)).await;
+      recording = DeviceRecording::load(&recordings[0]).expect("Test, assuming infallible.");
+    }
+    assert_eq!(recording.header().address(), "RecordedDevice");
+    assert_eq!(recording.command_count(), 2);
+
+    // Replaying the same commands matches the recording. Replaying the same
+    // device twice keeps track of each replay separately.
+    let server = ButtplugServer::default();
+    let builder = ReplayDeviceCommunicationManagerBuilder::default()
+      .recording(recording.clone())
+      .recording(recording);
+    let report = builder.report();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device_index = scan_for_device(&server).await;
+    assert_eq!(report.remaining_commands(), 4);
+    assert!(server
+      .parse_message(vibrate(device_index, 0.5))
+      .await
+      .is_ok());
+    assert_eq!(report.remaining_commands(), 3);
+    assert!(report.mismatches().is_empty());
+
+    // Anything else doesn't.
+    assert!(server
+      .parse_message(vibrate(device_index, 0.25))
+      .await
+      .is_err());
+    assert_eq!(report.mismatches().len(), 1);
+    assert!(!report.is_complete());
+    let _ = std::fs::remove_dir_all(&directory);
+  });
+}
+
+#[test]
+fn test_server_device_command_interval() {
+  async_manager::block_on(async {
+    let user_config_json = r#"{
+      "version": 63,
+      "protocols": {
+        "aneros": {
+          "min-command-interval": 100
+        }
+      }
+    }"#;
+    let server = ButtplugServerBuilder::default()
+      .user_device_configuration_json(Some(user_config_json.to_owned()))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = da.device_index();
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let vibrate = |speeds: Vec<(u32, f64)>| {
+      server.parse_message(
+        messages::VibrateCmd::new(
+          device_index,
+          speeds
+            .into_iter()
+            .map(|(index, speed)| messages::VibrateSubcommand::new(index, speed))
+            .collect(),
+        )
+        .into(),
+      )
+    };
+
+    // The first command goes straight out.
+    vibrate(vec![(0, 0.5)])
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+
+    // Anything else sent during the interval gets merged, with the latest
+    // value for each feature winning.
+    let superseded = vibrate(vec![(0, 0.1)]);
+    let latest = vibrate(vec![(0, 1.0), (1, 1.0)]);
+    assert!(check_test_recv_empty(&command_receiver));
+    superseded.await.expect("Test, assuming infallible.");
+    latest.await.expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 127], false)),
+    );
+
+    // Stopping throws out anything still waiting to be sent.
+    let pending = vibrate(vec![(0, 0.5), (1, 0.5)]);
+    server
+      .parse_message(messages::StopDeviceCmd::new(device_index).into())
+      .await
+      .expect("Test, assuming infallible.");
+    pending.await.expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    Delay::new(Duration::from_millis(200)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[test]
+fn test_server_device_command_interval_linear_order() {
+  async_manager::block_on(async {
+    let user_config_json = r#"{
+      "version": 63,
+      "protocols": {
+        "vorze-sa": {
+          "min-command-interval": 100
+        }
+      }
+    }"#;
+    let server = ButtplugServerBuilder::default()
+      .user_device_configuration_json(Some(user_config_json.to_owned()))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("VorzePiston").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = da.device_index();
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let linear = |position: f64| {
+      server.parse_message(
+        messages::LinearCmd::new(
+          device_index,
+          vec![messages::VectorSubcommand::new(0, 500, position)],
+        )
+        .into(),
+      )
+    };
+
+    // Every stroke sent during the interval makes it to the device, in the
+    // order they were sent, rather than only the latest one.
+    for result in future::join_all([linear(0.5), linear(0.25), linear(0.75)]).await {
+      result.expect("Test, assuming infallible.");
+    }
+    let mut positions = vec![];
+    while let Ok(command) = command_receiver
+      .lock()
+      .expect("Test, assuming infallible.")
+      .try_recv()
+    {
+      if let DeviceImplCommand::Write(write) = command {
+        positions.push(write.data[1]);
+      }
+    }
+    assert_eq!(positions, vec![100, 50, 150]);
+  });
+}
+
+/// Creates a server with a single device using the given arbitration policy,
+/// and returns the server along with the device and its index.
+async fn setup_arbitration_test(
+  policy: DeviceArbitrationPolicy,
+  device_name: &str,
+) -> (ButtplugServer, Arc<TestDeviceInternal>, u32) {
+  let server = ButtplugServerBuilder::default()
+    .device_arbitration_policy(policy)
+    .finish()
+    .expect("Test, assuming infallible.");
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+  let device = helper.add_ble_device(device_name).await;
+  server
+    .parse_message(
+      messages::RequestServerInfo::new("Scanner", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let mut device_index = 100;
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      device_index = da.device_index();
+      break;
+    }
+  }
+  (server, device, device_index)
+}
+
+fn assert_device_in_use(result: Result<ButtplugServerMessage, messages::Error>) {
+  match result {
+    Err(err) => assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceInUse(_))
+    )),
+    Ok(msg) => panic!("Should get back DeviceInUse error, got {:?}", msg),
+  }
+}
+
+#[test]
+fn test_server_multiple_sessions() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let first = server.new_session(0);
+    let second = server.new_session(0);
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(first.parse_message(msg.clone().into()).await.is_ok());
+    assert!(second
+      .parse_message(
+        messages::RequestServerInfo::new("Other Client", ButtplugMessageSpecVersion::Version2)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(first.parse_message(msg.into()).await.is_err());
+    assert!(first.connected());
+    assert!(second.connected());
+    assert_eq!(first.client_name(), Some("Test Client".to_owned()));
+    assert_eq!(
+      second.spec_version(),
+      Some(ButtplugMessageSpecVersion::Version2)
+    );
+    assert!(first.disconnect().await.is_ok());
+    assert!(!first.connected());
+    assert!(second.connected());
+  });
+}
+
+#[test]
+fn test_server_exclusive_device_arbitration() {
+  async_manager::block_on(async {
+    let (server, device, device_index) =
+      setup_arbitration_test(DeviceArbitrationPolicy::ExclusiveLock, "Massage Demo").await;
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let game = server.new_session(0);
+    let visualizer = server.new_session(0);
+    for session in [&game, &visualizer] {
+      session
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+    }
+    let vibrate = |speed| {
+      messages::VibrateCmd::new(
+        device_index,
+        vec![
+          messages::VibrateSubcommand::new(0, speed),
+          messages::VibrateSubcommand::new(1, speed),
+        ],
+      )
+      .into()
+    };
+
+    game
+      .parse_message(vibrate(0.5))
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+
+    // The device is locked to the game, so the visualizer can't touch it.
+    assert_device_in_use(visualizer.parse_message(vibrate(1.0)).await);
+    assert_device_in_use(
+      visualizer
+        .parse_message(messages::StopDeviceCmd::new(device_index).into())
+        .await,
+    );
+    assert!(visualizer
+      .parse_message(messages::StopAllDevices::default().into())
+      .await
+      .is_ok());
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Once the game leaves, its devices are stopped and free for others.
+    assert!(game.disconnect().await.is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    assert!(visualizer.parse_message(vibrate(1.0)).await.is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+  });
+}
+
+#[test]
+fn test_server_priority_device_arbitration() {
+  async_manager::block_on(async {
+    let (server, _device, device_index) =
+      setup_arbitration_test(DeviceArbitrationPolicy::Priority, "Massage Demo").await;
+    let low = server.new_session(1);
+    let high = server.new_session(5);
+    for session in [&low, &high] {
+      session
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+    }
+    let vibrate = || {
+      messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)]).into()
+    };
+    assert!(low.parse_message(vibrate()).await.is_ok());
+    assert!(high.parse_message(vibrate()).await.is_ok());
+    assert_device_in_use(low.parse_message(vibrate()).await);
+    assert!(high.disconnect().await.is_ok());
+    assert!(low.parse_message(vibrate()).await.is_ok());
+  });
+}
+
+#[test]
+fn test_server_device_reads_do_not_claim() {
+  async_manager::block_on(async {
+    let (server, device, device_index) =
+      setup_arbitration_test(DeviceArbitrationPolicy::ExclusiveLock, "Flamingo").await;
+    device.set_read_value(Endpoint::RxBLEBattery, vec![50]);
+    let monitor = server.new_session(0);
+    let game = server.new_session(0);
+    for session in [&monitor, &game] {
+      session
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+    }
+    let battery = || messages::BatteryLevelCmd::new(device_index).into();
+    let vibrate = || {
+      messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)]).into()
+    };
+
+    // Reading the battery doesn't lock anyone else out of the device.
+    match monitor
+      .parse_message(battery())
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      ButtplugServerMessage::BatteryLevelReading(reading) => {
+        assert_eq!(reading.battery_level(), 0.5)
+      }
+      msg => panic!("Should get back BatteryLevelReading, got {:?}", msg),
+    }
+    assert!(game.parse_message(vibrate()).await.is_ok());
+
+    // Reads still respect whoever does own the device.
+    assert_device_in_use(monitor.parse_message(battery()).await);
+    assert_device_in_use(monitor.parse_message(vibrate()).await);
+    assert!(game.parse_message(battery()).await.is_ok());
+  });
+}
+
+#[test]
+fn test_server_claims_survive_configuration_update() {
+  async_manager::block_on(async {
+    let (server, device, device_index) =
+      setup_arbitration_test(DeviceArbitrationPolicy::ExclusiveLock, "Massage Demo").await;
+    let mut recv = Box::pin(server.event_stream());
+    let game = server.new_session(0);
+    let visualizer = server.new_session(0);
+    for session in [&game, &visualizer] {
+      session
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+    }
+    let vibrate = || {
+      messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)]).into()
+    };
+    assert!(game.parse_message(vibrate()).await.is_ok());
+
+    // New limits re-announce the device to clients, but it never went away,
+    // so the game should still have it.
+    let config = protocol_config_with_user_config(&format!(
+      r#"{{
+        "version": 63,
+        "user-config": {{
+          "{}": {{
+            "limits": {{
+              "vibrate-max": 0.5
+            }}
+          }}
+        }}
+      }}"#,
+      device.address()
+    ));
+    server
+      .update_device_configuration(config)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(matches!(
+      next_device_event(&mut recv).await,
+      Some(ButtplugServerMessage::DeviceRemoved(_))
+    ));
+    assert!(matches!(
+      next_device_event(&mut recv).await,
+      Some(ButtplugServerMessage::DeviceAdded(_))
+    ));
+    // Give anything reacting to those events a chance to run.
+    Delay::new(Duration::from_millis(50)).await;
+    assert_device_in_use(visualizer.parse_message(vibrate()).await);
+    assert!(game.parse_message(vibrate()).await.is_ok());
+  });
+}
+
+fn assert_handshake_error(
+  result: Result<ButtplugServerMessage, messages::Error>,
+  expected: ButtplugHandshakeError,
+) {
+  match result {
+    Err(err) => match err.original_error() {
+      ButtplugError::ButtplugHandshakeError(e) => {
+        assert_eq!(format!("{:?}", e), format!("{:?}", expected))
+      }
+      e => panic!("Should get back handshake error, got {:?}", e),
+    },
+    Ok(msg) => panic!("Should get back handshake error, got {:?}", msg),
+  }
+}
+
+#[test]
+fn test_server_auth_token() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .auth_token("secret")
+      .finish()
+      .expect("Test, assuming infallible.");
+    let mut msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+
+    // Missing token
+    assert_handshake_error(
+      server.parse_message(msg.clone().into()).await,
+      ButtplugHandshakeError::AuthenticationRequired,
+    );
+    // Wrong token, including ones that share a prefix with the real one.
+    for token in ["wrong", "secre", "secret2"] {
+      msg.set_auth_token(Some(token.to_owned()));
+      assert_handshake_error(
+        server.parse_message(msg.clone().into()).await,
+        ButtplugHandshakeError::InvalidCredentials,
+      );
+    }
+    assert!(!server.connected());
+
+    // Commands are rejected until a handshake succeeds.
+    assert_handshake_error(
+      server
+        .parse_message(messages::StartScanning::default().into())
+        .await,
+      ButtplugHandshakeError::RequestServerInfoExpected,
+    );
+    assert_handshake_error(
+      server
+        .parse_message(
+          messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, 0.5)]).into(),
+        )
+        .await,
+      ButtplugHandshakeError::RequestServerInfoExpected,
+    );
+
+    msg.set_auth_token(Some("secret".to_owned()));
+    assert!(server.parse_message(msg.into()).await.is_ok());
+    assert!(server.connected());
+  });
+}
+
+#[test]
+fn test_server_request_log() {
+  use buttplug::util::logging::ButtplugLogLayer;
+  use tracing_subscriber::layer::SubscriberExt;
+  let subscriber = tracing_subscriber::registry().with(ButtplugLogLayer::default());
+  let _guard = tracing::subscriber::set_default(subscriber);
+  let msg = messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+  async_manager::block_on(async {
+    let (server, recv) = setup_test_server(msg.into()).await;
+    let other_session = server.new_session(0);
+    let other_server = ButtplugServer::default();
+    let other_server_session = other_server.new_session(0);
+    pin_mut!(recv);
+    assert!(server
+      .parse_message(messages::RequestLog::new(messages::LogLevel::Debug).into())
+      .await
+      .is_ok());
+    // Logs from other sessions and other servers shouldn't show up.
+    tracing::info!("Unscoped log message");
+    for client in [&other_session, &other_server_session].iter() {
+      assert!(client
+        .parse_message(
+          messages::RequestServerInfo::new("Other Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into()
+        )
+        .await
+        .is_ok());
+    }
+    assert!(other_server
+      .device_manager()
+      .add_comm_manager(TestDeviceCommunicationManagerBuilder::default())
+      .is_ok());
+    // Server wide logs should.
+    assert!(server
+      .device_manager()
+      .add_comm_manager(TestDeviceCommunicationManagerBuilder::default())
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::Log(log) = msg {
+        // Trace level events should be filtered out.
+        assert_ne!(*log.log_level(), messages::LogLevel::Trace);
+        assert!(!log.log_message().contains("Unscoped log message"));
+        assert!(!log.log_message().contains("Other Client"));
+        if log.log_message().contains("Added device comm manager") {
+          assert_eq!(*log.log_level(), messages::LogLevel::Info);
+          break;
+        }
+      }
+    }
+    assert!(server
+      .parse_message(messages::RequestLog::new(messages::LogLevel::Off).into())
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::RequestDeviceList::default().into())
+      .await
+      .is_ok());
+    select! {
+      msg = recv.next().fuse() => panic!("Should not get any messages after turning logs off: {:?}", msg),
+      _ = Delay::new(Duration::from_millis(100)).fuse() => {}
+    };
+  });
+}
+
+// TODO Test sending system message (Id 0)
+// TODO Test sending system message (Ok but Id > 0)
+// TODO Test repeated handshake
+// TODO Test scan with no comm managers
+// TODO Test message with no RequestServerInfo first
+// TODO Test sending device command for device that doesn't exist (in server)
+
+#[test]
+fn test_server_device_configuration_messages_disabled_by_default() {
+  async_manager::block_on(async {
+    let (server, _) = setup_test_server(
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await;
+    assert!(server
+      .parse_message(messages::RequestKnownDeviceList::default().into())
+      .await
+      .is_err());
+    assert!(server
+      .parse_message(
+        messages::SetDeviceUserConfig::new("SomeDevice", None, None, Some(true)).into()
+      )
+      .await
+      .is_err());
+    assert!(server.device_manager().device_user_config().is_empty());
+  });
+}
+
+#[test]
+fn test_server_device_configuration_messages() {
+  async_manager::block_on(async {
+    let save_path = std::env::temp_dir().join(format!(
+      "buttplug-user-config-test-{}.json",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_file(&save_path);
+    let server = ButtplugServerBuilder::default()
+      .allow_device_configuration_messages(true)
+      .user_device_configuration_save_path(save_path.clone())
+      .finish()
+      .expect("Test, assuming infallible.");
+    let mut recv = Box::pin(server.event_stream());
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "ConfigDevice")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let device_index = match next_device_event(&mut recv).await {
+      Some(ButtplugServerMessage::DeviceAdded(da)) => da.device_index(),
+      msg => panic!("Expected DeviceAdded, got {:?}", msg),
+    };
+
+    let known_devices = match server
+      .parse_message(messages::RequestKnownDeviceList::default().into())
+      .await
+    {
+      Ok(ButtplugServerMessage::KnownDeviceList(list)) => list.devices().clone(),
+      msg => panic!("Expected KnownDeviceList, got {:?}", msg),
+    };
+    assert_eq!(known_devices.len(), 1);
+    assert_eq!(known_devices[0].address(), "ConfigDevice");
+    assert_eq!(known_devices[0].device_index(), Some(device_index));
+    assert!(known_devices[0].connected());
+
+    // Denying a connected device disconnects it, and is written back.
+    assert!(server
+      .parse_message(
+        messages::SetDeviceUserConfig::new(
+          "ConfigDevice",
+          Some("Bedside".to_owned()),
+          None,
+          Some(true)
+        )
+        .into()
+      )
+      .await
+      .is_ok());
+    match next_device_event(&mut recv).await {
+      Some(ButtplugServerMessage::DeviceRemoved(dr)) => assert_eq!(dr.device_index(), device_index),
+      msg => panic!("Expected DeviceRemoved, got {:?}", msg),
+    }
+    let saved = load_protocol_config_from_json(
+      &std::fs::read_to_string(&save_path).expect("Test, assuming infallible."),
+      true,
+    )
+    .expect("Test, assuming infallible.");
+    let saved_config = &saved.user_config["ConfigDevice"];
+    assert_eq!(saved_config.display_name().as_deref(), Some("Bedside"));
+    assert_eq!(*saved_config.deny(), Some(true));
+
+    let known_devices = match server
+      .parse_message(messages::RequestKnownDeviceList::default().into())
+      .await
+    {
+      Ok(ButtplugServerMessage::KnownDeviceList(list)) => list.devices().clone(),
+      msg => panic!("Expected KnownDeviceList, got {:?}", msg),
+    };
+    assert!(!known_devices[0].connected());
+    assert_eq!(known_devices[0].deny(), Some(true));
+    assert_eq!(known_devices[0].display_name().as_deref(), Some("Bedside"));
+
+    // Clearing everything removes the entry.
+    assert!(server
+      .parse_message(messages::SetDeviceUserConfig::new("ConfigDevice", None, None, None).into())
+      .await
+      .is_ok());
+    let saved = load_protocol_config_from_json(
+      &std::fs::read_to_string(&save_path).expect("Test, assuming infallible."),
+      true,
+    )
+    .expect("Test, assuming infallible.");
+    assert!(saved.user_config.is_empty());
+    let _ = std::fs::remove_file(&save_path);
+  });
+}
+
+#[test]
+fn test_server_user_device_configuration_save_path_rejects_non_json() {
+  for path in ["user-config.toml", "user-config.yml", "user-config.yaml"] {
+    assert!(ButtplugServerBuilder::default()
+      .user_device_configuration_save_path(path.into())
+      .finish()
+      .is_err());
+  }
+}
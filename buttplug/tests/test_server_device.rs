@@ -4,17 +4,21 @@ use buttplug::{
     errors::{ButtplugDeviceError, ButtplugError},
     messages::{
       self,
+      ActuatorType,
+      ButtplugDeviceMessage,
       ButtplugDeviceMessageType,
+      ButtplugMessage,
       ButtplugServerMessage,
+      SensorType,
       BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
-  device::Endpoint,
-  server::comm_managers::test::TestDeviceCommunicationManagerBuilder,
+  device::{ButtplugDeviceEvent, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+  server::comm_managers::test::{check_test_recv_value, TestDeviceCommunicationManagerBuilder},
   server::{ButtplugServer, ButtplugServerBuilder},
   util::async_manager,
 };
-use futures::{pin_mut, StreamExt};
+use futures::{pin_mut, Stream, StreamExt};
 use std::matches;
 
 // Test devices that have protocols that support movements not all devices do.
@@ -119,6 +123,609 @@ fn test_server_raw_message() {
   });
 }
 
+#[test]
+fn test_server_raw_subscribe_notification() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .allow_raw_messages(true)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    assert!(server
+      .parse_message(messages::RawSubscribeCmd::new(0, Endpoint::Rx).into())
+      .await
+      .is_ok());
+    // Notifications on endpoints that aren't subscribed should be dropped.
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::Tx,
+      vec![1],
+    ));
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::Rx,
+      vec![3],
+    ));
+    // Device events are handled in order, so once the reading from Rx shows
+    // up, the earlier notification on Tx has been dealt with, and subscribing
+    // to Tx can't race it.
+    let mut subscribed_tx = false;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+        continue;
+      } else if let ButtplugServerMessage::RawReading(reading) = msg {
+        assert_eq!(reading.id(), 0);
+        assert_eq!(reading.device_index(), 0);
+        if subscribed_tx {
+          assert_eq!(reading.endpoint(), Endpoint::Tx);
+          assert_eq!(*reading.data(), vec![2]);
+          return;
+        }
+        assert_eq!(reading.endpoint(), Endpoint::Rx);
+        assert_eq!(*reading.data(), vec![3]);
+        assert!(server
+          .parse_message(messages::RawSubscribeCmd::new(0, Endpoint::Tx).into())
+          .await
+          .is_ok());
+        subscribed_tx = true;
+        device.send_event(ButtplugDeviceEvent::Notification(
+          device.address(),
+          Endpoint::Tx,
+          vec![2],
+        ));
+      } else {
+        panic!("Returned message was not a RawReading message: {:?}", msg);
+      }
+    }
+  });
+}
+
+/// Waits for the next RawReading on a stream, returning its data.
+async fn next_raw_reading(recv: impl Stream<Item = ButtplugServerMessage>) -> Vec<u8> {
+  pin_mut!(recv);
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::RawReading(reading) = msg {
+      return reading.data().clone();
+    }
+  }
+  panic!("Event stream ended without a RawReading.");
+}
+
+#[test]
+fn test_server_raw_readings_only_go_to_subscribed_sessions() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .allow_raw_messages(true)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Scanner", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let first = server.new_session(0);
+    let second = server.new_session(0);
+    for session in [&first, &second] {
+      assert!(session
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into()
+        )
+        .await
+        .is_ok());
+    }
+    let first_recv = first.event_stream();
+    pin_mut!(first_recv);
+    let second_recv = second.event_stream();
+    pin_mut!(second_recv);
+    let notify = |data| {
+      device.send_event(ButtplugDeviceEvent::Notification(
+        device.address(),
+        Endpoint::Rx,
+        data,
+      ))
+    };
+    let subscribe = || messages::RawSubscribeCmd::new(0, Endpoint::Rx).into();
+    let unsubscribe = || messages::RawUnsubscribeCmd::new(0, Endpoint::Rx).into();
+
+    assert!(first.parse_message(subscribe()).await.is_ok());
+    notify(vec![1]);
+    assert_eq!(next_raw_reading(&mut first_recv).await, vec![1]);
+
+    // Once the first session stops listening, the device stays subscribed for
+    // the second one. Neither session sees readings from while it wasn't
+    // subscribed.
+    assert!(second.parse_message(subscribe()).await.is_ok());
+    assert!(first.parse_message(unsubscribe()).await.is_ok());
+    notify(vec![2]);
+    assert_eq!(next_raw_reading(&mut second_recv).await, vec![2]);
+    assert!(first.parse_message(subscribe()).await.is_ok());
+    notify(vec![3]);
+    assert_eq!(next_raw_reading(&mut first_recv).await, vec![3]);
+    assert_eq!(next_raw_reading(&mut second_recv).await, vec![3]);
+
+    // Disconnecting doesn't take subscriptions away from other sessions.
+    assert!(first.disconnect().await.is_ok());
+    notify(vec![4]);
+    assert_eq!(next_raw_reading(&mut second_recv).await, vec![4]);
+  });
+}
+
+#[test]
+fn test_server_disconnect_keeps_protocol_subscriptions() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .allow_raw_messages(true)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    // The Lelo F1s protocol subscribes to Rx when the device connects.
+    let device = helper.add_ble_device("F1s").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    assert!(device.is_subscribed(&Endpoint::Rx));
+    for endpoint in [Endpoint::Rx, Endpoint::Tx] {
+      assert!(server
+        .parse_message(messages::RawSubscribeCmd::new(0, endpoint).into())
+        .await
+        .is_ok());
+    }
+    assert!(device.is_subscribed(&Endpoint::Tx));
+    // Unsubscribing from Rx only stops the client from getting its readings.
+    assert!(server
+      .parse_message(messages::RawUnsubscribeCmd::new(0, Endpoint::Rx).into())
+      .await
+      .is_ok());
+    assert!(device.is_subscribed(&Endpoint::Rx));
+    assert!(server
+      .parse_message(messages::RawSubscribeCmd::new(0, Endpoint::Rx).into())
+      .await
+      .is_ok());
+    assert!(server.disconnect().await.is_ok());
+    assert!(device.is_subscribed(&Endpoint::Rx));
+    assert!(!device.is_subscribed(&Endpoint::Tx));
+  });
+}
+
+const SENSOR_DEVICE_CONFIG_JSON: &str = r#"{
+  "version": 63,
+  "protocols": {
+    "aneros": {
+      "btle": {
+        "names": [
+          "Sensor Test"
+        ],
+        "services": {
+          "0000fff0-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000fff1-0000-1000-8000-00805f9b34fb",
+            "rxpressure": "0000fff2-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      },
+      "defaults": {
+        "name": {
+          "en-us": "Sensor Test Device"
+        },
+        "messages": {
+          "VibrateCmd": {
+            "FeatureCount": 1,
+            "StepCount": [
+              127
+            ]
+          },
+          "SensorReadCmd": {
+            "FeatureCount": 1,
+            "SensorType": [
+              "Pressure"
+            ],
+            "SensorRange": [
+              [
+                [
+                  0,
+                  255
+                ]
+              ]
+            ]
+          },
+          "SensorSubscribeCmd": {
+            "FeatureCount": 2,
+            "SensorType": [
+              "Pressure",
+              "Pressure"
+            ],
+            "SensorRange": [
+              [
+                [
+                  0,
+                  255
+                ]
+              ],
+              [
+                [
+                  0,
+                  255
+                ]
+              ]
+            ]
+          }
+        }
+      }
+    }
+  }
+}"#;
+
+#[test]
+fn test_server_sensor_read_and_subscribe() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .device_configuration_json(Some(SENSOR_DEVICE_CONFIG_JSON.to_owned()))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Sensor Test").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        let attrs = da
+          .device_messages()
+          .get(&ButtplugDeviceMessageType::SensorReadCmd)
+          .expect("Test, assuming infallible.");
+        assert_eq!(attrs.sensor_type, Some(vec![SensorType::Pressure]));
+        assert_eq!(attrs.sensor_range, Some(vec![vec![(0, 255)]]));
+        break;
+      }
+    }
+    match server
+      .parse_message(messages::SensorReadCmd::new(0, 0, SensorType::Pressure).into())
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      ButtplugServerMessage::SensorReading(reading) => {
+        assert_eq!(reading.sensor_index(), 0);
+        assert_eq!(reading.sensor_type(), SensorType::Pressure);
+      }
+      msg => panic!(
+        "Returned message was not a SensorReading message: {:?}",
+        msg
+      ),
+    }
+    // Sensor types have to match what the device says is at the index.
+    assert!(server
+      .parse_message(messages::SensorReadCmd::new(0, 0, SensorType::Button).into())
+      .await
+      .is_err());
+    assert!(server
+      .parse_message(messages::SensorReadCmd::new(0, 1, SensorType::Pressure).into())
+      .await
+      .is_err());
+    assert!(server
+      .parse_message(messages::SensorSubscribeCmd::new(0, 0, SensorType::Pressure).into())
+      .await
+      .is_ok());
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::RxPressure,
+      vec![2],
+    ));
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+        continue;
+      } else if let ButtplugServerMessage::SensorReading(reading) = msg {
+        assert_eq!(reading.id(), 0);
+        assert_eq!(reading.device_index(), 0);
+        assert_eq!(reading.sensor_index(), 0);
+        assert_eq!(reading.sensor_type(), SensorType::Pressure);
+        assert_eq!(*reading.data(), vec![2]);
+        break;
+      } else {
+        panic!(
+          "Returned message was not a SensorReading message: {:?}",
+          msg
+        );
+      }
+    }
+    assert!(server
+      .parse_message(messages::SensorUnsubscribeCmd::new(0, 0, SensorType::Pressure).into())
+      .await
+      .is_ok());
+  });
+}
+
+#[test]
+fn test_server_sensor_subscriptions_share_endpoint() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .device_configuration_json(Some(SENSOR_DEVICE_CONFIG_JSON.to_owned()))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Sensor Test").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    // Both pressure sensors arrive on the same endpoint.
+    for sensor_index in 0..2 {
+      assert!(server
+        .parse_message(
+          messages::SensorSubscribeCmd::new(0, sensor_index, SensorType::Pressure).into()
+        )
+        .await
+        .is_ok());
+    }
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::RxPressure,
+      vec![2],
+    ));
+    let mut sensor_indexes = vec![];
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+        continue;
+      } else if let ButtplugServerMessage::SensorReading(reading) = msg {
+        assert_eq!(*reading.data(), vec![2]);
+        sensor_indexes.push(reading.sensor_index());
+        if sensor_indexes.len() == 2 {
+          break;
+        }
+      } else {
+        panic!(
+          "Returned message was not a SensorReading message: {:?}",
+          msg
+        );
+      }
+    }
+    assert_eq!(sensor_indexes, vec![0, 1]);
+    // Dropping one sensor keeps the endpoint subscribed for the other.
+    assert!(server
+      .parse_message(messages::SensorUnsubscribeCmd::new(0, 0, SensorType::Pressure).into())
+      .await
+      .is_ok());
+    assert!(device.is_subscribed(&Endpoint::RxPressure));
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::RxPressure,
+      vec![3],
+    ));
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::SensorReading(reading) = msg {
+        assert_eq!(reading.sensor_index(), 1);
+        assert_eq!(*reading.data(), vec![3]);
+        break;
+      }
+    }
+    assert!(server
+      .parse_message(messages::SensorUnsubscribeCmd::new(0, 1, SensorType::Pressure).into())
+      .await
+      .is_ok());
+    assert!(!device.is_subscribed(&Endpoint::RxPressure));
+  });
+}
+
+#[test]
+fn test_server_scalar_cmd_non_vibrate_actuator() {
+  async_manager::block_on(async {
+    // A template device with a vibrator, and an oscillator that's only
+    // described by its ScalarCmd attributes.
+    let user_config = r#"{
+      "version": 1,
+      "protocols": {
+        "scalar-test": {
+          "btle": {
+            "names": ["Scalar Test"],
+            "services": {
+              "0000fff0-0000-1000-8000-00805f9b34fb": {
+                "tx": "0000fff1-0000-1000-8000-00805f9b34fb"
+              }
+            }
+          },
+          "defaults": {
+            "name": { "en-us": "Scalar Test Device" },
+            "messages": {
+              "VibrateCmd": { "FeatureCount": 1, "StepCount": [20] },
+              "ScalarCmd": { "FeatureCount": 1, "StepCount": [10], "ActuatorType": ["Oscillate"] }
+            }
+          },
+          "template": { "packet": [1, { "speed": 0 }, { "scalar": 0 }] }
+        }
+      }
+    }"#;
+    let server = ButtplugServerBuilder::default()
+      .user_device_configuration_json(Some(user_config.to_owned()))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Scalar Test").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    let device_index = loop {
+      match recv.next().await {
+        Some(ButtplugServerMessage::DeviceAdded(da)) => {
+          // Generic vibrators come before the configured actuators.
+          assert_eq!(
+            da.device_messages()[&ButtplugDeviceMessageType::ScalarCmd].actuator_type,
+            Some(vec![ActuatorType::Vibrate, ActuatorType::Oscillate])
+          );
+          break da.device_index();
+        }
+        Some(_) => continue,
+        None => panic!("Device should have been added."),
+      }
+    };
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(
+        messages::ScalarCmd::new(
+          device_index,
+          vec![messages::ScalarSubcommand::new(
+            1,
+            0.5,
+            ActuatorType::Oscillate,
+          )],
+        )
+        .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![1, 0, 5], false)),
+    );
+    // Vibrators still go through VibrateCmd, and keep the oscillator going.
+    server
+      .parse_message(
+        messages::ScalarCmd::new(
+          device_index,
+          vec![messages::ScalarSubcommand::new(
+            0,
+            1.0,
+            ActuatorType::Vibrate,
+          )],
+        )
+        .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![1, 20, 5], false)),
+    );
+    // Mismatched actuator types are rejected.
+    assert!(server
+      .parse_message(
+        messages::ScalarCmd::new(
+          device_index,
+          vec![messages::ScalarSubcommand::new(
+            1,
+            0.5,
+            ActuatorType::Inflate
+          )],
+        )
+        .into(),
+      )
+      .await
+      .is_err());
+  });
+}
+
 #[test]
 fn test_server_no_raw_message() {
   async_manager::block_on(async {
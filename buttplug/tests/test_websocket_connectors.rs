@@ -8,6 +8,7 @@ mod websocket_connector_tests {
       ButtplugRemoteClientConnector,
       ButtplugRemoteServerConnector,
       ButtplugWebsocketClientTransport,
+      ButtplugWebsocketServerTlsIdentity,
       ButtplugWebsocketServerTransport,
       ButtplugWebsocketServerTransportBuilder,
     },
@@ -18,6 +19,7 @@ mod websocket_connector_tests {
   use futures_timer::Delay;
   use std::sync::Arc;
   use std::time::Duration;
+  use tokio::{io::AsyncWriteExt, net::TcpStream};
 
   #[test]
   fn test_client_ws_client_server_ws_server_insecure() {
@@ -107,6 +109,170 @@ mod websocket_connector_tests {
         .expect("Test, assuming infallible.");
     });
   }
+
+  fn test_tls_identities() -> Vec<ButtplugWebsocketServerTlsIdentity> {
+    let tls_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/util/tls");
+    vec![
+      ButtplugWebsocketServerTlsIdentity::from_pem_files(
+        format!("{}/cert.pem", tls_dir),
+        format!("{}/key.pem", tls_dir),
+      )
+      .expect("Test, assuming infallible."),
+      ButtplugWebsocketServerTlsIdentity::from_pkcs12_file(
+        format!("{}/identity.p12", tls_dir),
+        "buttplug",
+      )
+      .expect("Test, assuming infallible."),
+    ]
+  }
+
+  #[test]
+  fn test_client_ws_client_server_ws_server_secure() {
+    async_manager::block_on(async move {
+      for (i, identity) in test_tls_identities().into_iter().enumerate() {
+        let port = 12350 + i as u16;
+        let server = Arc::new(ButtplugRemoteServer::default());
+        let server_clone = server.clone();
+        async_manager::spawn(async move {
+          let connector = ButtplugRemoteServerConnector::<
+            ButtplugWebsocketServerTransport,
+            ButtplugServerJSONSerializer,
+          >::new(
+            ButtplugWebsocketServerTransportBuilder::default()
+              .disable_insecure_port()
+              .secure_port(port, identity)
+              .finish(),
+          );
+          server_clone
+            .start(connector)
+            .await
+            .expect("Test, assuming infallible.");
+        });
+        let mut connected = false;
+        for _ in 0..10u8 {
+          let connector = ButtplugRemoteClientConnector::<
+            ButtplugWebsocketClientTransport,
+            ButtplugClientJSONSerializer,
+          >::new(ButtplugWebsocketClientTransport::new_secure_connector(
+            &format!("wss://127.0.0.1:{}", port),
+            true,
+          ));
+
+          let client = ButtplugClient::new("Test Client");
+          if client.connect(connector).await.is_ok() {
+            connected = true;
+            break;
+          }
+          Delay::new(Duration::from_secs(1)).await;
+        }
+        assert!(connected);
+        server
+          .disconnect()
+          .await
+          .expect("Test, assuming infallible.");
+      }
+    });
+  }
+
+  #[test]
+  fn test_client_ws_client_server_ws_server_plain_and_secure() {
+    async_manager::block_on(async move {
+      let server = Arc::new(ButtplugRemoteServer::default());
+      let server_clone = server.clone();
+      async_manager::spawn(async move {
+        let connector = ButtplugRemoteServerConnector::<
+          ButtplugWebsocketServerTransport,
+          ButtplugServerJSONSerializer,
+        >::new(
+          ButtplugWebsocketServerTransportBuilder::default()
+            .port(12352)
+            .secure_port(12353, test_tls_identities().remove(0))
+            .finish(),
+        );
+        server_clone
+          .start(connector)
+          .await
+          .expect("Test, assuming infallible.");
+      });
+      let mut connected = false;
+      for _ in 0..10u8 {
+        let connector = ButtplugRemoteClientConnector::<
+          ButtplugWebsocketClientTransport,
+          ButtplugClientJSONSerializer,
+        >::new(ButtplugWebsocketClientTransport::new_insecure_connector(
+          "ws://127.0.0.1:12352",
+        ));
+
+        let client = ButtplugClient::new("Test Client");
+        if client.connect(connector).await.is_ok() {
+          connected = true;
+          break;
+        }
+        Delay::new(Duration::from_secs(1)).await;
+      }
+      assert!(connected);
+      server
+        .disconnect()
+        .await
+        .expect("Test, assuming infallible.");
+    });
+  }
+
+  #[test]
+  fn test_client_ws_client_server_ws_server_survives_bad_handshake() {
+    async_manager::block_on(async move {
+      let server = Arc::new(ButtplugRemoteServer::default());
+      let server_clone = server.clone();
+      async_manager::spawn(async move {
+        let connector = ButtplugRemoteServerConnector::<
+          ButtplugWebsocketServerTransport,
+          ButtplugServerJSONSerializer,
+        >::new(
+          ButtplugWebsocketServerTransportBuilder::default()
+            .port(12354)
+            .secure_port(12355, test_tls_identities().remove(0))
+            .finish(),
+        );
+        server_clone
+          .start(connector)
+          .await
+          .expect("Test, assuming infallible.");
+      });
+      // Send junk to both ports, which should fail the websocket and TLS
+      // handshakes without stopping the server from listening.
+      for port in [12354u16, 12355] {
+        for _ in 0..10u8 {
+          if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)).await {
+            let _ = stream.write_all(b"not a handshake\r\n\r\n").await;
+            let _ = stream.shutdown().await;
+            break;
+          }
+          Delay::new(Duration::from_secs(1)).await;
+        }
+      }
+      let mut connected = false;
+      for _ in 0..10u8 {
+        let connector = ButtplugRemoteClientConnector::<
+          ButtplugWebsocketClientTransport,
+          ButtplugClientJSONSerializer,
+        >::new(ButtplugWebsocketClientTransport::new_insecure_connector(
+          "ws://127.0.0.1:12354",
+        ));
+
+        let client = ButtplugClient::new("Test Client");
+        if client.connect(connector).await.is_ok() {
+          connected = true;
+          break;
+        }
+        Delay::new(Duration::from_secs(1)).await;
+      }
+      assert!(connected);
+      server
+        .disconnect()
+        .await
+        .expect("Test, assuming infallible.");
+    });
+  }
 }
 
 // TODO Test disconnection event from server side
@@ -38,7 +38,7 @@ use tokio::sync::{
 };
 use tracing::*;
 
-struct ChannelTransport {
+pub struct ChannelTransport {
   outside_receiver: Arc<Mutex<Option<Receiver<ButtplugTransportIncomingMessage>>>>,
   outside_sender: Sender<ButtplugSerializedMessage>,
   disconnect_notifier: Arc<Notify>,
@@ -324,3 +324,42 @@ impl ChannelServerTestHelper {
       .await;
   }
 }
+
+/// Creates a client connector and server connector that talk to each other
+/// over channels, for running a remote client against a remote server.
+pub fn connected_channel_connectors() -> (
+  ButtplugRemoteClientConnector<ChannelTransport>,
+  ButtplugRemoteServerConnector<ChannelTransport, ButtplugServerJSONSerializer>,
+) {
+  fn forward(
+    mut receiver: Receiver<ButtplugSerializedMessage>,
+    sender: Sender<ButtplugTransportIncomingMessage>,
+  ) {
+    async_manager::spawn(async move {
+      while let Some(msg) = receiver.recv().await {
+        if sender
+          .send(ButtplugTransportIncomingMessage::Message(msg))
+          .await
+          .is_err()
+        {
+          return;
+        }
+      }
+    });
+  }
+  let (client_incoming_sender, client_incoming_receiver) = channel(256);
+  let (client_outgoing_sender, client_outgoing_receiver) = channel(256);
+  let (server_incoming_sender, server_incoming_receiver) = channel(256);
+  let (server_outgoing_sender, server_outgoing_receiver) = channel(256);
+  forward(client_outgoing_receiver, server_incoming_sender);
+  forward(server_outgoing_receiver, client_incoming_sender);
+  (
+    ButtplugRemoteClientConnector::<ChannelTransport>::new(ChannelTransport::new(
+      client_incoming_receiver,
+      client_outgoing_sender,
+    )),
+    ButtplugRemoteServerConnector::<ChannelTransport, ButtplugServerJSONSerializer>::new(
+      ChannelTransport::new(server_incoming_receiver, server_outgoing_sender),
+    ),
+  )
+}
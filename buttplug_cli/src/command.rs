@@ -0,0 +1,210 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Parsing for the lines typed into the REPL.
+
+use buttplug::device::Endpoint;
+use std::str::FromStr;
+
+pub const HELP: &str = "\
+Commands:
+  scan                                Start scanning for devices
+  stop-scan                           Stop scanning for devices
+  list                                List connected devices
+  vibrate <device> <speed> [speed...] Vibrate, one speed for all motors or one per motor
+  rotate <device> <speed> [cw|ccw]    Rotate, clockwise unless ccw is given
+  linear <device> <duration> <pos>    Move to a position (0.0-1.0) over a duration in ms
+  battery <device>                    Read the battery level
+  rssi <device>                       Read the RSSI level
+  raw write <device> <endpoint> <hex> Write bytes (e.g. 0a ff 01) to an endpoint
+  raw read <device> <endpoint> <len>  Read bytes from an endpoint
+  stop [device]                       Stop one device, or all of them
+  ping                                Ping the server
+  help                                Show this help
+  quit                                Disconnect and exit";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  Scan,
+  StopScan,
+  List,
+  Vibrate(u32, Vec<f64>),
+  Rotate(u32, f64, bool),
+  Linear(u32, u32, f64),
+  Battery(u32),
+  Rssi(u32),
+  RawWrite(u32, Endpoint, Vec<u8>),
+  RawRead(u32, Endpoint, u32),
+  Stop(Option<u32>),
+  Ping,
+  Help,
+  Quit,
+}
+
+fn parse_arg<T: FromStr>(arg: Option<&str>, name: &str) -> Result<T, String> {
+  let arg = arg.ok_or_else(|| format!("Missing argument: {}", name))?;
+  arg
+    .parse()
+    .map_err(|_| format!("Invalid {}: {}", name, arg))
+}
+
+fn parse_level(arg: Option<&str>, name: &str) -> Result<f64, String> {
+  let level: f64 = parse_arg(arg, name)?;
+  if !(0.0..=1.0).contains(&level) {
+    return Err(format!("{} must be between 0.0 and 1.0", name));
+  }
+  Ok(level)
+}
+
+fn parse_hex(args: &[&str]) -> Result<Vec<u8>, String> {
+  // Accept both "0a ff 01" and "0aff01".
+  let hex: String = args.concat();
+  if hex.is_empty() {
+    return Err("Missing argument: data".to_owned());
+  }
+  hex
+    .as_bytes()
+    .chunks(2)
+    .map(|digits| {
+      std::str::from_utf8(digits)
+        .ok()
+        .filter(|digits| digits.len() == 2)
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| format!("Invalid hex data: {}", hex))
+    })
+    .collect()
+}
+
+/// Parses a line of input. Returns Ok(None) for blank lines.
+pub fn parse_command(line: &str) -> Result<Option<Command>, String> {
+  let words: Vec<&str> = line.split_whitespace().collect();
+  let (command, args) = match words.split_first() {
+    Some(split) => split,
+    None => return Ok(None),
+  };
+  let mut args_iter = args.iter().copied();
+  let command = match command.to_lowercase().as_str() {
+    "scan" => Command::Scan,
+    "stop-scan" => Command::StopScan,
+    "list" | "ls" => Command::List,
+    "vibrate" => {
+      let device = parse_arg(args_iter.next(), "device")?;
+      let speeds = args_iter
+        .by_ref()
+        .map(|arg| parse_level(Some(arg), "speed"))
+        .collect::<Result<Vec<_>, _>>()?;
+      if speeds.is_empty() {
+        return Err("Missing argument: speed".to_owned());
+      }
+      Command::Vibrate(device, speeds)
+    }
+    "rotate" => {
+      let device = parse_arg(args_iter.next(), "device")?;
+      let speed = parse_level(args_iter.next(), "speed")?;
+      let clockwise = match args_iter.next() {
+        None | Some("cw") => true,
+        Some("ccw") => false,
+        Some(direction) => return Err(format!("Invalid direction: {}", direction)),
+      };
+      Command::Rotate(device, speed, clockwise)
+    }
+    "linear" => Command::Linear(
+      parse_arg(args_iter.next(), "device")?,
+      parse_arg(args_iter.next(), "duration")?,
+      parse_level(args_iter.next(), "position")?,
+    ),
+    "battery" => Command::Battery(parse_arg(args_iter.next(), "device")?),
+    "rssi" => Command::Rssi(parse_arg(args_iter.next(), "device")?),
+    "raw" => {
+      let subcommand = args_iter.next();
+      let device = parse_arg(args_iter.next(), "device")?;
+      let endpoint = parse_arg(args_iter.next(), "endpoint")?;
+      match subcommand {
+        Some("write") => Command::RawWrite(
+          device,
+          endpoint,
+          parse_hex(&args_iter.by_ref().collect::<Vec<_>>())?,
+        ),
+        Some("read") => Command::RawRead(device, endpoint, parse_arg(args_iter.next(), "length")?),
+        _ => return Err("Expected raw write or raw read".to_owned()),
+      }
+    }
+    "stop" => Command::Stop(
+      args_iter
+        .next()
+        .map(|arg| parse_arg(Some(arg), "device"))
+        .transpose()?,
+    ),
+    "ping" => Command::Ping,
+    "help" | "?" => Command::Help,
+    "quit" | "exit" => Command::Quit,
+    _ => {
+      return Err(format!(
+        "Unknown command: {}. Type help for a list.",
+        command
+      ))
+    }
+  };
+  if args_iter.next().is_some() {
+    return Err("Too many arguments".to_owned());
+  }
+  Ok(Some(command))
+}
+
+#[cfg(test)]
+mod test {
+  use super::{parse_command, Command};
+  use buttplug::device::Endpoint;
+
+  #[test]
+  fn test_parse_command() {
+    assert_eq!(parse_command("  "), Ok(None));
+    assert_eq!(parse_command("scan"), Ok(Some(Command::Scan)));
+    assert_eq!(
+      parse_command("vibrate 0 0.5"),
+      Ok(Some(Command::Vibrate(0, vec![0.5])))
+    );
+    assert_eq!(
+      parse_command("vibrate 1 0.25 1"),
+      Ok(Some(Command::Vibrate(1, vec![0.25, 1.0])))
+    );
+    assert_eq!(
+      parse_command("rotate 0 0.5 ccw"),
+      Ok(Some(Command::Rotate(0, 0.5, false)))
+    );
+    assert_eq!(
+      parse_command("linear 2 500 0.9"),
+      Ok(Some(Command::Linear(2, 500, 0.9)))
+    );
+    assert_eq!(
+      parse_command("raw write 0 tx 0a ff01"),
+      Ok(Some(Command::RawWrite(
+        0,
+        Endpoint::Tx,
+        vec![0x0a, 0xff, 0x01]
+      )))
+    );
+    assert_eq!(
+      parse_command("raw read 0 rx 4"),
+      Ok(Some(Command::RawRead(0, Endpoint::Rx, 4)))
+    );
+    assert_eq!(parse_command("stop"), Ok(Some(Command::Stop(None))));
+    assert_eq!(parse_command("stop 3"), Ok(Some(Command::Stop(Some(3)))));
+  }
+
+  #[test]
+  fn test_parse_command_errors() {
+    assert!(parse_command("dance").is_err());
+    assert!(parse_command("vibrate").is_err());
+    assert!(parse_command("vibrate 0").is_err());
+    assert!(parse_command("vibrate 0 1.5").is_err());
+    assert!(parse_command("rotate 0 0.5 sideways").is_err());
+    assert!(parse_command("raw write 0 tx 0").is_err());
+    assert!(parse_command("raw write 0 nowhere 00").is_err());
+    assert!(parse_command("battery 0 1").is_err());
+  }
+}
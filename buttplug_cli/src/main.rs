@@ -0,0 +1,313 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Interactive Buttplug client, for poking at devices by hand.
+//!
+//! Connects to a server (or runs one in process), then reads commands from
+//! stdin one line at a time, printing client events as they come in.
+
+mod command;
+
+use buttplug::{
+  client::{
+    ButtplugClient,
+    ButtplugClientDevice,
+    ButtplugClientError,
+    ButtplugClientEvent,
+    LinearCommand,
+    RotateCommand,
+    VibrateCommand,
+  },
+  connector::{
+    ButtplugPipeClientTransportBuilder,
+    ButtplugRemoteClientConnector,
+    ButtplugTcpClientTransportBuilder,
+    ButtplugWebsocketClientTransport,
+  },
+  core::messages::serializer::ButtplugClientJSONSerializer,
+};
+use clap::{ArgGroup, Parser};
+use command::{parse_command, Command, HELP};
+use futures::{Stream, StreamExt};
+use std::{error::Error, process::ExitCode, sync::Arc};
+use tokio::{
+  io::{AsyncBufReadExt, BufReader},
+  sync::oneshot,
+};
+use tracing::Level;
+
+#[derive(Parser, Debug)]
+#[command(
+  name = "buttplug-cli",
+  version,
+  about = "Interactive Buttplug client for testing devices by hand",
+  group(ArgGroup::new("connection").args(["websocket", "pipe", "tcp"]))
+)]
+struct Options {
+  /// Name the client reports to the server.
+  #[arg(long, default_value = "Buttplug CLI")]
+  client_name: String,
+
+  /// Connect to a websocket server at this address (ws:// or wss://).
+  #[arg(long)]
+  websocket: Option<String>,
+
+  /// Don't verify the server certificate when connecting over wss://.
+  #[arg(long, requires = "websocket")]
+  accept_invalid_certs: bool,
+
+  /// Connect to a server listening on this named pipe.
+  #[arg(long)]
+  pipe: Option<String>,
+
+  /// Connect to a server listening on this TCP address (host:port), using
+  /// newline framing.
+  #[arg(long)]
+  tcp: Option<String>,
+
+  /// Token to send during the handshake, for servers that require one.
+  #[arg(long, requires = "connection")]
+  auth_token: Option<String>,
+
+  /// Log level (error, warn, info, debug, trace). Logs go to stderr.
+  #[arg(long, default_value_t = Level::WARN)]
+  log: Level,
+}
+
+/// Connects to whatever the options point at. With no connection options, an
+/// in-process server is started with every comm manager this binary was
+/// built with.
+async fn connect(client: &ButtplugClient, options: &Options) -> Result<(), ButtplugClientError> {
+  // Every transport ends up wrapped in the same connector type, so build
+  // that the same way for each of them.
+  macro_rules! connect_remote {
+    ($transport:expr) => {{
+      let mut connector =
+        ButtplugRemoteClientConnector::<_, ButtplugClientJSONSerializer>::new($transport);
+      if let Some(token) = &options.auth_token {
+        connector = connector.with_auth_token(token);
+      }
+      client.connect(connector).await
+    }};
+  }
+
+  if let Some(address) = &options.websocket {
+    if address.starts_with("wss://") {
+      connect_remote!(ButtplugWebsocketClientTransport::new_secure_connector(
+        address,
+        options.accept_invalid_certs
+      ))
+    } else {
+      connect_remote!(ButtplugWebsocketClientTransport::new_insecure_connector(
+        address
+      ))
+    }
+  } else if let Some(address) = &options.pipe {
+    connect_remote!(ButtplugPipeClientTransportBuilder::new(address).finish())
+  } else if let Some(address) = &options.tcp {
+    connect_remote!(ButtplugTcpClientTransportBuilder::new(address).finish())
+  } else {
+    client.connect_in_process(None).await
+  }
+}
+
+fn describe_device(device: &ButtplugClientDevice) -> String {
+  let mut messages: Vec<String> = device
+    .allowed_messages
+    .keys()
+    .map(|message_type| format!("{:?}", message_type))
+    .collect();
+  messages.sort();
+  format!(
+    "{}: {} [{}]",
+    device.index(),
+    device.name,
+    messages.join(", ")
+  )
+}
+
+/// Prints client events as they arrive, and lets the REPL know once the
+/// server has gone away.
+async fn print_events(
+  mut events: impl Stream<Item = ButtplugClientEvent> + Unpin,
+  disconnect_sender: oneshot::Sender<()>,
+) {
+  while let Some(event) = events.next().await {
+    match event {
+      ButtplugClientEvent::DeviceAdded(device) => {
+        println!("Device added: {}", describe_device(&device))
+      }
+      ButtplugClientEvent::DeviceRemoved(device) => {
+        println!("Device removed: {}: {}", device.index(), device.name)
+      }
+      ButtplugClientEvent::ScanningFinished => println!("Scanning finished."),
+      ButtplugClientEvent::PingTimeout => println!("Server ping timed out."),
+      ButtplugClientEvent::ServerConnect => println!("Connected to server."),
+      ButtplugClientEvent::ServerDisconnect => {
+        println!("Server disconnected.");
+        let _ = disconnect_sender.send(());
+        return;
+      }
+      ButtplugClientEvent::Reconnecting => println!("Reconnecting to server..."),
+      ButtplugClientEvent::Reconnected => println!("Reconnected to server."),
+      ButtplugClientEvent::Error(err) => println!("Server error: {}", err),
+      ButtplugClientEvent::Log(level, message) => println!("Server log [{:?}]: {}", level, message),
+    }
+  }
+}
+
+fn find_device(
+  client: &ButtplugClient,
+  index: u32,
+) -> Result<Arc<ButtplugClientDevice>, Box<dyn Error>> {
+  client
+    .devices()
+    .into_iter()
+    .find(|device| device.index() == index)
+    .ok_or_else(|| format!("No device with index {}", index).into())
+}
+
+/// Runs a single command, returning false if the REPL should exit.
+async fn run_command(client: &ButtplugClient, command: Command) -> Result<bool, Box<dyn Error>> {
+  match command {
+    Command::Scan => client.start_scanning().await?,
+    Command::StopScan => client.stop_scanning().await?,
+    Command::List => {
+      let devices = client.devices();
+      if devices.is_empty() {
+        println!("No devices connected.");
+      }
+      for device in devices {
+        println!("{}", describe_device(&device));
+      }
+    }
+    Command::Vibrate(index, speeds) => {
+      let command = if speeds.len() == 1 {
+        VibrateCommand::Speed(speeds[0])
+      } else {
+        VibrateCommand::SpeedVec(speeds)
+      };
+      find_device(client, index)?.vibrate(command).await?
+    }
+    Command::Rotate(index, speed, clockwise) => {
+      find_device(client, index)?
+        .rotate(RotateCommand::Rotate(speed, clockwise))
+        .await?
+    }
+    Command::Linear(index, duration, position) => {
+      find_device(client, index)?
+        .linear(LinearCommand::Linear(duration, position))
+        .await?
+    }
+    Command::Battery(index) => println!(
+      "Battery level: {:.0}%",
+      find_device(client, index)?.battery_level().await? * 100.0
+    ),
+    Command::Rssi(index) => println!(
+      "RSSI level: {}",
+      find_device(client, index)?.rssi_level().await?
+    ),
+    Command::RawWrite(index, endpoint, data) => {
+      find_device(client, index)?
+        .raw_write(endpoint, data, false)
+        .await?
+    }
+    Command::RawRead(index, endpoint, length) => {
+      let data = find_device(client, index)?
+        .raw_read(endpoint, length, 0)
+        .await?;
+      let hex: Vec<String> = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+      println!("Read from {}: {}", endpoint, hex.join(" "));
+    }
+    Command::Stop(Some(index)) => find_device(client, index)?.stop().await?,
+    Command::Stop(None) => client.stop_all_devices().await?,
+    Command::Ping => client.ping().await?,
+    Command::Help => println!("{}", HELP),
+    Command::Quit => return Ok(false),
+  }
+  Ok(true)
+}
+
+async fn run(options: Options) -> Result<(), Box<dyn Error>> {
+  let client = ButtplugClient::new(&options.client_name);
+  // Subscribe before connecting, so events sent during the handshake (like
+  // devices the server already knows about) aren't missed.
+  let (disconnect_sender, mut disconnect_receiver) = oneshot::channel();
+  tokio::spawn(print_events(client.event_stream(), disconnect_sender));
+  connect(&client, &options).await?;
+  if let Some(name) = client.server_name() {
+    println!("Server name: {}", name);
+  }
+  println!("Type help for a list of commands.");
+
+  let mut lines = BufReader::new(tokio::io::stdin()).lines();
+  loop {
+    let line = tokio::select! {
+      line = lines.next_line() => match line? {
+        Some(line) => line,
+        // stdin closed, so treat it like quit.
+        None => break,
+      },
+      _ = &mut disconnect_receiver => return Ok(()),
+      _ = tokio::signal::ctrl_c() => break,
+    };
+    let command = match parse_command(&line) {
+      Ok(Some(command)) => command,
+      Ok(None) => continue,
+      Err(err) => {
+        println!("{}", err);
+        continue;
+      }
+    };
+    match run_command(&client, command).await {
+      Ok(true) => {}
+      Ok(false) => break,
+      Err(err) => println!("Error: {}", err),
+    }
+  }
+  if client.connected() {
+    client.stop_all_devices().await?;
+    client.disconnect().await?;
+  }
+  Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+  let options = Options::parse();
+  tracing_subscriber::fmt()
+    .with_max_level(options.log)
+    .with_writer(std::io::stderr)
+    .init();
+  if let Err(err) = run(options).await {
+    eprintln!("{}", err);
+    return ExitCode::FAILURE;
+  }
+  ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+  use super::Options;
+  use clap::{CommandFactory, Parser};
+
+  #[test]
+  fn test_options() {
+    Options::command().debug_assert();
+    let options = Options::parse_from(["buttplug-cli", "--websocket", "ws://127.0.0.1:12345"]);
+    assert_eq!(options.websocket.as_deref(), Some("ws://127.0.0.1:12345"));
+    assert!(Options::try_parse_from([
+      "buttplug-cli",
+      "--websocket",
+      "ws://127.0.0.1:12345",
+      "--pipe",
+      "buttplug"
+    ])
+    .is_err());
+    assert!(Options::try_parse_from(["buttplug-cli", "--auth-token", "secret"]).is_err());
+  }
+}
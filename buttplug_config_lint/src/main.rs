@@ -0,0 +1,132 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Device configuration linter.
+//!
+//! Checks a device configuration file (the built in one by default) and an
+//! optional user configuration file for mistakes that schema validation lets
+//! through, and can show what the user configuration changes once merged.
+
+use buttplug::util::{
+  device_configuration::{
+    load_protocol_config,
+    load_protocol_config_from_json,
+    ProtocolConfiguration,
+    ProtocolConfigurationFormat,
+    DEVICE_CONFIGURATION_JSON,
+  },
+  device_configuration_lint::{
+    diff_protocol_configuration,
+    lint_protocol_configuration,
+    lint_user_configuration,
+    ConfigurationIssueSeverity,
+  },
+};
+use clap::Parser;
+use std::{
+  error::Error,
+  fs,
+  path::{Path, PathBuf},
+  process::ExitCode,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+  name = "buttplug-config-lint",
+  version,
+  about = "Checks Buttplug device configuration files for mistakes"
+)]
+struct Options {
+  /// Device configuration file to check, instead of the built in one. Files
+  /// ending in .yml/.yaml or .toml are read as YAML or TOML, anything else as
+  /// JSON.
+  #[arg(long)]
+  device_config: Option<PathBuf>,
+
+  /// User device configuration file to check against the device
+  /// configuration.
+  #[arg(long)]
+  user_device_config: Option<PathBuf>,
+
+  /// Show what the user device configuration changes once merged.
+  #[arg(long, requires = "user_device_config")]
+  diff: bool,
+
+  /// Exit with an error if there are any warnings.
+  #[arg(long)]
+  deny_warnings: bool,
+}
+
+fn load_config(
+  path: &Path,
+  skip_version_check: bool,
+) -> Result<ProtocolConfiguration, Box<dyn Error>> {
+  let config =
+    fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+  let format = ProtocolConfigurationFormat::from_path(path)?;
+  load_protocol_config(&config, format, skip_version_check)
+    .map_err(|err| format!("{}: {}", path.display(), err).into())
+}
+
+/// Prints all issues found, returning whether the run should fail.
+fn run(options: &Options) -> Result<bool, Box<dyn Error>> {
+  let main_config = match &options.device_config {
+    Some(path) => load_config(path, false)?,
+    None => load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON, false)?,
+  };
+  let mut issues = lint_protocol_configuration(&main_config);
+  if let Some(path) = &options.user_device_config {
+    // Same as the server, user configs aren't held to the version check.
+    let user_config = load_config(path, true)?;
+    issues.extend(lint_user_configuration(&main_config, &user_config));
+    if options.diff {
+      let mut merged = main_config.clone();
+      merged.merge(user_config);
+      let changes = diff_protocol_configuration(&main_config, &merged);
+      if changes.is_empty() {
+        println!("User configuration makes no changes.");
+      }
+      for change in changes {
+        println!("{}", change);
+      }
+    }
+  }
+
+  let errors = issues
+    .iter()
+    .filter(|issue| issue.severity == ConfigurationIssueSeverity::Error)
+    .count();
+  let warnings = issues.len() - errors;
+  for issue in &issues {
+    eprintln!("{}", issue);
+  }
+  eprintln!("{} errors, {} warnings", errors, warnings);
+  Ok(errors > 0 || (options.deny_warnings && warnings > 0))
+}
+
+fn main() -> ExitCode {
+  match run(&Options::parse()) {
+    Ok(false) => ExitCode::SUCCESS,
+    Ok(true) => ExitCode::FAILURE,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      ExitCode::FAILURE
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::Options;
+  use clap::{CommandFactory, Parser};
+
+  #[test]
+  fn test_options() {
+    Options::command().debug_assert();
+    assert!(Options::try_parse_from(["buttplug-config-lint", "--diff"]).is_err());
+  }
+}
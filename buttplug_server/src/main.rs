@@ -0,0 +1,426 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Standalone Buttplug websocket server.
+//!
+//! Builds a server out of command line options, then serves any number of
+//! websocket clients at once until interrupted with Ctrl-C, at which point all
+//! devices are stopped before exiting.
+
+use buttplug::{
+  connector::{
+    ButtplugRemoteServerConnector,
+    ButtplugWebsocketServerTlsIdentity,
+    ButtplugWebsocketServerTransportBuilder,
+  },
+  core::messages::{serializer::ButtplugServerJSONSerializer, StopAllDevices},
+  server::{ButtplugRemoteServer, ButtplugServerBuilder, ButtplugServerError},
+  util::device_configuration::{check_user_config_save_path, ProtocolConfigurationFormat},
+};
+use clap::{ArgGroup, Parser};
+use std::{error::Error, fs, path::PathBuf, process::ExitCode, time::Duration};
+use tracing::Level;
+
+#[derive(Parser, Debug)]
+#[command(
+  name = "buttplug-server",
+  version,
+  about = "Standalone Buttplug websocket server",
+  group(
+    ArgGroup::new("config_files")
+      .args(["device_config", "user_device_config"])
+      .multiple(true)
+  )
+)]
+struct Options {
+  /// Name the server reports to clients.
+  #[arg(long, default_value = "Buttplug Server")]
+  server_name: String,
+
+  /// Port to listen for insecure (ws://) client connections on.
+  #[arg(long, default_value_t = 12345)]
+  port: u16,
+
+  /// Port to listen for secure (wss://) client connections on. Requires
+  /// --cert and --key.
+  #[arg(long, requires_all = ["cert", "key"])]
+  secure_port: Option<u16>,
+
+  /// PEM certificate chain for the secure port.
+  #[arg(long)]
+  cert: Option<PathBuf>,
+
+  /// PEM private key for the secure port.
+  #[arg(long)]
+  key: Option<PathBuf>,
+
+  /// Only listen on the secure port.
+  #[arg(long, requires = "secure_port")]
+  secure_only: bool,
+
+  /// Listen on all network interfaces, instead of only localhost.
+  #[arg(long)]
+  all_interfaces: bool,
+
+  /// Maximum time between client pings, in milliseconds. 0 disables the ping
+  /// timer.
+  #[arg(long, default_value_t = 0)]
+  ping_time: u32,
+
+  /// Allow clients to send raw device messages.
+  #[arg(long)]
+  allow_raw: bool,
+
+  /// Token clients have to send during the handshake.
+  #[arg(long)]
+  auth_token: Option<String>,
+
+  /// Device configuration file, to use instead of the built in one. Files
+  /// ending in .yml/.yaml or .toml are read as YAML or TOML, anything else as
+  /// JSON.
+  #[arg(long)]
+  device_config: Option<PathBuf>,
+
+  /// User device configuration file, in any of the device configuration
+  /// formats.
+  #[arg(long)]
+  user_device_config: Option<PathBuf>,
+
+  /// Reload the device configuration files whenever they change. Changes to
+  /// the user configuration apply to connected devices too.
+  #[arg(long, requires = "config_files")]
+  watch_config: bool,
+
+  /// JSON file to remember device indexes in, so devices keep the same index
+  /// across server restarts. Created if it doesn't exist.
+  #[arg(long)]
+  device_index_store: Option<PathBuf>,
+
+  /// Record traffic with every device to a file in this directory, for
+  /// reproducing protocol bugs without the hardware.
+  #[arg(long)]
+  device_recording_dir: Option<PathBuf>,
+
+  /// Let clients list known devices and change their display names and
+  /// allow/deny entries. Changes are written back to --user-device-config if
+  /// it's a JSON file, and only kept until the server exits otherwise. Use
+  /// with --auth-token.
+  #[arg(long)]
+  allow_device_config_messages: bool,
+
+  /// Protocol script to use for devices of a protocol, as NAME=FILE. NAME is
+  /// the protocol's name in the device configuration. Can be given more than
+  /// once.
+  #[cfg(feature = "scripted-protocols")]
+  #[arg(long, value_name = "NAME=FILE", value_parser = parse_protocol_script)]
+  protocol_script: Vec<(String, PathBuf)>,
+
+  /// Find Bluetooth LE devices.
+  #[cfg(feature = "btleplug-manager")]
+  #[arg(long)]
+  use_bluetooth_le: bool,
+
+  /// Find devices connected over serial ports.
+  #[cfg(feature = "serial-manager")]
+  #[arg(long)]
+  use_serial: bool,
+
+  /// Find devices through a Lovense HID dongle.
+  #[cfg(feature = "lovense-dongle-manager")]
+  #[arg(long)]
+  use_lovense_dongle_hid: bool,
+
+  /// Find devices through a Lovense serial dongle.
+  #[cfg(feature = "lovense-dongle-manager")]
+  #[arg(long)]
+  use_lovense_dongle_serial: bool,
+
+  /// Find devices through the Lovense Connect app.
+  #[cfg(feature = "lovense-connect-service-manager")]
+  #[arg(long)]
+  use_lovense_connect: bool,
+
+  /// Find XInput gamepads (Windows only).
+  #[cfg(feature = "xinput-manager")]
+  #[arg(long)]
+  use_xinput: bool,
+
+  /// Accept devices connecting over websockets.
+  #[cfg(feature = "websocket-server-manager")]
+  #[arg(long)]
+  use_device_websocket_server: bool,
+
+  /// Port to accept websocket devices on.
+  #[cfg(feature = "websocket-server-manager")]
+  #[arg(long, requires = "use_device_websocket_server")]
+  device_websocket_server_port: Option<u16>,
+
+  /// Log level (error, warn, info, debug, trace).
+  #[arg(long, default_value_t = Level::INFO)]
+  log: Level,
+}
+
+/// How often to check config files for changes, with --watch-config.
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before listening again after a client connection fails,
+/// so a persistent failure doesn't spin.
+const CONNECTION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads a config file, picking its format from the file extension.
+fn read_config(
+  path: &Option<PathBuf>,
+) -> Result<Option<(String, ProtocolConfigurationFormat)>, Box<dyn Error>> {
+  path
+    .as_ref()
+    .map(|path| {
+      let format = ProtocolConfigurationFormat::from_path(path)?;
+      fs::read_to_string(path)
+        .map(|config| (config, format))
+        .map_err(|err| format!("Cannot read {}: {}", path.display(), err).into())
+    })
+    .transpose()
+}
+
+#[cfg(feature = "scripted-protocols")]
+fn parse_protocol_script(arg: &str) -> Result<(String, PathBuf), String> {
+  match arg.split_once('=') {
+    Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+      Ok((name.to_owned(), PathBuf::from(path)))
+    }
+    _ => Err("Expected NAME=FILE".to_owned()),
+  }
+}
+
+// Everything in here is optional, so with no comm manager features enabled
+// nothing gets used.
+#[allow(unused_variables)]
+fn add_comm_managers(
+  options: &Options,
+  server: &ButtplugRemoteServer,
+) -> Result<(), ButtplugServerError> {
+  let device_manager = server.device_manager();
+  #[cfg(feature = "btleplug-manager")]
+  if options.use_bluetooth_le {
+    use buttplug::server::comm_managers::btleplug::BtlePlugCommunicationManagerBuilder;
+    device_manager.add_comm_manager(BtlePlugCommunicationManagerBuilder::default())?;
+  }
+  #[cfg(feature = "serial-manager")]
+  if options.use_serial {
+    use buttplug::server::comm_managers::serialport::SerialPortCommunicationManagerBuilder;
+    device_manager.add_comm_manager(SerialPortCommunicationManagerBuilder::default())?;
+  }
+  #[cfg(feature = "lovense-dongle-manager")]
+  {
+    use buttplug::server::comm_managers::lovense_dongle::{
+      LovenseHIDDongleCommunicationManagerBuilder,
+      LovenseSerialDongleCommunicationManagerBuilder,
+    };
+    if options.use_lovense_dongle_hid {
+      device_manager.add_comm_manager(LovenseHIDDongleCommunicationManagerBuilder::default())?;
+    }
+    if options.use_lovense_dongle_serial {
+      device_manager.add_comm_manager(LovenseSerialDongleCommunicationManagerBuilder::default())?;
+    }
+  }
+  #[cfg(feature = "lovense-connect-service-manager")]
+  if options.use_lovense_connect {
+    use buttplug::server::comm_managers::lovense_connect_service::LovenseConnectServiceCommunicationManagerBuilder;
+    device_manager.add_comm_manager(LovenseConnectServiceCommunicationManagerBuilder::default())?;
+  }
+  #[cfg(feature = "xinput-manager")]
+  if options.use_xinput {
+    #[cfg(target_os = "windows")]
+    {
+      use buttplug::server::comm_managers::xinput::XInputDeviceCommunicationManagerBuilder;
+      device_manager.add_comm_manager(XInputDeviceCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(not(target_os = "windows"))]
+    tracing::warn!("XInput is only available on Windows, ignoring --use-xinput.");
+  }
+  #[cfg(feature = "websocket-server-manager")]
+  if options.use_device_websocket_server {
+    use buttplug::server::comm_managers::websocket_server::websocket_server_comm_manager::WebsocketServerDeviceCommunicationManagerBuilder;
+    let mut builder = WebsocketServerDeviceCommunicationManagerBuilder::default()
+      .listen_on_all_interfaces(options.all_interfaces);
+    if let Some(port) = options.device_websocket_server_port {
+      builder = builder.server_port(port);
+    }
+    device_manager.add_comm_manager(builder)?;
+  }
+  Ok(())
+}
+
+fn build_server(options: &Options) -> Result<ButtplugRemoteServer, Box<dyn Error>> {
+  let mut builder = ButtplugServerBuilder::default();
+  builder
+    .name(&options.server_name)
+    .max_ping_time(options.ping_time)
+    .allow_raw_messages(options.allow_raw);
+  if let Some((config, format)) = read_config(&options.device_config)? {
+    builder.device_configuration(Some(config), format);
+  }
+  if let Some((config, format)) = read_config(&options.user_device_config)? {
+    builder.user_device_configuration(Some(config), format);
+  }
+  if let Some(token) = &options.auth_token {
+    builder.auth_token(token);
+  }
+  if let Some(path) = &options.device_index_store {
+    builder.device_index_store(path.clone());
+  }
+  if let Some(path) = &options.device_recording_dir {
+    builder.device_recording_directory(path.clone());
+  }
+  if options.allow_device_config_messages {
+    builder.allow_device_configuration_messages(true);
+    if let Some(path) = &options.user_device_config {
+      match check_user_config_save_path(path) {
+        Ok(()) => {
+          builder.user_device_configuration_save_path(path.clone());
+        }
+        Err(err) => tracing::warn!("{} Device configuration changes won't be saved.", err),
+      }
+    }
+  }
+  let server = builder.finish()?;
+  #[cfg(feature = "scripted-protocols")]
+  for (name, path) in &options.protocol_script {
+    let script =
+      fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    server.device_manager().add_protocol_script(name, &script)?;
+  }
+  if options.watch_config {
+    server.watch_device_configuration(
+      options.device_config.clone(),
+      options.user_device_config.clone(),
+      CONFIG_WATCH_INTERVAL,
+    );
+  }
+  let server = ButtplugRemoteServer::new(server);
+  add_comm_managers(options, &server)?;
+  Ok(server)
+}
+
+fn build_transport(
+  options: &Options,
+) -> Result<ButtplugWebsocketServerTransportBuilder, Box<dyn Error>> {
+  let mut builder = ButtplugWebsocketServerTransportBuilder::default();
+  builder
+    .listen_on_all_interfaces(options.all_interfaces)
+    .port(options.port);
+  if let (Some(port), Some(cert), Some(key)) = (options.secure_port, &options.cert, &options.key) {
+    builder.secure_port(
+      port,
+      ButtplugWebsocketServerTlsIdentity::from_pem_files(cert, key)?,
+    );
+    if options.secure_only {
+      builder.disable_insecure_port();
+    }
+  }
+  Ok(builder)
+}
+
+/// Serves clients forever, each in its own task, listening for the next client
+/// as soon as one connects. Only returns if the transport can't be set up at
+/// all, which shows up as the first connection failing. The transport keeps
+/// listening through bad client handshakes, so failures after that are logged
+/// and retried instead of taking the daemon down.
+async fn serve(
+  server: &ButtplugRemoteServer,
+  transport: &ButtplugWebsocketServerTransportBuilder,
+) -> Result<(), Box<dyn Error>> {
+  let mut accepted_client = false;
+  loop {
+    tracing::info!("Waiting for client connection.");
+    match server
+      .accept(ButtplugRemoteServerConnector::<
+        _,
+        ButtplugServerJSONSerializer,
+      >::new(transport.finish()))
+      .await
+    {
+      Ok(connection) => {
+        accepted_client = true;
+        tracing::info!("Client connected.");
+        tokio::spawn(async move {
+          connection.await;
+          tracing::info!("Client disconnected.");
+        });
+      }
+      Err(err) if !accepted_client => return Err(format!("Cannot run server: {}", err).into()),
+      Err(err) => {
+        tracing::error!("Cannot accept client connection: {}", err);
+        tokio::time::sleep(CONNECTION_RETRY_INTERVAL).await;
+      }
+    }
+  }
+}
+
+async fn run(options: Options) -> Result<(), Box<dyn Error>> {
+  let server = build_server(&options)?;
+  let transport = build_transport(&options)?;
+  let result = tokio::select! {
+    result = serve(&server, &transport) => result,
+    _ = tokio::signal::ctrl_c() => {
+      tracing::info!("Received Ctrl-C, shutting down.");
+      Ok(())
+    }
+  };
+  if let Err(err) = server
+    .device_manager()
+    .parse_message(StopAllDevices::default().into())
+    .await
+  {
+    tracing::error!("Cannot stop devices during shutdown: {:?}", err);
+  }
+  server.disconnect().await?;
+  result
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+  let options = Options::parse();
+  tracing_subscriber::fmt().with_max_level(options.log).init();
+  if let Err(err) = run(options).await {
+    tracing::error!("{}", err);
+    return ExitCode::FAILURE;
+  }
+  ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+  use super::Options;
+  use clap::{CommandFactory, Parser};
+  #[cfg(feature = "scripted-protocols")]
+  use std::path::PathBuf;
+
+  #[test]
+  fn test_options() {
+    Options::command().debug_assert();
+    let options = Options::parse_from(["buttplug-server", "--port", "12346", "--log", "debug"]);
+    assert_eq!(options.port, 12346);
+    assert_eq!(options.log, tracing::Level::DEBUG);
+    assert!(Options::try_parse_from(["buttplug-server", "--secure-port", "12347"]).is_err());
+    assert!(Options::try_parse_from(["buttplug-server", "--watch-config"]).is_err());
+    #[cfg(feature = "scripted-protocols")]
+    {
+      let options = Options::parse_from([
+        "buttplug-server",
+        "--protocol-script",
+        "my-vibe=my-vibe.rhai",
+      ]);
+      assert_eq!(
+        options.protocol_script,
+        vec![("my-vibe".to_owned(), PathBuf::from("my-vibe.rhai"))]
+      );
+      assert!(
+        Options::try_parse_from(["buttplug-server", "--protocol-script", "my-vibe"]).is_err()
+      );
+    }
+  }
+}